@@ -0,0 +1,190 @@
+//! Lua 5.1 abstract syntax tree. Every statement and expression carries the
+//! span of source it was parsed from.
+
+/// Byte range of a node in the source, with the lines it starts and ends on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: u32,
+    pub end_line: u32,
+}
+impl Span {
+    /// The span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span { start: self.start, end: other.end, line: self.line, end_line: other.end_line }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub block: Block,
+    /// Name as it appears in error messages, e.g. `test.lua`.
+    pub name: String,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stats: Vec<Stat>,
+    pub span: Span,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub kind: StatKind,
+    pub span: Span,
+}
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum StatKind {
+    /// `local a, b = ...`
+    Local { names: Vec<Name>, exprs: Vec<Expr> },
+    /// `a, b.c, d[e] = ...`; every target is a `Name` or `Index`.
+    Assign { targets: Vec<Expr>, exprs: Vec<Expr> },
+    /// A function or method call used as a statement.
+    Call(Expr),
+    Do(Block),
+    While { cond: Expr, body: Block },
+    Repeat { body: Block, cond: Expr },
+    /// `if c1 then ... elseif c2 then ... else ... end`
+    If { clauses: Vec<(Expr, Block)>, else_block: Option<Block> },
+    NumericFor { var: Name, start: Expr, limit: Expr, step: Option<Expr>, body: Block },
+    GenericFor { names: Vec<Name>, exprs: Vec<Expr>, body: Block },
+    /// `function a.b.c:m() ... end`
+    Function { name: FuncName, body: FunctionBody },
+    LocalFunction { name: Name, body: FunctionBody },
+    Return(Vec<Expr>),
+    Break,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncName {
+    /// `a.b.c`: the first name and the fields after it.
+    pub path: Vec<Name>,
+    /// `:m`; the body then takes an implicit `self` first parameter.
+    pub method: Option<Name>,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionBody {
+    pub params: Vec<Name>,
+    pub is_vararg: bool,
+    pub block: Block,
+    /// From `function` to the closing `end`.
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Nil,
+    True,
+    False,
+    Number(f64),
+    /// Lua strings are byte strings; escapes like `\255` need not be UTF-8.
+    String(Vec<u8>),
+    Vararg,
+    Function(Box<FunctionBody>),
+    Table(Vec<TableField>),
+    Binary { op: BinOp, lhs: Box<Expr>, rhs: Box<Expr> },
+    Unary { op: UnOp, expr: Box<Expr> },
+    Name(String),
+    /// `obj[key]`, and `obj.name` with a string key.
+    Index { obj: Box<Expr>, key: Box<Expr> },
+    Call { func: Box<Expr>, args: Vec<Expr> },
+    MethodCall { obj: Box<Expr>, method: Name, args: Vec<Expr> },
+    /// A parenthesised expression, which truncates calls and `...` to one value.
+    Paren(Box<Expr>),
+}
+impl ExprKind {
+    /// Calls and `...`, which produce a variable number of values at the end
+    /// of an expression list.
+    pub fn is_multi(&self) -> bool {
+        matches!(self, ExprKind::Call { .. } | ExprKind::MethodCall { .. } | ExprKind::Vararg)
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableField {
+    /// `{ x }`
+    Positional(Expr),
+    /// `{ name = x }`
+    Named(Name, Expr),
+    /// `{ [k] = x }`
+    Keyed(Expr, Expr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+impl BinOp {
+    /// Left and right binding priorities, as in lparser.c. A right priority
+    /// lower than the left one makes the operator right-associative.
+    pub fn priority(&self) -> (u8, u8) {
+        match self {
+            BinOp::Add | BinOp::Sub => (6, 6),
+            BinOp::Mul | BinOp::Div | BinOp::Mod => (7, 7),
+            BinOp::Pow => (10, 9),
+            BinOp::Concat => (5, 4),
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => (3, 3),
+            BinOp::And => (2, 2),
+            BinOp::Or => (1, 1),
+        }
+    }
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Pow => "^",
+            BinOp::Concat => "..",
+            BinOp::Eq => "==",
+            BinOp::Ne => "~=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+        }
+    }
+}
+/// Priority of the unary operators, binding tighter than everything but `^`.
+pub const UNARY_PRIORITY: u8 = 8;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,
+    Len,
+}
+impl UnOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnOp::Neg => "-",
+            UnOp::Not => "not",
+            UnOp::Len => "#",
+        }
+    }
+}
@@ -0,0 +1,1499 @@
+//! Code generator turning the AST into `FunctionBlock`s. It follows lcode.c
+//! and the code-emitting half of lparser.c step for step, so that registers,
+//! constants, jumps and line info come out the way `luac` produces them.
+
+use std::collections::HashMap;
+
+use gc::Gc;
+
+use super::{ast::*, SyntaxError};
+use crate::vm::{
+    chunk_parser::{FunctionBlock, LocalVar, LuaConstant, VARARG_HASARG, VARARG_ISVARARG, VARARG_NEEDSARG},
+    instruction::{
+        arg_a, arg_b, arg_c, arg_sbx, create_abc, create_abx, op_of, set_a, set_b, set_c, set_op, set_sbx, VMInst,
+        VMOpcode, MAXARG_SBX,
+    },
+    FIELDS_PER_FLUSH,
+};
+
+type Result<T> = std::result::Result<T, SyntaxError>;
+
+/// End of a jump list; a jump with this offset points to itself.
+const NO_JUMP: i32 = -1;
+/// `A` of a TESTSET whose value is not needed.
+const NO_REG: u32 = 255;
+const MULTRET: i32 = -1;
+/// Marks a constant index in an RK operand.
+const BIT_RK: u32 = 1 << 8;
+const MAX_INDEX_RK: u32 = BIT_RK - 1;
+const MAXARG_C: u32 = 511;
+const MAX_STACK: u32 = 250;
+const MAX_VARS: usize = 200;
+const MAX_UPVALUES: usize = 60;
+
+/// Instructions that are followed by the jump they guard.
+fn is_test(op: VMOpcode) -> bool {
+    matches!(op, VMOpcode::EQ | VMOpcode::LT | VMOpcode::LE | VMOpcode::TEST | VMOpcode::TESTSET | VMOpcode::TFORLOOP)
+}
+/// Encodes a table size hint as the "floating point byte" NEWTABLE takes.
+fn int2fb(mut x: u32) -> u32 {
+    let mut e = 0;
+    while x >= 16 {
+        x = (x + 1) >> 1;
+        e += 1;
+    }
+    if x < 8 {
+        x
+    } else {
+        ((e + 1) << 3) | (x - 8)
+    }
+}
+
+/// Where the value of an expression is, as far as code has been emitted for it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExpKind {
+    /// No value, as for an empty expression list.
+    Void,
+    Nil,
+    True,
+    False,
+    /// Constant at the index.
+    K(u32),
+    /// Number constant not yet in the constant table.
+    KNum(f64),
+    /// Local variable in the register.
+    Local(u32),
+    Upval(u32),
+    /// Global named by the string constant at the index.
+    Global(u32),
+    /// Table register and RK key.
+    Indexed(u32, u32),
+    /// Comparison; the pc is that of its jump.
+    Jmp(usize),
+    /// Result of the instruction at the pc, whose target register is still open.
+    Relocable(usize),
+    /// Value sitting in the register.
+    NonReloc(u32),
+    /// Open call at the pc.
+    Call(usize),
+    /// Open VARARG at the pc.
+    Vararg(usize),
+}
+#[derive(Debug, Clone, Copy)]
+struct ExpDesc {
+    k: ExpKind,
+    /// Jumps to patch when the expression is true.
+    t: i32,
+    /// Jumps to patch when the expression is false.
+    f: i32,
+}
+impl ExpDesc {
+    fn new(k: ExpKind) -> Self {
+        Self { k, t: NO_JUMP, f: NO_JUMP }
+    }
+    fn has_jumps(&self) -> bool {
+        self.t != self.f
+    }
+    fn is_numeral(&self) -> bool {
+        matches!(self.k, ExpKind::KNum(_)) && self.t == NO_JUMP && self.f == NO_JUMP
+    }
+    fn has_multret(&self) -> bool {
+        matches!(self.k, ExpKind::Call(_) | ExpKind::Vararg(_))
+    }
+    fn reg(&self) -> u32 {
+        match self.k {
+            ExpKind::NonReloc(r) => r,
+            k => unreachable!("expression not in a register: {:?}", k),
+        }
+    }
+}
+
+#[derive(Hash, PartialEq, Eq)]
+enum ConstKey {
+    Nil,
+    Boolean(bool),
+    Number(u64),
+    String(Vec<u8>),
+}
+struct BlockCnt {
+    break_list: i32,
+    /// Active locals outside the block.
+    nactvar: u32,
+    /// Whether some local of the block is captured as an upvalue.
+    upval: bool,
+    breakable: bool,
+}
+struct UpvalDesc {
+    name: String,
+    /// Captures a local of the enclosing function rather than one of its upvalues.
+    local: bool,
+    index: u32,
+}
+struct FuncState {
+    code: Vec<u32>,
+    lines: Vec<u32>,
+    constants: Vec<LuaConstant>,
+    const_index: HashMap<ConstKey, u32>,
+    protos: Vec<FunctionBlock>,
+    upvalues: Vec<UpvalDesc>,
+    local_vars: Vec<LocalVar>,
+    /// Indices into `local_vars` of the active locals; one per register.
+    active: Vec<usize>,
+    /// Locals declared but not active yet.
+    declared: Vec<usize>,
+    blocks: Vec<BlockCnt>,
+    free_reg: u32,
+    max_stack: u32,
+    /// Jumps to the next instruction emitted.
+    jpc: i32,
+    last_target: i32,
+    num_params: u8,
+    is_vararg: u8,
+    line_defined: u32,
+    last_line_defined: u32,
+}
+impl FuncState {
+    fn new(line_defined: u32) -> Self {
+        Self {
+            code: Vec::new(),
+            lines: Vec::new(),
+            constants: Vec::new(),
+            const_index: HashMap::new(),
+            protos: Vec::new(),
+            upvalues: Vec::new(),
+            local_vars: Vec::new(),
+            active: Vec::new(),
+            declared: Vec::new(),
+            blocks: Vec::new(),
+            free_reg: 0,
+            // registers 0 and 1 are always valid
+            max_stack: 2,
+            jpc: NO_JUMP,
+            last_target: -1,
+            num_params: 0,
+            is_vararg: 0,
+            line_defined,
+            last_line_defined: 0,
+        }
+    }
+    fn pc(&self) -> i32 {
+        self.code.len() as i32
+    }
+    fn nactvar(&self) -> u32 {
+        self.active.len() as u32
+    }
+}
+
+/// Compiles a parsed chunk into its main function. `source` is the chunk name
+/// recorded in the bytecode, e.g. `@test.lua`.
+pub fn generate(chunk: &Chunk, source: &str) -> Result<FunctionBlock> {
+    let mut gen = CodeGen { funcs: Vec::new(), line: 1, chunk: chunk.name.clone(), source: source.to_string() };
+    gen.funcs.push(FuncState::new(0));
+    gen.fs().is_vararg = VARARG_ISVARARG;
+    gen.chunk(&chunk.block)?;
+    gen.mark(chunk.block.span.end_line);
+    Ok(gen.close_func()?.0)
+}
+
+struct CodeGen {
+    funcs: Vec<FuncState>,
+    /// Line of the last token the parser would have consumed; instructions
+    /// are attributed to it.
+    line: u32,
+    chunk: String,
+    source: String,
+}
+impl CodeGen {
+    fn fs(&mut self) -> &mut FuncState {
+        self.funcs.last_mut().unwrap()
+    }
+    fn mark(&mut self, line: u32) {
+        self.line = self.line.max(line);
+    }
+    fn error(&self, msg: &str) -> SyntaxError {
+        SyntaxError::new(&self.chunk, self.line, msg.to_string())
+    }
+    /// Checks a limit of the function at `level`, where the main function is 0.
+    fn check_limit(&self, level: usize, v: usize, limit: usize, what: &str) -> Result<()> {
+        if v <= limit {
+            return Ok(());
+        }
+        let msg = match self.funcs[level].line_defined {
+            0 => format!("main function has more than {} {}", limit, what),
+            line => format!("function at line {} has more than {} {}", line, limit, what),
+        };
+        Err(self.error(&msg))
+    }
+
+    // Emitting instructions
+
+    fn code(&mut self, i: u32) -> usize {
+        self.discharge_jpc();
+        let line = self.line;
+        let fs = self.fs();
+        fs.code.push(i);
+        fs.lines.push(line);
+        fs.code.len() - 1
+    }
+    fn code_abc(&mut self, op: VMOpcode, a: u32, b: u32, c: u32) -> usize {
+        self.code(create_abc(op, a, b, c))
+    }
+    fn code_abx(&mut self, op: VMOpcode, a: u32, bx: u32) -> usize {
+        self.code(create_abx(op, a, bx))
+    }
+    fn code_asbx(&mut self, op: VMOpcode, a: u32, sbx: i32) -> usize {
+        self.code(create_abx(op, a, (sbx + MAXARG_SBX) as u32))
+    }
+    /// Attributes the last instruction to `line`.
+    fn fix_line(&mut self, line: u32) {
+        *self.fs().lines.last_mut().unwrap() = line;
+    }
+    fn nil(&mut self, from: u32, n: u32) {
+        let fs = self.fs();
+        // no jumps to the current position?
+        if fs.pc() > fs.last_target {
+            if fs.code.is_empty() {
+                if from >= fs.nactvar() {
+                    // registers start out nil
+                    return;
+                }
+            } else {
+                let previous = fs.code.last_mut().unwrap();
+                if op_of(*previous) == VMOpcode::LOADNIL {
+                    let (pfrom, pto) = (arg_a(*previous), arg_b(*previous));
+                    if pfrom <= from && from <= pto + 1 {
+                        if from + n - 1 > pto {
+                            set_b(previous, from + n - 1);
+                        }
+                        return;
+                    }
+                }
+            }
+        }
+        self.code_abc(VMOpcode::LOADNIL, from, from + n - 1, 0);
+    }
+    fn ret(&mut self, first: u32, nret: i32) {
+        self.code_abc(VMOpcode::RETURN, first, (nret + 1) as u32, 0);
+    }
+    fn set_list(&mut self, base: u32, nelems: u32, tostore: i32) {
+        let c = (nelems - 1) / FIELDS_PER_FLUSH + 1;
+        let b = if tostore == MULTRET { 0 } else { tostore as u32 };
+        if c <= MAXARG_C {
+            self.code_abc(VMOpcode::SETLIST, base, b, c);
+        } else {
+            self.code_abc(VMOpcode::SETLIST, base, b, 0);
+            self.code(c);
+        }
+        self.fs().free_reg = base + 1;
+    }
+
+    // Jump lists
+
+    fn jump(&mut self) -> Result<i32> {
+        let jpc = std::mem::replace(&mut self.fs().jpc, NO_JUMP);
+        let mut j = self.code_asbx(VMOpcode::JMP, 0, NO_JUMP) as i32;
+        self.concat(&mut j, jpc)?;
+        Ok(j)
+    }
+    fn cond_jump(&mut self, op: VMOpcode, a: u32, b: u32, c: u32) -> Result<i32> {
+        self.code_abc(op, a, b, c);
+        self.jump()
+    }
+    fn fix_jump(&mut self, pc: i32, dest: i32) -> Result<()> {
+        let offset = dest - (pc + 1);
+        if offset.abs() > MAXARG_SBX {
+            return Err(self.error("control structure too long"));
+        }
+        set_sbx(&mut self.fs().code[pc as usize], offset);
+        Ok(())
+    }
+    /// Marks the current pc as a jump target and returns it.
+    fn get_label(&mut self) -> i32 {
+        let fs = self.fs();
+        fs.last_target = fs.pc();
+        fs.last_target
+    }
+    fn get_jump(&mut self, pc: i32) -> i32 {
+        let offset = arg_sbx(self.fs().code[pc as usize]);
+        if offset == NO_JUMP {
+            NO_JUMP
+        } else {
+            pc + 1 + offset
+        }
+    }
+    /// The instruction controlling the jump at `pc`: its test, if it has one.
+    fn jump_control(&mut self, pc: i32) -> usize {
+        let pc = pc as usize;
+        if pc >= 1 && is_test(op_of(self.fs().code[pc - 1])) {
+            pc - 1
+        } else {
+            pc
+        }
+    }
+    /// Whether some jump in the list is not a TESTSET, so needs a value loaded.
+    fn need_value(&mut self, mut list: i32) -> bool {
+        while list != NO_JUMP {
+            let control = self.jump_control(list);
+            if op_of(self.fs().code[control]) != VMOpcode::TESTSET {
+                return true;
+            }
+            list = self.get_jump(list);
+        }
+        false
+    }
+    fn patch_test_reg(&mut self, node: i32, reg: u32) -> bool {
+        let control = self.jump_control(node);
+        let i = &mut self.fs().code[control];
+        if op_of(*i) != VMOpcode::TESTSET {
+            return false;
+        }
+        if reg != NO_REG && reg != arg_b(*i) {
+            set_a(i, reg);
+        } else {
+            // no register to put the value in, or it is already there
+            *i = create_abc(VMOpcode::TEST, arg_b(*i), 0, arg_c(*i));
+        }
+        true
+    }
+    fn remove_values(&mut self, mut list: i32) {
+        while list != NO_JUMP {
+            self.patch_test_reg(list, NO_REG);
+            list = self.get_jump(list);
+        }
+    }
+    fn patch_list_aux(&mut self, mut list: i32, vtarget: i32, reg: u32, dtarget: i32) -> Result<()> {
+        while list != NO_JUMP {
+            let next = self.get_jump(list);
+            if self.patch_test_reg(list, reg) {
+                self.fix_jump(list, vtarget)?;
+            } else {
+                self.fix_jump(list, dtarget)?;
+            }
+            list = next;
+        }
+        Ok(())
+    }
+    fn discharge_jpc(&mut self) {
+        let fs = self.fs();
+        let (jpc, pc) = (fs.jpc, fs.pc());
+        fs.jpc = NO_JUMP;
+        // offsets to the current pc always fit
+        self.patch_list_aux(jpc, pc, NO_REG, pc).unwrap();
+    }
+    fn patch_list(&mut self, list: i32, target: i32) -> Result<()> {
+        if target == self.fs().pc() {
+            self.patch_to_here(list)
+        } else {
+            self.patch_list_aux(list, target, NO_REG, target)
+        }
+    }
+    fn patch_to_here(&mut self, list: i32) -> Result<()> {
+        self.get_label();
+        let mut jpc = self.fs().jpc;
+        self.concat(&mut jpc, list)?;
+        self.fs().jpc = jpc;
+        Ok(())
+    }
+    fn concat(&mut self, l1: &mut i32, l2: i32) -> Result<()> {
+        if l2 == NO_JUMP {
+            return Ok(());
+        }
+        if *l1 == NO_JUMP {
+            *l1 = l2;
+            return Ok(());
+        }
+        let mut list = *l1;
+        loop {
+            let next = self.get_jump(list);
+            if next == NO_JUMP {
+                break;
+            }
+            list = next;
+        }
+        self.fix_jump(list, l2)
+    }
+
+    // Registers and constants
+
+    fn check_stack(&mut self, n: u32) -> Result<()> {
+        let fs = self.fs();
+        let new_stack = fs.free_reg + n;
+        if new_stack > fs.max_stack {
+            if new_stack >= MAX_STACK {
+                return Err(self.error("function or expression too complex"));
+            }
+            fs.max_stack = new_stack;
+        }
+        Ok(())
+    }
+    fn reserve_regs(&mut self, n: u32) -> Result<()> {
+        self.check_stack(n)?;
+        self.fs().free_reg += n;
+        Ok(())
+    }
+    fn free_register(&mut self, reg: u32) {
+        let fs = self.fs();
+        if reg & BIT_RK == 0 && reg >= fs.nactvar() {
+            fs.free_reg -= 1;
+            debug_assert_eq!(reg, fs.free_reg);
+        }
+    }
+    fn free_exp(&mut self, e: &ExpDesc) {
+        if let ExpKind::NonReloc(r) = e.k {
+            self.free_register(r);
+        }
+    }
+    fn add_k(&mut self, key: ConstKey, value: LuaConstant) -> u32 {
+        let fs = self.fs();
+        if let Some(idx) = fs.const_index.get(&key) {
+            return *idx;
+        }
+        let idx = fs.constants.len() as u32;
+        fs.constants.push(value);
+        fs.const_index.insert(key, idx);
+        idx
+    }
+    fn string_k(&mut self, s: &[u8]) -> u32 {
+        let value = LuaConstant::LUA_TSTRING(s.into());
+        self.add_k(ConstKey::String(s.to_vec()), value)
+    }
+    fn number_k(&mut self, n: f64) -> u32 {
+        // 0 and -0 are the same table key
+        let bits = if n == 0.0 { 0 } else { n.to_bits() };
+        self.add_k(ConstKey::Number(bits), LuaConstant::LUA_TNUMBER(n))
+    }
+    fn bool_k(&mut self, b: bool) -> u32 {
+        self.add_k(ConstKey::Boolean(b), LuaConstant::LUA_TBOOLEAN(b))
+    }
+    fn nil_k(&mut self) -> u32 {
+        self.add_k(ConstKey::Nil, LuaConstant::LUA_TNIL)
+    }
+
+    // Discharging expressions
+
+    fn set_returns(&mut self, e: &mut ExpDesc, nresults: i32) -> Result<()> {
+        match e.k {
+            ExpKind::Call(pc) => set_c(&mut self.fs().code[pc], (nresults + 1) as u32),
+            ExpKind::Vararg(pc) => {
+                let fs = self.fs();
+                let free = fs.free_reg;
+                set_b(&mut fs.code[pc], (nresults + 1) as u32);
+                set_a(&mut fs.code[pc], free);
+                self.reserve_regs(1)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    fn set_one_ret(&mut self, e: &mut ExpDesc) {
+        match e.k {
+            ExpKind::Call(pc) => e.k = ExpKind::NonReloc(arg_a(self.fs().code[pc])),
+            ExpKind::Vararg(pc) => {
+                set_b(&mut self.fs().code[pc], 2);
+                e.k = ExpKind::Relocable(pc);
+            }
+            _ => {}
+        }
+    }
+    fn discharge_vars(&mut self, e: &mut ExpDesc) {
+        match e.k {
+            ExpKind::Local(r) => e.k = ExpKind::NonReloc(r),
+            ExpKind::Upval(idx) => e.k = ExpKind::Relocable(self.code_abc(VMOpcode::GETUPVAL, 0, idx, 0)),
+            ExpKind::Global(k) => e.k = ExpKind::Relocable(self.code_abx(VMOpcode::GETGLOBAL, 0, k)),
+            ExpKind::Indexed(t, key) => {
+                self.free_register(key);
+                self.free_register(t);
+                e.k = ExpKind::Relocable(self.code_abc(VMOpcode::GETTABLE, 0, t, key));
+            }
+            ExpKind::Call(_) | ExpKind::Vararg(_) => self.set_one_ret(e),
+            _ => {}
+        }
+    }
+    fn code_label(&mut self, a: u32, b: u32, jump: u32) -> i32 {
+        // these may be jump targets
+        self.get_label();
+        self.code_abc(VMOpcode::LOADBOOL, a, b, jump) as i32
+    }
+    fn discharge2reg(&mut self, e: &mut ExpDesc, reg: u32) {
+        self.discharge_vars(e);
+        match e.k {
+            ExpKind::Nil => self.nil(reg, 1),
+            ExpKind::False | ExpKind::True => {
+                self.code_abc(VMOpcode::LOADBOOL, reg, (e.k == ExpKind::True) as u32, 0);
+            }
+            ExpKind::K(idx) => {
+                self.code_abx(VMOpcode::LOADK, reg, idx);
+            }
+            ExpKind::KNum(n) => {
+                let idx = self.number_k(n);
+                self.code_abx(VMOpcode::LOADK, reg, idx);
+            }
+            ExpKind::Relocable(pc) => set_a(&mut self.fs().code[pc], reg),
+            ExpKind::NonReloc(r) => {
+                if r != reg {
+                    self.code_abc(VMOpcode::MOVE, reg, r, 0);
+                }
+            }
+            // nothing to do for `Void` and `Jmp`
+            _ => return,
+        }
+        e.k = ExpKind::NonReloc(reg);
+    }
+    fn discharge2anyreg(&mut self, e: &mut ExpDesc) -> Result<()> {
+        if !matches!(e.k, ExpKind::NonReloc(_)) {
+            self.reserve_regs(1)?;
+            let reg = self.fs().free_reg - 1;
+            self.discharge2reg(e, reg);
+        }
+        Ok(())
+    }
+    fn exp2reg(&mut self, e: &mut ExpDesc, reg: u32) -> Result<()> {
+        self.discharge2reg(e, reg);
+        if let ExpKind::Jmp(pc) = e.k {
+            self.concat(&mut e.t, pc as i32)?;
+        }
+        if e.has_jumps() {
+            let mut p_f = NO_JUMP;
+            let mut p_t = NO_JUMP;
+            if self.need_value(e.t) || self.need_value(e.f) {
+                let fj = if matches!(e.k, ExpKind::Jmp(_)) { NO_JUMP } else { self.jump()? };
+                p_f = self.code_label(reg, 0, 1);
+                p_t = self.code_label(reg, 1, 0);
+                self.patch_to_here(fj)?;
+            }
+            let end = self.get_label();
+            self.patch_list_aux(e.f, end, reg, p_f)?;
+            self.patch_list_aux(e.t, end, reg, p_t)?;
+        }
+        e.t = NO_JUMP;
+        e.f = NO_JUMP;
+        e.k = ExpKind::NonReloc(reg);
+        Ok(())
+    }
+    fn exp2nextreg(&mut self, e: &mut ExpDesc) -> Result<()> {
+        self.discharge_vars(e);
+        self.free_exp(e);
+        self.reserve_regs(1)?;
+        let reg = self.fs().free_reg - 1;
+        self.exp2reg(e, reg)
+    }
+    fn exp2anyreg(&mut self, e: &mut ExpDesc) -> Result<u32> {
+        self.discharge_vars(e);
+        if let ExpKind::NonReloc(r) = e.k {
+            if !e.has_jumps() {
+                return Ok(r);
+            }
+            // not a local: the value can go in its register
+            if r >= self.fs().nactvar() {
+                self.exp2reg(e, r)?;
+                return Ok(r);
+            }
+        }
+        self.exp2nextreg(e)?;
+        Ok(e.reg())
+    }
+    fn exp2val(&mut self, e: &mut ExpDesc) -> Result<()> {
+        if e.has_jumps() {
+            self.exp2anyreg(e)?;
+        } else {
+            self.discharge_vars(e);
+        }
+        Ok(())
+    }
+    /// Makes `e` usable as an RK operand: a constant index or a register.
+    fn exp2rk(&mut self, e: &mut ExpDesc) -> Result<u32> {
+        self.exp2val(e)?;
+        match e.k {
+            ExpKind::KNum(_) | ExpKind::True | ExpKind::False | ExpKind::Nil
+                if self.fs().constants.len() as u32 <= MAX_INDEX_RK =>
+            {
+                let idx = match e.k {
+                    ExpKind::Nil => self.nil_k(),
+                    ExpKind::KNum(n) => self.number_k(n),
+                    k => self.bool_k(k == ExpKind::True),
+                };
+                e.k = ExpKind::K(idx);
+                return Ok(idx | BIT_RK);
+            }
+            ExpKind::K(idx) if idx <= MAX_INDEX_RK => return Ok(idx | BIT_RK),
+            _ => {}
+        }
+        self.exp2anyreg(e)
+    }
+    fn store_var(&mut self, var: &ExpDesc, ex: &mut ExpDesc) -> Result<()> {
+        match var.k {
+            ExpKind::Local(r) => {
+                self.free_exp(ex);
+                return self.exp2reg(ex, r);
+            }
+            ExpKind::Upval(idx) => {
+                let e = self.exp2anyreg(ex)?;
+                self.code_abc(VMOpcode::SETUPVAL, e, idx, 0);
+            }
+            ExpKind::Global(k) => {
+                let e = self.exp2anyreg(ex)?;
+                self.code_abx(VMOpcode::SETGLOBAL, e, k);
+            }
+            ExpKind::Indexed(t, key) => {
+                let e = self.exp2rk(ex)?;
+                self.code_abc(VMOpcode::SETTABLE, t, key, e);
+            }
+            k => unreachable!("cannot assign to {:?}", k),
+        }
+        self.free_exp(ex);
+        Ok(())
+    }
+    /// `obj:key`, leaving the method and `obj` in two fresh registers.
+    fn self_(&mut self, e: &mut ExpDesc, key: &mut ExpDesc) -> Result<()> {
+        let obj = self.exp2anyreg(e)?;
+        self.free_exp(e);
+        let func = self.fs().free_reg;
+        self.reserve_regs(2)?;
+        let k = self.exp2rk(key)?;
+        self.code_abc(VMOpcode::SELF, func, obj, k);
+        self.free_exp(key);
+        e.k = ExpKind::NonReloc(func);
+        Ok(())
+    }
+    fn indexed(&mut self, t: &mut ExpDesc, k: &mut ExpDesc) -> Result<()> {
+        let key = self.exp2rk(k)?;
+        t.k = ExpKind::Indexed(t.reg(), key);
+        Ok(())
+    }
+
+    // Conditions and operators
+
+    fn invert_jump(&mut self, e: &ExpDesc) {
+        let ExpKind::Jmp(pc) = e.k else { unreachable!() };
+        let control = self.jump_control(pc as i32);
+        let i = &mut self.fs().code[control];
+        set_a(i, (arg_a(*i) == 0) as u32);
+    }
+    fn jump_on_cond(&mut self, e: &mut ExpDesc, cond: bool) -> Result<i32> {
+        if let ExpKind::Relocable(pc) = e.k {
+            let ie = self.fs().code[pc];
+            if op_of(ie) == VMOpcode::NOT {
+                // test the operand of the NOT instead
+                let fs = self.fs();
+                fs.code.pop();
+                fs.lines.pop();
+                return self.cond_jump(VMOpcode::TEST, arg_b(ie), 0, !cond as u32);
+            }
+        }
+        self.discharge2anyreg(e)?;
+        self.free_exp(e);
+        self.cond_jump(VMOpcode::TESTSET, NO_REG, e.reg(), cond as u32)
+    }
+    fn go_if_true(&mut self, e: &mut ExpDesc) -> Result<()> {
+        self.discharge_vars(e);
+        let pc = match e.k {
+            ExpKind::K(_) | ExpKind::KNum(_) | ExpKind::True => NO_JUMP,
+            ExpKind::False => self.jump()?,
+            ExpKind::Jmp(pc) => {
+                self.invert_jump(e);
+                pc as i32
+            }
+            _ => self.jump_on_cond(e, false)?,
+        };
+        self.concat(&mut e.f, pc)?;
+        self.patch_to_here(e.t)?;
+        e.t = NO_JUMP;
+        Ok(())
+    }
+    fn go_if_false(&mut self, e: &mut ExpDesc) -> Result<()> {
+        self.discharge_vars(e);
+        let pc = match e.k {
+            ExpKind::Nil | ExpKind::False => NO_JUMP,
+            ExpKind::True => self.jump()?,
+            ExpKind::Jmp(pc) => pc as i32,
+            _ => self.jump_on_cond(e, true)?,
+        };
+        self.concat(&mut e.t, pc)?;
+        self.patch_to_here(e.f)?;
+        e.f = NO_JUMP;
+        Ok(())
+    }
+    fn code_not(&mut self, e: &mut ExpDesc) -> Result<()> {
+        self.discharge_vars(e);
+        match e.k {
+            ExpKind::Nil | ExpKind::False => e.k = ExpKind::True,
+            ExpKind::K(_) | ExpKind::KNum(_) | ExpKind::True => e.k = ExpKind::False,
+            ExpKind::Jmp(_) => self.invert_jump(e),
+            ExpKind::Relocable(_) | ExpKind::NonReloc(_) => {
+                self.discharge2anyreg(e)?;
+                self.free_exp(e);
+                e.k = ExpKind::Relocable(self.code_abc(VMOpcode::NOT, 0, e.reg(), 0));
+            }
+            k => unreachable!("cannot negate {:?}", k),
+        }
+        std::mem::swap(&mut e.t, &mut e.f);
+        self.remove_values(e.f);
+        self.remove_values(e.t);
+        Ok(())
+    }
+    /// Folds arithmetic on two numerals, unless that would divide by zero or
+    /// produce a NaN, which are left for run time.
+    fn const_folding(op: VMOpcode, e1: &mut ExpDesc, e2: &ExpDesc) -> bool {
+        if !e1.is_numeral() || !e2.is_numeral() {
+            return false;
+        }
+        let (ExpKind::KNum(v1), ExpKind::KNum(v2)) = (e1.k, e2.k) else { unreachable!() };
+        let r = match op {
+            VMOpcode::ADD => v1 + v2,
+            VMOpcode::SUB => v1 - v2,
+            VMOpcode::MUL => v1 * v2,
+            VMOpcode::DIV if v2 != 0.0 => v1 / v2,
+            VMOpcode::MOD if v2 != 0.0 => v1 - (v1 / v2).floor() * v2,
+            VMOpcode::POW => v1.powf(v2),
+            VMOpcode::UNM => -v1,
+            _ => return false,
+        };
+        if r.is_nan() {
+            return false;
+        }
+        e1.k = ExpKind::KNum(r);
+        true
+    }
+    fn code_arith(&mut self, op: VMOpcode, e1: &mut ExpDesc, e2: &mut ExpDesc) -> Result<()> {
+        if Self::const_folding(op.clone(), e1, e2) {
+            return Ok(());
+        }
+        let o2 = if op != VMOpcode::UNM && op != VMOpcode::LEN { self.exp2rk(e2)? } else { 0 };
+        let o1 = self.exp2rk(e1)?;
+        if o1 > o2 {
+            self.free_exp(e1);
+            self.free_exp(e2);
+        } else {
+            self.free_exp(e2);
+            self.free_exp(e1);
+        }
+        e1.k = ExpKind::Relocable(self.code_abc(op, 0, o1, o2));
+        Ok(())
+    }
+    fn code_comp(&mut self, op: VMOpcode, mut cond: bool, e1: &mut ExpDesc, e2: &mut ExpDesc) -> Result<()> {
+        let mut o1 = self.exp2rk(e1)?;
+        let mut o2 = self.exp2rk(e2)?;
+        self.free_exp(e2);
+        self.free_exp(e1);
+        if !cond && op != VMOpcode::EQ {
+            // `a > b` is `b < a`
+            std::mem::swap(&mut o1, &mut o2);
+            cond = true;
+        }
+        e1.k = ExpKind::Jmp(self.cond_jump(op, cond as u32, o1, o2)? as usize);
+        Ok(())
+    }
+    fn prefix(&mut self, op: UnOp, e: &mut ExpDesc) -> Result<()> {
+        let mut e2 = ExpDesc::new(ExpKind::KNum(0.0));
+        match op {
+            UnOp::Neg => {
+                if !e.is_numeral() {
+                    self.exp2anyreg(e)?;
+                }
+                self.code_arith(VMOpcode::UNM, e, &mut e2)
+            }
+            UnOp::Not => self.code_not(e),
+            UnOp::Len => {
+                self.exp2anyreg(e)?;
+                self.code_arith(VMOpcode::LEN, e, &mut e2)
+            }
+        }
+    }
+    /// Prepares the left operand once the operator has been read.
+    fn infix(&mut self, op: BinOp, v: &mut ExpDesc) -> Result<()> {
+        match op {
+            BinOp::And => self.go_if_true(v),
+            BinOp::Or => self.go_if_false(v),
+            // operands of CONCAT must be consecutive registers
+            BinOp::Concat => self.exp2nextreg(v),
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Mod | BinOp::Pow => {
+                if !v.is_numeral() {
+                    self.exp2rk(v)?;
+                }
+                Ok(())
+            }
+            _ => self.exp2rk(v).map(|_| ()),
+        }
+    }
+    fn posfix(&mut self, op: BinOp, e1: &mut ExpDesc, e2: &mut ExpDesc) -> Result<()> {
+        match op {
+            BinOp::And => {
+                self.discharge_vars(e2);
+                self.concat(&mut e2.f, e1.f)?;
+                *e1 = *e2;
+                Ok(())
+            }
+            BinOp::Or => {
+                self.discharge_vars(e2);
+                self.concat(&mut e2.t, e1.t)?;
+                *e1 = *e2;
+                Ok(())
+            }
+            BinOp::Concat => {
+                self.exp2val(e2)?;
+                if let ExpKind::Relocable(pc) = e2.k {
+                    if op_of(self.fs().code[pc]) == VMOpcode::CONCAT {
+                        // extend `b .. c` into `a .. b .. c`
+                        self.free_exp(e1);
+                        set_b(&mut self.fs().code[pc], e1.reg());
+                        e1.k = ExpKind::Relocable(pc);
+                        return Ok(());
+                    }
+                }
+                self.exp2nextreg(e2)?;
+                self.code_arith(VMOpcode::CONCAT, e1, e2)
+            }
+            BinOp::Add => self.code_arith(VMOpcode::ADD, e1, e2),
+            BinOp::Sub => self.code_arith(VMOpcode::SUB, e1, e2),
+            BinOp::Mul => self.code_arith(VMOpcode::MUL, e1, e2),
+            BinOp::Div => self.code_arith(VMOpcode::DIV, e1, e2),
+            BinOp::Mod => self.code_arith(VMOpcode::MOD, e1, e2),
+            BinOp::Pow => self.code_arith(VMOpcode::POW, e1, e2),
+            BinOp::Eq => self.code_comp(VMOpcode::EQ, true, e1, e2),
+            BinOp::Ne => self.code_comp(VMOpcode::EQ, false, e1, e2),
+            BinOp::Lt => self.code_comp(VMOpcode::LT, true, e1, e2),
+            BinOp::Le => self.code_comp(VMOpcode::LE, true, e1, e2),
+            BinOp::Gt => self.code_comp(VMOpcode::LT, false, e1, e2),
+            BinOp::Ge => self.code_comp(VMOpcode::LE, false, e1, e2),
+        }
+    }
+
+    // Variables and scopes
+
+    fn new_local_var(&mut self, name: &str) -> Result<()> {
+        let fs = self.fs();
+        let count = fs.active.len() + fs.declared.len() + 1;
+        self.check_limit(self.funcs.len() - 1, count, MAX_VARS, "local variables")?;
+        let fs = self.fs();
+        fs.local_vars.push(LocalVar { name: name.to_string(), start_pc: 0, end_pc: 0 });
+        let idx = fs.local_vars.len() - 1;
+        fs.declared.push(idx);
+        Ok(())
+    }
+    /// Activates the next `n` declared locals.
+    fn adjust_local_vars(&mut self, n: usize) {
+        let fs = self.fs();
+        let pc = fs.pc() as u32;
+        for idx in fs.declared.drain(..n).collect::<Vec<_>>() {
+            fs.local_vars[idx].start_pc = pc;
+            fs.active.push(idx);
+        }
+    }
+    fn remove_vars(&mut self, to_level: u32) {
+        let fs = self.fs();
+        let pc = fs.pc() as u32;
+        while fs.nactvar() > to_level {
+            let idx = fs.active.pop().unwrap();
+            fs.local_vars[idx].end_pc = pc;
+        }
+    }
+    fn enter_block(&mut self, breakable: bool) {
+        let fs = self.fs();
+        let nactvar = fs.nactvar();
+        fs.blocks.push(BlockCnt { break_list: NO_JUMP, nactvar, upval: false, breakable });
+    }
+    fn leave_block(&mut self) -> Result<()> {
+        let bl = self.fs().blocks.pop().unwrap();
+        self.remove_vars(bl.nactvar);
+        if bl.upval {
+            self.code_abc(VMOpcode::CLOSE, bl.nactvar, 0, 0);
+        }
+        let fs = self.fs();
+        fs.free_reg = fs.nactvar();
+        self.patch_to_here(bl.break_list)
+    }
+    /// Resolves `name` in function `level` and those enclosing it.
+    fn single_var_aux(&mut self, level: usize, name: &str, base: bool) -> Result<ExpKind> {
+        let fs = &mut self.funcs[level];
+        if let Some(reg) = fs.active.iter().rposition(|i| fs.local_vars[*i].name == name) {
+            if !base {
+                // the local will be captured: its block must close it
+                if let Some(bl) = fs.blocks.iter_mut().rev().find(|bl| bl.nactvar <= reg as u32) {
+                    bl.upval = true;
+                }
+            }
+            return Ok(ExpKind::Local(reg as u32));
+        }
+        if level == 0 {
+            return Ok(ExpKind::Global(0));
+        }
+        let (local, index) = match self.single_var_aux(level - 1, name, false)? {
+            ExpKind::Local(reg) => (true, reg),
+            ExpKind::Upval(idx) => (false, idx),
+            k => return Ok(k),
+        };
+        let fs = &mut self.funcs[level];
+        if let Some(idx) = fs.upvalues.iter().position(|u| u.local == local && u.index == index) {
+            return Ok(ExpKind::Upval(idx as u32));
+        }
+        let count = fs.upvalues.len() + 1;
+        self.check_limit(level, count, MAX_UPVALUES, "upvalues")?;
+        let fs = &mut self.funcs[level];
+        fs.upvalues.push(UpvalDesc { name: name.to_string(), local, index });
+        Ok(ExpKind::Upval(fs.upvalues.len() as u32 - 1))
+    }
+    fn single_var(&mut self, name: &str) -> Result<ExpDesc> {
+        let top = self.funcs.len() - 1;
+        let k = match self.single_var_aux(top, name, true)? {
+            ExpKind::Global(_) => ExpKind::Global(self.string_k(name.as_bytes())),
+            k => k,
+        };
+        Ok(ExpDesc::new(k))
+    }
+    /// Fits the values of an expression list to `nvars` variables.
+    fn adjust_assign(&mut self, nvars: usize, nexps: usize, e: &mut ExpDesc) -> Result<()> {
+        let mut extra = nvars as i32 - nexps as i32;
+        if e.has_multret() {
+            // the call itself provides one of them
+            extra = (extra + 1).max(0);
+            self.set_returns(e, extra)?;
+            if extra > 1 {
+                self.reserve_regs(extra as u32 - 1)?;
+            }
+        } else {
+            if e.k != ExpKind::Void {
+                self.exp2nextreg(e)?;
+            }
+            if extra > 0 {
+                let reg = self.fs().free_reg;
+                self.reserve_regs(extra as u32)?;
+                self.nil(reg, extra as u32);
+            }
+        }
+        Ok(())
+    }
+
+    // Functions
+
+    fn close_func(&mut self) -> Result<(FunctionBlock, Vec<UpvalDesc>)> {
+        self.remove_vars(0);
+        self.ret(0, 0);
+        let fs = self.funcs.pop().unwrap();
+        let list_instructions = VMInst::decode_all(&fs.code).map_err(|e| self.error(&e.to_string()))?;
+        let func = FunctionBlock {
+            source_name: self.source.clone(),
+            line_def: fs.line_defined,
+            last_line_def: fs.last_line_defined,
+            num_upval: fs.upvalues.len() as u8,
+            num_param: fs.num_params,
+            is_vararg: fs.is_vararg,
+            max_stack_size: fs.max_stack as u8,
+            list_instructions,
+            list_const: fs.constants.into_iter().map(Gc::new).collect(),
+            list_fnproto: fs.protos.into_iter().map(Gc::new).collect(),
+            line_info: fs.lines,
+            local_vars: fs.local_vars,
+            upvalue_names: fs.upvalues.iter().map(|u| u.name.clone()).collect(),
+        };
+        Ok((func, fs.upvalues))
+    }
+    /// Compiles a function body and emits the CLOSURE creating it.
+    fn body(&mut self, body: &FunctionBody, needself: bool, line: u32) -> Result<ExpDesc> {
+        self.funcs.push(FuncState::new(line));
+        if needself {
+            self.new_local_var("self")?;
+            self.adjust_local_vars(1);
+        }
+        for param in &body.params {
+            self.new_local_var(&param.name)?;
+        }
+        let mut nparams = body.params.len();
+        if body.is_vararg {
+            // `arg`, until the body turns out to use `...`
+            self.new_local_var("arg")?;
+            self.fs().is_vararg = VARARG_HASARG | VARARG_NEEDSARG | VARARG_ISVARARG;
+            nparams += 1;
+        }
+        self.adjust_local_vars(nparams);
+        let fs = self.fs();
+        let nactvar = fs.nactvar();
+        fs.num_params = (nactvar - (fs.is_vararg & VARARG_HASARG) as u32) as u8;
+        self.reserve_regs(nactvar)?;
+        self.chunk(&body.block)?;
+        self.mark(body.span.end_line);
+        self.fs().last_line_defined = body.span.end_line;
+        let (func, upvalues) = self.close_func()?;
+        let fs = self.fs();
+        fs.protos.push(func);
+        let idx = fs.protos.len() as u32 - 1;
+        let pc = self.code_abx(VMOpcode::CLOSURE, 0, idx);
+        for u in upvalues {
+            let op = if u.local { VMOpcode::MOVE } else { VMOpcode::GETUPVAL };
+            self.code_abc(op, 0, u.index, 0);
+        }
+        Ok(ExpDesc::new(ExpKind::Relocable(pc)))
+    }
+
+    // Expressions
+
+    fn expr(&mut self, e: &Expr) -> Result<ExpDesc> {
+        let k = match &e.kind {
+            ExprKind::Nil => ExpKind::Nil,
+            ExprKind::True => ExpKind::True,
+            ExprKind::False => ExpKind::False,
+            ExprKind::Number(n) => ExpKind::KNum(*n),
+            ExprKind::String(s) => {
+                self.mark(e.span.end_line);
+                ExpKind::K(self.string_k(s))
+            }
+            ExprKind::Vararg => {
+                self.mark(e.span.end_line);
+                // the body uses `...`, so needs no `arg` table
+                self.fs().is_vararg &= !VARARG_NEEDSARG;
+                ExpKind::Vararg(self.code_abc(VMOpcode::VARARG, 0, 1, 0))
+            }
+            ExprKind::Function(body) => return self.body(body, false, body.span.line),
+            ExprKind::Table(fields) => return self.constructor(fields, e.span),
+            ExprKind::Name(name) => {
+                self.mark(e.span.end_line);
+                return self.single_var(name);
+            }
+            ExprKind::Binary { op, lhs, rhs } => {
+                let mut v = self.expr(lhs)?;
+                self.infix(*op, &mut v)?;
+                let mut v2 = self.expr(rhs)?;
+                self.posfix(*op, &mut v, &mut v2)?;
+                return Ok(v);
+            }
+            ExprKind::Unary { op, expr } => {
+                let mut v = self.expr(expr)?;
+                self.prefix(*op, &mut v)?;
+                return Ok(v);
+            }
+            ExprKind::Index { obj, key } => {
+                let mut v = self.expr(obj)?;
+                self.exp2anyreg(&mut v)?;
+                let mut k = self.expr(key)?;
+                self.exp2val(&mut k)?;
+                self.indexed(&mut v, &mut k)?;
+                return Ok(v);
+            }
+            ExprKind::Call { func, args } => {
+                let mut v = self.expr(func)?;
+                self.exp2nextreg(&mut v)?;
+                self.func_args(&mut v, args, e.span, func.span.end_line)?;
+                return Ok(v);
+            }
+            ExprKind::MethodCall { obj, method, args } => {
+                let mut v = self.expr(obj)?;
+                self.mark(method.span.end_line);
+                let mut key = ExpDesc::new(ExpKind::K(self.string_k(method.name.as_bytes())));
+                self.self_(&mut v, &mut key)?;
+                self.func_args(&mut v, args, e.span, method.span.end_line)?;
+                return Ok(v);
+            }
+            ExprKind::Paren(inner) => {
+                let mut v = self.expr(inner)?;
+                self.mark(e.span.end_line);
+                self.discharge_vars(&mut v);
+                return Ok(v);
+            }
+        };
+        self.mark(e.span.end_line);
+        Ok(ExpDesc::new(k))
+    }
+    /// Emits the call of the function in `f`, which sits in the register
+    /// below its arguments.
+    fn func_args(&mut self, f: &mut ExpDesc, args: &[Expr], span: Span, prefix_line: u32) -> Result<()> {
+        // `f "str"` and `f {...}` are attributed to their argument's line,
+        // `f(...)` to that of the parenthesis, which follows the prefix
+        let line = match args {
+            [arg] if arg.span.end == span.end => arg.span.line,
+            _ => prefix_line,
+        };
+        let mut a = ExpDesc::new(ExpKind::Void);
+        if !args.is_empty() {
+            a = self.explist(args)?.0;
+            if a.has_multret() {
+                self.set_returns(&mut a, MULTRET)?;
+            }
+        }
+        self.mark(span.end_line);
+        let base = f.reg();
+        let nparams = if a.has_multret() {
+            MULTRET
+        } else {
+            if a.k != ExpKind::Void {
+                self.exp2nextreg(&mut a)?;
+            }
+            (self.fs().free_reg - (base + 1)) as i32
+        };
+        *f = ExpDesc::new(ExpKind::Call(self.code_abc(VMOpcode::CALL, base, (nparams + 1) as u32, 2)));
+        self.fix_line(line);
+        // the call leaves one result, unless changed later
+        self.fs().free_reg = base + 1;
+        Ok(())
+    }
+    /// Evaluates all but the last expression into consecutive registers and
+    /// returns the last one still open, with the count.
+    fn explist(&mut self, exprs: &[Expr]) -> Result<(ExpDesc, usize)> {
+        let mut v = self.expr(&exprs[0])?;
+        for e in &exprs[1..] {
+            self.exp2nextreg(&mut v)?;
+            v = self.expr(e)?;
+        }
+        Ok((v, exprs.len()))
+    }
+    fn constructor(&mut self, fields: &[TableField], span: Span) -> Result<ExpDesc> {
+        self.mark(span.line);
+        let pc = self.code_abc(VMOpcode::NEWTABLE, 0, 0, 0);
+        let mut t = ExpDesc::new(ExpKind::Relocable(pc));
+        // fix it at the top of the stack
+        self.exp2nextreg(&mut t)?;
+        let table = t.reg();
+        let mut last = ExpDesc::new(ExpKind::Void);
+        let (mut na, mut nh, mut tostore) = (0u32, 0u32, 0u32);
+        for field in fields {
+            // close the previous list item
+            if last.k != ExpKind::Void {
+                self.exp2nextreg(&mut last)?;
+                last.k = ExpKind::Void;
+                if tostore == FIELDS_PER_FLUSH {
+                    self.set_list(table, na, tostore as i32);
+                    tostore = 0;
+                }
+            }
+            let (mut key, value) = match field {
+                TableField::Positional(value) => {
+                    last = self.expr(value)?;
+                    na += 1;
+                    tostore += 1;
+                    continue;
+                }
+                TableField::Named(name, value) => {
+                    self.mark(name.span.end_line);
+                    (ExpDesc::new(ExpKind::K(self.string_k(name.name.as_bytes()))), value)
+                }
+                TableField::Keyed(key, value) => {
+                    let mut k = self.expr(key)?;
+                    self.exp2val(&mut k)?;
+                    (k, value)
+                }
+            };
+            let reg = self.fs().free_reg;
+            nh += 1;
+            let rk_key = self.exp2rk(&mut key)?;
+            let mut val = self.expr(value)?;
+            let rk_val = self.exp2rk(&mut val)?;
+            self.code_abc(VMOpcode::SETTABLE, table, rk_key, rk_val);
+            self.fs().free_reg = reg;
+        }
+        self.mark(span.end_line);
+        if tostore > 0 {
+            if last.has_multret() {
+                self.set_returns(&mut last, MULTRET)?;
+                self.set_list(table, na, MULTRET);
+                // the open call's values are not counted
+                na -= 1;
+            } else {
+                if last.k != ExpKind::Void {
+                    self.exp2nextreg(&mut last)?;
+                }
+                self.set_list(table, na, tostore as i32);
+            }
+        }
+        let fs = self.fs();
+        set_b(&mut fs.code[pc], int2fb(na));
+        set_c(&mut fs.code[pc], int2fb(nh));
+        Ok(t)
+    }
+
+    // Statements
+
+    fn chunk(&mut self, block: &Block) -> Result<()> {
+        for stat in &block.stats {
+            self.statement(stat)?;
+            let fs = self.fs();
+            fs.free_reg = fs.nactvar();
+        }
+        Ok(())
+    }
+    fn block(&mut self, block: &Block) -> Result<()> {
+        self.enter_block(false);
+        self.chunk(block)?;
+        self.leave_block()
+    }
+    /// Compiles a condition, returning the jumps taken when it is false.
+    fn cond(&mut self, e: &Expr) -> Result<i32> {
+        let mut v = self.expr(e)?;
+        if v.k == ExpKind::Nil {
+            v.k = ExpKind::False;
+        }
+        self.go_if_true(&mut v)?;
+        Ok(v.f)
+    }
+    fn break_stat(&mut self) -> Result<()> {
+        let fs = self.fs();
+        let mut upval = false;
+        let Some(idx) = fs.blocks.iter().rposition(|bl| {
+            upval |= !bl.breakable && bl.upval;
+            bl.breakable
+        }) else {
+            return Err(self.error("no loop to break"));
+        };
+        if upval {
+            let nactvar = fs.blocks[idx].nactvar;
+            self.code_abc(VMOpcode::CLOSE, nactvar, 0, 0);
+        }
+        let j = self.jump()?;
+        let mut list = self.fs().blocks[idx].break_list;
+        self.concat(&mut list, j)?;
+        self.fs().blocks[idx].break_list = list;
+        Ok(())
+    }
+    fn statement(&mut self, stat: &Stat) -> Result<()> {
+        let line = stat.span.line;
+        self.mark(line);
+        match &stat.kind {
+            StatKind::Local { names, exprs } => {
+                for name in names {
+                    self.new_local_var(&name.name)?;
+                }
+                let (mut e, nexps) = match exprs.is_empty() {
+                    true => (ExpDesc::new(ExpKind::Void), 0),
+                    false => self.explist(exprs)?,
+                };
+                self.mark(stat.span.end_line);
+                self.adjust_assign(names.len(), nexps, &mut e)?;
+                self.adjust_local_vars(names.len());
+            }
+            StatKind::Assign { targets, exprs } => self.assignment(targets, exprs, stat.span)?,
+            StatKind::Call(call) => {
+                let e = self.expr(call)?;
+                let ExpKind::Call(pc) = e.k else { unreachable!() };
+                // a call statement uses no results
+                set_c(&mut self.fs().code[pc], 1);
+            }
+            StatKind::Do(block) => {
+                self.block(block)?;
+                self.mark(stat.span.end_line);
+            }
+            StatKind::While { cond, body } => {
+                let while_init = self.get_label();
+                let cond_exit = self.cond(cond)?;
+                self.enter_block(true);
+                self.block(body)?;
+                let j = self.jump()?;
+                self.patch_list(j, while_init)?;
+                self.mark(stat.span.end_line);
+                self.leave_block()?;
+                // false conditions finish the loop
+                self.patch_to_here(cond_exit)?;
+            }
+            StatKind::Repeat { body, cond } => {
+                let repeat_init = self.get_label();
+                // loop block, then scope block
+                self.enter_block(true);
+                self.enter_block(false);
+                self.chunk(body)?;
+                // the condition sees the body's locals
+                let cond_exit = self.cond(cond)?;
+                if !self.fs().blocks.last().unwrap().upval {
+                    self.leave_block()?;
+                    self.patch_list(cond_exit, repeat_init)?;
+                } else {
+                    // locals captured: close them on every path
+                    self.break_stat()?;
+                    self.patch_to_here(cond_exit)?;
+                    self.leave_block()?;
+                    let j = self.jump()?;
+                    self.patch_list(j, repeat_init)?;
+                }
+                self.leave_block()?;
+            }
+            StatKind::If { clauses, else_block } => {
+                let mut escape = NO_JUMP;
+                let mut false_list = NO_JUMP;
+                for (i, (cond, block)) in clauses.iter().enumerate() {
+                    if i > 0 {
+                        let j = self.jump()?;
+                        self.concat(&mut escape, j)?;
+                        self.patch_to_here(false_list)?;
+                    }
+                    false_list = self.cond(cond)?;
+                    self.block(block)?;
+                }
+                match else_block {
+                    Some(block) => {
+                        let j = self.jump()?;
+                        self.concat(&mut escape, j)?;
+                        self.patch_to_here(false_list)?;
+                        self.block(block)?;
+                    }
+                    None => self.concat(&mut escape, false_list)?,
+                }
+                self.patch_to_here(escape)?;
+                self.mark(stat.span.end_line);
+            }
+            StatKind::NumericFor { var, start, limit, step, body } => {
+                self.enter_block(true);
+                let base = self.fs().free_reg;
+                for name in ["(for index)", "(for limit)", "(for step)", &var.name] {
+                    self.new_local_var(name)?;
+                }
+                self.exp1(start)?;
+                self.exp1(limit)?;
+                match step {
+                    Some(step) => self.exp1(step)?,
+                    None => {
+                        let (reg, one) = (self.fs().free_reg, self.number_k(1.0));
+                        self.code_abx(VMOpcode::LOADK, reg, one);
+                        self.reserve_regs(1)?;
+                    }
+                }
+                self.for_body(base, line, 1, true, body)?;
+                self.mark(stat.span.end_line);
+                self.leave_block()?;
+            }
+            StatKind::GenericFor { names, exprs, body } => {
+                self.enter_block(true);
+                let base = self.fs().free_reg;
+                for name in ["(for generator)", "(for state)", "(for control)"] {
+                    self.new_local_var(name)?;
+                }
+                for name in names {
+                    self.new_local_var(&name.name)?;
+                }
+                let (mut e, nexps) = self.explist(exprs)?;
+                self.adjust_assign(3, nexps, &mut e)?;
+                // room to call the generator
+                self.check_stack(3)?;
+                self.for_body(base, exprs[0].span.line, names.len(), false, body)?;
+                self.mark(stat.span.end_line);
+                self.leave_block()?;
+            }
+            StatKind::Function { name, body } => {
+                let mut v = self.single_var(&name.path[0].name)?;
+                for field in name.path[1..].iter().chain(&name.method) {
+                    self.exp2anyreg(&mut v)?;
+                    self.mark(field.span.end_line);
+                    let mut key = ExpDesc::new(ExpKind::K(self.string_k(field.name.as_bytes())));
+                    self.indexed(&mut v, &mut key)?;
+                }
+                let mut b = self.body(body, name.method.is_some(), line)?;
+                self.store_var(&v, &mut b)?;
+                // the definition happens on the first line
+                self.fix_line(line);
+            }
+            StatKind::LocalFunction { name, body } => {
+                self.new_local_var(&name.name)?;
+                let reg = self.fs().free_reg;
+                let v = ExpDesc::new(ExpKind::Local(reg));
+                self.reserve_regs(1)?;
+                self.adjust_local_vars(1);
+                let mut b = self.body(body, false, name.span.end_line)?;
+                self.store_var(&v, &mut b)?;
+                // debug information only sees the variable from here on
+                let fs = self.fs();
+                let (pc, idx) = (fs.pc() as u32, *fs.active.last().unwrap());
+                fs.local_vars[idx].start_pc = pc;
+            }
+            StatKind::Return(exprs) => {
+                let (first, nret) = if exprs.is_empty() {
+                    (0, 0)
+                } else {
+                    let (mut e, n) = self.explist(exprs)?;
+                    self.mark(stat.span.end_line);
+                    if e.has_multret() {
+                        self.set_returns(&mut e, MULTRET)?;
+                        if let (ExpKind::Call(pc), 1) = (e.k, n) {
+                            set_op(&mut self.fs().code[pc], VMOpcode::TAILCALL);
+                        }
+                        (self.fs().nactvar(), MULTRET)
+                    } else if n == 1 {
+                        (self.exp2anyreg(&mut e)?, 1)
+                    } else {
+                        self.exp2nextreg(&mut e)?;
+                        (self.fs().nactvar(), n as i32)
+                    }
+                };
+                self.ret(first, nret);
+            }
+            StatKind::Break => self.break_stat()?,
+        }
+        Ok(())
+    }
+    /// An expression evaluated into the next register.
+    fn exp1(&mut self, e: &Expr) -> Result<()> {
+        let mut v = self.expr(e)?;
+        self.exp2nextreg(&mut v)
+    }
+    fn for_body(&mut self, base: u32, line: u32, nvars: usize, numeric: bool, body: &Block) -> Result<()> {
+        // the control variables
+        self.adjust_local_vars(3);
+        let prep = match numeric {
+            true => self.code_asbx(VMOpcode::FORPREP, base, NO_JUMP) as i32,
+            false => self.jump()?,
+        };
+        // scope for the declared variables
+        self.enter_block(false);
+        self.adjust_local_vars(nvars);
+        self.reserve_regs(nvars as u32)?;
+        self.block(body)?;
+        self.leave_block()?;
+        self.patch_to_here(prep)?;
+        let end_for = match numeric {
+            true => self.code_asbx(VMOpcode::FORLOOP, base, NO_JUMP) as i32,
+            false => self.code_abc(VMOpcode::TFORLOOP, base, 0, nvars as u32) as i32,
+        };
+        // the loop instruction belongs to the `for` line
+        self.fix_line(line);
+        let back = if numeric { end_for } else { self.jump()? };
+        self.patch_list(back, prep + 1)
+    }
+    fn assignment(&mut self, targets: &[Expr], exprs: &[Expr], span: Span) -> Result<()> {
+        let mut lhs = vec![self.expr(&targets[0])?];
+        for target in &targets[1..] {
+            let v = self.expr(target)?;
+            if let ExpKind::Local(reg) = v.k {
+                self.check_conflict(&mut lhs, reg)?;
+            }
+            lhs.push(v);
+        }
+        let (mut e, nexps) = self.explist(exprs)?;
+        self.mark(span.end_line);
+        let nvars = lhs.len();
+        let last = lhs.pop().unwrap();
+        if nexps == nvars {
+            self.set_one_ret(&mut e);
+            self.store_var(&last, &mut e)?;
+        } else {
+            self.adjust_assign(nvars, nexps, &mut e)?;
+            if nexps > nvars {
+                // drop the extra values
+                self.fs().free_reg -= (nexps - nvars) as u32;
+            }
+            let mut e = ExpDesc::new(ExpKind::NonReloc(self.fs().free_reg - 1));
+            self.store_var(&last, &mut e)?;
+        }
+        // the remaining values sit in consecutive registers, last on top
+        for var in lhs.iter().rev() {
+            let mut e = ExpDesc::new(ExpKind::NonReloc(self.fs().free_reg - 1));
+            self.store_var(var, &mut e)?;
+        }
+        Ok(())
+    }
+    /// In `t[i], i = ...`, a local assigned later in the list may also be the
+    /// table or key of an earlier indexed target; those use a copy instead.
+    fn check_conflict(&mut self, lhs: &mut [ExpDesc], reg: u32) -> Result<()> {
+        let extra = self.fs().free_reg;
+        let mut conflict = false;
+        for v in lhs.iter_mut() {
+            if let ExpKind::Indexed(t, key) = &mut v.k {
+                if *t == reg {
+                    conflict = true;
+                    *t = extra;
+                }
+                if *key == reg {
+                    conflict = true;
+                    *key = extra;
+                }
+            }
+        }
+        if conflict {
+            self.code_abc(VMOpcode::MOVE, extra, reg, 0);
+            self.reserve_regs(1)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+//! Lua 5.1 lexer, following llex.c.
+
+use super::{ast::Span, SyntaxError};
+use crate::vm::parse_number;
+
+const KEYWORDS: [&str; 21] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "if", "in", "local", "nil", "not", "or",
+    "repeat", "return", "then", "true", "until", "while",
+];
+
+/// Whether `s` can be written as a name: an identifier that is not a keyword.
+pub fn is_name(s: &[u8]) -> bool {
+    match s.split_first() {
+        Some((first, rest)) => {
+            (first.is_ascii_alphabetic() || *first == b'_')
+                && rest.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+                && !KEYWORDS.iter().any(|k| k.as_bytes() == s)
+        }
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Name(String),
+    String(Vec<u8>),
+    Number(f64),
+    /// A reserved word or punctuation, spelled as in the source.
+    Sym(&'static str),
+    /// Any other single byte, which no rule of the grammar accepts.
+    Other(u8),
+    Eof,
+}
+
+pub struct Lexer<'a> {
+    src: &'a [u8],
+    pos: usize,
+    line: u32,
+    chunk: &'a str,
+}
+impl<'a> Lexer<'a> {
+    /// `chunk` is the name used in error messages.
+    pub fn new(src: &'a [u8], chunk: &'a str) -> Self {
+        let mut lexer = Self { src, pos: 0, line: 1, chunk };
+        // a leading `#!` line is skipped, as `luaL_loadfile` does
+        if src.starts_with(b"#") {
+            while !matches!(lexer.peek(), None | Some(b'\n' | b'\r')) {
+                lexer.pos += 1;
+            }
+        }
+        lexer
+    }
+    pub fn source(&self) -> &'a [u8] {
+        self.src
+    }
+    pub fn chunk(&self) -> &'a str {
+        self.chunk
+    }
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+    fn peek_at(&self, n: usize) -> Option<u8> {
+        self.src.get(self.pos + n).copied()
+    }
+    fn error(&self, msg: &str, start: Option<usize>) -> SyntaxError {
+        let near = match start {
+            Some(start) => String::from_utf8_lossy(&self.src[start..self.pos]).into_owned(),
+            None => "<eof>".to_string(),
+        };
+        SyntaxError::new(self.chunk, self.line, format!("{} near '{}'", msg, near))
+    }
+    /// Consumes a line break: `\n`, `\r`, `\n\r` or `\r\n`.
+    fn newline(&mut self) {
+        let first = self.src[self.pos];
+        self.pos += 1;
+        if matches!(self.peek(), Some(c @ (b'\n' | b'\r')) if c != first) {
+            self.pos += 1;
+        }
+        self.line += 1;
+    }
+    /// Reads the token after the current position, returning it with its span.
+    pub fn next_token(&mut self) -> Result<(Token, Span), SyntaxError> {
+        loop {
+            let start = self.pos;
+            let line = self.line;
+            let Some(c) = self.peek() else {
+                let span = Span { start, end: start, line, end_line: line };
+                return Ok((Token::Eof, span));
+            };
+            let token = match c {
+                b'\n' | b'\r' => {
+                    self.newline();
+                    continue;
+                }
+                b' ' | b'\t' | b'\x0b' | b'\x0c' => {
+                    self.pos += 1;
+                    continue;
+                }
+                b'-' if self.peek_at(1) == Some(b'-') => {
+                    self.pos += 2;
+                    if self.peek() == Some(b'[') {
+                        let sep = self.skip_sep();
+                        if sep >= 0 {
+                            self.pos += 1;
+                            self.long_string(sep as usize, true)?;
+                            continue;
+                        }
+                    }
+                    while !matches!(self.peek(), None | Some(b'\n' | b'\r')) {
+                        self.pos += 1;
+                    }
+                    continue;
+                }
+                b'[' => {
+                    let sep = self.skip_sep();
+                    if sep >= 0 {
+                        self.pos += 1;
+                        Token::String(self.long_string(sep as usize, false)?)
+                    } else if sep == -1 {
+                        Token::Sym("[")
+                    } else {
+                        return Err(self.error("invalid long string delimiter", Some(start)));
+                    }
+                }
+                b'"' | b'\'' => Token::String(self.string(c, start)?),
+                b'.' if self.peek_at(1) == Some(b'.') => {
+                    if self.peek_at(2) == Some(b'.') {
+                        self.pos += 3;
+                        Token::Sym("...")
+                    } else {
+                        self.pos += 2;
+                        Token::Sym("..")
+                    }
+                }
+                b'.' if !self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) => {
+                    self.pos += 1;
+                    Token::Sym(".")
+                }
+                b'0'..=b'9' | b'.' => Token::Number(self.number(start)?),
+                c if c.is_ascii_alphabetic() || c == b'_' => {
+                    while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_') {
+                        self.pos += 1;
+                    }
+                    let word = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+                    match KEYWORDS.iter().find(|k| **k == word) {
+                        Some(k) => Token::Sym(k),
+                        None => Token::Name(word.to_string()),
+                    }
+                }
+                _ => {
+                    const DOUBLE: [&str; 4] = ["==", "<=", ">=", "~="];
+                    const SINGLE: [&str; 19] =
+                        ["+", "-", "*", "/", "%", "^", "#", "<", ">", "=", "(", ")", "{", "}", "]", ";", ":", ",", "."];
+                    let rest = &self.src[self.pos..];
+                    if let Some(sym) = DOUBLE.iter().find(|s| rest.starts_with(s.as_bytes())) {
+                        self.pos += 2;
+                        Token::Sym(sym)
+                    } else if let Some(sym) = SINGLE.iter().find(|s| rest.starts_with(s.as_bytes())) {
+                        self.pos += 1;
+                        Token::Sym(sym)
+                    } else {
+                        self.pos += 1;
+                        Token::Other(c)
+                    }
+                }
+            };
+            let span = Span { start, end: self.pos, line, end_line: self.line };
+            return Ok((token, span));
+        }
+    }
+    /// At a `[` or `]`, skips `=` signs and returns their count if the same
+    /// bracket follows, otherwise `-count - 1`. The second bracket is left for
+    /// the caller.
+    fn skip_sep(&mut self) -> isize {
+        let bracket = self.src[self.pos];
+        let mut count = 0;
+        self.pos += 1;
+        while self.peek() == Some(b'=') {
+            self.pos += 1;
+            count += 1;
+        }
+        if self.peek() == Some(bracket) {
+            count
+        } else {
+            -count - 1
+        }
+    }
+    /// Reads a long string or comment of level `sep`, its opening bracket
+    /// already consumed.
+    fn long_string(&mut self, sep: usize, comment: bool) -> Result<Vec<u8>, SyntaxError> {
+        let mut out = Vec::new();
+        if matches!(self.peek(), Some(b'\n' | b'\r')) {
+            self.newline();
+        }
+        loop {
+            match self.peek() {
+                None => {
+                    let what = if comment { "unfinished long comment" } else { "unfinished long string" };
+                    return Err(self.error(what, None));
+                }
+                Some(b']') => {
+                    let at = self.pos;
+                    if self.skip_sep() == sep as isize {
+                        self.pos += 1;
+                        return Ok(out);
+                    }
+                    out.extend_from_slice(&self.src[at..self.pos]);
+                }
+                Some(b'[') => {
+                    let at = self.pos;
+                    if self.skip_sep() == sep as isize && sep == 0 {
+                        self.pos += 1;
+                        return Err(self.error("nesting of [[...]] is deprecated", Some(at)));
+                    }
+                    out.extend_from_slice(&self.src[at..self.pos]);
+                }
+                Some(b'\n' | b'\r') => {
+                    self.newline();
+                    out.push(b'\n');
+                }
+                Some(c) => {
+                    self.pos += 1;
+                    out.push(c);
+                }
+            }
+        }
+    }
+    fn string(&mut self, quote: u8, start: usize) -> Result<Vec<u8>, SyntaxError> {
+        let mut out = Vec::new();
+        self.pos += 1;
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error("unfinished string", None));
+            };
+            match c {
+                c if c == quote => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                b'\n' | b'\r' => return Err(self.error("unfinished string", Some(start))),
+                b'\\' => {
+                    self.pos += 1;
+                    let Some(e) = self.peek() else { continue };
+                    let byte = match e {
+                        b'a' => 0x07,
+                        b'b' => 0x08,
+                        b'f' => 0x0c,
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'v' => 0x0b,
+                        b'\n' | b'\r' => {
+                            self.newline();
+                            out.push(b'\n');
+                            continue;
+                        }
+                        b'0'..=b'9' => {
+                            let mut value = 0u32;
+                            for _ in 0..3 {
+                                match self.peek() {
+                                    Some(d @ b'0'..=b'9') => {
+                                        value = value * 10 + (d - b'0') as u32;
+                                        self.pos += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            if value > 255 {
+                                return Err(self.error("escape sequence too large", Some(start)));
+                            }
+                            out.push(value as u8);
+                            continue;
+                        }
+                        // any other escaped character stands for itself
+                        e => e,
+                    };
+                    self.pos += 1;
+                    out.push(byte);
+                }
+                c => {
+                    self.pos += 1;
+                    out.push(c);
+                }
+            }
+        }
+    }
+    /// Takes the whole run of characters a numeral could be made of, then
+    /// checks it, so `0xA.5` and `3..2` are one malformed number each.
+    fn number(&mut self, start: usize) -> Result<f64, SyntaxError> {
+        let hex = matches!(&self.src[start..], [b'0', b'x' | b'X', ..]);
+        while let Some(c) = self.peek() {
+            let sign = matches!(c, b'+' | b'-') && !hex && matches!(self.src[self.pos - 1], b'e' | b'E');
+            if !(c.is_ascii_alphanumeric() || c == b'_' || c == b'.' || sign) {
+                break;
+            }
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+        parse_number(text).ok_or_else(|| self.error("malformed number", Some(start)))
+    }
+}
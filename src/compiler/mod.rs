@@ -0,0 +1,66 @@
+//! Lua 5.1 compiler: a lexer and a parser producing an AST, and a code
+//! generator turning it into bytecode the VM runs.
+
+use std::fmt::Display;
+
+use self::{ast::Chunk, lexer::Lexer, parser::Parser};
+use crate::vm::chunk_parser::{ChunkHeader, LuaChunk};
+
+pub mod ast;
+pub mod codegen;
+pub mod lexer;
+pub mod parser;
+pub mod printer;
+
+/// A lexical or syntax error, displayed the way `luac` reports it:
+/// `test.lua:3: 'end' expected (to close 'function' at line 1) near '<eof>'`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub chunk: String,
+    pub line: u32,
+    /// The message, ending with the token it was found near.
+    pub message: String,
+}
+impl SyntaxError {
+    pub fn new(chunk: &str, line: u32, message: String) -> Self {
+        Self { chunk: chunk.to_string(), line, message }
+    }
+}
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.chunk, self.line, self.message)
+    }
+}
+impl std::error::Error for SyntaxError {}
+
+/// The name of a chunk as shown in messages, like `luaO_chunkid`: `@file`
+/// names a file, `=name` is used as is, and anything else is the source text.
+pub fn chunk_id(source: &str) -> String {
+    if let Some(name) = source.strip_prefix('@').or_else(|| source.strip_prefix('=')) {
+        return name.to_string();
+    }
+    let first = source.lines().next().unwrap_or("");
+    if first.len() < source.len() || first.len() > 40 {
+        let cut = (0..=first.len().min(40)).rev().find(|i| first.is_char_boundary(*i)).unwrap();
+        format!("[string \"{}...\"]", &first[..cut])
+    } else {
+        format!("[string \"{}\"]", first)
+    }
+}
+
+/// Parses Lua source into an AST. `chunkname` follows the `@file` / `=name`
+/// conventions of `chunk_id`.
+pub fn parse(source: &[u8], chunkname: &str) -> Result<Chunk, SyntaxError> {
+    let name = chunk_id(chunkname);
+    let mut parser = Parser::new(Lexer::new(source, &name))?;
+    let block = parser.chunk()?;
+    Ok(Chunk { block, name })
+}
+
+/// Compiles Lua source into a chunk, as `luac` would. `chunkname` is recorded
+/// as the source name of every function.
+pub fn compile(source: &[u8], chunkname: &str) -> Result<LuaChunk, SyntaxError> {
+    let chunk = parse(source, chunkname)?;
+    let func = codegen::generate(&chunk, chunkname)?;
+    Ok(LuaChunk { header: ChunkHeader::default(), func })
+}
@@ -0,0 +1,509 @@
+//! Recursive-descent parser for Lua 5.1, following the grammar and error
+//! messages of lparser.c.
+
+use super::{
+    ast::*,
+    lexer::{Lexer, Token},
+    SyntaxError,
+};
+
+type Result<T> = std::result::Result<T, SyntaxError>;
+
+/// Nesting limit for blocks and expressions, like `LUAI_MAXCCALLS`.
+const MAX_LEVELS: usize = 200;
+
+struct FuncState {
+    is_vararg: bool,
+    loops: usize,
+}
+
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+    token: Token,
+    span: Span,
+    ahead: Option<(Token, Span)>,
+    /// The last token consumed.
+    prev: Span,
+    funcs: Vec<FuncState>,
+    levels: usize,
+}
+impl<'a> Parser<'a> {
+    pub fn new(mut lexer: Lexer<'a>) -> Result<Self> {
+        let (token, span) = lexer.next_token()?;
+        Ok(Self { lexer, token, span, ahead: None, prev: Span::default(), funcs: Vec::new(), levels: 0 })
+    }
+    /// Parses a whole chunk, the body of a vararg function.
+    pub fn chunk(&mut self) -> Result<Block> {
+        self.funcs.push(FuncState { is_vararg: true, loops: 0 });
+        let block = self.block()?;
+        if self.token != Token::Eof {
+            return Err(self.error("'<eof>' expected"));
+        }
+        self.funcs.pop();
+        Ok(block)
+    }
+
+    fn advance(&mut self) -> Result<()> {
+        self.prev = self.span;
+        (self.token, self.span) = match self.ahead.take() {
+            Some(next) => next,
+            None => self.lexer.next_token()?,
+        };
+        Ok(())
+    }
+    fn lookahead(&mut self) -> Result<&Token> {
+        if self.ahead.is_none() {
+            self.ahead = Some(self.lexer.next_token()?);
+        }
+        Ok(&self.ahead.as_ref().unwrap().0)
+    }
+    fn check(&self, sym: &str) -> bool {
+        matches!(self.token, Token::Sym(s) if s == sym)
+    }
+    fn test_next(&mut self, sym: &str) -> Result<bool> {
+        if self.check(sym) {
+            self.advance()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+    fn expect(&mut self, sym: &str) -> Result<()> {
+        if !self.test_next(sym)? {
+            return Err(self.error(&format!("'{}' expected", sym)));
+        }
+        Ok(())
+    }
+    /// Expects `what` closing the `who` opened on line `line`.
+    fn check_match(&mut self, what: &str, who: &str, line: u32) -> Result<()> {
+        if self.test_next(what)? {
+            return Ok(());
+        }
+        if line == self.span.end_line {
+            Err(self.error(&format!("'{}' expected", what)))
+        } else {
+            Err(self.error(&format!("'{}' expected (to close '{}' at line {})", what, who, line)))
+        }
+    }
+    /// A syntax error at the current token.
+    fn error(&self, msg: &str) -> SyntaxError {
+        let near = match &self.token {
+            Token::Eof => "<eof>".to_string(),
+            Token::Other(c) if c.is_ascii_control() => format!("char({})", c),
+            _ => String::from_utf8_lossy(&self.lexer.source()[self.span.start..self.span.end]).into_owned(),
+        };
+        SyntaxError::new(self.lexer.chunk(), self.span.end_line, format!("{} near '{}'", msg, near))
+    }
+    /// The span from `start` to the last token consumed.
+    fn finish(&self, start: Span) -> Span {
+        start.to(self.prev)
+    }
+    fn enter_level(&mut self) -> Result<()> {
+        self.levels += 1;
+        if self.levels > MAX_LEVELS {
+            return Err(self.error("chunk has too many syntax levels"));
+        }
+        Ok(())
+    }
+    fn func(&mut self) -> &mut FuncState {
+        self.funcs.last_mut().unwrap()
+    }
+
+    fn name(&mut self) -> Result<Name> {
+        match &self.token {
+            Token::Name(name) => {
+                let name = Name { name: name.clone(), span: self.span };
+                self.advance()?;
+                Ok(name)
+            }
+            _ => Err(self.error("'<name>' expected")),
+        }
+    }
+    fn block_follow(&self) -> bool {
+        matches!(self.token, Token::Eof | Token::Sym("else" | "elseif" | "end" | "until"))
+    }
+    fn block(&mut self) -> Result<Block> {
+        self.enter_level()?;
+        let start = self.span;
+        let mut stats = Vec::new();
+        while !self.block_follow() {
+            let (stat, last) = self.statement()?;
+            stats.push(stat);
+            self.test_next(";")?;
+            if last {
+                break;
+            }
+        }
+        self.levels -= 1;
+        let span = match stats.is_empty() {
+            true => Span { end: start.start, end_line: start.line, ..start },
+            false => self.finish(start),
+        };
+        Ok(Block { stats, span })
+    }
+    /// A block that `break` may leave.
+    fn loop_block(&mut self) -> Result<Block> {
+        self.func().loops += 1;
+        let block = self.block()?;
+        self.func().loops -= 1;
+        Ok(block)
+    }
+    /// Parses one statement, returning whether it must end its block.
+    fn statement(&mut self) -> Result<(Stat, bool)> {
+        let start = self.span;
+        let line = start.line;
+        let mut last = false;
+        let kind = match self.token {
+            Token::Sym("if") => self.if_stat(line)?,
+            Token::Sym("while") => {
+                self.advance()?;
+                let cond = self.expr()?;
+                self.expect("do")?;
+                let body = self.loop_block()?;
+                self.check_match("end", "while", line)?;
+                StatKind::While { cond, body }
+            }
+            Token::Sym("do") => {
+                self.advance()?;
+                let block = self.block()?;
+                self.check_match("end", "do", line)?;
+                StatKind::Do(block)
+            }
+            Token::Sym("for") => self.for_stat(line)?,
+            Token::Sym("repeat") => {
+                self.advance()?;
+                let body = self.loop_block()?;
+                self.check_match("until", "repeat", line)?;
+                let cond = self.expr()?;
+                StatKind::Repeat { body, cond }
+            }
+            Token::Sym("function") => {
+                self.advance()?;
+                let mut path = vec![self.name()?];
+                while self.test_next(".")? {
+                    path.push(self.name()?);
+                }
+                let method = if self.test_next(":")? { Some(self.name()?) } else { None };
+                let body = self.body(start)?;
+                StatKind::Function { name: FuncName { path, method }, body }
+            }
+            Token::Sym("local") => {
+                self.advance()?;
+                if self.check("function") {
+                    let func_start = self.span;
+                    self.advance()?;
+                    let name = self.name()?;
+                    let body = self.body(func_start)?;
+                    StatKind::LocalFunction { name, body }
+                } else {
+                    let mut names = vec![self.name()?];
+                    while self.test_next(",")? {
+                        names.push(self.name()?);
+                    }
+                    let exprs = if self.test_next("=")? { self.expr_list()? } else { Vec::new() };
+                    StatKind::Local { names, exprs }
+                }
+            }
+            Token::Sym("return") => {
+                self.advance()?;
+                last = true;
+                if self.block_follow() || self.check(";") {
+                    StatKind::Return(Vec::new())
+                } else {
+                    StatKind::Return(self.expr_list()?)
+                }
+            }
+            Token::Sym("break") => {
+                self.advance()?;
+                if self.func().loops == 0 {
+                    return Err(self.error("no loop to break"));
+                }
+                last = true;
+                StatKind::Break
+            }
+            _ => self.expr_stat()?,
+        };
+        Ok((Stat { kind, span: self.finish(start) }, last))
+    }
+    fn if_stat(&mut self, line: u32) -> Result<StatKind> {
+        let mut clauses = Vec::new();
+        loop {
+            // `if` or `elseif`
+            self.advance()?;
+            let cond = self.expr()?;
+            self.expect("then")?;
+            clauses.push((cond, self.block()?));
+            if !self.check("elseif") {
+                break;
+            }
+        }
+        let else_block = if self.test_next("else")? { Some(self.block()?) } else { None };
+        self.check_match("end", "if", line)?;
+        Ok(StatKind::If { clauses, else_block })
+    }
+    fn for_stat(&mut self, line: u32) -> Result<StatKind> {
+        self.advance()?;
+        let var = self.name()?;
+        let kind = match self.token {
+            Token::Sym("=") => {
+                self.advance()?;
+                let start = self.expr()?;
+                self.expect(",")?;
+                let limit = self.expr()?;
+                let step = if self.test_next(",")? { Some(self.expr()?) } else { None };
+                self.expect("do")?;
+                let body = self.loop_block()?;
+                StatKind::NumericFor { var, start, limit, step, body }
+            }
+            Token::Sym("," | "in") => {
+                let mut names = vec![var];
+                while self.test_next(",")? {
+                    names.push(self.name()?);
+                }
+                self.expect("in")?;
+                let exprs = self.expr_list()?;
+                self.expect("do")?;
+                let body = self.loop_block()?;
+                StatKind::GenericFor { names, exprs, body }
+            }
+            _ => return Err(self.error("'=' or 'in' expected")),
+        };
+        self.check_match("end", "for", line)?;
+        Ok(kind)
+    }
+    fn expr_stat(&mut self) -> Result<StatKind> {
+        let first = self.suffixed_expr()?;
+        if first.kind.is_multi() {
+            return Ok(StatKind::Call(first));
+        }
+        let mut targets = vec![first];
+        loop {
+            if !matches!(targets.last().unwrap().kind, ExprKind::Name(_) | ExprKind::Index { .. }) {
+                return Err(self.error("syntax error"));
+            }
+            if !self.test_next(",")? {
+                break;
+            }
+            targets.push(self.suffixed_expr()?);
+        }
+        self.expect("=")?;
+        let exprs = self.expr_list()?;
+        Ok(StatKind::Assign { targets, exprs })
+    }
+    /// `(params) block end`, for a function starting at `start`.
+    fn body(&mut self, start: Span) -> Result<FunctionBody> {
+        self.expect("(")?;
+        let mut params = Vec::new();
+        let mut is_vararg = false;
+        if !self.check(")") {
+            loop {
+                match self.token {
+                    Token::Name(_) => params.push(self.name()?),
+                    Token::Sym("...") => {
+                        self.advance()?;
+                        is_vararg = true;
+                    }
+                    _ => return Err(self.error("<name> or '...' expected")),
+                }
+                if is_vararg || !self.test_next(",")? {
+                    break;
+                }
+            }
+        }
+        self.expect(")")?;
+        self.funcs.push(FuncState { is_vararg, loops: 0 });
+        let block = self.block()?;
+        self.funcs.pop();
+        self.check_match("end", "function", start.line)?;
+        Ok(FunctionBody { params, is_vararg, block, span: self.finish(start) })
+    }
+
+    fn expr_list(&mut self) -> Result<Vec<Expr>> {
+        let mut exprs = vec![self.expr()?];
+        while self.test_next(",")? {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+    pub fn expr(&mut self) -> Result<Expr> {
+        self.sub_expr(0)
+    }
+    /// An expression whose binary operators all bind tighter than `limit`.
+    fn sub_expr(&mut self, limit: u8) -> Result<Expr> {
+        self.enter_level()?;
+        let start = self.span;
+        let unary = match self.token {
+            Token::Sym("not") => Some(UnOp::Not),
+            Token::Sym("-") => Some(UnOp::Neg),
+            Token::Sym("#") => Some(UnOp::Len),
+            _ => None,
+        };
+        let mut lhs = match unary {
+            Some(op) => {
+                self.advance()?;
+                let expr = self.sub_expr(UNARY_PRIORITY)?;
+                Expr { kind: ExprKind::Unary { op, expr: Box::new(expr) }, span: self.finish(start) }
+            }
+            None => self.simple_expr()?,
+        };
+        while let Some(op) = self.binary_op() {
+            let (left, right) = op.priority();
+            if left <= limit {
+                break;
+            }
+            self.advance()?;
+            let rhs = self.sub_expr(right)?;
+            let span = lhs.span.to(rhs.span);
+            lhs = Expr { kind: ExprKind::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs) }, span };
+        }
+        self.levels -= 1;
+        Ok(lhs)
+    }
+    fn binary_op(&self) -> Option<BinOp> {
+        let Token::Sym(sym) = self.token else { return None };
+        Some(match sym {
+            "+" => BinOp::Add,
+            "-" => BinOp::Sub,
+            "*" => BinOp::Mul,
+            "/" => BinOp::Div,
+            "%" => BinOp::Mod,
+            "^" => BinOp::Pow,
+            ".." => BinOp::Concat,
+            "==" => BinOp::Eq,
+            "~=" => BinOp::Ne,
+            "<" => BinOp::Lt,
+            "<=" => BinOp::Le,
+            ">" => BinOp::Gt,
+            ">=" => BinOp::Ge,
+            "and" => BinOp::And,
+            "or" => BinOp::Or,
+            _ => return None,
+        })
+    }
+    fn simple_expr(&mut self) -> Result<Expr> {
+        let start = self.span;
+        let kind = match &self.token {
+            Token::Number(n) => ExprKind::Number(*n),
+            Token::String(s) => ExprKind::String(s.clone()),
+            Token::Sym("nil") => ExprKind::Nil,
+            Token::Sym("true") => ExprKind::True,
+            Token::Sym("false") => ExprKind::False,
+            Token::Sym("...") => {
+                if !self.funcs.last().unwrap().is_vararg {
+                    return Err(self.error("cannot use '...' outside a vararg function"));
+                }
+                ExprKind::Vararg
+            }
+            Token::Sym("{") => return self.table(),
+            Token::Sym("function") => {
+                self.advance()?;
+                let body = self.body(start)?;
+                return Ok(Expr { kind: ExprKind::Function(Box::new(body)), span: self.finish(start) });
+            }
+            _ => return self.suffixed_expr(),
+        };
+        self.advance()?;
+        Ok(Expr { kind, span: start })
+    }
+    /// A name or parenthesised expression.
+    fn primary_expr(&mut self) -> Result<Expr> {
+        let start = self.span;
+        match &self.token {
+            Token::Name(name) => {
+                let kind = ExprKind::Name(name.clone());
+                self.advance()?;
+                Ok(Expr { kind, span: start })
+            }
+            Token::Sym("(") => {
+                self.advance()?;
+                let inner = self.expr()?;
+                self.check_match(")", "(", start.line)?;
+                Ok(Expr { kind: ExprKind::Paren(Box::new(inner)), span: self.finish(start) })
+            }
+            _ => Err(self.error("unexpected symbol")),
+        }
+    }
+    /// A primary expression followed by any number of field accesses, index
+    /// operations and calls.
+    fn suffixed_expr(&mut self) -> Result<Expr> {
+        let start = self.span;
+        let mut expr = self.primary_expr()?;
+        loop {
+            let kind = match self.token {
+                Token::Sym(".") => {
+                    self.advance()?;
+                    let field = self.name()?;
+                    let key = Expr { kind: ExprKind::String(field.name.into_bytes()), span: field.span };
+                    ExprKind::Index { obj: Box::new(expr), key: Box::new(key) }
+                }
+                Token::Sym("[") => {
+                    self.advance()?;
+                    let key = self.expr()?;
+                    self.expect("]")?;
+                    ExprKind::Index { obj: Box::new(expr), key: Box::new(key) }
+                }
+                Token::Sym(":") => {
+                    self.advance()?;
+                    let method = self.name()?;
+                    let args = self.call_args()?;
+                    ExprKind::MethodCall { obj: Box::new(expr), method, args }
+                }
+                Token::Sym("(" | "{") | Token::String(_) => {
+                    let args = self.call_args()?;
+                    ExprKind::Call { func: Box::new(expr), args }
+                }
+                _ => return Ok(expr),
+            };
+            expr = Expr { kind, span: self.finish(start) };
+        }
+    }
+    fn call_args(&mut self) -> Result<Vec<Expr>> {
+        let start = self.span;
+        match &self.token {
+            Token::Sym("(") => {
+                if start.line != self.prev.end_line {
+                    return Err(self.error("ambiguous syntax (function call x new statement)"));
+                }
+                self.advance()?;
+                let args = if self.check(")") { Vec::new() } else { self.expr_list()? };
+                self.check_match(")", "(", start.line)?;
+                Ok(args)
+            }
+            Token::Sym("{") => Ok(vec![self.table()?]),
+            Token::String(s) => {
+                let arg = Expr { kind: ExprKind::String(s.clone()), span: start };
+                self.advance()?;
+                Ok(vec![arg])
+            }
+            _ => Err(self.error("function arguments expected")),
+        }
+    }
+    fn table(&mut self) -> Result<Expr> {
+        let start = self.span;
+        self.expect("{")?;
+        let mut fields = Vec::new();
+        while !self.check("}") {
+            let named = matches!(self.token, Token::Name(_)) && self.lookahead()? == &Token::Sym("=");
+            let field = match self.token {
+                Token::Name(_) if named => {
+                    let name = self.name()?;
+                    self.advance()?;
+                    TableField::Named(name, self.expr()?)
+                }
+                Token::Sym("[") => {
+                    self.advance()?;
+                    let key = self.expr()?;
+                    self.expect("]")?;
+                    self.expect("=")?;
+                    TableField::Keyed(key, self.expr()?)
+                }
+                _ => TableField::Positional(self.expr()?),
+            };
+            fields.push(field);
+            if !self.test_next(",")? && !self.test_next(";")? {
+                break;
+            }
+        }
+        self.check_match("}", "{", start.line)?;
+        Ok(Expr { kind: ExprKind::Table(fields), span: self.finish(start) })
+    }
+}
@@ -0,0 +1,426 @@
+//! Prints an AST back as Lua source that parses to the same tree, with
+//! parentheses only where precedence needs them.
+
+use std::fmt::Write;
+
+use super::{ast::*, lexer::is_name};
+
+/// How the printer lays out its output.
+#[derive(Debug, Clone)]
+pub struct PrintOptions {
+    /// Spaces per level of nesting.
+    pub indent_width: usize,
+    /// Writes strings that span lines, or would need more escapes than
+    /// brackets, as long strings.
+    pub long_strings: bool,
+    /// Ends the first line of each statement with `-- pc N`, taking N from
+    /// the start of its span as the decompiler sets it to the statement's
+    /// first instruction.
+    pub pc_comments: bool,
+}
+impl Default for PrintOptions {
+    fn default() -> Self {
+        Self { indent_width: 4, long_strings: true, pc_comments: false }
+    }
+}
+
+/// Lua source for `block` as a whole chunk.
+pub fn print_block(block: &Block) -> String {
+    print_block_with(block, &PrintOptions::default())
+}
+
+/// Lua source for `block` as a whole chunk, laid out as `options` say.
+pub fn print_block_with(block: &Block, options: &PrintOptions) -> String {
+    let mut printer = Printer::new(options);
+    printer.block(block);
+    printer.comment();
+    printer.out
+}
+
+/// Lua source for a single expression.
+pub fn print_expr(expr: &Expr) -> String {
+    let options = PrintOptions::default();
+    let mut printer = Printer::new(&options);
+    printer.expr(expr, 0);
+    printer.out
+}
+
+/// A string literal for `s` in double quotes, escaping what the lexer would
+/// not read back as is.
+pub fn quote_string(s: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for (i, c) in s.iter().enumerate() {
+        match c {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(*c as char),
+            // a digit after the escape would be read as part of it
+            _ if s.get(i + 1).is_some_and(u8::is_ascii_digit) => write!(out, "\\{:03}", c).unwrap(),
+            _ => write!(out, "\\{}", c).unwrap(),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A long string literal for `s`, at the lowest level whose closing bracket
+/// does not occur in it, or `None` when the lexer would not read `s` back
+/// from one.
+pub fn long_string(s: &[u8]) -> Option<String> {
+    // carriage returns read back as newlines
+    if !s.iter().all(|c| matches!(c, b'\n' | b'\t' | 0x20..=0x7e)) {
+        return None;
+    }
+    let s = std::str::from_utf8(s).ok()?;
+    // `[[` in a level 0 string is an error
+    let level = (0..).find(|n| {
+        let close = format!("]{}]", "=".repeat(*n));
+        format!("{}{}", s, close).find(&close) == Some(s.len()) && (*n > 0 || !s.contains("[["))
+    })?;
+    let sep = "=".repeat(level);
+    // the newline right after the opening bracket is skipped
+    let newline = if s.starts_with('\n') { "\n" } else { "" };
+    Some(format!("[{sep}[{newline}{s}]{sep}]"))
+}
+
+/// A number literal that reads back as exactly `n`.
+pub fn number(n: f64) -> String {
+    if n.is_nan() {
+        "0/0".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "1/0" } else { "-1/0" }.to_string()
+    } else if n != 0.0 && (n.abs() >= 1e16 || n.abs() < 1e-5) {
+        format!("{:e}", n)
+    } else {
+        format!("{}", n)
+    }
+}
+
+/// Binding priority of an expression as an operand: binary operators have
+/// their left priority, unary ones `UNARY_PRIORITY`, and everything that
+/// never needs parentheses more than any operator.
+fn priority(e: &Expr) -> u8 {
+    match &e.kind {
+        ExprKind::Binary { op, .. } => op.priority().0,
+        ExprKind::Unary { .. } => UNARY_PRIORITY,
+        ExprKind::Number(n) if n.is_nan() || n.is_infinite() => BinOp::Div.priority().0,
+        ExprKind::Number(n) if n.is_sign_negative() => UNARY_PRIORITY,
+        _ => u8::MAX,
+    }
+}
+
+fn is_prefix(e: &Expr) -> bool {
+    matches!(
+        e.kind,
+        ExprKind::Name(_)
+            | ExprKind::Index { .. }
+            | ExprKind::Call { .. }
+            | ExprKind::MethodCall { .. }
+            | ExprKind::Paren(_)
+    )
+}
+
+struct Printer<'a> {
+    out: String,
+    indent: usize,
+    options: &'a PrintOptions,
+    /// The comment for the statement being printed, until its line ends.
+    comment: Option<String>,
+}
+impl<'a> Printer<'a> {
+    fn new(options: &'a PrintOptions) -> Self {
+        Self { out: String::new(), indent: 0, options, comment: None }
+    }
+
+    fn line(&mut self) {
+        self.comment();
+        self.out.push('\n');
+        self.out.push_str(&" ".repeat(self.indent * self.options.indent_width));
+    }
+    fn comment(&mut self) {
+        if let Some(comment) = self.comment.take() {
+            write!(self.out, " -- {}", comment).unwrap();
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        for (i, stat) in block.stats.iter().enumerate() {
+            // before the comment on the line
+            let end = self.out.len();
+            if i > 0 {
+                self.line();
+            }
+            let start = self.out.len();
+            self.stat(stat);
+            if i > 0 && self.out[start..].starts_with('(') {
+                // `f()\n(g)()` would read as one call
+                self.out.insert(end, ';');
+            }
+        }
+    }
+    fn nested(&mut self, block: &Block) {
+        self.indent += 1;
+        if !block.stats.is_empty() {
+            self.line();
+            self.block(block);
+        }
+        self.indent -= 1;
+        self.line();
+    }
+
+    fn stat(&mut self, stat: &Stat) {
+        if self.options.pc_comments {
+            self.comment = Some(format!("pc {}", stat.span.start + 1));
+        }
+        match &stat.kind {
+            StatKind::Local { names, exprs } => {
+                self.out.push_str("local ");
+                self.names(names);
+                if !exprs.is_empty() {
+                    self.out.push_str(" = ");
+                    self.exprs(exprs);
+                }
+            }
+            StatKind::Assign { targets, exprs } => {
+                self.exprs(targets);
+                self.out.push_str(" = ");
+                self.exprs(exprs);
+            }
+            StatKind::Call(call) => self.expr(call, 0),
+            StatKind::Do(block) => {
+                self.out.push_str("do");
+                self.nested(block);
+                self.out.push_str("end");
+            }
+            StatKind::While { cond, body } => {
+                self.out.push_str("while ");
+                self.expr(cond, 0);
+                self.out.push_str(" do");
+                self.nested(body);
+                self.out.push_str("end");
+            }
+            StatKind::Repeat { body, cond } => {
+                self.out.push_str("repeat");
+                self.nested(body);
+                self.out.push_str("until ");
+                self.expr(cond, 0);
+            }
+            StatKind::If { clauses, else_block } => {
+                for (i, (cond, block)) in clauses.iter().enumerate() {
+                    self.out.push_str(if i == 0 { "if " } else { "elseif " });
+                    self.expr(cond, 0);
+                    self.out.push_str(" then");
+                    self.nested(block);
+                }
+                if let Some(block) = else_block {
+                    self.out.push_str("else");
+                    self.nested(block);
+                }
+                self.out.push_str("end");
+            }
+            StatKind::NumericFor { var, start, limit, step, body } => {
+                write!(self.out, "for {} = ", var.name).unwrap();
+                self.expr(start, 0);
+                self.out.push_str(", ");
+                self.expr(limit, 0);
+                if let Some(step) = step {
+                    self.out.push_str(", ");
+                    self.expr(step, 0);
+                }
+                self.out.push_str(" do");
+                self.nested(body);
+                self.out.push_str("end");
+            }
+            StatKind::GenericFor { names, exprs, body } => {
+                self.out.push_str("for ");
+                self.names(names);
+                self.out.push_str(" in ");
+                self.exprs(exprs);
+                self.out.push_str(" do");
+                self.nested(body);
+                self.out.push_str("end");
+            }
+            StatKind::Function { name, body } => {
+                self.out.push_str("function ");
+                let path: Vec<&str> = name.path.iter().map(|n| n.name.as_str()).collect();
+                self.out.push_str(&path.join("."));
+                if let Some(method) = &name.method {
+                    write!(self.out, ":{}", method.name).unwrap();
+                }
+                self.function_body(body);
+            }
+            StatKind::LocalFunction { name, body } => {
+                write!(self.out, "local function {}", name.name).unwrap();
+                self.function_body(body);
+            }
+            StatKind::Return(exprs) => {
+                self.out.push_str("return");
+                if !exprs.is_empty() {
+                    self.out.push(' ');
+                    self.exprs(exprs);
+                }
+            }
+            StatKind::Break => self.out.push_str("break"),
+        }
+    }
+
+    fn names(&mut self, names: &[Name]) {
+        for (i, name) in names.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.out.push_str(&name.name);
+        }
+    }
+    fn exprs(&mut self, exprs: &[Expr]) {
+        for (i, e) in exprs.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.expr(e, 0);
+        }
+    }
+    fn function_body(&mut self, body: &FunctionBody) {
+        self.out.push('(');
+        self.names(&body.params);
+        if body.is_vararg {
+            self.out.push_str(if body.params.is_empty() { "..." } else { ", ..." });
+        }
+        self.out.push(')');
+        self.nested(&body.block);
+        self.out.push_str("end");
+    }
+
+    /// Prints `e` where an operator binds it only if its priority is more
+    /// than `limit`, as in `subexpr` of lparser.c.
+    fn expr(&mut self, e: &Expr, limit: u8) {
+        if priority(e) <= limit {
+            self.out.push('(');
+            self.expr(e, 0);
+            self.out.push(')');
+            return;
+        }
+        match &e.kind {
+            ExprKind::Nil => self.out.push_str("nil"),
+            ExprKind::True => self.out.push_str("true"),
+            ExprKind::False => self.out.push_str("false"),
+            ExprKind::Number(n) => self.out.push_str(&number(*n)),
+            ExprKind::String(s) => self.string(s),
+            ExprKind::Vararg => self.out.push_str("..."),
+            ExprKind::Function(body) => {
+                self.out.push_str("function");
+                self.function_body(body);
+            }
+            ExprKind::Table(fields) => self.table(fields),
+            ExprKind::Binary { op, lhs, rhs } => {
+                let (left, right) = op.priority();
+                // the left operand must end before `op` could extend it
+                self.operand(lhs, left);
+                write!(self.out, " {} ", op.as_str()).unwrap();
+                self.expr(rhs, right);
+            }
+            ExprKind::Unary { op, expr } => {
+                self.out.push_str(op.as_str());
+                let start = self.out.len();
+                self.expr(expr, UNARY_PRIORITY - 1);
+                let operand = &self.out[start..];
+                if *op == UnOp::Not || (*op == UnOp::Neg && operand.starts_with('-')) {
+                    self.out.insert(start, ' ');
+                }
+            }
+            ExprKind::Name(name) => self.out.push_str(name),
+            ExprKind::Index { obj, key } => {
+                self.prefix(obj);
+                match &key.kind {
+                    ExprKind::String(s) if is_name(s) => {
+                        write!(self.out, ".{}", String::from_utf8_lossy(s)).unwrap();
+                    }
+                    _ => {
+                        self.out.push('[');
+                        self.expr(key, 0);
+                        self.out.push(']');
+                    }
+                }
+            }
+            ExprKind::Call { func, args } => {
+                self.prefix(func);
+                self.args(args);
+            }
+            ExprKind::MethodCall { obj, method, args } => {
+                self.prefix(obj);
+                write!(self.out, ":{}", method.name).unwrap();
+                self.args(args);
+            }
+            ExprKind::Paren(inner) => {
+                self.out.push('(');
+                self.expr(inner, 0);
+                self.out.push(')');
+            }
+        }
+    }
+    /// The left operand of a binary operator with left priority `left`,
+    /// which would take the operand's own right operand if it bound tighter.
+    fn operand(&mut self, e: &Expr, left: u8) {
+        let right = match &e.kind {
+            ExprKind::Binary { op, .. } => op.priority().1,
+            _ => priority(e),
+        };
+        if left > right {
+            self.out.push('(');
+            self.expr(e, 0);
+            self.out.push(')');
+        } else {
+            self.expr(e, 0);
+        }
+    }
+    fn prefix(&mut self, e: &Expr) {
+        if is_prefix(e) {
+            self.expr(e, 0);
+        } else {
+            self.out.push('(');
+            self.expr(e, 0);
+            self.out.push(')');
+        }
+    }
+    fn string(&mut self, s: &[u8]) {
+        let quoted = quote_string(s);
+        let long = self.options.long_strings.then(|| long_string(s)).flatten().filter(|long| {
+            s.iter().filter(|c| **c == b'\n').count() > 1 || long.len() < quoted.len()
+        });
+        self.out.push_str(&long.unwrap_or(quoted));
+    }
+    fn args(&mut self, args: &[Expr]) {
+        self.out.push('(');
+        self.exprs(args);
+        self.out.push(')');
+    }
+    fn table(&mut self, fields: &[TableField]) {
+        if fields.is_empty() {
+            self.out.push_str("{}");
+            return;
+        }
+        self.out.push('{');
+        for (i, field) in fields.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            match field {
+                TableField::Positional(e) => self.expr(e, 0),
+                TableField::Named(name, e) => {
+                    write!(self.out, "{} = ", name.name).unwrap();
+                    self.expr(e, 0);
+                }
+                TableField::Keyed(k, e) => {
+                    self.out.push('[');
+                    self.expr(k, 0);
+                    self.out.push_str("] = ");
+                    self.expr(e, 0);
+                }
+            }
+        }
+        self.out.push('}');
+    }
+}
@@ -0,0 +1,176 @@
+//! The prompt of `luatest debug`: stops the script where asked and reads
+//! commands from stdin until told to go on.
+
+use std::io::{self, Lines, StdinLock, Write};
+
+use luatest::vm::{
+    debugger::{BreakpointKind, DebugHandler, Pause, Resume, StopReason},
+    error::RuntimeError,
+    GCLuaValue,
+};
+
+const HELP: &str = "commands:
+  c, continue        run to the next breakpoint
+  s, step            run to the next line, stepping into calls
+  n, next            run to the next line of this function, stepping over calls
+  f, finish          run until this function returns
+  b <file:line|line|name>
+                     set a breakpoint at a line, or on entry to a function
+  d <id>             delete a breakpoint
+  bl                 list breakpoints
+  bt                 show the stack
+  fr <n>             select frame n of the stack, 0 being where the script stopped
+  l, list            show the source around the selected frame's line
+  locals             show the locals of the selected frame
+  upvalues           show the upvalues of the selected frame
+  p <expr>           evaluate an expression in the selected frame
+  q, quit            stop the script
+  h, help            show this";
+
+pub struct Prompt {
+    lines: Lines<StdinLock<'static>>,
+    /// Set once stdin runs out, after which the script just runs.
+    done: bool,
+}
+impl Prompt {
+    pub fn new() -> Self {
+        Self { lines: io::stdin().lines(), done: false }
+    }
+}
+
+impl DebugHandler for Prompt {
+    fn paused(&mut self, pause: &mut Pause) -> anyhow::Result<Resume> {
+        if self.done {
+            return Ok(Resume::Continue);
+        }
+        if let StopReason::Breakpoint(id) = pause.reason {
+            println!("breakpoint {}", id);
+        }
+        let mut frame = 0;
+        show_line(pause, frame);
+        loop {
+            print!("(ldb) ");
+            io::stdout().flush()?;
+            let Some(line) = self.lines.next().transpose()? else {
+                println!();
+                self.done = true;
+                return Ok(Resume::Continue);
+            };
+            let (command, arg) = match line.trim().split_once(' ') {
+                Some((command, arg)) => (command, arg.trim()),
+                None => (line.trim(), ""),
+            };
+            match command {
+                "c" | "continue" => return Ok(Resume::Continue),
+                "s" | "step" => return Ok(Resume::StepInto),
+                "n" | "next" => return Ok(Resume::StepOver),
+                "f" | "finish" => return Ok(Resume::StepOut),
+                "q" | "quit" => return Ok(Resume::Abort),
+                "b" | "break" => match breakpoint(pause, arg) {
+                    Some(kind) => {
+                        let id = pause.breakpoints().add(kind);
+                        println!("breakpoint {} set", id);
+                    }
+                    None => println!("expected file:line, line or function name"),
+                },
+                "d" | "delete" => match arg.parse() {
+                    Ok(id) if pause.breakpoints().remove(id) => println!("breakpoint {} deleted", id),
+                    _ => println!("no breakpoint `{}`", arg),
+                },
+                "bl" => {
+                    for b in pause.breakpoints().iter() {
+                        match &b.kind {
+                            BreakpointKind::Line { source, line } => println!("{}\t{}:{}", b.id, source, line),
+                            BreakpointKind::Function(name) => println!("{}\tfunction {}", b.id, name),
+                        }
+                    }
+                }
+                "bt" => {
+                    for (level, info) in pause.frames().iter().enumerate() {
+                        let at = info.current_line.map_or(String::new(), |l| format!(":{}", l));
+                        let name = match &info.name {
+                            Some(name) => format!("function '{}'", name),
+                            None if info.what == "main" => "main chunk".to_string(),
+                            None => "function ?".to_string(),
+                        };
+                        let mark = if level == frame { '*' } else { ' ' };
+                        println!("{}#{} {}{} in {}", mark, level, info.short_src, at, name);
+                    }
+                }
+                "fr" | "frame" => match arg.parse() {
+                    Ok(level) if level < pause.vm.stack_depth() => {
+                        frame = level;
+                        show_line(pause, frame);
+                    }
+                    _ => println!("no frame `{}`", arg),
+                },
+                "l" | "list" => list(pause, frame),
+                "locals" => show_values(pause.locals(frame)),
+                "upvalues" => show_values(pause.upvalues(frame)),
+                "p" | "print" => match pause.evaluate(frame, arg) {
+                    Ok(values) => {
+                        let shown: Vec<String> = values.iter().map(|v| v.borrow().as_string(true)).collect();
+                        println!("{}", shown.join("\t"));
+                    }
+                    Err(e) => match e.downcast_ref::<RuntimeError>() {
+                        Some(e) => println!("{}", e.message),
+                        None => println!("{}", e),
+                    },
+                },
+                "h" | "help" => println!("{}", HELP),
+                "" => {}
+                _ => println!("unknown command `{}`, try help", command),
+            }
+        }
+    }
+}
+
+/// The breakpoint `arg` asks for: `file:line`, a line of the chunk stopped
+/// in, or a function name.
+fn breakpoint(pause: &Pause, arg: &str) -> Option<BreakpointKind> {
+    if let Some((source, line)) = arg.rsplit_once(':') {
+        let line = line.parse().ok()?;
+        return Some(BreakpointKind::Line { source: source.to_string(), line });
+    }
+    if let Ok(line) = arg.parse() {
+        let (chunk, _) = pause.location(0)?;
+        let source = chunk.strip_prefix('@').unwrap_or(&chunk).to_string();
+        return Some(BreakpointKind::Line { source, line });
+    }
+    (!arg.is_empty()).then(|| BreakpointKind::Function(arg.to_string()))
+}
+
+/// Prints where frame `level` is and the text of its line.
+fn show_line(pause: &mut Pause, level: usize) {
+    let Some(info) = pause.vm.get_info(level) else {
+        return;
+    };
+    let Some(line) = info.current_line else {
+        println!("{}", info.short_src);
+        return;
+    };
+    match pause.source_line(&info.source, line) {
+        Some(text) => println!("{}:{}\n{}\t{}", info.short_src, line, line, text),
+        None => println!("{}:{}", info.short_src, line),
+    }
+}
+
+fn list(pause: &mut Pause, level: usize) {
+    let Some((source, current)) = pause.location(level) else {
+        println!("no source line");
+        return;
+    };
+    for line in current.saturating_sub(5).max(1)..=current + 5 {
+        let Some(text) = pause.source_line(&source, line) else {
+            break;
+        };
+        let mark = if line == current { "=>" } else { "  " };
+        println!("{} {}\t{}", mark, line, text);
+    }
+}
+
+fn show_values(values: Vec<(String, GCLuaValue)>) {
+    for (name, value) in values {
+        println!("{} = {}", name, value.borrow().as_string(true));
+    }
+}
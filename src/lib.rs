@@ -0,0 +1,6 @@
+// gc_derive 0.4 emits its `Drop` impls inside an anonymous const.
+#![allow(non_local_definitions)]
+pub mod compiler;
+pub mod vm;
+#[macro_use]
+pub mod macros;
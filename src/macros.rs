@@ -8,6 +8,15 @@ macro_rules! discriminant_to_literal {
     };
 }
 #[macro_export]
+macro_rules! literal_to_discriminant {
+    (String, $literal:expr) => {
+        $literal.to_string()
+    };
+    ($discriminant_type:ident, $literal:expr) => {
+        $literal
+    };
+}
+#[macro_export]
 macro_rules! def_enum {
     (
         $ident:ident ($discriminant_type:ident) {
@@ -23,7 +32,8 @@ macro_rules! def_enum {
             ),* $(,)?
         }
     ) => {
-        use crate::discriminant_to_literal;
+        use $crate::discriminant_to_literal;
+        use $crate::literal_to_discriminant;
         use anyhow::bail;
         #[derive(Debug, Clone, Finalize, Trace, PartialEq)]
         pub enum $ident {
@@ -64,6 +74,13 @@ macro_rules! def_enum {
                     _ => bail!("No discriminant for val {}", discriminant)
                 }
             }
+            pub fn to_num(&self) -> $discriminant_type {
+                match self {
+                    $(
+                        $ident::$variant $( { $( $field: _, )* } )? => literal_to_discriminant!($discriminant_type, $discriminant),
+                    )*
+                }
+            }
         }
     };
 }
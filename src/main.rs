@@ -1,18 +1,281 @@
-use std::fs::File;
+//! `luatest`: runs, lists, decompiles and checks Lua 5.1 chunks.
 
-use vm::{chunk_parser::LuaChunk, LuaVM, decompiler::LuaDecompiler};
+mod debugger;
+mod repl;
 
-use crate::vm::chunk_parser::ChunkHeader;
+use std::{
+    collections::HashSet,
+    env, fs,
+    io::{self, BufReader, Read},
+    process,
+    rc::Rc,
+};
+
+use anyhow::{bail, Context};
+use luatest::{
+    compiler::{compile, printer::PrintOptions},
+    vm::{
+        chunk_parser::{FunctionBlock, LuaChunk, LuaConstant},
+        dap,
+        debugger::Debugger,
+        decompiler::LuaDecompiler,
+        dot::to_dot,
+        error::RuntimeError,
+        hostio::{HostIo, StdHostIo},
+        listing::listing,
+        string::LuaString,
+        table::{GCLuaTable, LuaTable},
+        trace::{JsonSink, TextSink, TraceSink},
+        verify::verify,
+        GCLuaValue, LuaVM, LuaValue,
+    },
+};
+
+const USAGE: &str = "usage: luatest <command> [options] <file> [args...]
+
+commands:
+  run [--trace text|json] <file> [args...]
+                         run a script or bytecode file, with args in `arg` and `...`;
+                         --trace writes each call, return and instruction to stderr
+  debug <file> [args...]  run a script under the debugger, stopping before its first line
+  dap                    serve the Debug Adapter Protocol on stdin and stdout
+  disasm <file>          list the instructions, like `luac -l`
+  decompile [--indent N] [--pc-comments] [--no-long-strings] <file>
+                         print Lua source recovered from the bytecode
+  verify <file>          check that bytecode is well formed
+  cfg --dot <file>       print the control-flow graph of every function as Graphviz DOT,
+                         for `dot -Tsvg`
+  info <file>            header fields, function count and constant statistics
+  repl                   read and run Lua interactively
+
+<file> is bytecode or Lua source, which is compiled first; `-` reads stdin.";
 
-pub mod vm;
-#[macro_use]
-pub mod macros;
 fn main() {
-    let main = LuaChunk::from_reader(&mut File::open("luac.out").unwrap()).unwrap();
-    // let mut decomp = LuaDecompiler::new(main);
-    // println!("{}", decomp.run());
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = cli(&args) {
+        match e.downcast_ref::<RuntimeError>() {
+            Some(e) => eprintln!("luatest: {}\n{}", e.message, e.traceback),
+            None => eprintln!("luatest: {:#}", e),
+        }
+        process::exit(1);
+    }
+}
+
+fn cli(args: &[String]) -> anyhow::Result<()> {
+    let Some((command, rest)) = args.split_first() else {
+        bail!("no command given\n{}", USAGE);
+    };
+    match command.as_str() {
+        "run" => {
+            let (trace, rest) = match rest {
+                [flag, format, rest @ ..] if flag == "--trace" => (Some(format.as_str()), rest),
+                [flag] if flag == "--trace" => bail!("--trace needs a format, text or json"),
+                _ => (None, rest),
+            };
+            let Some((path, script_args)) = rest.split_first() else {
+                bail!("no file given\n{}", USAGE);
+            };
+            run(path, script_args, trace)
+        }
+        "debug" => {
+            let Some((path, script_args)) = rest.split_first() else {
+                bail!("no file given\n{}", USAGE);
+            };
+            let (mut vm, chunk, args) = script(path, script_args, Rc::new(StdHostIo::new()))?;
+            let ldb = Debugger::new(debugger::Prompt::new());
+            ldb.attach(&mut vm);
+            ldb.stop_on_entry();
+            vm.process_chunk_with(chunk, args)?;
+            Ok(())
+        }
+        "dap" if rest.is_empty() => {
+            let host = Rc::new(StdHostIo::new());
+            dap::serve(BufReader::new(io::stdin()), io::stdout(), host, script)
+        }
+        "disasm" => {
+            print!("{}", listing(&load(file(rest)?)?.func));
+            Ok(())
+        }
+        "decompile" => decompile(rest),
+        "cfg" => match rest.split_first() {
+            Some((flag, rest)) if flag == "--dot" => {
+                print!("{}", to_dot(&load(file(rest)?)?.func));
+                Ok(())
+            }
+            _ => bail!("cfg needs a format, --dot\n{}", USAGE),
+        },
+        "verify" => {
+            let path = file(rest)?;
+            verify(&load(path)?.func)?;
+            println!("{}: ok", path);
+            Ok(())
+        }
+        "info" => {
+            let path = file(rest)?;
+            print!("{}", info(path, &load(path)?));
+            Ok(())
+        }
+        "repl" if rest.is_empty() => repl::repl(new_vm(Rc::new(StdHostIo::new()))),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => bail!("unknown command `{}`\n{}", command, USAGE),
+    }
+}
+
+/// The one file argument of a command.
+fn file(args: &[String]) -> anyhow::Result<&str> {
+    match args {
+        [path] => Ok(path),
+        [] => bail!("no file given\n{}", USAGE),
+        _ => bail!("unexpected arguments after `{}`\n{}", args[0], USAGE),
+    }
+}
+
+/// Reads `path`, or stdin for `-`, and compiles it unless it is bytecode.
+fn load(path: &str) -> anyhow::Result<LuaChunk> {
+    let (bytes, name) = if path == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes).context("cannot read stdin")?;
+        (bytes, "=stdin".to_string())
+    } else {
+        let bytes = fs::read(path).with_context(|| format!("cannot open {}", path))?;
+        (bytes, format!("@{}", path))
+    };
+    if bytes.starts_with(b"\x1bLua") {
+        LuaChunk::from_reader(&mut &bytes[..]).with_context(|| format!("cannot load {}", path))
+    } else {
+        Ok(compile(&bytes, &name)?)
+    }
+}
+
+/// A VM with the libraries scripts run from the command line get, doing
+/// their I/O through `host`.
+fn new_vm(host: Rc<dyn HostIo>) -> LuaVM {
     let mut vm = LuaVM::new();
-    println!("Output: {:#?}", vm.process_chunk(main));
-    //let header = ChunkHeader::from_reader(&mut File::open("luac.out").unwrap()).unwrap();
-    //println!("Hello, world! {:?}", header);
+    vm.open_base(host.clone());
+    vm.open_os(host.clone());
+    vm.open_io(host);
+    vm.open_coroutine();
+    vm.open_debug();
+    vm
+}
+
+fn run(path: &str, script_args: &[String], trace: Option<&str>) -> anyhow::Result<()> {
+    let sink: Option<Box<dyn TraceSink>> = match trace {
+        None => None,
+        Some("text") => Some(Box::new(TextSink::new(io::stderr()))),
+        Some("json") => Some(Box::new(JsonSink::new(io::stderr()))),
+        Some(format) => bail!("unknown trace format `{}`, expected text or json", format),
+    };
+    let (mut vm, chunk, args) = script(path, script_args, Rc::new(StdHostIo::new()))?;
+    vm.set_trace(sink);
+    vm.process_chunk_with(chunk, args)?;
+    Ok(())
+}
+
+/// Loads the script at `path` and makes a VM for it, with `script_args` in
+/// `arg`; also returns them as the values for `...`.
+fn script(path: &str, script_args: &[String], host: Rc<dyn HostIo>) -> anyhow::Result<(LuaVM, LuaChunk, Vec<GCLuaValue>)> {
+    let chunk = load(path)?;
+    verify(&chunk.func).with_context(|| format!("{}: bad code", path))?;
+    let mut vm = new_vm(host);
+    let string = |s: &str| LuaValue::String(s.into()).to_gc();
+    let mut arg = LuaTable::new();
+    arg.set_int(0, string(path));
+    for (i, a) in script_args.iter().enumerate() {
+        arg.set_int(i as i64 + 1, string(a));
+    }
+    vm.set_global("arg", LuaValue::Table(GCLuaTable::new(arg)).to_gc());
+    Ok((vm, chunk, script_args.iter().map(|a| string(a)).collect()))
+}
+
+fn decompile(args: &[String]) -> anyhow::Result<()> {
+    let mut options = PrintOptions::default();
+    let mut rest = args;
+    while let Some((flag, tail)) = rest.split_first().filter(|(flag, _)| flag.starts_with("--")) {
+        rest = tail;
+        match flag.as_str() {
+            "--indent" => {
+                let Some((width, tail)) = rest.split_first() else {
+                    bail!("--indent needs a width");
+                };
+                options.indent_width = width.parse().with_context(|| format!("bad indent width `{}`", width))?;
+                rest = tail;
+            }
+            "--pc-comments" => options.pc_comments = true,
+            "--no-long-strings" => options.long_strings = false,
+            _ => bail!("unknown option `{}`\n{}", flag, USAGE),
+        }
+    }
+    let chunk = load(file(rest)?)?;
+    println!("{}", LuaDecompiler::new(chunk).run_with(&options)?);
+    Ok(())
+}
+
+/// Counts over a function and the functions nested in it.
+#[derive(Default)]
+struct Stats {
+    functions: usize,
+    instructions: usize,
+    nils: usize,
+    booleans: usize,
+    numbers: usize,
+    strings: usize,
+    distinct_strings: HashSet<LuaString>,
+    string_bytes: usize,
+    /// Functions that have no line numbers or local names.
+    stripped: usize,
+}
+impl Stats {
+    fn add(&mut self, f: &FunctionBlock) {
+        self.functions += 1;
+        self.instructions += f.list_instructions.len();
+        for c in &f.list_const {
+            match &**c {
+                LuaConstant::LUA_TNIL => self.nils += 1,
+                LuaConstant::LUA_TBOOLEAN(_) => self.booleans += 1,
+                LuaConstant::LUA_TNUMBER(_) => self.numbers += 1,
+                LuaConstant::LUA_TSTRING(s) => {
+                    self.strings += 1;
+                    self.string_bytes += s.len();
+                    self.distinct_strings.insert(s.clone());
+                }
+            }
+        }
+        if f.line_info.is_empty() && f.local_vars.is_empty() {
+            self.stripped += 1;
+        }
+        for proto in &f.list_fnproto {
+            self.add(proto);
+        }
+    }
+}
+
+fn info(path: &str, chunk: &LuaChunk) -> String {
+    let h = &chunk.header;
+    let mut stats = Stats::default();
+    stats.add(&chunk.func);
+    let constants = stats.nils + stats.booleans + stats.numbers + stats.strings;
+    let mut out = format!("file: {}\n", path);
+    out += &format!("version: {}.{} (format {})\n", h.version >> 4, h.version & 0xf, h.format_version);
+    out += &format!("endianness: {}\n", if h.endianness == 1 { "little" } else { "big" });
+    out += &format!(
+        "sizes: int {}, size_t {}, instruction {}, number {} ({})\n",
+        h.size_int,
+        h.size_t,
+        h.size_inst,
+        h.size_lua_num,
+        if h.integral_flag == 0 { "floating point" } else { "integral" }
+    );
+    out += &format!("source: {}\n", chunk.func.source_name);
+    out += &format!("functions: {} ({} stripped)\n", stats.functions, stats.stripped);
+    out += &format!("instructions: {}\n", stats.instructions);
+    out += &format!(
+        "constants: {} (nil {}, boolean {}, number {}, string {})\n",
+        constants, stats.nils, stats.booleans, stats.numbers, stats.strings
+    );
+    out += &format!("strings: {} distinct, {} bytes\n", stats.distinct_strings.len(), stats.string_bytes);
+    out
 }
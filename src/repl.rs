@@ -0,0 +1,84 @@
+//! The interactive mode of `luatest`: reads chunks a line at a time and runs
+//! them in one VM, so globals carry over from one entry to the next.
+
+use std::io::{self, BufRead, Write};
+
+use luatest::{
+    compiler::compile,
+    vm::{chunk_parser::LuaChunk, error::RuntimeError, listing::listing, LuaVM},
+};
+
+const HELP: &str = "enter Lua statements, or `=expr` to see the value of expr
+  :dis    list the bytecode of the last input
+  :help   show this
+  :quit   leave (so does end of input)";
+
+pub fn repl(mut vm: LuaVM) -> anyhow::Result<()> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut last: Option<LuaChunk> = None;
+    loop {
+        prompt("> ")?;
+        let Some(line) = lines.next().transpose()? else {
+            println!();
+            return Ok(());
+        };
+        match line.trim() {
+            ":quit" | ":q" => return Ok(()),
+            ":help" => {
+                println!("{}", HELP);
+                continue;
+            }
+            ":dis" => {
+                match &last {
+                    Some(chunk) => print!("{}", listing(&chunk.func)),
+                    None => println!("nothing entered yet"),
+                }
+                continue;
+            }
+            "" => continue,
+            _ => {}
+        }
+        let mut source = match line.strip_prefix('=') {
+            Some(expr) => format!("return {}", expr),
+            None => line,
+        };
+        // a chunk cut short is continued on the next line
+        let chunk = loop {
+            match compile(source.as_bytes(), "=stdin") {
+                Ok(chunk) => break Some(chunk),
+                Err(e) if e.to_string().ends_with("'<eof>'") => {
+                    prompt(">> ")?;
+                    match lines.next().transpose()? {
+                        Some(more) => source = format!("{}\n{}", source, more),
+                        None => return Ok(()),
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    break None;
+                }
+            }
+        };
+        let Some(chunk) = chunk else {
+            continue;
+        };
+        last = Some(chunk.clone());
+        match vm.process_chunk(chunk) {
+            Ok(results) if !results.is_empty() => {
+                let shown: Vec<String> = results.iter().map(|v| v.borrow().as_string(true)).collect();
+                println!("{}", shown.join("\t"));
+            }
+            Ok(_) => {}
+            Err(e) => match e.downcast_ref::<RuntimeError>() {
+                Some(e) => eprintln!("{}\n{}", e.message, e.traceback),
+                None => eprintln!("{}", e),
+            },
+        }
+    }
+}
+
+fn prompt(p: &str) -> io::Result<()> {
+    print!("{}", p);
+    io::stdout().flush()
+}
@@ -0,0 +1,381 @@
+//! Control-flow graphs of `FunctionBlock`s: basic blocks with their edges,
+//! dominators, post-dominators and natural loops.
+
+use super::{
+    chunk_parser::FunctionBlock,
+    instruction::{arg_bx, arg_c, arg_sbx, op_of, VMOpcode},
+};
+
+/// What a word of the code array holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Slot {
+    Inst,
+    /// A MOVE or GETUPVAL after CLOSURE naming an upvalue of the new closure.
+    Pseudo,
+    /// The count after a SETLIST whose C is 0.
+    Data,
+}
+/// Classifies every word of `code`; `proto_upvals` is the upvalue count of
+/// each nested function, which says how many words follow a CLOSURE.
+pub fn slots(code: &[u32], proto_upvals: &[u32]) -> Vec<Slot> {
+    let mut slots = vec![Slot::Inst; code.len()];
+    let mut pc = 0;
+    while pc < code.len() {
+        let i = code[pc];
+        let (extra, slot) = match op_of(i) {
+            VMOpcode::CLOSURE => (proto_upvals.get(arg_bx(i) as usize).copied().unwrap_or(0), Slot::Pseudo),
+            VMOpcode::SETLIST if arg_c(i) == 0 => (1, Slot::Data),
+            _ => (0, Slot::Inst),
+        };
+        for s in slots.iter_mut().skip(pc + 1).take(extra as usize) {
+            *s = slot;
+        }
+        pc += 1 + extra as usize;
+    }
+    slots
+}
+
+/// EQ, LT, LE, TEST, TESTSET and TFORLOOP: they skip the next instruction,
+/// normally a JMP, unless their condition holds.
+pub fn is_test(op: &VMOpcode) -> bool {
+    matches!(op, VMOpcode::EQ | VMOpcode::LT | VMOpcode::LE | VMOpcode::TEST | VMOpcode::TESTSET | VMOpcode::TFORLOOP)
+}
+
+/// Where control goes after one instruction.
+enum Flow {
+    /// To the next instruction.
+    Next,
+    Jump(usize),
+    Cond { on_true: usize, on_false: usize },
+    Return,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    Always,
+    /// Taken when the block's condition holds: a comparison or TEST matches
+    /// its A (or C), FORLOOP or TFORLOOP continues.
+    True,
+    False,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    /// Index of the target block.
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    /// First pc of the block.
+    pub start: usize,
+    /// One past its last pc; pseudo-instructions and data words belong to
+    /// the block of the instruction they follow.
+    pub end: usize,
+    pub succs: Vec<Edge>,
+    pub preds: Vec<usize>,
+}
+
+/// A natural loop: the blocks that reach one of its back edges without
+/// passing through the header.
+#[derive(Debug, Clone)]
+pub struct Loop {
+    pub header: usize,
+    /// Blocks with a back edge to the header.
+    pub latches: Vec<usize>,
+    /// Blocks of the loop in ascending order, header included.
+    pub body: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    /// Blocks in code order; block 0 is the entry.
+    pub blocks: Vec<BasicBlock>,
+    /// Whether a block is reachable from the entry.
+    pub reachable: Vec<bool>,
+    /// Immediate dominator of each block; `None` for the entry and
+    /// unreachable blocks.
+    pub idom: Vec<Option<usize>>,
+    /// Immediate post-dominator of each block; `None` when that is the
+    /// function exit, or the block never reaches it.
+    pub ipdom: Vec<Option<usize>>,
+    /// Whether a block reaches a RETURN.
+    pub exits: Vec<bool>,
+    /// Natural loops, one per header, ordered by header.
+    pub loops: Vec<Loop>,
+}
+impl Cfg {
+    pub fn new(func: &FunctionBlock) -> Self {
+        let code: Vec<u32> = func.list_instructions.iter().map(|i| i.to_u32()).collect();
+        let upvals: Vec<u32> = func.list_fnproto.iter().map(|p| p.num_upval as u32).collect();
+        Self::from_code(&code, &upvals)
+    }
+    /// Builds the graph of encoded instructions; `proto_upvals` is as for
+    /// `slots`.
+    pub fn from_code(code: &[u32], proto_upvals: &[u32]) -> Self {
+        let n = code.len();
+        let slots = slots(code, proto_upvals);
+        let next_inst = |pc: usize| (pc + 1..n).find(|p| slots[*p] == Slot::Inst).unwrap_or(n);
+        let flow = |pc: usize| {
+            let i = code[pc];
+            let target = (pc as i64 + 1 + arg_sbx(i) as i64).clamp(0, n as i64) as usize;
+            match op_of(i) {
+                VMOpcode::JMP | VMOpcode::FORPREP => Flow::Jump(target),
+                VMOpcode::FORLOOP => Flow::Cond { on_true: target, on_false: pc + 1 },
+                VMOpcode::RETURN => Flow::Return,
+                VMOpcode::LOADBOOL if arg_c(i) != 0 => Flow::Jump((pc + 2).min(n)),
+                op if is_test(&op) => Flow::Cond { on_true: pc + 1, on_false: (pc + 2).min(n) },
+                _ => Flow::Next,
+            }
+        };
+        let is_jmp = |pc: usize| pc < n && slots[pc] == Slot::Inst && op_of(code[pc]) == VMOpcode::JMP;
+
+        let mut leader = vec![false; n + 1];
+        leader[0] = true;
+        for pc in (0..n).filter(|pc| slots[*pc] == Slot::Inst) {
+            match flow(pc) {
+                Flow::Next => {}
+                Flow::Jump(t) => {
+                    leader[t] = true;
+                    leader[next_inst(pc)] = true;
+                }
+                Flow::Cond { on_true, on_false } => {
+                    leader[on_false] = true;
+                    if is_test(&op_of(code[pc])) && is_jmp(on_true) {
+                        // the test and its jump end one block together
+                        continue;
+                    }
+                    leader[on_true] = true;
+                    leader[next_inst(pc)] = true;
+                }
+                Flow::Return => leader[next_inst(pc)] = true,
+            }
+        }
+
+        let starts: Vec<usize> = (0..n).filter(|pc| leader[*pc]).collect();
+        let mut block_at = vec![0; n + 1];
+        let mut blocks = Vec::new();
+        for (b, start) in starts.iter().enumerate() {
+            let end = starts.get(b + 1).copied().unwrap_or(n);
+            block_at[*start..end].fill(b);
+            blocks.push(BasicBlock { start: *start, end, succs: Vec::new(), preds: Vec::new() });
+        }
+        let nblocks = blocks.len();
+        for b in 0..nblocks {
+            let last = (blocks[b].start..blocks[b].end).rev().find(|pc| slots[*pc] == Slot::Inst).unwrap();
+            let edge = |pc: usize, kind| (pc < n).then(|| Edge { target: block_at[pc], kind });
+            let guarded = last > blocks[b].start && is_test(&op_of(code[last - 1])) && is_jmp(last);
+            let succs: Vec<Edge> = if guarded {
+                let Flow::Jump(t) = flow(last) else { unreachable!() };
+                [edge(t, EdgeKind::True), edge(last + 1, EdgeKind::False)].into_iter().flatten().collect()
+            } else {
+                match flow(last) {
+                    Flow::Next => edge(blocks[b].end, EdgeKind::Always).into_iter().collect(),
+                    Flow::Jump(t) => edge(t, EdgeKind::Always).into_iter().collect(),
+                    Flow::Cond { on_true, on_false } => {
+                        [edge(on_true, EdgeKind::True), edge(on_false, EdgeKind::False)].into_iter().flatten().collect()
+                    }
+                    Flow::Return => Vec::new(),
+                }
+            };
+            for e in &succs {
+                if !blocks[e.target].preds.contains(&b) {
+                    blocks[e.target].preds.push(b);
+                }
+            }
+            blocks[b].succs = succs;
+        }
+
+        let mut cfg = Cfg {
+            reachable: vec![false; nblocks],
+            idom: vec![None; nblocks],
+            ipdom: vec![None; nblocks],
+            exits: vec![false; nblocks],
+            loops: Vec::new(),
+            blocks,
+        };
+        if nblocks > 0 {
+            cfg.compute_dominators();
+            cfg.compute_post_dominators();
+            cfg.compute_loops();
+        }
+        cfg
+    }
+
+    /// Index of the block holding `pc`.
+    pub fn block_of(&self, pc: usize) -> Option<usize> {
+        let b = self.blocks.partition_point(|b| b.start <= pc).checked_sub(1)?;
+        (pc < self.blocks[b].end).then_some(b)
+    }
+    /// Whether every path from the entry to `b` passes through `a`.
+    pub fn dominates(&self, a: usize, mut b: usize) -> bool {
+        if !self.reachable[b] {
+            return false;
+        }
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom[b] {
+                Some(d) => b = d,
+                None => return false,
+            }
+        }
+    }
+    /// Whether every path from `b` to the exit passes through `a`.
+    pub fn post_dominates(&self, a: usize, mut b: usize) -> bool {
+        if !self.exits[b] {
+            return false;
+        }
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.ipdom[b] {
+                Some(d) => b = d,
+                None => return false,
+            }
+        }
+    }
+    /// Whether the edge from `from` to `to` closes a loop.
+    pub fn is_back_edge(&self, from: usize, to: usize) -> bool {
+        self.blocks[from].succs.iter().any(|e| e.target == to) && self.dominates(to, from)
+    }
+
+    /// Reverse postorder of the blocks `succs` reaches from `roots`.
+    fn reverse_postorder(&self, roots: &[usize], succs: impl Fn(usize) -> Vec<usize>) -> Vec<usize> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut order = Vec::new();
+        for root in roots {
+            if seen[*root] {
+                continue;
+            }
+            seen[*root] = true;
+            let mut stack = vec![(*root, succs(*root), 0)];
+            while let Some((b, next, i)) = stack.last_mut() {
+                if let Some(s) = next.get(*i).copied() {
+                    *i += 1;
+                    if !seen[s] {
+                        seen[s] = true;
+                        stack.push((s, succs(s), 0));
+                    }
+                } else {
+                    order.push(*b);
+                    stack.pop();
+                }
+            }
+        }
+        order.reverse();
+        order
+    }
+    /// Immediate dominators by the iterative algorithm of Cooper, Harvey and
+    /// Kennedy. `order` is a reverse postorder and `preds` gives the
+    /// predecessors in the direction dominance flows, with `None` for a
+    /// virtual root above the real ones; blocks it dominates directly get
+    /// `None`.
+    fn dominator_tree(&self, order: &[usize], preds: impl Fn(usize) -> Vec<Option<usize>>) -> Vec<Option<usize>> {
+        const ROOT: usize = usize::MAX;
+        let mut rpo_index = vec![usize::MAX; self.blocks.len()];
+        for (i, b) in order.iter().enumerate() {
+            rpo_index[*b] = i;
+        }
+        // `doms[b]` is the dominator so far, ROOT for the virtual root
+        let mut doms: Vec<Option<usize>> = vec![None; self.blocks.len()];
+        let index = |b: usize| if b == ROOT { 0 } else { rpo_index[b] + 1 };
+        let intersect = |doms: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while index(a) > index(b) {
+                    a = doms[a].unwrap();
+                }
+                while index(b) > index(a) {
+                    b = doms[b].unwrap();
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in order {
+                let mut new_idom = None;
+                for p in preds(*b) {
+                    let p = match p {
+                        None => ROOT,
+                        Some(p) if doms[p].is_some() => p,
+                        _ => continue,
+                    };
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(d) => intersect(&doms, p, d),
+                    });
+                }
+                if new_idom.is_some() && doms[*b] != new_idom {
+                    doms[*b] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        doms.into_iter().map(|d| d.filter(|d| *d != ROOT)).collect()
+    }
+    fn compute_dominators(&mut self) {
+        let order = self.reverse_postorder(&[0], |b| self.blocks[b].succs.iter().map(|e| e.target).collect());
+        for b in &order {
+            self.reachable[*b] = true;
+        }
+        // the entry hangs off the virtual root
+        self.idom = self.dominator_tree(&order, |b| {
+            let mut preds: Vec<Option<usize>> = self.blocks[b].preds.iter().map(|p| Some(*p)).collect();
+            if b == 0 {
+                preds.push(None);
+            }
+            preds
+        });
+    }
+    fn compute_post_dominators(&mut self) {
+        let returns: Vec<usize> = (0..self.blocks.len()).filter(|b| self.blocks[*b].succs.is_empty()).collect();
+        let order = self.reverse_postorder(&returns, |b| self.blocks[b].preds.clone());
+        for b in &order {
+            self.exits[*b] = true;
+        }
+        // every RETURN hangs off the virtual exit
+        self.ipdom = self.dominator_tree(&order, |b| {
+            let mut succs: Vec<Option<usize>> = self.blocks[b].succs.iter().map(|e| Some(e.target)).collect();
+            if succs.is_empty() {
+                succs.push(None);
+            }
+            succs
+        });
+    }
+    fn compute_loops(&mut self) {
+        let mut loops: Vec<Loop> = Vec::new();
+        for latch in 0..self.blocks.len() {
+            for e in &self.blocks[latch].succs {
+                let header = e.target;
+                if !self.dominates(header, latch) {
+                    continue;
+                }
+                let mut in_body = vec![false; self.blocks.len()];
+                in_body[header] = true;
+                let mut work = vec![latch];
+                while let Some(b) = work.pop() {
+                    if !in_body[b] {
+                        in_body[b] = true;
+                        work.extend(&self.blocks[b].preds);
+                    }
+                }
+                let body = (0..self.blocks.len()).filter(|b| in_body[*b]);
+                match loops.iter_mut().find(|l| l.header == header) {
+                    Some(l) => {
+                        l.latches.push(latch);
+                        l.body.extend(body);
+                        l.body.sort_unstable();
+                        l.body.dedup();
+                    }
+                    None => loops.push(Loop { header, latches: vec![latch], body: body.collect() }),
+                }
+            }
+        }
+        loops.sort_by_key(|l| l.header);
+        self.loops = loops;
+    }
+}
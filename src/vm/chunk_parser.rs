@@ -1,12 +1,12 @@
-use std::{io::Read, sync::Arc};
+use std::io::{Read, Write};
 
 use anyhow::bail;
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use gc::{Finalize, Gc, Trace};
-use rand::RngCore;
 
 use super::{
-    instruction::{VMInst, VMOpcode},
+    instruction::VMInst,
+    string::LuaString,
     GCLuaValue, LuaValue,
 };
 pub struct ChunkReader<'a> {
@@ -32,14 +32,17 @@ impl<'a> ChunkReader<'a> {
         Ok(self.reader.read_u64::<LittleEndian>()? as usize)
     }
     pub fn read_string(&mut self) -> anyhow::Result<String> {
+        Ok(String::from_utf8_lossy(&self.read_lua_string()?).to_string())
+    }
+    /// A string as stored, byte for byte.
+    pub fn read_lua_string(&mut self) -> anyhow::Result<Vec<u8>> {
         let len = self.read_sizet()?;
-        //println!("Len: {}", len);
         if len == 0 {
-            return Ok(String::new());
+            return Ok(Vec::new());
         }
         let mut bytes = self.read_bytes(len)?;
         bytes.pop();
-        Ok(String::from_utf8_lossy(&bytes).to_string())
+        Ok(bytes)
     }
     pub fn read_boolean(&mut self) -> anyhow::Result<bool> {
         Ok(self.reader.read_u8()? != 0)
@@ -53,9 +56,46 @@ impl Read for ChunkReader<'_> {
         self.reader.read(buf)
     }
 }
+/// Writes the luac format for the platform `ChunkReader` expects: little
+/// endian, 4-byte ints and 8-byte `size_t`s and numbers.
+pub struct ChunkWriter<'a> {
+    writer: &'a mut dyn Write,
+}
+impl<'a> ChunkWriter<'a> {
+    pub fn new(writer: &'a mut dyn Write) -> Self {
+        Self { writer }
+    }
+    pub fn write_byte(&mut self, v: u8) -> anyhow::Result<()> {
+        Ok(self.writer.write_u8(v)?)
+    }
+    pub fn write_int(&mut self, v: u32) -> anyhow::Result<()> {
+        Ok(self.writer.write_u32::<LittleEndian>(v)?)
+    }
+    pub fn write_sizet(&mut self, v: usize) -> anyhow::Result<()> {
+        Ok(self.writer.write_u64::<LittleEndian>(v as u64)?)
+    }
+    /// `None` is the absent string, as used for the source of nested functions.
+    pub fn write_string(&mut self, s: Option<&[u8]>) -> anyhow::Result<()> {
+        match s {
+            None => self.write_sizet(0),
+            Some(s) => {
+                self.write_sizet(s.len() + 1)?;
+                self.writer.write_all(s)?;
+                self.write_byte(0)
+            }
+        }
+    }
+    pub fn write_number(&mut self, v: f64) -> anyhow::Result<()> {
+        Ok(self.writer.write_f64::<LittleEndian>(v)?)
+    }
+}
+trait ToChunkWriter {
+    fn to_writer(&self, writer: &mut ChunkWriter, _info: Option<&str>) -> anyhow::Result<()>;
+}
 trait FromChunkReader: Sized {
-    fn from_reader(reader: &mut ChunkReader, info: Option<&str>) -> anyhow::Result<Self>;
+    fn from_reader(reader: &mut ChunkReader, _info: Option<&str>) -> anyhow::Result<Self>;
 }
+#[derive(Clone)]
 pub struct LuaChunk {
     pub header: ChunkHeader,
     pub func: FunctionBlock,
@@ -70,24 +110,68 @@ impl LuaChunk {
                 header.version
             );
         }
-        println!("Header: {:?}", header);
         let func = FunctionBlock::from_reader(&mut reader, None)?;
         Ok(Self { header, func })
     }
+    /// Writes the chunk in the format `luac` produces, loadable by the
+    /// reference interpreter and by `from_reader`.
+    pub fn write(&self, writer: &mut dyn Write) -> anyhow::Result<()> {
+        let mut writer = ChunkWriter::new(writer);
+        self.header.to_writer(&mut writer, None)?;
+        self.func.to_writer(&mut writer, None)
+    }
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes)?;
+        Ok(bytes)
+    }
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ChunkHeader {
-    version: u8,
-    format_version: u8,
-    endianness: u8,
-    size_int: u8,
-    size_t: u8,
-    size_Inst: u8,
-    size_luaNum: u8,
-    integral_flag: u8,
+    pub version: u8,
+    pub format_version: u8,
+    pub endianness: u8,
+    pub size_int: u8,
+    pub size_t: u8,
+    pub size_inst: u8,
+    pub size_lua_num: u8,
+    pub integral_flag: u8,
+}
+impl Default for ChunkHeader {
+    /// The header `luac` writes on a 64-bit little-endian machine.
+    fn default() -> Self {
+        Self {
+            version: 81,
+            format_version: 0,
+            endianness: 1,
+            size_int: 4,
+            size_t: 8,
+            size_inst: 4,
+            size_lua_num: 8,
+            integral_flag: 0,
+        }
+    }
+}
+impl ToChunkWriter for ChunkHeader {
+    fn to_writer(&self, writer: &mut ChunkWriter, _info: Option<&str>) -> anyhow::Result<()> {
+        writer.writer.write_all(&0x1B4C7561u32.to_be_bytes())?;
+        for b in [
+            self.version,
+            self.format_version,
+            self.endianness,
+            self.size_int,
+            self.size_t,
+            self.size_inst,
+            self.size_lua_num,
+            self.integral_flag,
+        ] {
+            writer.write_byte(b)?;
+        }
+        Ok(())
+    }
 }
 impl FromChunkReader for ChunkHeader {
-    fn from_reader(reader: &mut ChunkReader, info: Option<&str>) -> anyhow::Result<Self> {
+    fn from_reader(reader: &mut ChunkReader, _info: Option<&str>) -> anyhow::Result<Self> {
         let mut bytes = [0; 4];
         reader.read_exact(&mut bytes)?;
         let h = u32::from_be_bytes(bytes);
@@ -102,14 +186,20 @@ impl FromChunkReader for ChunkHeader {
             endianness: bytes[2],
             size_int: bytes[3],
             size_t: bytes[4],
-            size_Inst: bytes[5],
-            size_luaNum: bytes[6],
+            size_inst: bytes[5],
+            size_lua_num: bytes[6],
             integral_flag: bytes[7],
         })
     }
 }
+/// `is_vararg` flags. `HASARG` and `NEEDSARG` mark functions compiled with
+/// the 5.0-compatible `arg` table as their last parameter.
+pub const VARARG_HASARG: u8 = 1;
+pub const VARARG_ISVARARG: u8 = 2;
+pub const VARARG_NEEDSARG: u8 = 4;
 #[derive(Debug, Clone, Finalize, Trace)]
 pub struct FunctionBlock {
+    /// Chunk name, e.g. `@test.lua`; nested functions share their parent's.
     pub source_name: String,
     pub line_def: u32,
     pub last_line_def: u32,
@@ -120,11 +210,24 @@ pub struct FunctionBlock {
     pub list_instructions: Vec<VMInst>,
     pub list_const: Vec<Gc<LuaConstant>>,
     pub list_fnproto: Vec<Gc<FunctionBlock>>,
+    /// Source line of each instruction; empty in stripped chunks.
+    pub line_info: Vec<u32>,
+    pub local_vars: Vec<LocalVar>,
+    pub upvalue_names: Vec<String>,
+}
+/// A local variable's name and the pcs over which it is active.
+#[derive(Debug, Clone, Finalize, Trace)]
+pub struct LocalVar {
+    pub name: String,
+    pub start_pc: u32,
+    pub end_pc: u32,
 }
 impl FromChunkReader for FunctionBlock {
     fn from_reader(reader: &mut ChunkReader, info: Option<&str>) -> anyhow::Result<Self> {
-        let source_name = reader.read_string().unwrap();
-        println!("Source name: {}", source_name);
+        let mut source_name = reader.read_string().unwrap();
+        if source_name.is_empty() {
+            source_name = info.unwrap_or_default().to_string();
+        }
         let line_def = reader.read_int().unwrap();
         //println!("Line def: {}", line_def);
         let last_line_def = reader.read_int().unwrap();
@@ -137,16 +240,11 @@ impl FromChunkReader for FunctionBlock {
         //println!("is varg: {}", is_vararg);
         let max_stack_size = reader.read_byte().unwrap();
         //println!("mas stack: {}", max_stack_size);
-        let list_instructions: Vec<VMInst> = FromChunkReader::from_reader(reader, None).unwrap();
-        for inst in list_instructions.iter() {
-            println!("INST: {:?} {:?}", inst.opcode, inst.params);
-        }
+        let code: Vec<u32> = FromChunkReader::from_reader(reader, None)?;
+        let list_instructions = VMInst::decode_all(&code)?;
         let list_const: Vec<LuaConstant> = FromChunkReader::from_reader(reader, None).unwrap();
-        for (idx, c) in list_const.iter().enumerate() {
-            println!("CONST #{}: {:?}", idx, c);
-        }
         //println!("Reading FN prototypes");
-        let list_fnproto: Vec<FunctionBlock> = FromChunkReader::from_reader(reader, Some("s")).unwrap();
+        let list_fnproto: Vec<FunctionBlock> = FromChunkReader::from_reader(reader, Some(&source_name)).unwrap();
         let mut list_arc_const: Vec<Gc<LuaConstant>> = Vec::new();
         for c in list_const {
             list_arc_const.push(Gc::new(c));
@@ -155,9 +253,9 @@ impl FromChunkReader for FunctionBlock {
         for c in list_fnproto {
             list_arc_fnproto.push(Gc::new(c));
         }
-        reader.read_int();
-        reader.read_int();
-        reader.read_int();
+        let line_info = FromChunkReader::from_reader(reader, None)?;
+        let local_vars = FromChunkReader::from_reader(reader, None)?;
+        let upvalue_names = FromChunkReader::from_reader(reader, None)?;
         Ok(Self {
             source_name,
             line_def,
@@ -169,18 +267,93 @@ impl FromChunkReader for FunctionBlock {
             list_instructions,
             list_const: list_arc_const,
             list_fnproto: list_arc_fnproto,
+            line_info,
+            local_vars,
+            upvalue_names,
         })
     }
 }
+impl ToChunkWriter for FunctionBlock {
+    fn to_writer(&self, writer: &mut ChunkWriter, info: Option<&str>) -> anyhow::Result<()> {
+        writer.write_string((info != Some(self.source_name.as_str())).then_some(self.source_name.as_bytes()))?;
+        writer.write_int(self.line_def)?;
+        writer.write_int(self.last_line_def)?;
+        writer.write_byte(self.num_upval)?;
+        writer.write_byte(self.num_param)?;
+        writer.write_byte(self.is_vararg)?;
+        writer.write_byte(self.max_stack_size)?;
+        writer.write_int(self.list_instructions.len() as u32)?;
+        for inst in &self.list_instructions {
+            writer.write_int(inst.to_u32())?;
+        }
+        writer.write_int(self.list_const.len() as u32)?;
+        for c in &self.list_const {
+            c.to_writer(writer, None)?;
+        }
+        writer.write_int(self.list_fnproto.len() as u32)?;
+        for f in &self.list_fnproto {
+            f.to_writer(writer, Some(&self.source_name))?;
+        }
+        writer.write_int(self.line_info.len() as u32)?;
+        for line in &self.line_info {
+            writer.write_int(*line)?;
+        }
+        writer.write_int(self.local_vars.len() as u32)?;
+        for var in &self.local_vars {
+            writer.write_string(Some(var.name.as_bytes()))?;
+            writer.write_int(var.start_pc)?;
+            writer.write_int(var.end_pc)?;
+        }
+        writer.write_int(self.upvalue_names.len() as u32)?;
+        for name in &self.upvalue_names {
+            writer.write_string(Some(name.as_bytes()))?;
+        }
+        Ok(())
+    }
+}
+impl ToChunkWriter for LuaConstant {
+    fn to_writer(&self, writer: &mut ChunkWriter, _info: Option<&str>) -> anyhow::Result<()> {
+        match self {
+            LuaConstant::LUA_TNIL => writer.write_byte(0),
+            LuaConstant::LUA_TBOOLEAN(b) => {
+                writer.write_byte(1)?;
+                writer.write_byte(*b as u8)
+            }
+            LuaConstant::LUA_TNUMBER(n) => {
+                writer.write_byte(3)?;
+                writer.write_number(*n)
+            }
+            LuaConstant::LUA_TSTRING(s) => {
+                writer.write_byte(4)?;
+                writer.write_string(Some(s.as_bytes()))
+            }
+        }
+    }
+}
+impl FromChunkReader for u32 {
+    fn from_reader(reader: &mut ChunkReader, _info: Option<&str>) -> anyhow::Result<Self> {
+        reader.read_int()
+    }
+}
+impl FromChunkReader for String {
+    fn from_reader(reader: &mut ChunkReader, _info: Option<&str>) -> anyhow::Result<Self> {
+        reader.read_string()
+    }
+}
+impl FromChunkReader for LocalVar {
+    fn from_reader(reader: &mut ChunkReader, _info: Option<&str>) -> anyhow::Result<Self> {
+        Ok(Self { name: reader.read_string()?, start_pc: reader.read_int()?, end_pc: reader.read_int()? })
+    }
+}
 impl FromChunkReader for LuaConstant {
-    fn from_reader(reader: &mut ChunkReader, info: Option<&str>) -> anyhow::Result<Self> {
+    fn from_reader(reader: &mut ChunkReader, _info: Option<&str>) -> anyhow::Result<Self> {
         let x = reader.read_byte()?;
         //println!("Num: {}", x);
         Ok(match x {
             0 => LuaConstant::LUA_TNIL,
             1 => LuaConstant::LUA_TBOOLEAN(reader.read_boolean()?),
             3 => LuaConstant::LUA_TNUMBER(reader.read_number()?),
-            4 => LuaConstant::LUA_TSTRING(reader.read_string()?),
+            4 => LuaConstant::LUA_TSTRING(reader.read_lua_string()?.into()),
             _ => bail!("Unknown const {}", x),
         })
     }
@@ -190,7 +363,6 @@ where
     T: FromChunkReader,
 {
     fn from_reader(reader: &mut ChunkReader, info: Option<&str>) -> anyhow::Result<Self> {
-        let x = rand::thread_rng().next_u32();
         //println!("Genning {}", x);
         let size = reader.read_int()?;
         //println!("Size: {:?} on x {} with info {:?}", size, x, info);
@@ -201,7 +373,7 @@ where
         let mut vec: Vec<T> = Vec::new();
         for _ in 0..size {
             //println!("Size in {} on x {} with info {:?}", size, x, info);
-            vec.push(T::from_reader(reader, None).unwrap());
+            vec.push(T::from_reader(reader, info).unwrap());
         }
         Ok(vec)
     }
@@ -221,7 +393,7 @@ where
 //     }
 // }
 impl FromChunkReader for VMInst {
-    fn from_reader(reader: &mut ChunkReader, info: Option<&str>) -> anyhow::Result<Self> {
+    fn from_reader(reader: &mut ChunkReader, _info: Option<&str>) -> anyhow::Result<Self> {
         let v = reader.read_int()?;
         //println!("V: {}", v);
         VMInst::from_u32(v)
@@ -238,12 +410,13 @@ impl FromChunkReader for VMInst {
 //         Ok(vec)
 //     }
 // }
+#[allow(non_camel_case_types)]
 #[derive(Debug, Clone, Finalize, Trace)]
 pub enum LuaConstant {
     LUA_TNIL,
     LUA_TBOOLEAN(bool),
     LUA_TNUMBER(f64),
-    LUA_TSTRING(String),
+    LUA_TSTRING(LuaString),
 }
 
 impl LuaConstant {
@@ -0,0 +1,388 @@
+//! Conversions between Rust values and Lua values, so that native functions
+//! and host calls can be written with ordinary Rust signatures.
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    hash::Hash,
+    ops::{Deref, DerefMut},
+};
+
+use anyhow::anyhow;
+
+use super::{
+    fmt_number,
+    stdlib::arg_error,
+    string::LuaString,
+    table::{GCLuaTable, LuaKey, LuaTable},
+    GCLuaValue, LuaNativeFunction, LuaVM, LuaValue,
+};
+
+/// Why a value could not be converted. Displays as the parenthesised part of
+/// a "bad argument" error.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FromLuaError {
+    /// "number expected, got nil"
+    Expected { expected: String, got: &'static str },
+    /// Any other complaint, such as a number with a fractional part where an
+    /// integer is needed.
+    Invalid(String),
+}
+impl FromLuaError {
+    pub fn expected(expected: &str, got: &GCLuaValue) -> Self {
+        FromLuaError::Expected { expected: expected.to_string(), got: got.borrow().type_name() }
+    }
+}
+impl Display for FromLuaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FromLuaError::Expected { expected, got } => write!(f, "{} expected, got {}", expected, got),
+            FromLuaError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+impl std::error::Error for FromLuaError {}
+
+pub trait IntoLua {
+    fn into_lua(self) -> GCLuaValue;
+}
+pub trait FromLua: Sized {
+    fn from_lua(v: &GCLuaValue) -> Result<Self, FromLuaError>;
+    /// Conversion of an argument that was not passed at all.
+    fn from_missing() -> Result<Self, FromLuaError> {
+        Self::from_lua(&LuaValue::Nil.to_gc()).map_err(|e| match e {
+            FromLuaError::Expected { expected, .. } => FromLuaError::Expected { expected, got: "no value" },
+            e => e,
+        })
+    }
+}
+
+impl IntoLua for GCLuaValue {
+    fn into_lua(self) -> GCLuaValue {
+        self
+    }
+}
+impl FromLua for GCLuaValue {
+    fn from_lua(v: &GCLuaValue) -> Result<Self, FromLuaError> {
+        Ok(v.clone())
+    }
+}
+impl IntoLua for LuaValue {
+    fn into_lua(self) -> GCLuaValue {
+        self.to_gc()
+    }
+}
+impl IntoLua for f64 {
+    fn into_lua(self) -> GCLuaValue {
+        LuaValue::Number(self).to_gc()
+    }
+}
+impl FromLua for f64 {
+    fn from_lua(v: &GCLuaValue) -> Result<Self, FromLuaError> {
+        v.borrow().to_number().ok_or_else(|| FromLuaError::expected("number", v))
+    }
+}
+impl IntoLua for f32 {
+    fn into_lua(self) -> GCLuaValue {
+        LuaValue::Number(self as f64).to_gc()
+    }
+}
+impl FromLua for f32 {
+    fn from_lua(v: &GCLuaValue) -> Result<Self, FromLuaError> {
+        f64::from_lua(v).map(|n| n as f32)
+    }
+}
+macro_rules! integer_conversions {
+    ($($t:ty),*) => {
+        $(
+            impl IntoLua for $t {
+                fn into_lua(self) -> GCLuaValue {
+                    LuaValue::Number(self as f64).to_gc()
+                }
+            }
+            impl FromLua for $t {
+                /// Numbers (and numeric strings) with no fractional part that
+                /// fit in the target type.
+                fn from_lua(v: &GCLuaValue) -> Result<Self, FromLuaError> {
+                    let n = f64::from_lua(v)?;
+                    // MAX as f64 may round up to the next power of two, which
+                    // is exactly the first value that no longer fits
+                    if n.fract() != 0.0 || n < <$t>::MIN as f64 || n >= (<$t>::MAX as f64) + 1.0 {
+                        return Err(FromLuaError::Invalid("number has no integer representation".to_string()));
+                    }
+                    Ok(n as $t)
+                }
+            }
+        )*
+    };
+}
+integer_conversions!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+impl IntoLua for bool {
+    fn into_lua(self) -> GCLuaValue {
+        LuaValue::Boolean(self).to_gc()
+    }
+}
+impl FromLua for bool {
+    /// Truthiness, as `lua_toboolean`: only nil and false are false.
+    fn from_lua(v: &GCLuaValue) -> Result<Self, FromLuaError> {
+        Ok(v.borrow().truthy())
+    }
+}
+impl IntoLua for String {
+    fn into_lua(self) -> GCLuaValue {
+        LuaValue::String(self.into()).to_gc()
+    }
+}
+impl IntoLua for &str {
+    fn into_lua(self) -> GCLuaValue {
+        LuaValue::String(self.into()).to_gc()
+    }
+}
+impl IntoLua for LuaString {
+    fn into_lua(self) -> GCLuaValue {
+        LuaValue::String(self).to_gc()
+    }
+}
+impl FromLua for LuaString {
+    /// Strings, and numbers formatted as Lua would.
+    fn from_lua(v: &GCLuaValue) -> Result<Self, FromLuaError> {
+        match &*v.borrow() {
+            LuaValue::String(s) => Ok(s.clone()),
+            LuaValue::Number(n) => Ok(fmt_number(*n).into()),
+            _ => Err(FromLuaError::expected("string", v)),
+        }
+    }
+}
+impl FromLua for String {
+    /// Strings holding UTF-8, and numbers formatted as Lua would.
+    fn from_lua(v: &GCLuaValue) -> Result<Self, FromLuaError> {
+        match &*v.borrow() {
+            LuaValue::String(s) => {
+                String::from_utf8(s.as_bytes().to_vec()).map_err(|_| FromLuaError::Invalid("string is not UTF-8".to_string()))
+            }
+            LuaValue::Number(n) => Ok(fmt_number(*n)),
+            _ => Err(FromLuaError::expected("string", v)),
+        }
+    }
+}
+impl<T: IntoLua> IntoLua for Option<T> {
+    fn into_lua(self) -> GCLuaValue {
+        match self {
+            Some(v) => v.into_lua(),
+            None => LuaValue::Nil.to_gc(),
+        }
+    }
+}
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(v: &GCLuaValue) -> Result<Self, FromLuaError> {
+        if v.is_nil() {
+            return Ok(None);
+        }
+        T::from_lua(v).map(Some)
+    }
+    fn from_missing() -> Result<Self, FromLuaError> {
+        Ok(None)
+    }
+}
+impl IntoLua for GCLuaTable {
+    fn into_lua(self) -> GCLuaValue {
+        LuaValue::Table(self).to_gc()
+    }
+}
+impl FromLua for GCLuaTable {
+    fn from_lua(v: &GCLuaValue) -> Result<Self, FromLuaError> {
+        match &*v.borrow() {
+            LuaValue::Table(t) => Ok(t.clone()),
+            _ => Err(FromLuaError::expected("table", v)),
+        }
+    }
+}
+impl IntoLua for LuaNativeFunction {
+    fn into_lua(self) -> GCLuaValue {
+        LuaValue::NativeFunction(self).to_gc()
+    }
+}
+/// Sequences become array tables, indexed from 1.
+impl<T: IntoLua> IntoLua for Vec<T> {
+    fn into_lua(self) -> GCLuaValue {
+        let mut table = LuaTable::new();
+        for (i, v) in self.into_iter().enumerate() {
+            table.set_int(i as i64 + 1, v.into_lua());
+        }
+        LuaValue::Table(GCLuaTable::new(table)).to_gc()
+    }
+}
+impl<T: FromLua> FromLua for Vec<T> {
+    /// The array part `t[1]..t[#t]`.
+    fn from_lua(v: &GCLuaValue) -> Result<Self, FromLuaError> {
+        let table = GCLuaTable::from_lua(v)?;
+        let table = table.borrow();
+        (1..=table.len() as i64)
+            .map(|i| {
+                let item = table.get_int(i).unwrap_or_else(|| LuaValue::Nil.to_gc());
+                T::from_lua(&item).map_err(|e| FromLuaError::Invalid(format!("invalid element #{}: {}", i, e)))
+            })
+            .collect()
+    }
+}
+/// Entries whose key is nil or NaN cannot be stored and are skipped.
+impl<K: IntoLua, V: IntoLua> IntoLua for HashMap<K, V> {
+    fn into_lua(self) -> GCLuaValue {
+        let mut table = LuaTable::new();
+        for (k, v) in self {
+            if let Ok(k) = LuaKey::from_value(&k.into_lua()) {
+                table.set(k, v.into_lua());
+            }
+        }
+        LuaValue::Table(GCLuaTable::new(table)).to_gc()
+    }
+}
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+    fn from_lua(v: &GCLuaValue) -> Result<Self, FromLuaError> {
+        let table = GCLuaTable::from_lua(v)?;
+        let table = table.borrow();
+        let mut map = HashMap::new();
+        let mut key = None;
+        while let Some((k, v)) = table.next(key.as_ref()).map_err(|e| FromLuaError::Invalid(e.to_string()))? {
+            let k_value = k.to_value();
+            let rk = K::from_lua(&k_value).map_err(|e| FromLuaError::Invalid(format!("invalid key: {}", e)))?;
+            let rv = V::from_lua(&v).map_err(|e| {
+                FromLuaError::Invalid(format!("invalid value for key {}: {}", k_value.borrow().as_string(true), e))
+            })?;
+            map.insert(rk, rv);
+            key = Some(k);
+        }
+        Ok(map)
+    }
+}
+
+/// Any number of trailing values of one type, as arguments or results.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Variadic<T>(pub Vec<T>);
+impl<T> Deref for Variadic<T> {
+    type Target = Vec<T>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl<T> DerefMut for Variadic<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+impl<T> From<Vec<T>> for Variadic<T> {
+    fn from(v: Vec<T>) -> Self {
+        Variadic(v)
+    }
+}
+
+/// A value that failed to convert, with its 1-based position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BadValue {
+    pub position: usize,
+    pub error: FromLuaError,
+}
+/// Values that become a list of Lua values: function results or arguments.
+pub trait IntoLuaMulti {
+    fn into_lua_multi(self) -> Vec<GCLuaValue>;
+}
+pub trait FromLuaMulti: Sized {
+    /// Converts `values`, the first of which is at 1-based position `pos`.
+    fn from_lua_multi(values: &[GCLuaValue], pos: usize) -> Result<Self, BadValue>;
+}
+impl<T: IntoLua> IntoLuaMulti for T {
+    fn into_lua_multi(self) -> Vec<GCLuaValue> {
+        vec![self.into_lua()]
+    }
+}
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(values: &[GCLuaValue], pos: usize) -> Result<Self, BadValue> {
+        convert_at(values, 0, pos)
+    }
+}
+impl IntoLuaMulti for () {
+    fn into_lua_multi(self) -> Vec<GCLuaValue> {
+        Vec::new()
+    }
+}
+impl FromLuaMulti for () {
+    fn from_lua_multi(_: &[GCLuaValue], _: usize) -> Result<Self, BadValue> {
+        Ok(())
+    }
+}
+impl<T: IntoLua> IntoLuaMulti for Variadic<T> {
+    fn into_lua_multi(self) -> Vec<GCLuaValue> {
+        self.0.into_iter().map(IntoLua::into_lua).collect()
+    }
+}
+impl<T: FromLua> FromLuaMulti for Variadic<T> {
+    fn from_lua_multi(values: &[GCLuaValue], pos: usize) -> Result<Self, BadValue> {
+        (0..values.len()).map(|i| convert_at(values, i, pos)).collect::<Result<_, _>>().map(Variadic)
+    }
+}
+fn convert_at<T: FromLua>(values: &[GCLuaValue], i: usize, pos: usize) -> Result<T, BadValue> {
+    match values.get(i) {
+        Some(v) => T::from_lua(v),
+        None => T::from_missing(),
+    }
+    .map_err(|error| BadValue { position: pos + i, error })
+}
+/// Tuples convert element by element; only the last one may expand to (or
+/// absorb) several values, as with a `Variadic`.
+macro_rules! tuple_conversions {
+    ($($name:ident)* ; $last:ident) => {
+        #[allow(non_snake_case)]
+        impl<$($name: IntoLua,)* $last: IntoLuaMulti> IntoLuaMulti for ($($name,)* $last,) {
+            fn into_lua_multi(self) -> Vec<GCLuaValue> {
+                let ($($name,)* $last,) = self;
+                let mut out: Vec<GCLuaValue> = vec![$($name.into_lua()),*];
+                out.extend($last.into_lua_multi());
+                out
+            }
+        }
+        #[allow(non_snake_case)]
+        impl<$($name: FromLua,)* $last: FromLuaMulti> FromLuaMulti for ($($name,)* $last,) {
+            fn from_lua_multi(values: &[GCLuaValue], pos: usize) -> Result<Self, BadValue> {
+                let i = 0;
+                $(
+                    let $name = convert_at::<$name>(values, i, pos)?;
+                    let i = i + 1;
+                )*
+                let $last = $last::from_lua_multi(values.get(i..).unwrap_or(&[]), pos + i)?;
+                Ok(($($name,)* $last,))
+            }
+        }
+    };
+}
+tuple_conversions!(; A);
+tuple_conversions!(A ; B);
+tuple_conversions!(A B ; C);
+tuple_conversions!(A B C ; D);
+tuple_conversions!(A B C D ; E);
+tuple_conversions!(A B C D E ; F);
+tuple_conversions!(A B C D E F ; G);
+tuple_conversions!(A B C D E F G ; H);
+
+impl LuaNativeFunction {
+    /// Wraps a Rust function taking and returning convertible values. Bad
+    /// arguments are reported as "bad argument #2 to 'name' (number
+    /// expected, got nil)".
+    pub fn typed<A, R, F>(name: &str, func: F) -> Self
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut LuaVM, A) -> anyhow::Result<R> + 'static,
+    {
+        let fname = name.to_string();
+        Self::new(name, move |vm, args| {
+            let args = A::from_lua_multi(&args, 1).map_err(|e| arg_error(e.position, &fname, &e.error.to_string()))?;
+            Ok(func(vm, args)?.into_lua_multi())
+        })
+    }
+}
+impl LuaVM {
+    /// Calls `func` with converted arguments and converts its results.
+    pub fn call<R: FromLuaMulti>(&mut self, func: GCLuaValue, args: impl IntoLuaMulti) -> anyhow::Result<R> {
+        let results = self.call_value(func, args.into_lua_multi())?;
+        R::from_lua_multi(&results, 1).map_err(|e| anyhow!("bad result #{} ({})", e.position, e.error))
+    }
+}
@@ -0,0 +1,518 @@
+//! A Debug Adapter Protocol server, so editors such as VS Code can debug
+//! scripts run by `LuaVM`. Messages are read and answered one at a time:
+//! while the script runs nothing is read, and requests wait for the next
+//! stop. There is one thread, with id 1; frame ids are levels plus one.
+
+use std::{
+    cell::RefCell,
+    io::{self, BufRead, Write},
+    rc::Rc,
+};
+
+use anyhow::{anyhow, bail, Context};
+
+use super::{
+    chunk_parser::LuaChunk,
+    debug::DebugInfo,
+    debugger::{BreakpointKind, Breakpoints, DebugHandler, Debugger, Pause, Resume, StopReason},
+    error::{LuaError, RuntimeError},
+    hostio::{HostFile, HostIo, OpenMode},
+    json::Json,
+    table::{GCLuaTable, LuaKey},
+    GCLuaValue, LuaVM, LuaValue,
+};
+
+const THREAD_ID: usize = 1;
+
+/// The two ends of the protocol stream, with the sequence number of the
+/// last message sent.
+struct Connection {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    seq: usize,
+    /// Set once the client has gone, by a disconnect or end of input.
+    closed: bool,
+}
+impl Connection {
+    /// The next message, or `None` at end of input.
+    fn read(&mut self) -> anyhow::Result<Option<Json>> {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                self.closed = true;
+                return Ok(None);
+            }
+            match line.trim_end() {
+                "" if length.is_some() => break,
+                "" => {}
+                header => {
+                    if let Some(n) = header.strip_prefix("Content-Length:") {
+                        length = Some(n.trim().parse::<usize>().context("bad Content-Length")?);
+                    }
+                }
+            }
+        }
+        let mut body = vec![0; length.unwrap_or(0)];
+        self.input.read_exact(&mut body)?;
+        Ok(Some(Json::parse(std::str::from_utf8(&body)?)?))
+    }
+    fn send(&mut self, mut message: Json) -> anyhow::Result<()> {
+        self.seq += 1;
+        message.set("seq", self.seq.into());
+        let text = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+        self.output.flush()?;
+        Ok(())
+    }
+    /// Answers `request` with `result`'s body, or its error as the message.
+    fn respond(&mut self, request: &Json, result: anyhow::Result<Json>) -> anyhow::Result<()> {
+        let mut response = Json::object([
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+            ("success", result.is_ok().into()),
+        ]);
+        match result {
+            Ok(Json::Null) => {}
+            Ok(body) => response.set("body", body),
+            Err(e) => response.set("message", error_message(&e).into()),
+        }
+        self.send(response)
+    }
+    fn event(&mut self, event: &str, body: Json) -> anyhow::Result<()> {
+        self.send(Json::object([("type", "event".into()), ("event", event.into()), ("body", body)]))
+    }
+}
+type Shared = Rc<RefCell<Connection>>;
+
+/// An error as a Lua script would see it, without the traceback.
+fn error_message(e: &anyhow::Error) -> String {
+    match e.downcast_ref::<RuntimeError>() {
+        Some(e) => e.message.clone(),
+        None => e.to_string(),
+    }
+}
+
+/// The host given to launched scripts: `inner`, except that the standard
+/// streams are the client's output events and there is no input, since
+/// stdin may be carrying the protocol, and `os.exit` only ends the script.
+struct AdapterHost {
+    inner: Rc<dyn HostIo>,
+    conn: Shared,
+}
+struct OutputFile {
+    conn: Shared,
+    category: &'static str,
+}
+impl HostFile for OutputFile {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let body = Json::object([
+            ("category", self.category.into()),
+            ("output", String::from_utf8_lossy(data).into_owned().into()),
+        ]);
+        self.conn.borrow_mut().event("output", body).map_err(io::Error::other)
+    }
+}
+struct NoInput;
+impl HostFile for NoInput {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+impl HostIo for AdapterHost {
+    fn time(&self) -> f64 {
+        self.inner.time()
+    }
+    fn clock(&self) -> f64 {
+        self.inner.clock()
+    }
+    fn utc_offset(&self, time: i64) -> i64 {
+        self.inner.utc_offset(time)
+    }
+    fn getenv(&self, name: &str) -> Option<String> {
+        self.inner.getenv(name)
+    }
+    fn tmpname(&self) -> io::Result<String> {
+        self.inner.tmpname()
+    }
+    fn remove(&self, path: &str) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+    fn exit(&self, _code: i32) {}
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn HostFile>> {
+        self.inner.open(path, mode)
+    }
+    fn stdin(&self) -> Box<dyn HostFile> {
+        Box::new(NoInput)
+    }
+    fn stdout(&self) -> Box<dyn HostFile> {
+        Box::new(OutputFile { conn: self.conn.clone(), category: "stdout" })
+    }
+    fn stderr(&self) -> Box<dyn HostFile> {
+        Box::new(OutputFile { conn: self.conn.clone(), category: "stderr" })
+    }
+}
+
+/// What `launch` gives for a launch request: the VM, the chunk to run in it
+/// and the values of its `...`.
+pub type Launched = (LuaVM, LuaChunk, Vec<GCLuaValue>);
+
+/// Serves one debug session over `input` and `output`. A launch request
+/// calls `launch` with its `program` and `args` and a host, made from
+/// `host`, that the VM's libraries should use; the script starts once
+/// configuration is done and the session ends with a disconnect request or
+/// end of input.
+pub fn serve(
+    input: impl BufRead + 'static,
+    output: impl Write + 'static,
+    host: Rc<dyn HostIo>,
+    mut launch: impl FnMut(&str, &[String], Rc<dyn HostIo>) -> anyhow::Result<Launched>,
+) -> anyhow::Result<()> {
+    let conn: Shared =
+        Rc::new(RefCell::new(Connection { input: Box::new(input), output: Box::new(output), seq: 0, closed: false }));
+    let host: Rc<dyn HostIo> = Rc::new(AdapterHost { inner: host, conn: conn.clone() });
+    let debugger = Debugger::new(Adapter { conn: conn.clone(), refs: Vec::new() });
+    let mut launched = None;
+    let mut configured = false;
+    loop {
+        let Some(request) = conn.borrow_mut().read()? else {
+            return Ok(());
+        };
+        let (command, args) = command_of(&request);
+        let result = match command {
+            "initialize" => Ok(capabilities()),
+            "launch" => {
+                let program = args.get("program").and_then(Json::as_str).unwrap_or_default();
+                let script_args: Vec<String> = args
+                    .get("args")
+                    .and_then(Json::as_array)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|a| a.as_str().map(str::to_string))
+                    .collect();
+                if args.get("stopOnEntry").and_then(Json::as_bool) == Some(true) {
+                    debugger.stop_on_entry();
+                }
+                launch(program, &script_args, host.clone()).map(|l| {
+                    launched = Some(l);
+                    Json::Null
+                })
+            }
+            "configurationDone" => {
+                configured = true;
+                Ok(Json::Null)
+            }
+            "disconnect" | "terminate" => {
+                conn.borrow_mut().respond(&request, Ok(Json::Null))?;
+                return Ok(());
+            }
+            _ => common(command, &args, &mut debugger.breakpoints())
+                .unwrap_or_else(|| Err(anyhow!("`{}` needs a stopped script", command))),
+        };
+        conn.borrow_mut().respond(&request, result)?;
+        if command == "initialize" {
+            conn.borrow_mut().event("initialized", Json::Null)?;
+        }
+        if !configured {
+            continue;
+        }
+        let Some((mut vm, chunk, values)) = launched.take() else {
+            continue;
+        };
+        debugger.attach(&mut vm);
+        let code = match vm.process_chunk_with(chunk, values) {
+            Ok(_) => 0,
+            Err(_) if conn.borrow().closed => return Ok(()),
+            Err(e) => match e.downcast_ref::<LuaError>() {
+                Some(LuaError::Exit(code)) => *code,
+                _ => {
+                    let text = match e.downcast_ref::<RuntimeError>() {
+                        Some(e) => format!("{}\n{}\n", e.message, e.traceback),
+                        None => format!("{}\n", e),
+                    };
+                    let body = Json::object([("category", "stderr".into()), ("output", text.into())]);
+                    conn.borrow_mut().event("output", body)?;
+                    1
+                }
+            },
+        };
+        let mut conn = conn.borrow_mut();
+        conn.event("exited", Json::object([("exitCode", (code as f64).into())]))?;
+        conn.event("terminated", Json::Null)?;
+    }
+}
+
+fn command_of(request: &Json) -> (&str, Json) {
+    let command = request.get("command").and_then(Json::as_str).unwrap_or_default();
+    (command, request.get("arguments").cloned().unwrap_or(Json::object([])))
+}
+
+fn capabilities() -> Json {
+    Json::object([
+        ("supportsConfigurationDoneRequest", true.into()),
+        ("supportsFunctionBreakpoints", true.into()),
+        ("supportsEvaluateForHovers", true.into()),
+        ("supportsTerminateRequest", true.into()),
+    ])
+}
+
+/// Requests answered the same whether or not the script is stopped.
+fn common(command: &str, args: &Json, breakpoints: &mut Breakpoints) -> Option<anyhow::Result<Json>> {
+    Some(match command {
+        "setBreakpoints" => set_breakpoints(args, breakpoints),
+        "setFunctionBreakpoints" => set_function_breakpoints(args, breakpoints),
+        "threads" => Ok(Json::object([(
+            "threads",
+            vec![Json::object([("id", THREAD_ID.into()), ("name", "main".into())])].into(),
+        )])),
+        _ => return None,
+    })
+}
+
+/// Replaces the line breakpoints of one source.
+fn set_breakpoints(args: &Json, breakpoints: &mut Breakpoints) -> anyhow::Result<Json> {
+    let source = args.get("source").ok_or_else(|| anyhow!("no source given"))?;
+    let Some(path) = source.get("path").or_else(|| source.get("name")).and_then(Json::as_str) else {
+        bail!("source has no path");
+    };
+    breakpoints.clear_source(path);
+    let lines = args.get("breakpoints").and_then(Json::as_array).unwrap_or_default();
+    let set: Vec<Json> = lines
+        .iter()
+        .filter_map(|b| b.get("line").and_then(Json::as_f64))
+        .map(|line| {
+            let id = breakpoints.add(BreakpointKind::Line { source: path.to_string(), line: line as u32 });
+            Json::object([("id", id.into()), ("verified", true.into()), ("line", line.into())])
+        })
+        .collect();
+    Ok(Json::object([("breakpoints", set.into())]))
+}
+
+/// Replaces all function breakpoints.
+fn set_function_breakpoints(args: &Json, breakpoints: &mut Breakpoints) -> anyhow::Result<Json> {
+    let old: Vec<usize> = breakpoints
+        .iter()
+        .filter(|b| matches!(b.kind, BreakpointKind::Function(_)))
+        .map(|b| b.id)
+        .collect();
+    for id in old {
+        breakpoints.remove(id);
+    }
+    let names = args.get("breakpoints").and_then(Json::as_array).unwrap_or_default();
+    let set: Vec<Json> = names
+        .iter()
+        .filter_map(|b| b.get("name").and_then(Json::as_str))
+        .map(|name| {
+            let id = breakpoints.add(BreakpointKind::Function(name.to_string()));
+            Json::object([("id", id.into()), ("verified", true.into())])
+        })
+        .collect();
+    Ok(Json::object([("breakpoints", set.into())]))
+}
+
+/// Something the client can list the variables of, by its index plus one.
+enum Ref {
+    Locals(usize),
+    Upvalues(usize),
+    Globals,
+    Table(GCLuaTable),
+}
+
+struct Adapter {
+    conn: Shared,
+    /// Handed out while stopped; they lapse when the script goes on.
+    refs: Vec<Ref>,
+}
+impl DebugHandler for Adapter {
+    fn paused(&mut self, pause: &mut Pause) -> anyhow::Result<Resume> {
+        self.refs.clear();
+        let mut body = Json::object([("threadId", THREAD_ID.into()), ("allThreadsStopped", true.into())]);
+        let reason = match pause.reason {
+            StopReason::Entry => "entry",
+            StopReason::Step => "step",
+            StopReason::Breakpoint(id) => {
+                body.set("hitBreakpointIds", vec![id.into()].into());
+                match pause.breakpoints().iter().find(|b| b.id == id).map(|b| &b.kind) {
+                    Some(BreakpointKind::Function(_)) => "function breakpoint",
+                    _ => "breakpoint",
+                }
+            }
+        };
+        body.set("reason", reason.into());
+        self.conn.borrow_mut().event("stopped", body)?;
+        loop {
+            let Some(request) = self.conn.borrow_mut().read()? else {
+                return Ok(Resume::Abort);
+            };
+            let (command, args) = command_of(&request);
+            let resume = match command {
+                "continue" => Some(Resume::Continue),
+                "next" => Some(Resume::StepOver),
+                "stepIn" => Some(Resume::StepInto),
+                "stepOut" => Some(Resume::StepOut),
+                "disconnect" | "terminate" => Some(Resume::Abort),
+                _ => None,
+            };
+            let result = match command {
+                "continue" => Ok(Json::object([("allThreadsContinued", true.into())])),
+                _ if resume.is_some() => Ok(Json::Null),
+                "stackTrace" => Ok(stack_trace(pause, &args)),
+                "scopes" => self.scopes(pause, &args),
+                "variables" => self.variables(pause, &args),
+                "evaluate" => self.evaluate(pause, &args),
+                _ => common(command, &args, pause.breakpoints())
+                    .unwrap_or_else(|| Err(anyhow!("unsupported request `{}`", command))),
+            };
+            let mut conn = self.conn.borrow_mut();
+            conn.respond(&request, result)?;
+            if let Some(resume) = resume {
+                conn.closed |= resume == Resume::Abort;
+                return Ok(resume);
+            }
+        }
+    }
+}
+
+fn stack_trace(pause: &Pause, args: &Json) -> Json {
+    let start = args.get("startFrame").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+    let levels = match args.get("levels").and_then(Json::as_f64) {
+        Some(n) if n > 0.0 => n as usize,
+        _ => usize::MAX,
+    };
+    let frames = pause.frames();
+    let shown: Vec<Json> = frames
+        .iter()
+        .enumerate()
+        .skip(start)
+        .take(levels)
+        .map(|(level, info)| {
+            let mut frame = Json::object([
+                ("id", (level + 1).into()),
+                ("name", frame_name(info).into()),
+                ("line", info.current_line.unwrap_or(0).into()),
+                ("column", 1u32.into()),
+            ]);
+            if let Some(path) = info.source.strip_prefix('@') {
+                let name = path.rsplit('/').next().unwrap_or(path);
+                frame.set("source", Json::object([("name", name.into()), ("path", path.into())]));
+            }
+            frame
+        })
+        .collect();
+    Json::object([("stackFrames", shown.into()), ("totalFrames", frames.len().into())])
+}
+
+fn frame_name(info: &DebugInfo) -> String {
+    match &info.name {
+        Some(name) => name.clone(),
+        None if info.what == "main" => "main chunk".to_string(),
+        None => format!("function <{}:{}>", info.short_src, info.line_defined.unwrap_or(0)),
+    }
+}
+
+/// The level a request's `frameId` names, the innermost frame by default.
+fn level_of(pause: &Pause, args: &Json) -> anyhow::Result<usize> {
+    let level = match args.get("frameId").and_then(Json::as_f64) {
+        Some(id) if id >= 1.0 => id as usize - 1,
+        Some(id) => bail!("no frame {}", id),
+        None => 0,
+    };
+    if level >= pause.vm.stack_depth() {
+        bail!("no frame {}", level + 1);
+    }
+    Ok(level)
+}
+
+/// The entries of `table`, numeric keys first in order, then the rest by
+/// name.
+fn entries(table: &GCLuaTable) -> anyhow::Result<Vec<(String, GCLuaValue)>> {
+    let mut numbered = Vec::new();
+    let mut named = Vec::new();
+    let mut key = None;
+    while let Some((k, v)) = table.borrow().next(key.as_ref())? {
+        match &k {
+            LuaKey::Number(bits) => numbered.push((f64::from_bits(*bits), v)),
+            LuaKey::String(s) => named.push((s.to_string(), v)),
+            other => named.push((format!("[{}]", other.to_value().borrow().as_string(true)), v)),
+        }
+        key = Some(k);
+    }
+    numbered.sort_by(|a, b| a.0.total_cmp(&b.0));
+    named.sort_by(|a, b| a.0.cmp(&b.0));
+    let numbered = numbered.into_iter().map(|(n, v)| (format!("[{}]", LuaValue::Number(n).as_string(true)), v));
+    Ok(numbered.chain(named).collect())
+}
+
+impl Adapter {
+    fn add_ref(&mut self, r: Ref) -> usize {
+        self.refs.push(r);
+        self.refs.len()
+    }
+    /// A variable or evaluation result: the value shown as text, and a
+    /// reference to expand it by if it is a table.
+    fn describe(&mut self, value: &GCLuaValue) -> (String, usize) {
+        let value = value.borrow();
+        let reference = match &*value {
+            LuaValue::Table(t) => self.add_ref(Ref::Table(t.clone())),
+            _ => 0,
+        };
+        (value.as_string(true), reference)
+    }
+    fn scopes(&mut self, pause: &Pause, args: &Json) -> anyhow::Result<Json> {
+        let level = level_of(pause, args)?;
+        let scopes = vec![
+            ("Locals", Ref::Locals(level), false),
+            ("Upvalues", Ref::Upvalues(level), false),
+            ("Globals", Ref::Globals, true),
+        ];
+        let scopes: Vec<Json> = scopes
+            .into_iter()
+            .map(|(name, r, expensive)| {
+                Json::object([
+                    ("name", name.into()),
+                    ("variablesReference", self.add_ref(r).into()),
+                    ("expensive", expensive.into()),
+                ])
+            })
+            .collect();
+        Ok(Json::object([("scopes", scopes.into())]))
+    }
+    fn variables(&mut self, pause: &Pause, args: &Json) -> anyhow::Result<Json> {
+        let reference = args.get("variablesReference").and_then(Json::as_f64).unwrap_or(0.0) as usize;
+        let vars = match reference.checked_sub(1).and_then(|i| self.refs.get(i)) {
+            Some(Ref::Locals(level)) => pause.locals(*level),
+            Some(Ref::Upvalues(level)) => pause.upvalues(*level),
+            Some(Ref::Globals) => entries(&pause.vm.globals())?,
+            Some(Ref::Table(t)) => entries(t)?,
+            None => bail!("no variables with reference {}", reference),
+        };
+        let vars: Vec<Json> = vars
+            .into_iter()
+            .map(|(name, value)| {
+                let type_name = value.borrow().type_name();
+                let (text, reference) = self.describe(&value);
+                Json::object([
+                    ("name", name.into()),
+                    ("value", text.into()),
+                    ("type", type_name.into()),
+                    ("variablesReference", reference.into()),
+                ])
+            })
+            .collect();
+        Ok(Json::object([("variables", vars.into())]))
+    }
+    fn evaluate(&mut self, pause: &mut Pause, args: &Json) -> anyhow::Result<Json> {
+        let level = level_of(pause, args)?;
+        let expression = args.get("expression").and_then(Json::as_str).unwrap_or_default();
+        let values = pause.evaluate(level, expression)?;
+        let (result, reference) = match values.as_slice() {
+            [value] => self.describe(value),
+            _ => (values.iter().map(|v| v.borrow().as_string(true)).collect::<Vec<_>>().join(", "), 0),
+        };
+        Ok(Json::object([("result", result.into()), ("variablesReference", reference.into())]))
+    }
+}
@@ -0,0 +1,491 @@
+//! Introspection of running code, after ldebug.c: what each frame is
+//! running, its locals and the upvalues of functions, and hooks told about
+//! calls, returns, new lines and instruction counts as they happen.
+//!
+//! Levels count Lua frames of the running thread from the innermost, which
+//! is level 0. Native functions have no frames and so no levels.
+
+use std::rc::Rc;
+
+use anyhow::anyhow;
+
+use super::{
+    chunk_parser::{FunctionBlock, LuaConstant},
+    error::{LuaError, RuntimeError},
+    instruction::{arg_a, arg_b, arg_bx, arg_c, arg_sbx, op_of, VMOpcode, MASK_CBIT},
+    thread::{GCLuaThread, ReturnTo},
+    GCLuaValue, LuaVM, LuaValue,
+};
+
+/// What a hook is told about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    /// A function, Lua or native, was entered; also sent for tail calls.
+    Call,
+    Return,
+    /// Sent after `Return` once for each caller the returning function
+    /// replaced with a tail call.
+    TailReturn,
+    /// Code on a new line is about to run, or a jump went back.
+    Line(u32),
+    /// Another `count` instructions have run.
+    Count,
+}
+impl HookEvent {
+    /// The name `debug.sethook` hooks get.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Call => "call",
+            HookEvent::Return => "return",
+            HookEvent::TailReturn => "tail return",
+            HookEvent::Line(_) => "line",
+            HookEvent::Count => "count",
+        }
+    }
+}
+/// The events a hook wants.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HookMask {
+    pub call: bool,
+    pub ret: bool,
+    pub line: bool,
+    /// Call the hook every `count` instructions; 0 never does.
+    pub count: u32,
+}
+/// Signature of hooks. A hook that fails fails the instruction or call that
+/// set it off.
+pub type HookFn = dyn Fn(&mut LuaVM, HookEvent) -> anyhow::Result<()>;
+pub(crate) struct Hook {
+    pub(crate) mask: HookMask,
+    func: Rc<HookFn>,
+}
+/// How much of a deep stack a traceback shows: the innermost `head`
+/// levels and the outermost `tail`, with `...` for those in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TracebackLimits {
+    pub head: usize,
+    pub tail: usize,
+}
+impl Default for TracebackLimits {
+    /// The limits of the reference `debug.traceback`.
+    fn default() -> Self {
+        Self { head: 12, tail: 10 }
+    }
+}
+/// Registry key of the function hooks set with `debug.sethook` call.
+pub(crate) const HOOK_KEY: &str = "debug.hook";
+
+/// A function, and for a level what it is doing; `lua_Debug`.
+#[derive(Debug, Clone)]
+pub struct DebugInfo {
+    pub source: String,
+    pub short_src: String,
+    /// `"Lua"`, `"C"` for native functions or `"main"` for a main chunk.
+    pub what: &'static str,
+    /// Line of the instruction running; unknown for natives, stripped code
+    /// and functions asked about by value.
+    pub current_line: Option<u32>,
+    pub line_defined: Option<u32>,
+    pub last_line_defined: Option<u32>,
+    /// What the caller called the function, if it can tell.
+    pub name: Option<String>,
+    /// How `name` was found: `"global"`, `"local"`, `"method"`, `"field"`,
+    /// `"upvalue"`, or `""` with no name.
+    pub name_what: &'static str,
+    pub nups: usize,
+    pub func: GCLuaValue,
+    /// Lines that have code, for Lua functions with line information.
+    pub active_lines: Vec<u32>,
+}
+
+/// The short form of a chunk name used in messages, `luaO_chunkid`:
+/// `@file` is the file, `=name` the name, and anything else is source code.
+pub fn chunk_id(source: &str) -> String {
+    const ID_SIZE: usize = 60;
+    if let Some(name) = source.strip_prefix('=') {
+        return name.chars().take(ID_SIZE - 1).collect();
+    }
+    if let Some(file) = source.strip_prefix('@') {
+        // room left after ` '...' `
+        let room = ID_SIZE - 8;
+        let len = file.chars().count();
+        if len <= room {
+            return file.to_string();
+        }
+        return format!("...{}", file.chars().skip(len - room).collect::<String>());
+    }
+    // room left after ` [string "..."] `
+    let room = ID_SIZE - 17;
+    let first = source.lines().next().unwrap_or("");
+    let shown: String = first.chars().take(room).collect();
+    if shown.len() < source.len() {
+        format!("[string \"{}...\"]", shown)
+    } else {
+        format!("[string \"{}\"]", shown)
+    }
+}
+
+/// Name of the `n`th (from 1) local active at `pc`, `luaF_getlocalname`.
+pub fn local_name(f: &FunctionBlock, mut n: usize, pc: usize) -> Option<String> {
+    if n == 0 {
+        return None;
+    }
+    for var in f.local_vars.iter().take_while(|v| v.start_pc as usize <= pc) {
+        if pc < var.end_pc as usize {
+            n -= 1;
+            if n == 0 {
+                return Some(var.name.clone());
+            }
+        }
+    }
+    None
+}
+
+/// Whether `op` stores into its A register.
+fn sets_a(op: &VMOpcode) -> bool {
+    use VMOpcode::*;
+    !matches!(op, SETGLOBAL | SETUPVAL | SETTABLE | JMP | EQ | LT | LE | TEST | RETURN | TFORLOOP | SETLIST | CLOSE)
+}
+/// The last instruction before `pc` that stores into `reg`, following
+/// forward jumps that land before `pc` as `symbexec` does.
+fn last_store(f: &FunctionBlock, pc: usize, reg: u32) -> Option<usize> {
+    let code: Vec<u32> = f.list_instructions.iter().map(|i| i.to_u32()).collect();
+    let mut last = None;
+    let mut at = 0;
+    while at < pc.min(code.len()) {
+        let i = code[at];
+        let (op, a, b) = (op_of(i), arg_a(i), arg_b(i));
+        let stores = match op {
+            VMOpcode::LOADNIL => a <= reg && reg <= b,
+            VMOpcode::CALL | VMOpcode::TAILCALL => reg >= a,
+            VMOpcode::TFORLOOP => reg >= a + 2,
+            VMOpcode::SELF => reg == a || reg == a + 1,
+            _ => sets_a(&op) && reg == a,
+        };
+        if stores {
+            last = Some(at);
+        }
+        match op {
+            VMOpcode::JMP | VMOpcode::FORPREP => {
+                let dest = at as i64 + 1 + arg_sbx(i) as i64;
+                if at < dest as usize && dest as usize <= pc {
+                    at = dest as usize;
+                    continue;
+                }
+            }
+            VMOpcode::CLOSURE => {
+                let nup = f.list_fnproto.get(arg_bx(i) as usize).map_or(0, |p| p.num_upval as usize);
+                at += nup;
+            }
+            VMOpcode::SETLIST if arg_c(i) == 0 => at += 1,
+            _ => {}
+        }
+        at += 1;
+    }
+    last
+}
+/// What register `reg` holds at `pc` of `f`, named after the local it is or
+/// how it was loaded; `getobjname`.
+pub(crate) fn object_name(f: &FunctionBlock, pc: usize, reg: u32) -> Option<(String, &'static str)> {
+    if let Some(name) = local_name(f, reg as usize + 1, pc) {
+        return Some((name, "local"));
+    }
+    let load = last_store(f, pc, reg)?;
+    let i = f.list_instructions[load].to_u32();
+    let constant = |k: u32| match f.list_const.get(k as usize).map(|c| &**c) {
+        Some(LuaConstant::LUA_TSTRING(s)) => s.to_string(),
+        _ => "?".to_string(),
+    };
+    match op_of(i) {
+        VMOpcode::GETGLOBAL => Some((constant(arg_bx(i)), "global")),
+        VMOpcode::MOVE if arg_b(i) < arg_a(i) => object_name(f, load, arg_b(i)),
+        VMOpcode::GETTABLE if arg_c(i) & MASK_CBIT != 0 => Some((constant(arg_c(i) & !MASK_CBIT), "field")),
+        VMOpcode::GETTABLE => Some(("?".to_string(), "field")),
+        VMOpcode::SELF if arg_c(i) & MASK_CBIT != 0 => Some((constant(arg_c(i) & !MASK_CBIT), "method")),
+        VMOpcode::SELF => Some(("?".to_string(), "method")),
+        VMOpcode::GETUPVAL => {
+            let name = f.upvalue_names.get(arg_b(i) as usize).cloned().unwrap_or_else(|| "?".to_string());
+            Some((name, "upvalue"))
+        }
+        _ => None,
+    }
+}
+
+impl LuaVM {
+    /// Calls `hook` for the events in `mask` from now on, replacing any
+    /// hook set before. Hooks are not called while a hook runs.
+    pub fn set_hook<F>(&mut self, mask: HookMask, hook: F)
+    where
+        F: Fn(&mut LuaVM, HookEvent) -> anyhow::Result<()> + 'static,
+    {
+        self.registry.borrow_mut().set_str(HOOK_KEY, LuaValue::Nil.to_gc());
+        self.hook_countdown = mask.count;
+        self.hook = Some(Hook { mask, func: Rc::new(hook) });
+    }
+    pub fn clear_hook(&mut self) {
+        self.registry.borrow_mut().set_str(HOOK_KEY, LuaValue::Nil.to_gc());
+        self.hook = None;
+    }
+    /// The events the current hook gets, if there is one.
+    pub fn hook_mask(&self) -> Option<HookMask> {
+        self.hook.as_ref().map(|h| h.mask)
+    }
+    pub(super) fn run_hook(&mut self, event: HookEvent) -> anyhow::Result<()> {
+        if self.in_hook {
+            return Ok(());
+        }
+        let Some(func) = self.hook.as_ref().map(|h| h.func.clone()) else {
+            return Ok(());
+        };
+        self.in_hook = true;
+        let r = func(self, event);
+        self.in_hook = false;
+        r
+    }
+    /// Sends the count and line events due before the instruction at `pc`
+    /// of the innermost frame runs.
+    pub(super) fn instruction_hook(&mut self, pc: usize) -> anyhow::Result<()> {
+        let Some(mask) = self.hook_mask().filter(|_| !self.in_hook) else {
+            return Ok(());
+        };
+        if mask.count > 0 {
+            self.hook_countdown = self.hook_countdown.saturating_sub(1);
+            if self.hook_countdown == 0 {
+                self.hook_countdown = mask.count;
+                self.run_hook(HookEvent::Count)?;
+            }
+        }
+        if mask.line {
+            let frame = self.state.frames.last_mut().unwrap();
+            let old = frame.hook_pc.replace(pc);
+            let line = {
+                let f = frame.func.borrow();
+                let lines = &f.prototype.line_info;
+                lines.get(pc).copied().filter(|line| match old {
+                    Some(old) => pc <= old || lines.get(old) != Some(line),
+                    None => true,
+                })
+            };
+            if let Some(line) = line {
+                self.run_hook(HookEvent::Line(line))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of Lua frames in the running thread.
+    pub fn stack_depth(&self) -> usize {
+        self.state.frames.len()
+    }
+    /// The instruction the function at `level` is running; `None` while a
+    /// call hook runs for a function not yet started.
+    pub fn current_pc(&self, level: usize) -> Option<usize> {
+        self.state.frames[self.frame_index(level)?].pc.checked_sub(1)
+    }
+    fn frame_index(&self, level: usize) -> Option<usize> {
+        self.state.frames.len().checked_sub(level + 1)
+    }
+    /// What the frame at `frames[index]` is calling, from its current
+    /// instruction.
+    pub(crate) fn call_name(&self, index: usize) -> Option<(String, &'static str)> {
+        let frame = &self.state.frames[index];
+        let f = frame.func.borrow();
+        let pc = frame.pc.checked_sub(1)?;
+        let i = f.prototype.list_instructions.get(pc)?.to_u32();
+        match op_of(i) {
+            VMOpcode::CALL | VMOpcode::TAILCALL | VMOpcode::TFORLOOP => object_name(&f.prototype, pc, arg_a(i)),
+            _ => None,
+        }
+    }
+    /// The function running at `level` and where it is.
+    pub fn get_info(&self, level: usize) -> Option<DebugInfo> {
+        let index = self.frame_index(level)?;
+        let frame = &self.state.frames[index];
+        let mut info = self.function_info(&LuaValue::Function(frame.func.clone()).to_gc())?;
+        let pc = frame.pc.saturating_sub(1);
+        info.current_line = frame.func.borrow().prototype.line_info.get(pc).copied();
+        let called = matches!(frame.ret, ReturnTo::Register { .. }) && frame.tail_calls == 0;
+        if let Some((name, what)) = index.checked_sub(1).filter(|_| called).and_then(|caller| self.call_name(caller)) {
+            info.name = Some(name);
+            info.name_what = what;
+        }
+        Some(info)
+    }
+    /// What is known about a function without running it; `None` for
+    /// values that are not functions.
+    pub fn function_info(&self, func: &GCLuaValue) -> Option<DebugInfo> {
+        let info = match &*func.borrow() {
+            LuaValue::Function(f) => {
+                let f = f.borrow();
+                let p = &f.prototype;
+                let mut active_lines = p.line_info.clone();
+                active_lines.sort_unstable();
+                active_lines.dedup();
+                DebugInfo {
+                    source: p.source_name.clone(),
+                    short_src: chunk_id(&p.source_name),
+                    what: if p.line_def == 0 { "main" } else { "Lua" },
+                    current_line: None,
+                    line_defined: Some(p.line_def),
+                    last_line_defined: Some(p.last_line_def),
+                    name: None,
+                    name_what: "",
+                    nups: f.upvalues.len(),
+                    func: func.clone(),
+                    active_lines,
+                }
+            }
+            LuaValue::NativeFunction(f) => DebugInfo {
+                source: "=[C]".to_string(),
+                short_src: "[C]".to_string(),
+                what: "C",
+                current_line: None,
+                line_defined: None,
+                last_line_defined: None,
+                name: None,
+                name_what: "",
+                nups: f.upvalues.len(),
+                func: func.clone(),
+                active_lines: Vec::new(),
+            },
+            _ => return None,
+        };
+        Some(info)
+    }
+    /// The register holding local `n` (from 1) at `level`, and its name;
+    /// registers past the named locals are `(*temporary)`.
+    fn local_slot(&self, level: usize, n: usize) -> Option<(String, usize)> {
+        let frame = &self.state.frames[self.frame_index(level)?];
+        let f = frame.func.borrow();
+        let name = match local_name(&f.prototype, n, frame.pc.saturating_sub(1)) {
+            Some(name) => name,
+            None if n >= 1 && n <= f.prototype.max_stack_size as usize => "(*temporary)".to_string(),
+            None => return None,
+        };
+        Some((name, frame.base + n - 1))
+    }
+    /// Name and value of local `n` (from 1) of the function at `level`.
+    pub fn get_local(&self, level: usize, n: usize) -> Option<(String, GCLuaValue)> {
+        let (name, slot) = self.local_slot(level, n)?;
+        let value = self.state.stack.get(slot).cloned().unwrap_or_else(|| LuaValue::Nil.to_gc());
+        Some((name, value))
+    }
+    /// Sets local `n` (from 1) of the function at `level`, returning its name.
+    pub fn set_local(&mut self, level: usize, n: usize, value: GCLuaValue) -> Option<String> {
+        let (name, slot) = self.local_slot(level, n)?;
+        self.set_stack(slot, value);
+        Some(name)
+    }
+    /// Name and value of upvalue `n` (from 1) of `func`. Natives' upvalues
+    /// have empty names, as do those of stripped functions.
+    pub fn upvalue_of(&self, func: &GCLuaValue, n: usize) -> Option<(String, GCLuaValue)> {
+        let idx = n.checked_sub(1)?;
+        match &*func.borrow() {
+            LuaValue::Function(f) => {
+                let f = f.borrow();
+                let uv = f.upvalues.get(idx)?;
+                Some((f.prototype.upvalue_names.get(idx).cloned().unwrap_or_default(), self.get_upvalue(uv)))
+            }
+            LuaValue::NativeFunction(f) => Some((String::new(), f.upvalues.get(idx)?.clone())),
+            _ => None,
+        }
+    }
+    /// Sets upvalue `n` (from 1) of `func`, returning its name.
+    pub fn set_upvalue_of(&mut self, func: &GCLuaValue, n: usize, value: GCLuaValue) -> Option<String> {
+        let idx = n.checked_sub(1)?;
+        let lua = match &*func.borrow() {
+            LuaValue::Function(f) => {
+                let f = f.borrow();
+                Some((f.upvalues.get(idx)?.clone(), f.prototype.upvalue_names.get(idx).cloned().unwrap_or_default()))
+            }
+            _ => None,
+        };
+        if let Some((uv, name)) = lua {
+            self.set_upvalue(&uv, value);
+            return Some(name);
+        }
+        match &mut *func.0.try_borrow_mut().ok()? {
+            LuaValue::NativeFunction(f) => *f.upvalues.get_mut(idx)? = value,
+            _ => return None,
+        }
+        Some(String::new())
+    }
+    /// `stack traceback:` and a line for each frame from `level` out, as
+    /// `debug.traceback` writes them, cut down to the traceback limits.
+    pub fn traceback(&self, level: usize) -> String {
+        let mut lines = Vec::new();
+        for level in level..self.stack_depth() {
+            let info = self.get_info(level).unwrap();
+            let mut line = format!("{}:", info.short_src);
+            if let Some(current) = info.current_line {
+                line += &format!("{}:", current);
+            }
+            match (&info.name, info.what) {
+                (Some(name), _) => line += &format!(" in function '{}'", name),
+                (None, "main") => line += " in main chunk",
+                (None, _) => line += &format!(" in function <{}:{}>", info.short_src, info.line_defined.unwrap_or(0)),
+            }
+            lines.push(line);
+            if self.state.frames[self.frame_index(level).unwrap()].tail_calls > 0 {
+                lines.push("(tail call): ?".to_string());
+            }
+        }
+        let TracebackLimits { head, tail } = self.traceback_limits;
+        if lines.len() > head + tail {
+            lines.splice(head..lines.len() - tail, ["...".to_string()]);
+        }
+        let mut out = String::from("stack traceback:");
+        for line in lines {
+            out += "\n\t";
+            out += &line;
+        }
+        out
+    }
+    pub fn set_traceback_limits(&mut self, limits: TracebackLimits) {
+        self.traceback_limits = limits;
+    }
+    /// `error` as raised by the innermost frame, located there: the message
+    /// gets its `source:line:` and the stack is kept. Errors already located
+    /// and exits pass through.
+    pub(super) fn locate(&self, error: anyhow::Error) -> anyhow::Error {
+        if error.is::<RuntimeError>() || !LuaError::is_catchable(&error) {
+            return error;
+        }
+        let Some(info) = self.get_info(0) else {
+            return error;
+        };
+        let line = info.current_line.map_or("-1".to_string(), |l| l.to_string());
+        // like Lua, memory errors do not say where they happened
+        let message = match error.downcast_ref::<LuaError>() {
+            Some(LuaError::OutOfMemory) => error.to_string(),
+            _ => format!("{}:{}: {}", info.short_src, line, error),
+        };
+        RuntimeError { message, traceback: self.traceback(0), cause: error }.into()
+    }
+    /// The error for attempting `op` on `value`, read from register `reg` of
+    /// the innermost frame, naming the variable it came from if it can;
+    /// `luaG_typeerror`.
+    pub(super) fn operand_error(&self, op: &str, reg: u32, value: &GCLuaValue) -> anyhow::Error {
+        let type_name = value.borrow().type_name();
+        let frame = self.state.frames.last().unwrap();
+        let name = object_name(&frame.func.borrow().prototype, frame.pc.saturating_sub(1), reg);
+        match name {
+            Some((name, kind)) => anyhow!("attempt to {} {} '{}' (a {} value)", op, kind, name, type_name),
+            None => anyhow!("attempt to {} a {} value", op, type_name),
+        }
+    }
+    /// Runs `f` with `thread`'s stack and frames standing in for those of
+    /// the running thread, so levels refer to `thread`.
+    pub fn on_thread<R>(&mut self, thread: &GCLuaThread, f: impl FnOnce(&mut LuaVM) -> R) -> R {
+        if thread.ptr() == self.current_thread.ptr() {
+            return f(self);
+        }
+        let state = std::mem::take(&mut thread.borrow_mut().state);
+        let own = std::mem::replace(&mut self.state, state);
+        let current = std::mem::replace(&mut self.current_thread, thread.clone());
+        let r = f(self);
+        self.current_thread = current;
+        thread.borrow_mut().state = std::mem::replace(&mut self.state, own);
+        r
+    }
+}
@@ -0,0 +1,285 @@
+//! A source-level debugger built on hooks: breakpoints by line or by
+//! function, stepping a line at a time, and a look at the paused program's
+//! frames, variables and source. What happens at a pause is up to a
+//! `DebugHandler`, such as the `luatest debug` prompt.
+
+use std::{
+    cell::{RefCell, RefMut},
+    collections::HashMap,
+    fs,
+    rc::Rc,
+};
+
+use anyhow::bail;
+
+use super::{
+    debug::{DebugInfo, HookEvent, HookMask},
+    error::LuaError,
+    GCLuaValue, LuaVM,
+};
+use crate::compiler::compile;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BreakpointKind {
+    /// Stop before `line` of the chunk named by `source`, a path such as
+    /// `test.lua` matched against the end of chunk names.
+    Line { source: String, line: u32 },
+    /// Stop on entering a Lua function called by this name.
+    Function(String),
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub kind: BreakpointKind,
+}
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    list: Vec<Breakpoint>,
+    last_id: usize,
+}
+impl Breakpoints {
+    /// Adds a breakpoint and returns its id, counted from 1.
+    pub fn add(&mut self, kind: BreakpointKind) -> usize {
+        self.last_id += 1;
+        self.list.push(Breakpoint { id: self.last_id, kind });
+        self.last_id
+    }
+    /// Removes breakpoint `id`, returning whether there was one.
+    pub fn remove(&mut self, id: usize) -> bool {
+        let before = self.list.len();
+        self.list.retain(|b| b.id != id);
+        self.list.len() != before
+    }
+    /// Removes the line breakpoints in `source`.
+    pub fn clear_source(&mut self, source: &str) {
+        self.list.retain(|b| !matches!(&b.kind, BreakpointKind::Line { source: s, .. } if same_source(s, source)));
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.list.iter()
+    }
+    fn at_line(&self, chunk: &str, at: u32) -> Option<usize> {
+        self.list.iter().find_map(|b| match &b.kind {
+            BreakpointKind::Line { source, line } if *line == at && same_source(chunk, source) => Some(b.id),
+            _ => None,
+        })
+    }
+    fn at_function(&self, called: &str) -> Option<usize> {
+        self.list.iter().find_map(|b| match &b.kind {
+            BreakpointKind::Function(name) if name == called => Some(b.id),
+            _ => None,
+        })
+    }
+}
+
+/// Whether two chunk names or paths name the same file: `@` and `=` are
+/// dropped, and then one has to end with the other at a `/`.
+pub fn same_source(a: &str, b: &str) -> bool {
+    let strip = |s: &'static str| move |x: &str| x.strip_prefix(s).map(str::to_string);
+    let path = |x: &str| strip("@")(x).or_else(|| strip("=")(x)).unwrap_or_else(|| x.to_string());
+    let (a, b) = (path(a), path(b));
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    long == short || long.ends_with(&format!("/{}", short.trim_start_matches("./")))
+}
+
+/// Why the program stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// Before the first line, as asked with `Debugger::stop_on_entry`.
+    Entry,
+    /// A step finished.
+    Step,
+    /// The breakpoint with this id was hit.
+    Breakpoint(usize),
+}
+/// How to go on from a pause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    Continue,
+    /// Stop at the next line run, in whatever function.
+    StepInto,
+    /// Stop at the next line of this function or one it returns to.
+    StepOver,
+    /// Stop once this function has returned.
+    StepOut,
+    /// Stop the script with a `LuaError::Interrupted`.
+    Abort,
+}
+
+/// What to do when the program stops.
+pub trait DebugHandler {
+    /// Called before a line runs with the program stopped; nothing runs
+    /// until it returns, and hooks stay off while code it evaluates runs.
+    fn paused(&mut self, pause: &mut Pause) -> anyhow::Result<Resume>;
+}
+
+struct State {
+    breakpoints: Breakpoints,
+    /// While stepping, stop at a line of a frame no deeper than this.
+    step_depth: Option<usize>,
+    /// A stop due at the next line whatever the depth.
+    pending: Option<StopReason>,
+    handler: Box<dyn DebugHandler>,
+    sources: Sources,
+}
+
+/// A debugger driving `handler`. Clones share breakpoints and handler.
+#[derive(Clone)]
+pub struct Debugger(Rc<RefCell<State>>);
+impl Debugger {
+    pub fn new(handler: impl DebugHandler + 'static) -> Self {
+        Self(Rc::new(RefCell::new(State {
+            breakpoints: Breakpoints::default(),
+            step_depth: None,
+            pending: None,
+            handler: Box::new(handler),
+            sources: Sources::default(),
+        })))
+    }
+    /// Debugs `vm` from now on. This takes its hook, replacing any other.
+    pub fn attach(&self, vm: &mut LuaVM) {
+        let state = self.0.clone();
+        let mask = HookMask { call: true, line: true, ..HookMask::default() };
+        vm.set_hook(mask, move |vm, event| on_hook(&state, vm, event));
+    }
+    /// Stops before the first line that runs.
+    pub fn stop_on_entry(&self) {
+        self.0.borrow_mut().pending = Some(StopReason::Entry);
+    }
+    /// The breakpoints, for use while the program runs or before it starts;
+    /// a handler gets them through `Pause::breakpoints`.
+    pub fn breakpoints(&self) -> RefMut<'_, Breakpoints> {
+        RefMut::map(self.0.borrow_mut(), |s| &mut s.breakpoints)
+    }
+}
+
+fn on_hook(state: &Rc<RefCell<State>>, vm: &mut LuaVM, event: HookEvent) -> anyhow::Result<()> {
+    let mut st = state.borrow_mut();
+    let reason = match event {
+        // a Lua function being entered has not started running
+        HookEvent::Call if vm.current_pc(0).is_none() => {
+            let name = vm.get_info(0).and_then(|info| info.name);
+            if let Some(id) = name.and_then(|name| st.breakpoints.at_function(&name)) {
+                st.pending = Some(StopReason::Breakpoint(id));
+            }
+            None
+        }
+        HookEvent::Line(line) => {
+            let source = vm.get_info(0).map(|info| info.source).unwrap_or_default();
+            match st.breakpoints.at_line(&source, line) {
+                Some(id) => Some(StopReason::Breakpoint(id)),
+                None => st.pending.or(st.step_depth.filter(|d| vm.stack_depth() <= *d).map(|_| StopReason::Step)),
+            }
+        }
+        _ => None,
+    };
+    let Some(reason) = reason else {
+        return Ok(());
+    };
+    st.pending = None;
+    let State { breakpoints, handler, sources, .. } = &mut *st;
+    let resume = handler.paused(&mut Pause { vm, reason, breakpoints, sources })?;
+    let depth = vm.stack_depth();
+    st.step_depth = match resume {
+        Resume::Continue => None,
+        Resume::StepInto => Some(usize::MAX),
+        Resume::StepOver => Some(depth),
+        Resume::StepOut => Some(depth - 1),
+        Resume::Abort => return Err(LuaError::Interrupted("stopped by the debugger".to_string()).into()),
+    };
+    Ok(())
+}
+
+/// Source files read for showing lines, by chunk name.
+#[derive(Default)]
+struct Sources(HashMap<String, Option<Vec<String>>>);
+impl Sources {
+    fn lines(&mut self, chunk: &str) -> Option<&[String]> {
+        self.0
+            .entry(chunk.to_string())
+            .or_insert_with(|| {
+                let path = chunk.strip_prefix('@')?;
+                Some(fs::read_to_string(path).ok()?.lines().map(str::to_string).collect())
+            })
+            .as_deref()
+    }
+}
+
+/// The program while stopped. Levels count frames out from the innermost,
+/// where the program stopped, at 0.
+pub struct Pause<'a> {
+    pub vm: &'a mut LuaVM,
+    pub reason: StopReason,
+    breakpoints: &'a mut Breakpoints,
+    sources: &'a mut Sources,
+}
+impl Pause<'_> {
+    pub fn breakpoints(&mut self) -> &mut Breakpoints {
+        self.breakpoints
+    }
+    /// The frames of the running thread, innermost first.
+    pub fn frames(&self) -> Vec<DebugInfo> {
+        (0..self.vm.stack_depth()).filter_map(|level| self.vm.get_info(level)).collect()
+    }
+    /// The named locals in scope at `level`, in the order declared.
+    pub fn locals(&self, level: usize) -> Vec<(String, GCLuaValue)> {
+        (1..)
+            .map_while(|n| self.vm.get_local(level, n))
+            .filter(|(name, _)| !name.starts_with('('))
+            .collect()
+    }
+    pub fn upvalues(&self, level: usize) -> Vec<(String, GCLuaValue)> {
+        let Some(info) = self.vm.get_info(level) else {
+            return Vec::new();
+        };
+        (1..).map_while(|n| self.vm.upvalue_of(&info.func, n)).collect()
+    }
+    /// What `name` means at `level`: the innermost local of that name, else
+    /// an upvalue, else a global.
+    pub fn lookup(&self, level: usize, name: &str) -> GCLuaValue {
+        let local = self.locals(level).into_iter().rev().find(|(n, _)| n == name);
+        let upvalue = || self.upvalues(level).into_iter().find(|(n, _)| n == name);
+        match local.or_else(upvalue) {
+            Some((_, value)) => value,
+            None => self.vm.get_global(name),
+        }
+    }
+    /// Evaluates the Lua expression `expr` as if written at `level`: its
+    /// locals and upvalues are in scope, as copies.
+    pub fn evaluate(&mut self, level: usize, expr: &str) -> anyhow::Result<Vec<GCLuaValue>> {
+        if level >= self.vm.stack_depth() {
+            bail!("no frame at level {}", level);
+        }
+        let mut scope = self.upvalues(level);
+        scope.extend(self.locals(level));
+        scope.retain(|(name, _)| is_name(name));
+        let names: Vec<&str> = scope.iter().map(|(name, _)| name.as_str()).collect();
+        let mut source = String::new();
+        if !names.is_empty() {
+            source = format!("local {} = ...\n", names.join(", "));
+        }
+        source += &format!("return {}", expr);
+        let chunk = compile(source.as_bytes(), "=(eval)")?;
+        let values = scope.into_iter().map(|(_, value)| value).collect();
+        self.vm.process_chunk_with(chunk, values)
+    }
+    /// Where the function at `level` is: its chunk name and current line.
+    pub fn location(&self, level: usize) -> Option<(String, u32)> {
+        let info = self.vm.get_info(level)?;
+        Some((info.source, info.current_line?))
+    }
+    /// Line `line` of the file chunk `chunk` came from, if it can be read.
+    pub fn source_line(&mut self, chunk: &str, line: u32) -> Option<String> {
+        self.sources.lines(chunk)?.get(line.checked_sub(1)? as usize).cloned()
+    }
+}
+
+/// Whether `s` can be a Lua variable name.
+fn is_name(s: &str) -> bool {
+    const KEYWORDS: [&str; 21] = [
+        "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "if", "in", "local", "nil", "not",
+        "or", "repeat", "return", "then", "true", "until", "while",
+    ];
+    s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&s)
+}
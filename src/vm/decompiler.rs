@@ -1,83 +1,1890 @@
-use gc::Gc;
+//! Decompiles bytecode back into Lua source.
+//!
+//! Each block is run symbolically: registers above the active locals hold
+//! pending expressions that are folded into whatever consumes them, and a
+//! register whose value cannot be folded in order is turned into a local and
+//! the function decompiled again. Loops and conditionals are recovered from
+//! the control-flow graph, following the shapes the Lua 5.1 code generator
+//! produces.
 
-use super::{chunk_parser::{LuaChunk, FunctionBlock, LuaConstant}, instruction::{VMInst, VMOpcode, MASK_CBIT}, LuaFunction, LuaValue};
+use std::collections::{HashMap, HashSet};
 
-pub struct InstStream {
-    pub idx: usize,
-    pub func: FunctionBlock,
+use anyhow::{anyhow, bail};
+
+use super::{
+    cfg::{is_test, slots, Cfg, EdgeKind, Slot},
+    chunk_parser::{FunctionBlock, LuaChunk, LuaConstant, VARARG_HASARG, VARARG_ISVARARG},
+    instruction::{arg_a, arg_b, arg_bx, arg_c, arg_sbx, op_of, VMOpcode, MASK_CBIT},
+};
+use crate::compiler::{
+    ast::*,
+    lexer::is_name,
+    printer::{print_block_with, PrintOptions},
+};
+
+pub struct LuaDecompiler {
+    chunk: LuaChunk,
+    lv_idx: usize,
 }
-impl InstStream {
-    pub fn new(func: FunctionBlock) -> Self {
-        Self { idx: 0, func }
+impl LuaDecompiler {
+    pub fn new(chunk: LuaChunk) -> Self {
+        Self { chunk, lv_idx: 0 }
     }
-    pub fn next(&mut self) -> Option<VMInst> {
-        let x = self.func.list_instructions.get(self.idx).cloned()?;    
-        self.idx += 1;
-        Some(x)
+    pub fn chunk(&self) -> &LuaChunk {
+        &self.chunk
     }
-    pub fn peek_ahead(&self, amount: usize) -> Option<VMInst> {
-        self.func.list_instructions.get((self.idx - 1) + amount).cloned()
+    /// The main function as a block of statements.
+    pub fn decompile(&mut self) -> anyhow::Result<Block> {
+        let func = self.chunk.func.clone();
+        Ok(decompile_function(&func, &mut self.lv_idx, Vec::new())?.0.block)
     }
-    pub fn next_inst_is(&self, opcode: VMOpcode) -> bool {
-        let v = self.peek_ahead(1);
-        if let Some(v) = v {
-            if v.opcode == opcode {
-                return true;
+    /// The main function as Lua source.
+    pub fn run(&mut self) -> anyhow::Result<String> {
+        self.run_with(&PrintOptions::default())
+    }
+    /// The main function as Lua source laid out as `options` say; the span
+    /// of each statement starts at its first instruction.
+    pub fn run_with(&mut self, options: &PrintOptions) -> anyhow::Result<String> {
+        Ok(print_block_with(&self.decompile()?, options))
+    }
+}
+
+/// The body of `f`, whose upvalues are named `upvalues`, with the globals it
+/// and the functions in it use.
+fn decompile_function(
+    f: &FunctionBlock,
+    lv_idx: &mut usize,
+    upvalues: Vec<String>,
+) -> anyhow::Result<(FunctionBody, HashSet<String>)> {
+    let mut forced = HashSet::new();
+    loop {
+        let mut func = Func::new(f, forced.clone(), *lv_idx, upvalues.clone());
+        match func.body() {
+            Ok(body) => {
+                *lv_idx = func.lv_idx;
+                return Ok((body, func.globals));
             }
+            Err(Fail::Force(pc)) if forced.insert(pc) => {}
+            Err(Fail::Force(pc)) => bail!("cannot place the value computed at pc {}", pc + 1),
+            Err(Fail::Error(e)) => return Err(e),
         }
-        false
     }
-    pub fn get_const(&self, idx: u32) -> Gc<LuaConstant> {
-        self.func.list_const.get(idx as usize).unwrap().clone()
+}
+
+enum Fail {
+    /// The value written at this pc has to be kept in a local.
+    Force(usize),
+    Error(anyhow::Error),
+}
+impl From<anyhow::Error> for Fail {
+    fn from(e: anyhow::Error) -> Self {
+        Fail::Error(e)
     }
 }
-pub struct LuaDecompiler {
-    chunk: LuaChunk,
-    iter: InstStream,
-    lv_idx: usize
+type Res<T> = std::result::Result<T, Fail>;
+
+fn ex(kind: ExprKind) -> Expr {
+    Expr { kind, span: Span::default() }
 }
-impl LuaDecompiler {
-    pub fn new(chunk: LuaChunk) -> Self {
-        let chunk_func = chunk.func.clone();
-        Self { chunk, lv_idx: 0, iter: InstStream::new(chunk_func) }
+fn name(s: &str) -> Name {
+    Name { name: s.to_string(), span: Span::default() }
+}
+fn binary(op: BinOp, lhs: Expr, rhs: Expr) -> Expr {
+    ex(ExprKind::Binary { op, lhs: Box::new(lhs), rhs: Box::new(rhs) })
+}
+fn not(e: Expr) -> Expr {
+    ex(ExprKind::Unary { op: UnOp::Not, expr: Box::new(e) })
+}
+fn block(stats: Vec<Stat>) -> Block {
+    Block { stats, span: Span::default() }
+}
+
+fn constant(c: &LuaConstant) -> Expr {
+    ex(match c {
+        LuaConstant::LUA_TNIL => ExprKind::Nil,
+        LuaConstant::LUA_TBOOLEAN(true) => ExprKind::True,
+        LuaConstant::LUA_TBOOLEAN(false) => ExprKind::False,
+        LuaConstant::LUA_TNUMBER(n) => ExprKind::Number(*n),
+        LuaConstant::LUA_TSTRING(s) => ExprKind::String(s.as_bytes().to_vec()),
+    })
+}
+
+/// Whether `e` always evaluates to a boolean.
+fn is_boolean(e: &Expr) -> bool {
+    match &e.kind {
+        ExprKind::True | ExprKind::False => true,
+        ExprKind::Unary { op, .. } => *op == UnOp::Not,
+        ExprKind::Binary { op: BinOp::And | BinOp::Or, lhs, rhs } => is_boolean(lhs) && is_boolean(rhs),
+        ExprKind::Binary { op, .. } => {
+            matches!(op, BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge)
+        }
+        _ => false,
+    }
+}
+/// The negation of a condition, where only its truth matters.
+fn negate(e: Expr) -> Expr {
+    match e.kind {
+        ExprKind::Unary { op: UnOp::Not, expr } => *expr,
+        ExprKind::Binary { op: BinOp::Eq, lhs, rhs } => ex(ExprKind::Binary { op: BinOp::Ne, lhs, rhs }),
+        ExprKind::Binary { op: BinOp::Ne, lhs, rhs } => ex(ExprKind::Binary { op: BinOp::Eq, lhs, rhs }),
+        ExprKind::Binary { op: BinOp::And, lhs, rhs } => binary(BinOp::Or, negate(*lhs), negate(*rhs)),
+        ExprKind::Binary { op: BinOp::Or, lhs, rhs } => binary(BinOp::And, negate(*lhs), negate(*rhs)),
+        kind => not(ex(kind)),
+    }
+}
+/// `lhs op rhs`, leaning left when `rhs` is itself an `op`, which for `and`
+/// and `or` means the same.
+fn chain(op: BinOp, lhs: Expr, rhs: Expr) -> Expr {
+    match rhs.kind {
+        ExprKind::Binary { op: o, lhs: l, rhs: r } if o == op => chain(op, chain(op, lhs, *l), *r),
+        kind => binary(op, lhs, ex(kind)),
+    }
+}
+/// Decodes the "floating point byte" size hints of NEWTABLE.
+fn fb2int(x: u32) -> usize {
+    let (m, e) = ((x & 7) as usize, x >> 3);
+    if e == 0 {
+        m
+    } else {
+        (m + 8) << (e - 1)
+    }
+}
+/// `e` truncated to one value, for the last place of an expression list.
+fn single(e: Expr) -> Expr {
+    if e.kind.is_multi() {
+        ex(ExprKind::Paren(Box::new(e)))
+    } else {
+        e
     }
-    fn get_lv_name(&mut self) -> String {
+}
+
+/// The names in `a.b.c` when `target` is one.
+fn func_path(target: &Expr) -> Option<Vec<Name>> {
+    match &target.kind {
+        ExprKind::Name(n) => Some(vec![name(n)]),
+        ExprKind::Index { obj, key } => match &key.kind {
+            ExprKind::String(k) if is_name(k) => {
+                let mut path = func_path(obj)?;
+                path.push(name(&String::from_utf8_lossy(k)));
+                Some(path)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// `function a.b.c()`, or `function a.b:c()` when the first parameter is
+/// `self`.
+fn function_stat(mut path: Vec<Name>, mut body: FunctionBody) -> StatKind {
+    let method = if path.len() > 1 && body.params.first().is_some_and(|p| p.name == "self") {
+        body.params.remove(0);
+        path.pop()
+    } else {
+        None
+    };
+    StatKind::Function { name: FuncName { path, method }, body }
+}
+
+/// What a register holds that has not been consumed yet.
+#[derive(Clone)]
+enum Value {
+    Expr(Expr),
+    /// A table constructor still being filled by SETTABLE and SETLIST.
+    Table(Vec<TableField>),
+    /// `obj:name` set up by SELF, waiting for its CALL.
+    Method(Expr, Name),
+    /// The copy of the object SELF puts after the method.
+    SelfArg,
+    /// All results of a call or `...`, up to the top of the stack.
+    Multi(Expr),
+    /// The first of a fixed number of results of a call or `...`.
+    Spread(Expr, usize),
+    /// One of the other results of a `Spread`.
+    SpreadRest,
+}
+
+#[derive(Clone)]
+struct Pending {
+    value: Value,
+    /// Where it was computed.
+    pc: usize,
+}
+
+#[derive(Clone)]
+struct State {
+    /// Names of the active locals, which live in the lowest registers.
+    locals: Vec<String>,
+    pending: Vec<Option<Pending>>,
+    /// Where each register was last written.
+    defs: Vec<Option<usize>>,
+    /// A local that the value being built is for, written like a temporary.
+    target: Option<usize>,
+    /// The stores of a multiple assignment so far, first target first.
+    group: Vec<(Expr, Expr)>,
+    /// Where the lowest temporary was computed when the group started.
+    group_pc: usize,
+    /// Declarations of locals from the debug info still in scope, innermost
+    /// last.
+    scopes: Vec<Scope>,
+    /// How many regions deep the walk is.
+    depth: usize,
+}
+
+/// A local from the debug info, with its register.
+struct DebugLocal {
+    name: String,
+    reg: usize,
+    start: usize,
+    end: usize,
+}
+
+/// Where a declaration of locals from the debug info went.
+#[derive(Clone)]
+struct Scope {
+    /// The register of the first local.
+    reg: usize,
+    /// Where they go out of scope.
+    end: usize,
+    /// The region the declaration is in, and its index there.
+    depth: usize,
+    at: usize,
+}
+
+struct LoopCtx {
+    header: usize,
+    /// The blocks jumping back to the header that this loop accounts for.
+    latches: Vec<usize>,
+    /// Where `break` goes.
+    exit: Option<usize>,
+    /// Where a JMP at the exit goes, which breaks may jump to directly.
+    exit_to: Option<usize>,
+    repeat: bool,
+    /// Whether the walk of the body has started at the header.
+    entered: bool,
+    /// The `until` condition, once found.
+    until: Option<Expr>,
+}
+
+/// How a block ends.
+enum Term {
+    /// Falls through, or does not end in a jump at all.
+    None,
+    Jump(usize),
+    /// A test and the JMP after it.
+    Cond(usize),
+    ForPrep(usize),
+    ForLoop,
+    TForLoop,
+    Return,
+}
+
+/// Where an operand of a value expression goes.
+#[derive(Clone, Copy, PartialEq)]
+enum Label {
+    Operand(usize),
+    /// The LOADBOOL loading true.
+    True,
+    /// The LOADBOOL loading false.
+    False,
+    /// With the operand's value as the result.
+    End,
+}
+/// Where an expression over some of the operands goes when it holds or not.
+#[derive(Clone, Copy)]
+enum Exit {
+    Operand(usize),
+    Top,
+}
+/// One operand of a value expression: a value, or a comparison, that jumps
+/// to `jump` when its truth is `on` and goes on to `fall` when not.
+struct Operand {
+    expr: Expr,
+    value: bool,
+    on: bool,
+    jump: Option<Label>,
+    fall: Label,
+}
+impl Operand {
+    /// Whether going to `label` means going to `exit` with the truth `holds`.
+    fn reaches(&self, label: Label, exit: Exit, holds: bool) -> bool {
+        let jumps = Some(label) == self.jump;
+        match (label, exit) {
+            (Label::Operand(l), Exit::Operand(e)) => l == e,
+            (Label::True, Exit::Top) => holds,
+            (Label::False, Exit::Top) => !holds,
+            (Label::End, Exit::Top) => (self.on == jumps) == holds,
+            _ => false,
+        }
+    }
+}
+enum Tree {
+    Operand(usize, Option<bool>),
+    Binary(BinOp, Box<Tree>, Box<Tree>),
+}
+
+struct Func<'a> {
+    f: &'a FunctionBlock,
+    code: Vec<u32>,
+    slots: Vec<Slot>,
+    cfg: Cfg,
+    /// Registers live on entry to each block.
+    live: Vec<Vec<bool>>,
+    forced: HashSet<usize>,
+    lv_idx: usize,
+    /// Pending registers consumed by the current instruction, with where they
+    /// were computed.
+    taken: Vec<(usize, usize)>,
+    loops: Vec<LoopCtx>,
+    /// Blocks the enclosing regions end at.
+    stops: Vec<usize>,
+    /// Where the constructors of tables that have to be locals end.
+    table_ends: HashSet<usize>,
+    /// Named locals; empty for a stripped chunk.
+    debug: Vec<DebugLocal>,
+    /// The instruction being decompiled.
+    pc: usize,
+    /// The first instruction of the statement being built.
+    first: Option<usize>,
+    /// What the enclosing function calls the upvalues.
+    upvalues: Vec<String>,
+    /// Globals used here or in nested functions.
+    globals: HashSet<String>,
+    /// The globals each closure made here uses, by where it was made.
+    closures: HashMap<usize, HashSet<String>>,
+}
+
+impl<'a> Func<'a> {
+    fn new(f: &'a FunctionBlock, forced: HashSet<usize>, lv_idx: usize, upvalues: Vec<String>) -> Self {
+        let code: Vec<u32> = f.list_instructions.iter().map(|i| i.to_u32()).collect();
+        let upvals: Vec<u32> = f.list_fnproto.iter().map(|p| p.num_upval as u32).collect();
+        let slots = slots(&code, &upvals);
+        let cfg = Cfg::from_code(&code, &upvals);
+        // registers go to locals in the order they are declared, each to the
+        // lowest one not held by a local still active
+        let mut debug: Vec<DebugLocal> = Vec::new();
+        for l in &f.local_vars {
+            let (start, end) = (l.start_pc as usize, l.end_pc as usize);
+            let reg = debug.iter().filter(|d| d.start <= start && start < d.end).count();
+            debug.push(DebugLocal { name: l.name.clone(), reg, start, end });
+        }
+        let mut func = Self {
+            f,
+            code,
+            slots,
+            cfg,
+            live: Vec::new(),
+            forced,
+            lv_idx,
+            taken: Vec::new(),
+            loops: Vec::new(),
+            stops: Vec::new(),
+            table_ends: HashSet::new(),
+            debug,
+            pc: 0,
+            first: None,
+            upvalues,
+            globals: HashSet::new(),
+            closures: HashMap::new(),
+        };
+        func.live = func.liveness();
+        func
+    }
+
+    fn new_name(&mut self) -> String {
         let s = format!("lv_{}", self.lv_idx);
         self.lv_idx += 1;
         s
     }
-    pub fn run(&mut self) -> String {
-        let mut out = String::new();
-        while let Some(inst) = self.iter.next() {
-            match inst.opcode {
-                VMOpcode::LOADK => {
-                    let loadk_reg = inst.params[0].get_num_val();
-                    let const_loc = inst.params[1].get_num_val();
-                    let mut builder = String::new();
-                    let mut local = true;
-                    if let Some(inst) = self.iter.peek_ahead(1) {
-                        if inst.opcode == VMOpcode::SETGLOBAL && loadk_reg == inst.params[0].get_num_val() {
-                            builder.push_str(&self.iter.get_const(inst.params[1].get_num_val()).non_gc_asvalue().as_string(false));
-                            local = false;
+    /// The name of the local in register `r` at `at`, from the debug info
+    /// when the chunk has it.
+    fn local_name(&mut self, r: usize, at: usize) -> String {
+        let local = self.debug.iter().rev().find(|l| l.reg == r && l.start <= at && at < l.end);
+        match local {
+            Some(local) => local.name.clone(),
+            None => self.new_name(),
+        }
+    }
+
+    fn body(&mut self) -> Res<FunctionBody> {
+        let nregs = 256;
+        let mut st = State {
+            locals: Vec::new(),
+            pending: vec![None; nregs],
+            defs: vec![None; nregs],
+            target: None,
+            group: Vec::new(),
+            group_pc: 0,
+            scopes: Vec::new(),
+            depth: 0,
+        };
+        for r in 0..self.f.num_param as usize {
+            let name = self.local_name(r, 0);
+            st.locals.push(name);
+        }
+        let params = st.locals.iter().map(|p| name(p)).collect();
+        // the implicit `arg` of a vararg function follows the parameters
+        if self.f.is_vararg & VARARG_HASARG != 0 {
+            st.locals.push("arg".to_string());
+        }
+        let mut out = Vec::new();
+        if !self.cfg.blocks.is_empty() {
+            self.region(&mut st, 0, None, &mut out)?;
+        }
+        self.flush(&mut st, &mut out)?;
+        if matches!(out.last(), Some(Stat { kind: StatKind::Return(values), .. }) if values.is_empty()) {
+            out.pop();
+        }
+        let is_vararg = self.f.is_vararg & VARARG_ISVARARG != 0;
+        Ok(FunctionBody { params, is_vararg, block: block(out), span: Span::default() })
+    }
+
+    // Registers
+
+    fn take(&mut self, st: &mut State, r: u32) -> Res<Value> {
+        let r = r as usize;
+        // a value computed into a local reads the old value until it is written
+        if r < st.locals.len() && (st.target != Some(r) || st.pending[r].is_none()) {
+            let local = ex(ExprKind::Name(st.locals[r].clone()));
+            if st.group.iter().any(|(target, _)| *target == local) {
+                // a multiple assignment would read the old value
+                return Err(Self::break_group(st));
+            }
+            return Ok(Value::Expr(local));
+        }
+        match st.pending[r].take() {
+            Some(p) => {
+                self.taken.push((r, p.pc));
+                Ok(p.value)
+            }
+            None => Err(match st.defs[r] {
+                Some(pc) => Fail::Force(pc),
+                None => anyhow!("register {} is read before it is written", r).into(),
+            }),
+        }
+    }
+    fn take_expr(&mut self, st: &mut State, r: u32) -> Res<Expr> {
+        let pc = st.pending[r as usize].as_ref().map(|p| p.pc);
+        match self.take(st, r)? {
+            Value::Expr(e) | Value::Multi(e) => Ok(e),
+            Value::Table(fields) => Ok(ex(ExprKind::Table(fields))),
+            Value::Spread(..) | Value::SpreadRest => Err(Fail::Force(pc.unwrap())),
+            Value::Method(..) | Value::SelfArg => Err(anyhow!("register {} holds a method", r).into()),
+        }
+    }
+    /// A register, or a constant when `v` has the RK bit.
+    fn rk(&mut self, st: &mut State, v: u32) -> Res<Expr> {
+        if v & MASK_CBIT != 0 {
+            return Ok(constant(&self.f.list_const[(v & !MASK_CBIT) as usize]));
+        }
+        self.take_expr(st, v)
+    }
+    /// The values of registers `from` on, `b - 1` of them or, when `b` is 0,
+    /// up to the open results of a call or `...`.
+    fn take_list(&mut self, st: &mut State, from: u32, b: u32) -> Res<Vec<Expr>> {
+        let mut list = Vec::new();
+        if b == 0 {
+            let last = (from as usize..st.pending.len())
+                .rev()
+                .find(|r| matches!(st.pending[*r], Some(Pending { value: Value::Multi(_), .. })))
+                .ok_or_else(|| anyhow!("no open results after register {}", from))?;
+            for r in from..=last as u32 {
+                list.push(self.take_expr(st, r)?);
+            }
+        } else {
+            for r in from..from + b - 1 {
+                list.push(self.take_expr(st, r)?);
+            }
+            if let Some(last) = list.pop() {
+                list.push(single(last));
+            }
+        }
+        Ok(list)
+    }
+    /// Checks the pending registers the instruction at hand consumed were
+    /// computed in the order it uses them, with nothing left in between.
+    fn check_takes(&mut self, st: &State) -> Res<()> {
+        let taken = std::mem::take(&mut self.taken);
+        for pair in taken.windows(2) {
+            if pair[1].0 < pair[0].0 {
+                return Err(Fail::Force(pair[1].1));
+            }
+        }
+        if let Some((lo, pc)) = taken.first() {
+            if st.pending[lo + 1..].iter().any(Option::is_some) {
+                return Err(Fail::Force(*pc));
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, st: &mut State, r: u32, value: Value, pc: usize, out: &mut Vec<Stat>) -> Res<()> {
+        let r = r as usize;
+        let def = st.defs[r].replace(pc);
+        if r < st.locals.len() && st.target != Some(r) {
+            let value = match value {
+                Value::Expr(e) | Value::Multi(e) => e,
+                Value::Table(fields) => ex(ExprKind::Table(fields)),
+                _ => return Err(anyhow!("pc {}: cannot assign to a local", pc + 1).into()),
+            };
+            let target = ex(ExprKind::Name(st.locals[r].clone()));
+            return self.store(st, target, value, out);
+        }
+        if let Some(old) = &st.pending[r] {
+            return Err(Fail::Force(old.pc));
+        }
+        // temporaries above are freed first, so this register was a local
+        if let Some(def) = def.filter(|_| st.pending[r + 1..].iter().any(Option::is_some)) {
+            return Err(Fail::Force(def));
+        }
+        if !st.group.is_empty() {
+            return Err(Self::break_group(st));
+        }
+        let (spread, table) =
+            (matches!(value, Value::Spread(..) | Value::SpreadRest), matches!(value, Value::Table(_)));
+        st.pending[r] = Some(Pending { value, pc });
+        if self.forced.contains(&pc) {
+            if table {
+                // declared once filled
+                let end = self.constructor_end(r, pc);
+                self.table_ends.insert(end);
+                if end != pc {
+                    return Ok(());
+                }
+            }
+            if !spread {
+                self.flush(st, out)?;
+            }
+        }
+        Ok(())
+    }
+    /// The last SETTABLE or SETLIST filling the table created in `r` at `pc`.
+    fn constructor_end(&self, r: usize, pc: usize) -> usize {
+        let mut end = pc;
+        let mut room = fb2int(arg_c(self.code[pc]));
+        for p in (pc + 1..self.code.len()).filter(|p| self.slots[*p] == Slot::Inst) {
+            let i = self.code[p];
+            match op_of(i) {
+                VMOpcode::SETTABLE if arg_a(i) as usize == r && room > 0 => {
+                    room -= 1;
+                    end = p;
+                    continue;
+                }
+                VMOpcode::SETLIST if arg_a(i) as usize == r => {
+                    end = p;
+                    continue;
+                }
+                _ => {}
+            }
+            let (reads, writes) = self.effects(p);
+            if reads.contains(&r) || writes.iter().any(|w| *w <= r) {
+                break;
+            }
+        }
+        end
+    }
+
+    /// Declares every pending register as a local, in order.
+    fn flush(&mut self, st: &mut State, out: &mut Vec<Stat>) -> Res<()> {
+        if !st.group.is_empty() {
+            return Err(Self::break_group(st));
+        }
+        let Some(top) = (st.locals.len()..st.pending.len()).rev().find(|r| st.pending[*r].is_some()) else {
+            return Ok(());
+        };
+        self.declare_regs(st, top, self.pc + 1, out)
+    }
+    /// Declares the registers up to `top` as locals active at `at`, with
+    /// what is pending in them.
+    fn declare_regs(&mut self, st: &mut State, top: usize, at: usize, out: &mut Vec<Stat>) -> Res<()> {
+        // a register used up below one still pending was a local of its own
+        if let Some(pc) = (st.locals.len()..top).find(|r| st.pending[*r].is_none()).and_then(|r| st.defs[r]) {
+            return Err(Fail::Force(pc));
+        }
+        let mut names = Vec::new();
+        let mut exprs = Vec::new();
+        let mut r = st.locals.len();
+        let closure = st.pending[r].as_ref().and_then(|p| self.closures.get(&p.pc)).cloned();
+        while r <= top {
+            let (e, count) = match st.pending[r].take().map(|p| p.value) {
+                None => (ex(ExprKind::Nil), 1),
+                Some(Value::Expr(e) | Value::Multi(e)) => (e, 1),
+                Some(Value::Table(fields)) => (ex(ExprKind::Table(fields)), 1),
+                Some(Value::Spread(e, n)) => (e, n),
+                Some(_) => return Err(anyhow!("register {} is left over from a call", r).into()),
+            };
+            exprs.push(e);
+            for reg in r..r + count {
+                st.pending[reg] = None;
+                let local = self.local_name(reg, at);
+                names.push(name(&local));
+                st.locals.push(local);
+            }
+            if count > 1 {
+                // the results fill the rest of the names
+                self.declare(&mut names, &mut exprs, out);
+            }
+            r += count;
+        }
+        // `local f = function` where the function does not use a global `f`
+        if let (Some(globals), [local], [Expr { kind: ExprKind::Function(body), .. }]) = (closure, &names[..], &exprs[..]) {
+            if !globals.contains(&local.name) {
+                let (name, body) = (local.clone(), (**body).clone());
+                out.push(self.stat(StatKind::LocalFunction { name, body }));
+                return Ok(());
+            }
+        }
+        self.declare(&mut names, &mut exprs, out);
+        Ok(())
+    }
+    fn declare(&mut self, names: &mut Vec<Name>, exprs: &mut Vec<Expr>, out: &mut Vec<Stat>) {
+        if names.is_empty() {
+            return;
+        }
+        while matches!(exprs.last(), Some(Expr { kind: ExprKind::Nil, .. }))
+            && !exprs.len().checked_sub(2).is_some_and(|i| exprs[i].kind.is_multi())
+        {
+            exprs.pop();
+        }
+        out.push(self.stat(StatKind::Local { names: std::mem::take(names), exprs: std::mem::take(exprs) }));
+    }
+    /// Declares locals up to and including register `r`.
+    fn declare_up_to(&mut self, state: &mut State, r: usize, out: &mut Vec<Stat>) -> Res<()> {
+        self.flush(state, out)?;
+        let mut names = Vec::new();
+        while state.locals.len() <= r {
+            let local = self.local_name(state.locals.len(), self.pc + 1);
+            names.push(name(&local));
+            state.locals.push(local);
+        }
+        self.declare(&mut names, &mut Vec::new(), out);
+        Ok(())
+    }
+    /// Assigns `value` to `target`. A multiple assignment evaluates all it
+    /// needs into temporaries and then stores them last to first, so a store
+    /// while temporaries are pending starts a group of stores that ends with
+    /// the one that uses up the last of them.
+    fn store(&mut self, st: &mut State, target: Expr, value: Expr, out: &mut Vec<Stat>) -> Res<()> {
+        let pending = st.pending[st.locals.len()..].iter().flatten().next().map(|p| p.pc);
+        if st.group.is_empty() {
+            st.group_pc = pending.unwrap_or(0);
+        }
+        st.group.insert(0, (target, value));
+        if pending.is_none() {
+            let (targets, exprs): (Vec<_>, Vec<_>) = std::mem::take(&mut st.group).into_iter().unzip();
+            if let ([target], [Expr { kind: ExprKind::Function(body), .. }]) = (&targets[..], &exprs[..]) {
+                if let Some(path) = func_path(target) {
+                    out.push(self.stat(function_stat(path, (**body).clone())));
+                    return Ok(());
+                }
+            }
+            out.push(self.stat(StatKind::Assign { targets, exprs }));
+        }
+        Ok(())
+    }
+    /// Anything but a store while stores are grouped means the group was a
+    /// mistake: the lowest pending register is a local instead.
+    fn break_group(st: &State) -> Fail {
+        Fail::Force(st.group_pc)
+    }
+    /// A statement from the instructions run since the last one.
+    fn stat(&mut self, kind: StatKind) -> Stat {
+        let pc = self.first.unwrap_or(self.pc);
+        self.stat_at(pc, kind)
+    }
+    /// A statement whose span is the instruction at `pc` and its line.
+    fn stat_at(&mut self, pc: usize, kind: StatKind) -> Stat {
+        self.first = None;
+        let line = self.f.line_info.get(pc).copied().unwrap_or(0);
+        Stat { kind, span: Span { start: pc, end: pc + 1, line, end_line: line } }
+    }
+    fn emit(&mut self, state: &mut State, out: &mut Vec<Stat>, kind: StatKind) -> Res<()> {
+        self.flush(state, out)?;
+        out.push(self.stat(kind));
+        Ok(())
+    }
+
+    // Instructions
+
+    /// Runs one instruction that does not end its block.
+    fn step(&mut self, s: &mut State, pc: usize, out: &mut Vec<Stat>) -> Res<()> {
+        // a scope ends before the CLOSE leaving it, which is not yet past it
+        self.enter(s, pc, op_of(self.code[pc]) != VMOpcode::CLOSE, out)?;
+        self.first.get_or_insert(pc);
+        self.instruction(s, pc, out)?;
+        if self.table_ends.remove(&pc) {
+            self.flush(s, out)?;
+        }
+        Ok(())
+    }
+    /// Moves on to `pc`: the locals the debug info ends there go out of
+    /// scope when `close`, in a `do` block if their region goes on, and those
+    /// it starts there are declared.
+    fn enter(&mut self, s: &mut State, pc: usize, close: bool, out: &mut Vec<Stat>) -> Res<()> {
+        self.pc = pc;
+        // the locals of the function last until its final return
+        let mut closed = None;
+        while close && pc + 1 < self.code.len() {
+            match s.scopes.last() {
+                Some(scope) if scope.end <= pc && scope.depth == s.depth => closed = s.scopes.pop(),
+                _ => break,
+            }
+        }
+        if let Some(scope) = closed.filter(|scope| scope.reg < s.locals.len()) {
+            self.flush(s, out)?;
+            s.locals.truncate(scope.reg);
+            let stats = out.split_off(scope.at);
+            if !stats.is_empty() {
+                let span = stats[0].span;
+                out.push(Stat { kind: StatKind::Do(block(stats)), span });
+            }
+        }
+        let news = self.debug.iter().filter(|l| l.start <= pc && pc < l.end && l.reg >= s.locals.len());
+        let Some((top, end)) = news.map(|l| (l.reg, l.end)).max() else {
+            return Ok(());
+        };
+        s.scopes.push(Scope { reg: s.locals.len(), end, depth: s.depth, at: out.len() });
+        self.declare_regs(s, top, pc, out)
+    }
+    fn instruction(&mut self, s: &mut State, pc: usize, out: &mut Vec<Stat>) -> Res<()> {
+        let i = self.code[pc];
+        let (a, b, c, bx) = (arg_a(i), arg_b(i), arg_c(i), arg_bx(i));
+        let arith = |op: VMOpcode| match op {
+            VMOpcode::ADD => Some(BinOp::Add),
+            VMOpcode::SUB => Some(BinOp::Sub),
+            VMOpcode::MUL => Some(BinOp::Mul),
+            VMOpcode::DIV => Some(BinOp::Div),
+            VMOpcode::MOD => Some(BinOp::Mod),
+            VMOpcode::POW => Some(BinOp::Pow),
+            _ => None,
+        };
+        let op = op_of(i);
+        let value = match op {
+            VMOpcode::MOVE => Some(Value::Expr(self.take_expr(s, b)?)),
+            VMOpcode::LOADK => Some(Value::Expr(constant(&self.f.list_const[bx as usize]))),
+            VMOpcode::LOADBOOL => Some(Value::Expr(ex(if b != 0 { ExprKind::True } else { ExprKind::False }))),
+            VMOpcode::LOADNIL => {
+                self.check_takes(s)?;
+                // declared together if forced
+                let forced = self.forced.remove(&pc);
+                for r in a..=b {
+                    if r == b && forced {
+                        self.forced.insert(pc);
+                    }
+                    self.set(s, r, Value::Expr(ex(ExprKind::Nil)), pc, out)?;
+                }
+                return Ok(());
+            }
+            VMOpcode::GETUPVAL => Some(Value::Expr(ex(ExprKind::Name(self.upvalue(b))))),
+            VMOpcode::GETGLOBAL => Some(Value::Expr(self.global(bx))),
+            VMOpcode::GETTABLE => {
+                let obj = self.take_expr(s, b)?;
+                let key = self.rk(s, c)?;
+                Some(Value::Expr(ex(ExprKind::Index { obj: Box::new(obj), key: Box::new(key) })))
+            }
+            VMOpcode::SETGLOBAL => {
+                let value = self.take_expr(s, a)?;
+                self.check_takes(s)?;
+                let target = self.global(bx);
+                return self.store(s, target, value, out);
+            }
+            VMOpcode::SETUPVAL => {
+                let value = self.take_expr(s, a)?;
+                self.check_takes(s)?;
+                let target = ex(ExprKind::Name(self.upvalue(b)));
+                return self.store(s, target, value, out);
+            }
+            VMOpcode::SETTABLE => {
+                // a constructor sets no more keys than NEWTABLE makes room for
+                let room = |p: &Pending| match &p.value {
+                    Value::Table(fields) => {
+                        let keys = fields.iter().filter(|f| !matches!(f, TableField::Positional(_))).count();
+                        keys < fb2int(arg_c(self.code[p.pc]))
+                    }
+                    _ => false,
+                };
+                if s.pending[a as usize].as_ref().is_some_and(room) {
+                    let key = self.rk(s, b)?;
+                    let value = self.rk(s, c)?;
+                    self.check_takes(s)?;
+                    let Some(Pending { value: Value::Table(fields), .. }) = &mut s.pending[a as usize] else {
+                        unreachable!()
+                    };
+                    fields.push(match key.kind {
+                        ExprKind::String(k) if is_name(&k) => {
+                            TableField::Named(name(&String::from_utf8_lossy(&k)), value)
+                        }
+                        kind => TableField::Keyed(ex(kind), value),
+                    });
+                    return Ok(());
+                }
+                // a table filled past its constructor is a local
+                if let Some(Pending { value: Value::Table(_), pc }) = &s.pending[a as usize] {
+                    return Err(Fail::Force(*pc));
+                }
+                let obj = self.take_expr(s, a)?;
+                let key = self.rk(s, b)?;
+                let value = self.rk(s, c)?;
+                self.check_takes(s)?;
+                let target = ex(ExprKind::Index { obj: Box::new(obj), key: Box::new(key) });
+                return self.store(s, target, value, out);
+            }
+            VMOpcode::NEWTABLE => Some(Value::Table(Vec::new())),
+            VMOpcode::SELF => {
+                let obj = self.take_expr(s, b)?;
+                let key = self.rk(s, c)?;
+                self.check_takes(s)?;
+                let ExprKind::String(method) = key.kind.clone() else {
+                    return Err(anyhow!("pc {}: method name is not a string", pc + 1).into());
+                };
+                if !is_name(&method) {
+                    return Err(anyhow!("pc {}: method name is not a name", pc + 1).into());
+                }
+                let method = name(&String::from_utf8_lossy(&method));
+                self.set(s, a, Value::Method(obj, method), pc, out)?;
+                return self.set(s, a + 1, Value::SelfArg, pc, out);
+            }
+            op if arith(op.clone()).is_some() => {
+                let lhs = self.rk(s, b)?;
+                let rhs = self.rk(s, c)?;
+                Some(Value::Expr(binary(arith(op).unwrap(), lhs, rhs)))
+            }
+            VMOpcode::UNM | VMOpcode::NOT | VMOpcode::LEN => {
+                let operand = self.take_expr(s, b)?;
+                let op = match op {
+                    VMOpcode::UNM => UnOp::Neg,
+                    VMOpcode::NOT => UnOp::Not,
+                    _ => UnOp::Len,
+                };
+                Some(Value::Expr(ex(ExprKind::Unary { op, expr: Box::new(operand) })))
+            }
+            VMOpcode::CONCAT => {
+                let mut parts = Vec::new();
+                for r in b..=c {
+                    parts.push(self.take_expr(s, r)?);
+                }
+                let last = parts.pop().unwrap();
+                Some(Value::Expr(parts.into_iter().rev().fold(last, |acc, e| binary(BinOp::Concat, e, acc))))
+            }
+            VMOpcode::CALL | VMOpcode::TAILCALL => {
+                let call = match self.take(s, a)? {
+                    Value::Method(obj, method) => {
+                        self.take(s, a + 1)?;
+                        let args = self.take_list(s, a + 2, b.saturating_sub(1))?;
+                        ex(ExprKind::MethodCall { obj: Box::new(obj), method, args })
+                    }
+                    Value::Expr(func) => {
+                        let args = self.take_list(s, a + 1, b)?;
+                        ex(ExprKind::Call { func: Box::new(func), args })
+                    }
+                    Value::Table(fields) => {
+                        let args = self.take_list(s, a + 1, b)?;
+                        ex(ExprKind::Call { func: Box::new(ex(ExprKind::Table(fields))), args })
+                    }
+                    _ => return Err(anyhow!("pc {}: bad function register", pc + 1).into()),
+                };
+                self.check_takes(s)?;
+                let c = if op == VMOpcode::TAILCALL { 0 } else { c };
+                return self.results(s, a, c, call, pc, out);
+            }
+            VMOpcode::VARARG => {
+                self.check_takes(s)?;
+                return self.results(s, a, b, ex(ExprKind::Vararg), pc, out);
+            }
+            VMOpcode::RETURN => {
+                let values = self.take_list(s, a, b)?;
+                self.check_takes(s)?;
+                return self.emit(s, out, StatKind::Return(values));
+            }
+            VMOpcode::SETLIST => {
+                let items = self.take_list(s, a + 1, if b == 0 { 0 } else { b + 1 })?;
+                self.check_takes(s)?;
+                let Some(Pending { value: Value::Table(fields), .. }) = &mut s.pending[a as usize] else {
+                    return Err(anyhow!("pc {}: SETLIST outside a table constructor", pc + 1).into());
+                };
+                fields.extend(items.into_iter().map(TableField::Positional));
+                return Ok(());
+            }
+            VMOpcode::CLOSE => None,
+            VMOpcode::CLOSURE => return self.closure(s, pc, out),
+            op => return Err(anyhow!("pc {}: unexpected {:?}", pc + 1, op).into()),
+        };
+        self.check_takes(s)?;
+        if let Some(value) = value {
+            self.set(s, a, value, pc, out)?;
+        }
+        Ok(())
+    }
+    /// Stores the results of a call or `...` in registers `a` on, `c - 1` of
+    /// them or all of them when `c` is 0, or runs the call as a statement.
+    fn results(&mut self, s: &mut State, a: u32, c: u32, e: Expr, pc: usize, out: &mut Vec<Stat>) -> Res<()> {
+        match c {
+            0 => self.set(s, a, Value::Multi(e), pc, out),
+            1 if !matches!(e.kind, ExprKind::Vararg) => self.emit(s, out, StatKind::Call(e)),
+            1 => Ok(()),
+            2 => self.set(s, a, Value::Expr(e), pc, out),
+            _ => {
+                self.set(s, a, Value::Spread(e, c as usize - 1), pc, out)?;
+                for r in a + 1..a + c - 1 {
+                    self.set(s, r, Value::SpreadRest, pc, out)?;
+                }
+                if self.forced.contains(&pc) {
+                    self.flush(s, out)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// A CLOSURE and the pseudo-instructions after it that say what it
+    /// captures. One that captures its own register is a `local function`.
+    fn closure(&mut self, s: &mut State, pc: usize, out: &mut Vec<Stat>) -> Res<()> {
+        let i = self.code[pc];
+        let (a, bx) = (arg_a(i) as usize, arg_bx(i) as usize);
+        let proto = self.f.list_fnproto[bx].clone();
+        let next = pc + 1 + proto.num_upval as usize;
+        let captures: Vec<(VMOpcode, usize)> =
+            (pc + 1..next).map(|p| (op_of(self.code[p]), arg_b(self.code[p]) as usize)).collect();
+        // a function that captures the register it goes to is its own local
+        let recursive = a == s.locals.len() && captures.iter().any(|c| *c == (VMOpcode::MOVE, a));
+        let name_of_self = recursive.then(|| self.local_name(a, next));
+        let mut upvalues = Vec::new();
+        for (op, b) in captures {
+            upvalues.push(match op {
+                VMOpcode::GETUPVAL => self.upvalue(b as u32),
+                _ if b < s.locals.len() => s.locals[b].clone(),
+                _ if b == a && recursive => name_of_self.clone().unwrap(),
+                // only locals are captured
+                _ => match &s.pending[b] {
+                    Some(p) => return Err(Fail::Force(p.pc)),
+                    None => return Err(anyhow!("pc {}: closure captures a temporary", pc + 1).into()),
+                },
+            });
+        }
+        let (body, globals) = decompile_function(&proto, &mut self.lv_idx, upvalues)?;
+        self.globals.extend(globals.iter().cloned());
+        self.closures.insert(pc, globals);
+        let Some(local) = name_of_self else {
+            return self.set(s, a as u32, Value::Expr(ex(ExprKind::Function(Box::new(body)))), pc, out);
+        };
+        self.flush(s, out)?;
+        if let Some(l) = self.debug.iter().find(|l| l.reg == a && l.start <= next && next < l.end) {
+            s.scopes.push(Scope { reg: a, end: l.end, depth: s.depth, at: out.len() });
+        }
+        s.locals.push(local.clone());
+        self.emit(s, out, StatKind::LocalFunction { name: name(&local), body })
+    }
+    fn upvalue(&self, idx: u32) -> String {
+        match self.upvalues.get(idx as usize).or(self.f.upvalue_names.get(idx as usize)) {
+            Some(name) => name.clone(),
+            None => format!("up_{}", idx),
+        }
+    }
+    fn global(&mut self, bx: u32) -> Expr {
+        match &*self.f.list_const[bx as usize] {
+            LuaConstant::LUA_TSTRING(s) if is_name(s.as_bytes()) => {
+                self.globals.insert(s.to_string());
+                ex(ExprKind::Name(s.to_string()))
+            }
+            c => ex(ExprKind::Index { obj: Box::new(ex(ExprKind::Name("_G".to_string()))), key: Box::new(constant(c)) }),
+        }
+    }
+
+    /// The condition under which the test at `pc` takes its jump.
+    fn test_expr(&mut self, s: &mut State, pc: usize) -> Res<Expr> {
+        let i = self.code[pc];
+        let (a, b, c) = (arg_a(i), arg_b(i), arg_c(i));
+        let e = match op_of(i) {
+            op @ (VMOpcode::EQ | VMOpcode::LT | VMOpcode::LE) => {
+                let lhs = self.rk(s, b)?;
+                let rhs = self.rk(s, c)?;
+                let e = match op {
+                    VMOpcode::EQ => binary(BinOp::Eq, lhs, rhs),
+                    VMOpcode::LT => binary(BinOp::Lt, lhs, rhs),
+                    _ => binary(BinOp::Le, lhs, rhs),
+                };
+                if a != 0 {
+                    e
+                } else {
+                    negate(e)
+                }
+            }
+            VMOpcode::TEST => {
+                let v = self.take_expr(s, a)?;
+                if c != 0 {
+                    v
+                } else {
+                    not(v)
+                }
+            }
+            VMOpcode::TESTSET => {
+                let v = self.take_expr(s, b)?;
+                if c != 0 {
+                    v
+                } else {
+                    not(v)
+                }
+            }
+            op => return Err(anyhow!("pc {}: {:?} is not a test", pc + 1, op).into()),
+        };
+        self.check_takes(s)?;
+        Ok(e)
+    }
+}
+
+// Control flow
+
+impl Func<'_> {
+    fn start(&self, b: usize) -> usize {
+        self.cfg.blocks[b].start
+    }
+    fn insts(&self, from: usize, to: usize) -> Vec<usize> {
+        (from..to).filter(|pc| self.slots[*pc] == Slot::Inst).collect()
+    }
+    fn jump_target(&self, pc: usize) -> usize {
+        (pc as i64 + 1 + arg_sbx(self.code[pc]) as i64) as usize
+    }
+    fn succ(&self, b: usize, kind: EdgeKind) -> Option<usize> {
+        self.cfg.blocks[b].succs.iter().find(|e| e.kind == kind).map(|e| e.target)
+    }
+    /// Where a conditional block goes when its condition holds, and when not.
+    fn targets(&self, b: usize) -> Res<(usize, usize)> {
+        match (self.succ(b, EdgeKind::True), self.succ(b, EdgeKind::False)) {
+            (Some(t), Some(f)) => Ok((t, f)),
+            _ => Err(anyhow!("block at pc {} does not branch", self.start(b) + 1).into()),
+        }
+    }
+    /// The target of a block that is nothing but a JMP.
+    fn jump_only(&self, b: usize) -> Option<usize> {
+        // closing upvalues is all a block leaving a scope does besides jump
+        match self.insts(self.start(b), self.cfg.blocks[b].end)[..] {
+            [ref closes @ .., pc]
+                if op_of(self.code[pc]) == VMOpcode::JMP
+                    && closes.iter().all(|p| op_of(self.code[*p]) == VMOpcode::CLOSE) =>
+            {
+                self.succ(b, EdgeKind::Always)
+            }
+            _ => None,
+        }
+    }
+
+    fn terminator(&self, b: usize) -> Term {
+        let block = &self.cfg.blocks[b];
+        let Some(last) = (block.start..block.end).rev().find(|pc| self.slots[*pc] == Slot::Inst) else {
+            return Term::None;
+        };
+        let guarded =
+            last > block.start && self.slots[last - 1] == Slot::Inst && is_test(&op_of(self.code[last - 1]));
+        match op_of(self.code[last]) {
+            VMOpcode::JMP if guarded && op_of(self.code[last - 1]) == VMOpcode::TFORLOOP => Term::TForLoop,
+            VMOpcode::JMP if guarded => Term::Cond(last - 1),
+            VMOpcode::JMP => Term::Jump(last),
+            VMOpcode::FORPREP => Term::ForPrep(last),
+            VMOpcode::FORLOOP => Term::ForLoop,
+            VMOpcode::TFORLOOP => Term::TForLoop,
+            VMOpcode::RETURN => Term::Return,
+            op if is_test(&op) => Term::Cond(last),
+            _ => Term::None,
+        }
+    }
+
+    /// Registers the word at `pc` reads and writes; B or C of 0 counts as
+    /// up to the top of the stack.
+    fn effects(&self, pc: usize) -> (Vec<usize>, Vec<usize>) {
+        let i = self.code[pc];
+        let (a, b, c) = (arg_a(i) as usize, arg_b(i) as usize, arg_c(i) as usize);
+        let top = (self.f.max_stack_size as usize).max(a + 1);
+        let rk = |v: usize| (v & MASK_CBIT as usize == 0).then_some(v);
+        let regs = |from: usize, to: usize| (from..to).collect::<Vec<_>>();
+        match self.slots[pc] {
+            Slot::Pseudo if op_of(i) == VMOpcode::MOVE => return (vec![b], Vec::new()),
+            Slot::Pseudo | Slot::Data => return (Vec::new(), Vec::new()),
+            Slot::Inst => {}
+        }
+        match op_of(i) {
+            VMOpcode::MOVE | VMOpcode::UNM | VMOpcode::NOT | VMOpcode::LEN => (vec![b], vec![a]),
+            VMOpcode::LOADK
+            | VMOpcode::LOADBOOL
+            | VMOpcode::GETUPVAL
+            | VMOpcode::GETGLOBAL
+            | VMOpcode::NEWTABLE
+            | VMOpcode::CLOSURE => (Vec::new(), vec![a]),
+            VMOpcode::LOADNIL => (Vec::new(), regs(a, b + 1)),
+            VMOpcode::GETTABLE => (std::iter::once(b).chain(rk(c)).collect(), vec![a]),
+            VMOpcode::SELF => (std::iter::once(b).chain(rk(c)).collect(), vec![a, a + 1]),
+            VMOpcode::SETTABLE => (std::iter::once(a).chain(rk(b)).chain(rk(c)).collect(), Vec::new()),
+            VMOpcode::ADD | VMOpcode::SUB | VMOpcode::MUL | VMOpcode::DIV | VMOpcode::MOD | VMOpcode::POW => {
+                (rk(b).into_iter().chain(rk(c)).collect(), vec![a])
+            }
+            VMOpcode::EQ | VMOpcode::LT | VMOpcode::LE => (rk(b).into_iter().chain(rk(c)).collect(), Vec::new()),
+            VMOpcode::SETGLOBAL | VMOpcode::SETUPVAL | VMOpcode::TEST => (vec![a], Vec::new()),
+            VMOpcode::TESTSET => (vec![b], vec![a]),
+            VMOpcode::CONCAT => (regs(b, c + 1), vec![a]),
+            VMOpcode::CALL | VMOpcode::TAILCALL => {
+                let reads = if b == 0 { regs(a, top) } else { regs(a, a + b) };
+                (reads, if c == 0 { vec![a] } else { regs(a, a + c - 1) })
+            }
+            VMOpcode::RETURN => (if b == 0 { regs(a, top) } else { regs(a, a + b - 1) }, Vec::new()),
+            VMOpcode::VARARG => (Vec::new(), if b == 0 { vec![a] } else { regs(a, a + b - 1) }),
+            VMOpcode::SETLIST => (if b == 0 { regs(a, top) } else { regs(a, a + b + 1) }, Vec::new()),
+            VMOpcode::FORPREP => (vec![a, a + 2], vec![a]),
+            VMOpcode::FORLOOP => (vec![a, a + 1, a + 2], vec![a, a + 3]),
+            VMOpcode::TFORLOOP => (vec![a, a + 1, a + 2], regs(a + 3, a + 3 + c)),
+            _ => (Vec::new(), Vec::new()),
+        }
+    }
+    /// Registers live on entry to each block, by the usual backward fixed
+    /// point. The write of TESTSET is conditional and does not kill.
+    fn liveness(&self) -> Vec<Vec<bool>> {
+        let n = self.cfg.blocks.len();
+        let mut uses = vec![vec![false; 256]; n];
+        let mut defs = vec![vec![false; 256]; n];
+        for (b, block) in self.cfg.blocks.iter().enumerate() {
+            for pc in block.start..block.end {
+                let (reads, writes) = self.effects(pc);
+                for r in reads.into_iter().filter(|r| *r < 256) {
+                    if !defs[b][r] {
+                        uses[b][r] = true;
+                    }
+                }
+                if self.slots[pc] == Slot::Inst && op_of(self.code[pc]) == VMOpcode::TESTSET {
+                    continue;
+                }
+                for r in writes.into_iter().filter(|r| *r < 256) {
+                    defs[b][r] = true;
+                }
+            }
+        }
+        let mut live = uses;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..n).rev() {
+                for e in &self.cfg.blocks[b].succs {
+                    for r in 0..256 {
+                        if live[e.target][r] && !defs[b][r] && !live[b][r] {
+                            live[b][r] = true;
+                            changed = true;
                         }
                     }
-                    if local {
-                        builder.push_str("local ");
-                        builder.push_str(&self.get_lv_name());
-                        builder.push_str(" = ");
-                        builder.push_str(&self.iter.get_const(const_loc).non_gc_asvalue().as_string(true))
-                    } else {
-                        builder.push_str(" = ");
-                        builder.push_str(&self.iter.get_const(const_loc).non_gc_asvalue().as_string(true))
+                }
+            }
+        }
+        live
+    }
+
+    /// Tries `f`, which works on a copy of the state, keeping the names it
+    /// hands out only if it succeeds. A value that has to be a local is one
+    /// whatever shape `f` was looking for.
+    fn speculate<T>(&mut self, f: impl FnOnce(&mut Self) -> Res<T>) -> Res<Option<T>> {
+        let (lv_idx, taken, first) = (self.lv_idx, self.taken.clone(), self.first);
+        match f(self) {
+            Ok(v) => Ok(Some(v)),
+            Err(Fail::Force(pc)) => Err(Fail::Force(pc)),
+            Err(Fail::Error(_)) => {
+                self.lv_idx = lv_idx;
+                self.taken = taken;
+                self.first = first;
+                Ok(None)
+            }
+        }
+    }
+
+    // Regions
+
+    /// Decompiles the blocks from `start` on until `stop`, a return, or a
+    /// `break` out of the innermost loop.
+    fn region(&mut self, s: &mut State, start: usize, stop: Option<usize>, out: &mut Vec<Stat>) -> Res<()> {
+        self.stops.extend(stop);
+        s.depth += 1;
+        let result = self.walk(s, start, stop, out);
+        s.depth -= 1;
+        let depth = s.depth;
+        s.scopes.retain(|scope| scope.depth <= depth);
+        if stop.is_some() {
+            self.stops.pop();
+        }
+        result
+    }
+    fn walk(&mut self, s: &mut State, start: usize, stop: Option<usize>, out: &mut Vec<Stat>) -> Res<()> {
+        let mut cur = start;
+        let mut seen = HashSet::new();
+        loop {
+            // a loop body starts and ends at its header
+            let entering = seen.is_empty() && self.loops.last().is_some_and(|l| l.header == cur && !l.entered);
+            if entering {
+                self.loops.last_mut().unwrap().entered = true;
+            }
+            if Some(cur) == stop && !entering {
+                return Ok(());
+            }
+            if self.loops.last().is_some_and(|l| l.exit == Some(cur) || l.exit_to == Some(cur)) {
+                return self.emit(s, out, StatKind::Break);
+            }
+            let outer = self.stops.contains(&cur) || self.loops.iter().any(|l| l.header == cur || l.exit == Some(cur));
+            if (outer && !entering) || !seen.insert(cur) {
+                return Err(anyhow!("unstructured jump to pc {}", self.start(cur) + 1).into());
+            }
+            let latches = self.new_latches(cur);
+            let next = if latches.is_empty() {
+                self.block(s, cur, stop, out)?
+            } else {
+                self.emit_loop(s, cur, latches, out)?
+            };
+            match next {
+                Some(next) => cur = next,
+                None => return Ok(()),
+            }
+        }
+    }
+    /// Decompiles `start` to `stop` as a block of its own, whose locals go
+    /// out of scope at the end.
+    fn scoped(&mut self, s: &mut State, start: usize, stop: Option<usize>, prefix: Vec<Stat>) -> Res<Block> {
+        let n = s.locals.len();
+        let mut out = prefix;
+        self.region(s, start, stop, &mut out)?;
+        self.flush(s, &mut out)?;
+        s.locals.truncate(n);
+        Ok(block(out))
+    }
+    fn branch(&mut self, s: &mut State, start: usize, merge: usize, prefix: Vec<Stat>) -> Res<Block> {
+        if start == merge {
+            return Ok(block(prefix));
+        }
+        self.scoped(s, start, Some(merge), prefix)
+    }
+
+    /// Runs one block, returning where control continues.
+    fn block(&mut self, s: &mut State, b: usize, stop: Option<usize>, out: &mut Vec<Stat>) -> Res<Option<usize>> {
+        let term = self.terminator(b);
+        let end = match term {
+            Term::Cond(pc) | Term::Jump(pc) | Term::ForPrep(pc) => pc,
+            _ => self.cfg.blocks[b].end,
+        };
+        for pc in self.insts(self.start(b), end) {
+            self.step(s, pc, out)?;
+        }
+        match term {
+            Term::None => Ok(self.succ(b, EdgeKind::Always)),
+            Term::Return => Ok(None),
+            Term::Jump(pc) => {
+                let target = self.jump_target(pc);
+                if target < self.code.len()
+                    && self.slots[target] == Slot::Inst
+                    && op_of(self.code[target]) == VMOpcode::TFORLOOP
+                {
+                    return self.generic_for(s, pc, target, out);
+                }
+                Ok(self.succ(b, EdgeKind::Always))
+            }
+            Term::Cond(pc) => {
+                self.enter(s, pc, true, out)?;
+                self.conditional(s, b, pc, stop, out)
+            }
+            Term::ForPrep(pc) => self.numeric_for(s, pc, out),
+            Term::ForLoop | Term::TForLoop => {
+                Err(anyhow!("loop end at pc {} outside its loop", self.cfg.blocks[b].end).into())
+            }
+        }
+    }
+
+    // Loops
+
+    /// Back edges into `h` no loop being decompiled accounts for.
+    fn new_latches(&self, h: usize) -> Vec<usize> {
+        let claimed = |p: &usize| self.loops.iter().any(|l| l.latches.contains(p));
+        self.cfg.blocks[h].preds.iter().copied().filter(|p| self.cfg.is_back_edge(*p, h) && !claimed(p)).collect()
+    }
+    fn push_loop(&mut self, header: usize, latches: Vec<usize>, exit: Option<usize>, repeat: bool) {
+        let exit_to = exit.and_then(|e| self.jump_only(e));
+        self.loops.push(LoopCtx { header, latches, exit, exit_to, repeat, entered: false, until: None });
+    }
+
+    /// A `while` or `repeat` loop at `header`; a loop ending in a conditional
+    /// jump back is a `repeat`.
+    fn emit_loop(&mut self, s: &mut State, header: usize, latches: Vec<usize>, out: &mut Vec<Stat>) -> Res<Option<usize>> {
+        self.enter(s, self.start(header), true, out)?;
+        self.flush(s, out)?;
+        let at = self.first.take().unwrap_or(self.start(header));
+        // the jump back ending the loop, even when nothing reaches it
+        let back = self.cfg.blocks[header].preds.iter().copied().filter(|p| self.start(*p) >= self.start(header));
+        let mut last = back.chain(latches.iter().copied()).max_by_key(|b| self.start(*b)).unwrap();
+        // a `repeat` whose body has captured locals closes them on both ways out
+        if let [p] = self.cfg.blocks[last].preds[..] {
+            if self.jump_only(last) == Some(header) && matches!(self.terminator(p), Term::Cond(_)) {
+                last = p;
+            }
+        }
+        let exit = self.cfg.block_of(self.cfg.blocks[last].end);
+        let repeat = matches!(self.terminator(last), Term::Cond(_));
+        self.push_loop(header, latches, exit, repeat);
+        let kind = if repeat { self.repeat_loop(s, header) } else { self.while_loop(s, header, exit) };
+        self.loops.pop();
+        out.push(self.stat_at(at, kind?));
+        Ok(exit)
+    }
+    fn repeat_loop(&mut self, s: &mut State, header: usize) -> Res<StatKind> {
+        let n = s.locals.len();
+        let mut body = Vec::new();
+        self.region(s, header, None, &mut body)?;
+        let pc = self.start(header) + 1;
+        let cond = self.loops.last_mut().unwrap().until.take();
+        let cond = cond.ok_or_else(|| anyhow!("loop at pc {} has no condition", pc))?;
+        s.locals.truncate(n);
+        Ok(StatKind::Repeat { body: block(body), cond })
+    }
+    fn while_loop(&mut self, s: &mut State, header: usize, exit: Option<usize>) -> Res<StatKind> {
+        let mut spec = s.clone();
+        if let Some((cond, start)) = self.speculate(|this| this.while_cond(&mut spec, header, exit))?.flatten() {
+            *s = spec;
+            let body = self.scoped(s, start, Some(header), Vec::new())?;
+            return Ok(StatKind::While { cond, body });
+        }
+        let body = self.scoped(s, header, Some(header), Vec::new())?;
+        Ok(StatKind::While { cond: ex(ExprKind::True), body })
+    }
+    /// The condition of a loop whose header is nothing but a test that enters
+    /// the body or leaves, with where the body starts.
+    fn while_cond(&mut self, s: &mut State, header: usize, exit: Option<usize>) -> Res<Option<(Expr, usize)>> {
+        let Term::Cond(pc) = self.terminator(header) else {
+            return Ok(None);
+        };
+        let mut prefix = Vec::new();
+        for p in self.insts(self.start(header), pc) {
+            self.step(s, p, &mut prefix)?;
+        }
+        let (e, t, f) = self.merge_cond(s, header, pc)?;
+        if !prefix.is_empty() || s.pending[s.locals.len()..].iter().any(Option::is_some) {
+            return Ok(None);
+        }
+        let Some(lp) = self.cfg.loops.iter().find(|l| l.header == header) else {
+            return Ok(None);
+        };
+        let inside = |b: usize| b != header && lp.body.contains(&b);
+        let leaves = |b: usize| exit == Some(b) || exit.and_then(|x| self.jump_only(x)) == Some(b);
+        Ok(if inside(f) && leaves(t) {
+            Some((negate(e), f))
+        } else if inside(t) && leaves(f) {
+            Some((e, t))
+        } else {
+            None
+        })
+    }
+
+    /// Makes the registers of a loop at `pc` from `a` on free; locals there
+    /// have gone out of scope, which only a stripped chunk leaves unsaid.
+    /// A register below `a` that is not a local was one whose value got
+    /// folded into where it was used.
+    fn reuse(s: &mut State, a: usize, pc: usize) -> Res<()> {
+        if s.locals.len() < a {
+            return Err(match (s.locals.len()..a).find_map(|r| s.defs[r]) {
+                Some(def) => Fail::Force(def),
+                None => anyhow!("pc {}: loop registers are not above the locals", pc + 1).into(),
+            });
+        }
+        s.locals.truncate(a);
+        Ok(())
+    }
+    fn numeric_for(&mut self, s: &mut State, pc: usize, out: &mut Vec<Stat>) -> Res<Option<usize>> {
+        let a = arg_a(self.code[pc]);
+        let start = self.take_expr(s, a)?;
+        let limit = self.take_expr(s, a + 1)?;
+        let step = self.take_expr(s, a + 2)?;
+        self.check_takes(s)?;
+        self.flush(s, out)?;
+        let at = self.first.take().unwrap_or(pc);
+        Self::reuse(s, a as usize, pc)?;
+        let forloop = self.jump_target(pc);
+        let (Some(body), Some(latch)) = (self.cfg.block_of(pc + 1), self.cfg.block_of(forloop)) else {
+            return Err(anyhow!("pc {}: loop without a body", pc + 1).into());
+        };
+        let exit = self.cfg.block_of(forloop + 1);
+        s.locals.extend(["(for index)", "(for limit)", "(for step)"].map(String::from));
+        let var = self.local_name(a as usize + 3, pc + 1);
+        s.locals.push(var.clone());
+        let body = self.loop_body(s, body, latch, exit)?;
+        s.locals.truncate(a as usize);
+        let step = match step.kind {
+            ExprKind::Number(1.0) => None,
+            _ => Some(step),
+        };
+        out.push(self.stat_at(at, StatKind::NumericFor { var: name(&var), start, limit, step, body }));
+        Ok(exit)
+    }
+    /// A generic `for`, from the JMP at `pc` to its TFORLOOP at `tpc`.
+    fn generic_for(&mut self, s: &mut State, pc: usize, tpc: usize, out: &mut Vec<Stat>) -> Res<Option<usize>> {
+        let i = self.code[tpc];
+        let (a, c) = (arg_a(i), arg_c(i));
+        let spread = matches!(&s.pending[a as usize], Some(Pending { value: Value::Spread(_, n), .. }) if *n >= 3);
+        let exprs = if spread {
+            let Value::Spread(e, _) = self.take(s, a)? else { unreachable!() };
+            self.take(s, a + 1)?;
+            self.take(s, a + 2)?;
+            vec![e]
+        } else {
+            let mut exprs = Vec::new();
+            for r in a..a + 3 {
+                exprs.push(self.take_expr(s, r)?);
+            }
+            while exprs.len() > 1 && matches!(exprs.last().unwrap().kind, ExprKind::Nil) {
+                exprs.pop();
+            }
+            let last = exprs.pop().unwrap();
+            exprs.push(single(last));
+            exprs
+        };
+        self.check_takes(s)?;
+        self.flush(s, out)?;
+        let at = self.first.take().unwrap_or(pc);
+        Self::reuse(s, a as usize, tpc)?;
+        let (Some(body), Some(latch)) = (self.cfg.block_of(pc + 1), self.cfg.block_of(tpc)) else {
+            return Err(anyhow!("pc {}: loop without a body", pc + 1).into());
+        };
+        let exit = self.cfg.block_of(tpc + 2);
+        s.locals.extend(["(for generator)", "(for state)", "(for control)"].map(String::from));
+        let mut names = Vec::new();
+        for r in a + 3..a + 3 + c {
+            let local = self.local_name(r as usize, pc + 1);
+            names.push(name(&local));
+            s.locals.push(local);
+        }
+        let body = self.loop_body(s, body, latch, exit)?;
+        s.locals.truncate(a as usize);
+        out.push(self.stat_at(at, StatKind::GenericFor { names, exprs, body }));
+        Ok(exit)
+    }
+    fn loop_body(&mut self, s: &mut State, body: usize, latch: usize, exit: Option<usize>) -> Res<Block> {
+        self.push_loop(body, vec![latch], exit, false);
+        let result = if body == latch { Ok(block(Vec::new())) } else { self.scoped(s, body, Some(latch), Vec::new()) };
+        self.loops.pop();
+        result
+    }
+
+    // Conditionals
+
+    /// Whether `from` leads to `to` without leaving the loops being decompiled.
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut stack = vec![from];
+        let mut seen = HashSet::new();
+        while let Some(n) = stack.pop() {
+            if n == to {
+                return true;
+            }
+            if !seen.insert(n) || self.loops.iter().any(|l| l.header == n || l.exit == Some(n)) {
+                continue;
+            }
+            stack.extend(self.cfg.blocks[n].succs.iter().map(|e| e.target));
+        }
+        false
+    }
+    /// Whether the region at `b` can end at `m` without leaving the one it is
+    /// part of, which ends at `stop`.
+    fn usable(&self, m: usize, b: usize, stop: Option<usize>) -> bool {
+        // a `break`, even one that post-dominates the test because the loop
+        // has no other way out, is not where the branches meet
+        let exit = |x: Option<usize>| self.loops.last().is_some_and(|l| x.is_some() && (l.exit == x || l.exit_to == x));
+        if exit(Some(m)) || exit(self.jump_only(m)) {
+            return false;
+        }
+        if Some(m) == stop {
+            return true;
+        }
+        if self.stops.contains(&m) || self.loops.iter().any(|l| l.header == m || l.exit == Some(m)) {
+            return false;
+        }
+        match stop {
+            Some(stop) if self.start(stop) > self.start(b) => {
+                self.start(b) < self.start(m) && self.start(m) < self.start(stop)
+            }
+            _ => self.start(m) > self.start(b),
+        }
+    }
+
+    /// A block ending in a test: the `until` of a `repeat`, a value built
+    /// with `and`, `or` or a comparison, or an `if` statement.
+    fn conditional(
+        &mut self,
+        s: &mut State,
+        b: usize,
+        pc: usize,
+        stop: Option<usize>,
+        out: &mut Vec<Stat>,
+    ) -> Res<Option<usize>> {
+        if let Some(ctx) = self.loops.last().filter(|l| l.repeat) {
+            let (header, exit) = (ctx.header, ctx.exit);
+            let mut spec = s.clone();
+            if let Some((e, t, f)) = self.speculate(|this| this.merge_cond(&mut spec, b, pc))? {
+                let back = |x: usize| x == header || self.jump_only(x) == Some(header);
+                let until = if back(t) && exit == Some(f) {
+                    Some(negate(e))
+                } else if back(f) && exit == Some(t) {
+                    Some(e)
+                } else {
+                    None
+                };
+                if until.is_some() {
+                    *s = spec;
+                    self.flush(s, out)?;
+                    self.loops.last_mut().unwrap().until = until;
+                    return Ok(None);
+                }
+            }
+        }
+
+        let merge = self.cfg.ipdom[b].filter(|m| self.usable(*m, b, stop));
+        if let Some(m) = merge {
+            if let Some(r) = self.value_register(b, pc, m) {
+                // a value still in `r` that nothing used has to be a local first
+                let tested = self.effects(pc).0.contains(&r);
+                if let Some(p) = s.pending[r].as_ref().filter(|_| r >= s.locals.len() && !tested) {
+                    return Err(Fail::Force(p.pc));
+                }
+                let mut spec = s.clone();
+                if let Some(e) = self.speculate(|this| this.value(&mut spec, b, pc, m, r))? {
+                    spec.target = None;
+                    *s = spec;
+                    self.set(s, r as u32, Value::Expr(e), pc, out)?;
+                    return Ok(Some(m));
+                }
+                self.declare_up_to(s, r, out)?;
+            }
+        }
+
+        let i = self.code[pc];
+        let mut absorbed = vec![b];
+        let (e, t, f, prefix) = if op_of(i) == VMOpcode::TESTSET {
+            // the jump also copies the tested value
+            let a = arg_a(i) as usize;
+            self.declare_up_to(s, a, out)?;
+            let e = self.test_expr(s, pc)?;
+            let v = self.take_expr(s, arg_b(i))?;
+            self.check_takes(s)?;
+            let target = ex(ExprKind::Name(s.locals[a].clone()));
+            let (t, f) = self.targets(b)?;
+            (e, t, f, vec![self.stat(StatKind::Assign { targets: vec![target], exprs: vec![v] })])
+        } else {
+            let e = self.test_expr(s, pc)?;
+            let (t, f) = self.targets(b)?;
+            self.merge_tests(s, &mut absorbed, &[], e, t, f).map(|(e, t, f)| (e, t, f, Vec::new()))?
+        };
+        // a merge point folded into the condition is not where the branches meet
+        let merge = merge.filter(|m| !absorbed.contains(m));
+        self.flush(s, out)?;
+        let (then_t, else_t, cond, then_prefix, else_prefix) = if self.start(f) < self.start(t) {
+            (f, t, negate(e), Vec::new(), prefix)
+        } else {
+            (t, f, e, prefix, Vec::new())
+        };
+        // with no merge point in the region, the then branch either ends it,
+        // or leaves it and the else branch is what follows
+        let merge = merge.or(stop.filter(|stop| [t, f].contains(stop))).unwrap_or_else(|| match stop {
+            Some(stop) if !self.reaches(then_t, else_t) && self.reaches(then_t, stop) => stop,
+            _ => else_t,
+        });
+        let at = self.first.take().unwrap_or(pc);
+        let then_block = self.branch(s, then_t, merge, then_prefix)?;
+        let mut else_block = if else_t != merge || !else_prefix.is_empty() {
+            Some(self.branch(s, else_t, merge, else_prefix)?)
+        } else {
+            None
+        };
+        let mut clauses = vec![(cond, then_block)];
+        if let Some(Block { stats, .. }) = &else_block {
+            if let [Stat { kind: StatKind::If { .. }, .. }] = &stats[..] {
+                let StatKind::If { clauses: more, else_block: rest } = else_block.take().unwrap().stats.remove(0).kind
+                else {
+                    unreachable!()
+                };
+                clauses.extend(more);
+                else_block = rest;
+            }
+        }
+        if clauses.len() == 1 && clauses[0].1.stats.is_empty() {
+            if let Some(other) = else_block.take() {
+                let (cond, _) = clauses.pop().unwrap();
+                clauses.push((negate(cond), other));
+            }
+        }
+        out.push(self.stat_at(at, StatKind::If { clauses, else_block }));
+        Ok(Some(merge))
+    }
+
+    /// The condition of the test at `pc` ending `b`, extended with `and` and
+    /// `or` over the pure tests it jumps to, and where it goes when the
+    /// result holds or not.
+    fn merge_cond(&mut self, s: &mut State, b: usize, pc: usize) -> Res<(Expr, usize, usize)> {
+        let e = self.test_expr(s, pc)?;
+        let (t, f) = self.targets(b)?;
+        self.merge_tests(s, &mut vec![b], &[], e, t, f)
+    }
+    /// Folds the tests `t` or `f` lead to into `e`, when only the blocks in
+    /// `absorbed` reach them. Each one is folded with those it leads to first,
+    /// short of `keep`, so that `a or b and c` becomes one test.
+    #[allow(clippy::too_many_arguments)]
+    fn merge_tests(
+        &mut self,
+        s: &mut State,
+        absorbed: &mut Vec<usize>,
+        keep: &[usize],
+        mut e: Expr,
+        mut t: usize,
+        mut f: usize,
+    ) -> Res<(Expr, usize, usize)> {
+        'outer: loop {
+            for cand in [f, t] {
+                let preds = &self.cfg.blocks[cand].preds;
+                if absorbed.contains(&cand) || keep.contains(&cand) || !preds.iter().all(|p| absorbed.contains(p)) {
+                    continue;
+                }
+                let Term::Cond(cpc) = self.terminator(cand) else {
+                    continue;
+                };
+                // a TEST of a register still needed tests a value
+                let ci = self.code[cpc];
+                let (ct, cf) = self.targets(cand)?;
+                match op_of(ci) {
+                    VMOpcode::TESTSET => continue,
+                    VMOpcode::TEST if self.live[ct][arg_a(ci) as usize] || self.live[cf][arg_a(ci) as usize] => continue,
+                    _ => {}
+                }
+                let mut spec = s.clone();
+                let mut inner = absorbed.clone();
+                inner.push(cand);
+                let mut inner_keep = keep.to_vec();
+                inner_keep.push(if cand == f { t } else { f });
+                let Some((es, ts, fs)) = self.speculate(|this| {
+                    let mut stats = Vec::new();
+                    for p in this.insts(this.start(cand), cpc) {
+                        this.step(&mut spec, p, &mut stats)?;
+                    }
+                    if !stats.is_empty() {
+                        return Err(anyhow!("statements in a condition").into());
                     }
-                    out.push_str(&format!("{}\n", builder));
+                    let es = this.test_expr(&mut spec, cpc)?;
+                    let (ts, fs) = this.targets(cand)?;
+                    this.merge_tests(&mut spec, &mut inner, &inner_keep, es, ts, fs)
+                })?
+                else {
+                    continue;
+                };
+                let merged = if cand == f && ts == t {
+                    Some((chain(BinOp::Or, e.clone(), es), t, fs))
+                } else if cand == f && fs == t {
+                    Some((chain(BinOp::Or, e.clone(), negate(es)), t, ts))
+                } else if cand == t && fs == f {
+                    Some((chain(BinOp::And, e.clone(), es), ts, f))
+                } else if cand == t && ts == f {
+                    Some((chain(BinOp::And, e.clone(), negate(es)), fs, f))
+                } else {
+                    None
+                };
+                if let Some(merged) = merged {
+                    *s = spec;
+                    *absorbed = inner;
+                    (e, t, f) = merged;
+                    continue 'outer;
                 }
-                VMOpcode::ADD => {
+            }
+            return Ok((e, t, f));
+        }
+    }
 
+    /// The one register the region from the test at `pc` ending `b` to `m`
+    /// computes a value into, if it is a value region at all: no loops, no
+    /// returns.
+    fn value_register(&self, b: usize, pc: usize, m: usize) -> Option<usize> {
+        let mut region = Vec::new();
+        let mut stack = vec![b];
+        let mut seen = HashSet::new();
+        while let Some(n) = stack.pop() {
+            if n == m || !seen.insert(n) {
+                continue;
+            }
+            let block = &self.cfg.blocks[n];
+            if block.succs.is_empty() || self.stops.contains(&n) {
+                return None;
+            }
+            for e in &block.succs {
+                if self.cfg.is_back_edge(n, e.target) {
+                    return None;
+                }
+                stack.push(e.target);
+            }
+            region.push(n);
+        }
+        let mut written = Vec::new();
+        for n in region {
+            let block = &self.cfg.blocks[n];
+            let from = if n == b { pc } else { block.start };
+            for pc in from..block.end {
+                for r in self.effects(pc).1 {
+                    if r < 256 && self.live[m][r] && !written.contains(&r) {
+                        written.push(r);
+                    }
                 }
-                _ => ()
             }
         }
-        out
+        match written[..] {
+            [r] => Some(r),
+            _ => None,
+        }
     }
-}
\ No newline at end of file
+    /// The value the region from the test at `pc` ending `b` leaves in `r`
+    /// when it reaches `m`. The region is the code of one expression: its
+    /// operands in order, each but the last ending in a test, and the
+    /// LOADBOOL pair comparisons jump to.
+    fn value(&mut self, s: &mut State, b: usize, pc: usize, m: usize, r: usize) -> Res<Expr> {
+        let fail = |at: usize| anyhow!("pc {}: no value for register {}", at + 1, r);
+        let mut region = Vec::new();
+        let mut stack = vec![b];
+        while let Some(n) = stack.pop() {
+            if n != m && !region.contains(&n) {
+                region.push(n);
+                stack.extend(self.cfg.blocks[n].succs.iter().map(|e| e.target));
+            }
+        }
+        region.sort_by_key(|n| self.start(*n));
+        let blocks: Vec<usize> = region.into_iter().filter(|n| self.label(*n, m, r, &[]).is_none()).collect();
+
+        s.target = Some(r);
+        let mut leaves = Vec::new();
+        for (k, &n) in blocks.iter().enumerate() {
+            let term = self.terminator(n);
+            let end = match term {
+                Term::Cond(p) | Term::Jump(p) => p,
+                Term::None => self.cfg.blocks[n].end,
+                _ => return Err(fail(self.start(n)).into()),
+            };
+            let from = if n == b { pc } else { self.start(n) };
+            let mut stats = Vec::new();
+            for p in self.insts(from, end) {
+                self.step(s, p, &mut stats)?;
+            }
+            if !stats.is_empty() {
+                return Err(fail(self.start(n)).into());
+            }
+            let leaf = match term {
+                Term::Cond(p) => {
+                    let i = self.code[p];
+                    let (a, c) = (arg_a(i), arg_c(i) != 0);
+                    let (expr, value, on) = match op_of(i) {
+                        VMOpcode::TEST => (self.take_expr(s, a)?, a as usize == r, c),
+                        VMOpcode::TESTSET if a as usize == r => (self.take_expr(s, arg_b(i))?, true, c),
+                        _ => (self.test_expr(s, p)?, false, true),
+                    };
+                    self.check_takes(s)?;
+                    let (t, f) = self.targets(n)?;
+                    let (jump, fall) = (self.label(t, m, r, &blocks), self.label(f, m, r, &blocks));
+                    let (Some(jump), Some(fall)) = (jump, fall) else {
+                        return Err(fail(p).into());
+                    };
+                    // only a value the test leaves in `r` can be the result
+                    if (jump == Label::End && !value) || fall == Label::End {
+                        return Err(fail(p).into());
+                    }
+                    Operand { expr, value: matches!(op_of(i), VMOpcode::TEST | VMOpcode::TESTSET), on, jump: Some(jump), fall }
+                }
+                _ if k + 1 == blocks.len()
+                    && self.label(self.succ(n, EdgeKind::Always).unwrap_or(m), m, r, &blocks) == Some(Label::End) =>
+                {
+                    let expr = self.take_expr(s, r as u32)?;
+                    self.taken.clear();
+                    Operand { expr, value: true, on: true, jump: None, fall: Label::End }
+                }
+                _ => return Err(fail(self.start(n)).into()),
+            };
+            if s.pending[r..].iter().any(Option::is_some) {
+                return Err(fail(self.start(n)).into());
+            }
+            leaves.push(leaf);
+        }
+        let tree = match leaves.len() {
+            0 => None,
+            n => Self::operands(&leaves, 0, n - 1, Exit::Top, Exit::Top),
+        };
+        let tree = tree.ok_or_else(|| fail(pc))?;
+        Ok(Self::expression(&mut leaves, tree))
+    }
+    /// Where going to block `n` leads in the value region ending at `m`, with
+    /// the blocks of its operands in `operands`.
+    fn label(&self, n: usize, m: usize, r: usize, operands: &[usize]) -> Option<Label> {
+        let loadbool = |value: u32| match self.insts(self.start(n), self.cfg.blocks[n].end)[..] {
+            [p] => {
+                let i = self.code[p];
+                op_of(i) == VMOpcode::LOADBOOL
+                    && arg_a(i) as usize == r
+                    && arg_b(i) == value
+                    && (arg_c(i) != 0) == (value == 0)
+            }
+            _ => false,
+        };
+        if n == m || self.jump_only(n) == Some(m) {
+            Some(Label::End)
+        } else if loadbool(1) {
+            Some(Label::True)
+        } else if loadbool(0) {
+            Some(Label::False)
+        } else {
+            operands.iter().position(|o| *o == n).map(Label::Operand)
+        }
+    }
+    /// How operands `from..=to` combine into an expression that goes to `t`
+    /// when it holds and to `f` when not.
+    fn operands(leaves: &[Operand], from: usize, to: usize, t: Exit, f: Exit) -> Option<Tree> {
+        let leaf = &leaves[to];
+        if from == to {
+            let Some(jump) = leaf.jump else {
+                return (to + 1 == leaves.len()).then_some(Tree::Operand(to, None));
+            };
+            let holds = if leaf.reaches(jump, t, true) {
+                true
+            } else if leaf.reaches(jump, f, false) {
+                false
+            } else {
+                return None;
+            };
+            let other = if holds { f } else { t };
+            return leaf.reaches(leaf.fall, other, !holds).then_some(Tree::Operand(to, Some(holds)));
+        }
+        for k in (from..to).rev() {
+            let Some(jump) = leaves[k].jump else {
+                continue;
+            };
+            let next = Exit::Operand(k + 1);
+            let (op, lt, lf) = if leaves[k].reaches(jump, t, true) {
+                (BinOp::Or, t, next)
+            } else if leaves[k].reaches(jump, f, false) {
+                (BinOp::And, next, f)
+            } else {
+                continue;
+            };
+            if let (Some(lhs), Some(rhs)) =
+                (Self::operands(leaves, from, k, lt, lf), Self::operands(leaves, k + 1, to, t, f))
+            {
+                return Some(Tree::Binary(op, Box::new(lhs), Box::new(rhs)));
+            }
+        }
+        None
+    }
+    fn expression(leaves: &mut [Operand], tree: Tree) -> Expr {
+        match tree {
+            Tree::Operand(k, holds) => {
+                let leaf = &mut leaves[k];
+                let expr = std::mem::replace(&mut leaf.expr, ex(ExprKind::Nil));
+                match holds {
+                    Some(holds) if holds != leaf.on => negate(expr),
+                    // a value that only loads a boolean
+                    Some(_) if leaf.value && matches!(leaf.jump, Some(Label::True | Label::False)) && !is_boolean(&expr) => {
+                        not(not(expr))
+                    }
+                    _ => expr,
+                }
+            }
+            Tree::Binary(op, lhs, rhs) => {
+                let lhs = Self::expression(leaves, *lhs);
+                let rhs = Self::expression(leaves, *rhs);
+                chain(op, lhs, rhs)
+            }
+        }
+    }
+}
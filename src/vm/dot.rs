@@ -0,0 +1,119 @@
+//! Graphviz renderings of control-flow graphs: each prototype is a cluster of
+//! its basic blocks, listing their instructions, with nested closures as
+//! clusters of their own linked from the CLOSURE that creates them.
+
+use std::fmt::Write;
+
+use super::{
+    cfg::{slots, Cfg, EdgeKind, Slot},
+    chunk_parser::FunctionBlock,
+    instruction::{arg_bx, VMOpcode},
+    listing::instruction,
+};
+
+/// Renders `func` and the functions nested in it as a DOT digraph, for
+/// `dot -Tsvg`.
+pub fn to_dot(func: &FunctionBlock) -> String {
+    let mut out = String::new();
+    out.push_str("digraph lua {\n");
+    out.push_str("\tcompound=true;\n");
+    out.push_str("\tnode [shape=box, fontname=\"monospace\", fontsize=10];\n");
+    out.push_str("\tedge [fontname=\"monospace\", fontsize=9];\n");
+    write_function(&mut out, func, "f0");
+    out.push_str("}\n");
+    out
+}
+
+/// Escapes `s` for a double-quoted DOT string.
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push(' '),
+            '\n' => out.push_str("\\l"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn write_function(out: &mut String, f: &FunctionBlock, id: &str) {
+    let cfg = Cfg::new(f);
+    let code: Vec<u32> = f.list_instructions.iter().map(|i| i.to_u32()).collect();
+    let upvals: Vec<u32> = f.list_fnproto.iter().map(|p| p.num_upval as u32).collect();
+    let slots = slots(&code, &upvals);
+    let source = f.source_name.trim_start_matches(['@', '=']);
+    let name = if f.line_def == 0 { "main" } else { "function" };
+
+    writeln!(out, "\tsubgraph cluster_{} {{", id).unwrap();
+    writeln!(out, "\t\tlabel=\"{}\";", escape(&format!("{} <{}:{},{}>", name, source, f.line_def, f.last_line_def)))
+        .unwrap();
+    let headers: Vec<usize> = cfg.loops.iter().map(|l| l.header).collect();
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        let mut label = String::new();
+        for pc in (block.start..block.end).filter(|pc| slots[*pc] != Slot::Data) {
+            let line = match f.line_info.get(pc) {
+                Some(line) if *line > 0 => line.to_string(),
+                _ => "-".to_string(),
+            };
+            writeln!(label, "{:<4} [{}] {}", pc + 1, line, instruction(f, pc)).unwrap();
+        }
+        let mut attrs = String::new();
+        if !cfg.reachable[b] {
+            attrs.push_str(", style=dashed, fontcolor=gray50, color=gray50");
+        } else if headers.contains(&b) {
+            attrs.push_str(", penwidth=2");
+        }
+        writeln!(out, "\t\t{}_b{} [label=\"{}\"{}];", id, b, escape(&label), attrs).unwrap();
+    }
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for edge in &block.succs {
+            let mut attrs = Vec::new();
+            let mut color = match edge.kind {
+                EdgeKind::Always => None,
+                EdgeKind::True => Some("darkgreen"),
+                EdgeKind::False => Some("red3"),
+            };
+            if edge.kind != EdgeKind::Always {
+                let kind = if edge.kind == EdgeKind::True { "true" } else { "false" };
+                attrs.push(format!("label=\"{}\"", kind));
+            }
+            if cfg.is_back_edge(b, edge.target) {
+                color = Some("blue");
+                attrs.push("penwidth=2, constraint=false".to_string());
+            }
+            if let Some(color) = color {
+                attrs.push(format!("color={}, fontcolor={}", color, color));
+            }
+            write!(out, "\t\t{}_b{} -> {}_b{}", id, b, id, edge.target).unwrap();
+            if !attrs.is_empty() {
+                write!(out, " [{}]", attrs.join(", ")).unwrap();
+            }
+            out.push_str(";\n");
+        }
+    }
+    out.push_str("\t}\n");
+
+    for (i, p) in f.list_fnproto.iter().enumerate() {
+        write_function(out, p, &format!("{}_{}", id, i));
+    }
+    for (pc, inst) in f.list_instructions.iter().enumerate() {
+        if inst.opcode != VMOpcode::CLOSURE || slots[pc] != Slot::Inst {
+            continue;
+        }
+        let (Some(b), bx) = (cfg.block_of(pc), arg_bx(code[pc]) as usize) else {
+            continue;
+        };
+        if f.list_fnproto.get(bx).is_none_or(|p| p.list_instructions.is_empty()) {
+            continue;
+        }
+        writeln!(
+            out,
+            "\t{}_b{} -> {}_{}_b0 [lhead=cluster_{}_{}, style=dashed, label=\"closure {}\"];",
+            id, b, id, bx, id, bx, bx
+        )
+        .unwrap();
+    }
+}
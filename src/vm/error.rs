@@ -0,0 +1,63 @@
+use std::fmt::Display;
+
+/// Errors raised by the VM that a host may want to tell apart from ordinary
+/// runtime errors. They travel inside `anyhow::Error` and can be recovered
+/// with `downcast_ref::<LuaError>()`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LuaError {
+    /// A runtime error with a Lua-style message.
+    Runtime(String),
+    /// The script called `os.exit`.
+    Exit(i32),
+    /// The host stopped the script, for the reason given.
+    Interrupted(String),
+    /// The script used up the instructions `LuaVM::set_fuel` allowed it.
+    OutOfFuel,
+    /// The script was still running at the `LuaVM::set_deadline` deadline.
+    DeadlineExceeded,
+    /// The script went past the `LuaVM::set_memory_limit` limit. Scripts can
+    /// catch it, as in Lua.
+    OutOfMemory,
+}
+impl Display for LuaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LuaError::Runtime(msg) => write!(f, "{}", msg),
+            LuaError::Exit(code) => write!(f, "script exited with code {}", code),
+            LuaError::Interrupted(why) => write!(f, "{}", why),
+            LuaError::OutOfFuel => write!(f, "instruction limit reached"),
+            LuaError::DeadlineExceeded => write!(f, "time limit reached"),
+            LuaError::OutOfMemory => write!(f, "not enough memory"),
+        }
+    }
+}
+impl std::error::Error for LuaError {}
+impl LuaError {
+    /// Whether script code (`coroutine.resume` and friends) may catch `e`.
+    /// An exit requested by the script, a stop by the host and running out
+    /// of instructions or time always reach the host; running out of memory
+    /// does not.
+    pub fn is_catchable(e: &anyhow::Error) -> bool {
+        !matches!(
+            e.downcast_ref::<LuaError>(),
+            Some(LuaError::Exit(_) | LuaError::Interrupted(_) | LuaError::OutOfFuel | LuaError::DeadlineExceeded)
+        )
+    }
+}
+/// An error that left Lua code, with where it was raised. `Display` gives
+/// the message as Lua reports it, after `source:line:`.
+#[derive(Debug)]
+pub struct RuntimeError {
+    pub message: String,
+    /// `stack traceback:` and a line per frame, innermost first, taken when
+    /// the error was raised.
+    pub traceback: String,
+    /// The error as it was raised.
+    pub cause: anyhow::Error,
+}
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+impl std::error::Error for RuntimeError {}
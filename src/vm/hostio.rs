@@ -0,0 +1,408 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    rc::Rc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{Local, Offset, TimeZone};
+use rand::RngCore;
+
+/// Everything the `os` and `io` libraries need from the outside world. The
+/// embedder decides what scripts can touch by choosing (or writing) the
+/// implementation handed to `LuaVM::open_os` / `LuaVM::open_io`.
+pub trait HostIo {
+    /// Seconds since the Unix epoch.
+    fn time(&self) -> f64;
+    /// Seconds of processor time used by the program, for `os.clock`.
+    fn clock(&self) -> f64;
+    /// Offset of local time from UTC in seconds at the given Unix time.
+    fn utc_offset(&self, time: i64) -> i64;
+    fn getenv(&self, name: &str) -> Option<String>;
+    /// Returns the name of a freshly created empty file.
+    fn tmpname(&self) -> io::Result<String>;
+    fn remove(&self, path: &str) -> io::Result<()>;
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    /// Called by `os.exit`. The script is stopped with `LuaError::Exit`
+    /// afterwards if this returns.
+    fn exit(&self, code: i32);
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn HostFile>>;
+    fn stdin(&self) -> Box<dyn HostFile>;
+    fn stdout(&self) -> Box<dyn HostFile>;
+    fn stderr(&self) -> Box<dyn HostFile>;
+}
+
+/// An open file handed out by a `HostIo`. Operations a stream does not
+/// support fail with an error by default.
+pub trait HostFile {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(EBADF))
+    }
+    fn write(&mut self, _data: &[u8]) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(EBADF))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(io::Error::from_raw_os_error(ESPIPE))
+    }
+}
+
+pub const ENOENT: i32 = 2;
+pub const EBADF: i32 = 9;
+pub const EINVAL: i32 = 22;
+pub const ESPIPE: i32 = 29;
+
+/// A parsed `fopen`-style mode string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenMode {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    pub truncate: bool,
+    pub create: bool,
+}
+impl OpenMode {
+    /// Accepts `r`, `w`, `a`, optionally followed by `+` and/or `b`.
+    pub fn parse(mode: &str) -> Option<Self> {
+        let mut chars = mode.chars();
+        let mut m = match chars.next()? {
+            'r' => Self { read: true, write: false, append: false, truncate: false, create: false },
+            'w' => Self { read: false, write: true, append: false, truncate: true, create: true },
+            'a' => Self { read: false, write: true, append: true, truncate: false, create: true },
+            _ => return None,
+        };
+        let rest: String = chars.filter(|c| *c != 'b').collect();
+        match rest.as_str() {
+            "" => (),
+            "+" => {
+                m.read = true;
+                m.write = true;
+            }
+            _ => return None,
+        }
+        Some(m)
+    }
+}
+
+/// `HostIo` backed by the real filesystem, environment and clock.
+pub struct StdHostIo {
+    started: Instant,
+}
+impl StdHostIo {
+    pub fn new() -> Self {
+        Self { started: Instant::now() }
+    }
+}
+impl Default for StdHostIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+struct StdFile(File);
+impl HostFile for StdFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+struct StdinFile;
+impl HostFile for StdinFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().read(buf)
+    }
+}
+struct StdoutFile;
+impl HostFile for StdoutFile {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        io::stdout().write_all(data)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+struct StderrFile;
+impl HostFile for StderrFile {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        io::stderr().write_all(data)
+    }
+}
+impl HostIo for StdHostIo {
+    fn time(&self) -> f64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as f64)
+            .unwrap_or(0.0)
+    }
+    /// Wall-clock time since the host was created; std has no portable
+    /// way to ask for processor time.
+    fn clock(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+    fn utc_offset(&self, time: i64) -> i64 {
+        match Local.timestamp_opt(time, 0).single() {
+            Some(t) => t.offset().fix().local_minus_utc() as i64,
+            None => 0,
+        }
+    }
+    fn getenv(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+    fn tmpname(&self) -> io::Result<String> {
+        let dir = std::env::temp_dir();
+        for _ in 0..16 {
+            let path = dir.join(format!("lua_{:08x}", rand::thread_rng().next_u32()));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(path.to_string_lossy().to_string()),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::from(io::ErrorKind::AlreadyExists))
+    }
+    fn remove(&self, path: &str) -> io::Result<()> {
+        match std::fs::metadata(path) {
+            Ok(m) if m.is_dir() => std::fs::remove_dir(path),
+            _ => std::fs::remove_file(path),
+        }
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+    fn exit(&self, code: i32) {
+        let _ = io::stdout().flush();
+        std::process::exit(code);
+    }
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn HostFile>> {
+        let file = OpenOptions::new()
+            .read(mode.read)
+            .write(mode.write && !mode.append)
+            .append(mode.append)
+            .truncate(mode.truncate)
+            .create(mode.create)
+            .open(path)?;
+        Ok(Box::new(StdFile(file)))
+    }
+    fn stdin(&self) -> Box<dyn HostFile> {
+        Box::new(StdinFile)
+    }
+    fn stdout(&self) -> Box<dyn HostFile> {
+        Box::new(StdoutFile)
+    }
+    fn stderr(&self) -> Box<dyn HostFile> {
+        Box::new(StderrFile)
+    }
+}
+
+type SharedBuf = Rc<RefCell<Vec<u8>>>;
+
+/// `HostIo` that keeps everything in memory: files, environment, clock and
+/// the standard streams. Meant for tests and for hosts that want scripts to
+/// see a fake filesystem.
+#[derive(Default)]
+pub struct MemoryHostIo {
+    files: RefCell<HashMap<String, SharedBuf>>,
+    env: RefCell<HashMap<String, String>>,
+    time: Cell<f64>,
+    clock: Cell<f64>,
+    utc_offset: Cell<i64>,
+    stdin: SharedBuf,
+    stdout: SharedBuf,
+    stderr: SharedBuf,
+    exit_code: Cell<Option<i32>>,
+    tmp_counter: Cell<u32>,
+}
+impl MemoryHostIo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn set_file(&self, path: &str, contents: impl Into<Vec<u8>>) {
+        self.files
+            .borrow_mut()
+            .insert(path.to_string(), Rc::new(RefCell::new(contents.into())));
+    }
+    pub fn file(&self, path: &str) -> Option<Vec<u8>> {
+        self.files.borrow().get(path).map(|f| f.borrow().clone())
+    }
+    pub fn set_env(&self, name: &str, value: &str) {
+        self.env.borrow_mut().insert(name.to_string(), value.to_string());
+    }
+    pub fn set_time(&self, time: f64) {
+        self.time.set(time);
+    }
+    pub fn set_clock(&self, clock: f64) {
+        self.clock.set(clock);
+    }
+    pub fn set_utc_offset(&self, offset: i64) {
+        self.utc_offset.set(offset);
+    }
+    pub fn set_stdin(&self, contents: impl Into<Vec<u8>>) {
+        *self.stdin.borrow_mut() = contents.into();
+    }
+    pub fn stdout_contents(&self) -> Vec<u8> {
+        self.stdout.borrow().clone()
+    }
+    pub fn stderr_contents(&self) -> Vec<u8> {
+        self.stderr.borrow().clone()
+    }
+    /// The code passed to `os.exit`, if the script called it.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code.get()
+    }
+}
+struct MemoryFile {
+    data: SharedBuf,
+    pos: usize,
+    readable: bool,
+    writable: bool,
+    append: bool,
+    seekable: bool,
+}
+impl HostFile for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.readable {
+            return Err(io::Error::from_raw_os_error(EBADF));
+        }
+        let data = self.data.borrow();
+        let n = buf.len().min(data.len().saturating_sub(self.pos));
+        buf[..n].copy_from_slice(&data[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if !self.writable {
+            return Err(io::Error::from_raw_os_error(EBADF));
+        }
+        let mut data = self.data.borrow_mut();
+        if self.append {
+            self.pos = data.len();
+        }
+        let end = self.pos + bytes.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if !self.seekable {
+            return Err(io::Error::from_raw_os_error(ESPIPE));
+        }
+        let len = self.data.borrow().len() as i64;
+        let new = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::Current(off) => self.pos as i64 + off,
+            SeekFrom::End(off) => len + off,
+        };
+        if new < 0 {
+            return Err(io::Error::from_raw_os_error(EINVAL));
+        }
+        self.pos = new as usize;
+        Ok(self.pos as u64)
+    }
+}
+impl HostIo for MemoryHostIo {
+    fn time(&self) -> f64 {
+        self.time.get()
+    }
+    fn clock(&self) -> f64 {
+        self.clock.get()
+    }
+    fn utc_offset(&self, _time: i64) -> i64 {
+        self.utc_offset.get()
+    }
+    fn getenv(&self, name: &str) -> Option<String> {
+        self.env.borrow().get(name).cloned()
+    }
+    fn tmpname(&self) -> io::Result<String> {
+        let n = self.tmp_counter.get() + 1;
+        self.tmp_counter.set(n);
+        let name = format!("/tmp/lua_{}", n);
+        self.set_file(&name, Vec::new());
+        Ok(name)
+    }
+    fn remove(&self, path: &str) -> io::Result<()> {
+        match self.files.borrow_mut().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::from_raw_os_error(ENOENT)),
+        }
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.borrow_mut();
+        match files.remove(from) {
+            Some(f) => {
+                files.insert(to.to_string(), f);
+                Ok(())
+            }
+            None => Err(io::Error::from_raw_os_error(ENOENT)),
+        }
+    }
+    fn exit(&self, code: i32) {
+        self.exit_code.set(Some(code));
+    }
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn HostFile>> {
+        let existing = self.files.borrow().get(path).cloned();
+        let data = match existing {
+            Some(data) => data,
+            None if mode.create => {
+                let data: SharedBuf = Default::default();
+                self.files.borrow_mut().insert(path.to_string(), data.clone());
+                data
+            }
+            None => return Err(io::Error::from_raw_os_error(ENOENT)),
+        };
+        if mode.truncate {
+            data.borrow_mut().clear();
+        }
+        Ok(Box::new(MemoryFile {
+            data,
+            pos: 0,
+            readable: mode.read,
+            writable: mode.write,
+            append: mode.append,
+            seekable: true,
+        }))
+    }
+    fn stdin(&self) -> Box<dyn HostFile> {
+        Box::new(MemoryFile {
+            data: self.stdin.clone(),
+            pos: 0,
+            readable: true,
+            writable: false,
+            append: false,
+            seekable: false,
+        })
+    }
+    fn stdout(&self) -> Box<dyn HostFile> {
+        Box::new(MemoryFile {
+            data: self.stdout.clone(),
+            pos: 0,
+            readable: false,
+            writable: true,
+            append: true,
+            seekable: false,
+        })
+    }
+    fn stderr(&self) -> Box<dyn HostFile> {
+        Box::new(MemoryFile {
+            data: self.stderr.clone(),
+            pos: 0,
+            readable: false,
+            writable: true,
+            append: true,
+            seekable: false,
+        })
+    }
+}
@@ -1,5 +1,4 @@
 use crate::def_enum;
-use bitflags::bitflags;
 use gc::{Finalize, Trace};
 def_enum! {
     VMOpcode (u32) {
@@ -87,6 +86,7 @@ impl VMOpcode {
         }
     }
 }
+#[allow(non_camel_case_types)]
 pub enum InstParamType {
     A,
     B,
@@ -94,6 +94,7 @@ pub enum InstParamType {
     Bx,
     sBx,
 }
+#[allow(non_camel_case_types)]
 #[derive(Debug, Clone, Finalize, Trace)]
 pub enum InstParam {
     A(u32),
@@ -103,12 +104,12 @@ pub enum InstParam {
     sBx(i32),
 }
 pub const MASK_CBIT: u32 = 0b00000000000000000000000100000000u32;
+#[allow(non_upper_case_globals)]
 impl InstParam {
     const MASK_B: u32 = 0b11111111100000000000000000000000u32;
     const MASK_C: u32 = 0b00000000011111111100000000000000u32;
     const MASK_Bx: u32 = 0b11111111111111111100000000000000u32;
     const MASK_A: u32 = 0b00000000000000000011111111000000u32;
-    const MASK_Op: u32 = 0b00000000000000000000000000111111u32;
     const A_SHIFT: u32 = 6;
     const B_SHIFT: u32 = 23;
     const C_SHIFT: u32 = 14;
@@ -134,13 +135,50 @@ impl InstParam {
             InstParam::sBx(_) => panic!("BAD"),
         }
     }
+    /// The param shifted into its field of an encoded instruction.
+    pub fn encode(&self) -> u32 {
+        match self {
+            InstParam::A(v) => (v << InstParam::A_SHIFT) & InstParam::MASK_A,
+            InstParam::B(v) => (v << InstParam::B_SHIFT) & InstParam::MASK_B,
+            InstParam::C(v) => (v << InstParam::C_SHIFT) & InstParam::MASK_C,
+            InstParam::Bx(v) => (v << InstParam::Bx_SHIFT) & InstParam::MASK_Bx,
+            InstParam::sBx(v) => (((v + 131071) as u32) << InstParam::Bx_SHIFT) & InstParam::MASK_Bx,
+        }
+    }
+    /// Value of an sBx param
+    pub fn get_signed_val(&self) -> i32 {
+        match self {
+            InstParam::sBx(v) => *v,
+            _ => panic!("BAD"),
+        }
+    }
 }
 #[derive(Debug, Clone, Finalize, Trace)]
 pub struct VMInst {
     pub opcode: VMOpcode,
     pub params: Vec<InstParam>,
+    /// The whole word when it is data for the instruction before it, like
+    /// the block number after a `SETLIST` with C = 0, rather than an
+    /// instruction; `opcode` and `params` mean nothing then.
+    data: Option<u32>,
 }
 impl VMInst {
+    /// A word of data, kept as is.
+    pub fn data(word: u32) -> Self {
+        Self { opcode: VMOpcode::MOVE, params: Vec::new(), data: Some(word) }
+    }
+    /// Decodes the code of a function, taking the word after a `SETLIST`
+    /// with C = 0 as data.
+    pub fn decode_all(code: &[u32]) -> anyhow::Result<Vec<Self>> {
+        let mut out: Vec<Self> = Vec::with_capacity(code.len());
+        for word in code {
+            let data = out.last().is_some_and(|prev| {
+                prev.data.is_none() && prev.opcode == VMOpcode::SETLIST && prev.params[2].get_num_val() == 0
+            });
+            out.push(if data { Self::data(*word) } else { Self::from_u32(*word)? });
+        }
+        Ok(out)
+    }
     pub fn from_u32(num: u32) -> anyhow::Result<Self> {
         //println!("Num {}", num & 0b00000000000000000011111111000000);
         let opcode = VMOpcode::from_num(num.to_le() & 0b00000000000000000000000000111111)?;
@@ -149,6 +187,59 @@ impl VMInst {
         for t in types {
             params.push(InstParam::parse(t, num));
         }
-        Ok(Self { opcode, params })
+        Ok(Self { opcode, params, data: None })
+    }
+    /// Encodes the instruction back into its 32-bit form.
+    pub fn to_u32(&self) -> u32 {
+        if let Some(word) = self.data {
+            return word;
+        }
+        self.params.iter().fold(self.opcode.to_num(), |acc, p| acc | p.encode())
     }
 }
+
+/// Largest sBx offset; sBx is stored in Bx with this bias.
+pub const MAXARG_SBX: i32 = 131071;
+// Field access on encoded instructions, for code that patches them in place.
+pub(crate) fn op_of(i: u32) -> VMOpcode {
+    VMOpcode::from_num(i & 0x3f).unwrap()
+}
+pub(crate) fn arg_a(i: u32) -> u32 {
+    (i >> 6) & 0xff
+}
+pub(crate) fn arg_b(i: u32) -> u32 {
+    (i >> 23) & 0x1ff
+}
+pub(crate) fn arg_c(i: u32) -> u32 {
+    (i >> 14) & 0x1ff
+}
+pub(crate) fn arg_bx(i: u32) -> u32 {
+    i >> 14
+}
+pub(crate) fn arg_sbx(i: u32) -> i32 {
+    (i >> 14) as i32 - MAXARG_SBX
+}
+pub(crate) fn set_op(i: &mut u32, op: VMOpcode) {
+    *i = (*i & !0x3f) | op.to_num();
+}
+pub(crate) fn set_a(i: &mut u32, v: u32) {
+    *i = (*i & !(0xff << 6)) | (v << 6);
+}
+pub(crate) fn set_b(i: &mut u32, v: u32) {
+    *i = (*i & !(0x1ff << 23)) | (v << 23);
+}
+pub(crate) fn set_c(i: &mut u32, v: u32) {
+    *i = (*i & !(0x1ff << 14)) | (v << 14);
+}
+pub(crate) fn set_bx(i: &mut u32, v: u32) {
+    *i = (*i & 0x3fff) | (v << 14);
+}
+pub(crate) fn set_sbx(i: &mut u32, v: i32) {
+    set_bx(i, (v + MAXARG_SBX) as u32);
+}
+pub(crate) fn create_abc(op: VMOpcode, a: u32, b: u32, c: u32) -> u32 {
+    op.to_num() | (a << 6) | (b << 23) | (c << 14)
+}
+pub(crate) fn create_abx(op: VMOpcode, a: u32, bx: u32) -> u32 {
+    op.to_num() | (a << 6) | (bx << 14)
+}
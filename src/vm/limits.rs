@@ -0,0 +1,158 @@
+//! Budgets for running untrusted scripts: instructions, call depth and
+//! time. Running out of instructions or time stops the script with an error
+//! scripts cannot catch. If that happens in code the host called, rather
+//! than in a coroutine or a callback from a native function, the run is
+//! kept where it stopped and can be resumed once the budget is raised.
+
+use std::time::Instant;
+
+use anyhow::bail;
+
+use super::{error::LuaError, GCLuaValue, LuaVM};
+
+/// Instructions run between looks at the clock.
+const DEADLINE_CHECK_INTERVAL: u32 = 1000;
+/// Default bound on running Lua functions, `LUAI_MAXCALLS`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 20000;
+/// Most calls nested through Rust at once, `LUAI_MAXCCALLS`. Each takes
+/// Rust stack, so unlike the Lua call depth this bound is fixed. Going this
+/// deep needs about 2.5 MB of stack in debug builds and under 512 KB in
+/// release builds, so in debug builds a VM must run on a thread with more
+/// than the 2 MB spawned threads get by default, or deep scripts abort the
+/// process instead of failing with an error.
+pub const MAX_C_CALLS: usize = 200;
+
+#[derive(Debug)]
+pub(crate) struct Limits {
+    fuel: Option<u64>,
+    max_call_depth: Option<usize>,
+    /// Calls from Rust into Lua, natives or coroutines now running.
+    c_calls: usize,
+    deadline: Option<Instant>,
+    /// Instructions left before the clock is next read.
+    deadline_countdown: u32,
+    /// Frames of the threads waiting on the running coroutine.
+    pub(crate) frames_below: usize,
+    /// The depth the stopped run was entered at, while it can be resumed.
+    pub(crate) suspended: Option<usize>,
+}
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            fuel: None,
+            max_call_depth: Some(DEFAULT_MAX_CALL_DEPTH),
+            c_calls: 0,
+            deadline: None,
+            deadline_countdown: 0,
+            frames_below: 0,
+            suspended: None,
+        }
+    }
+}
+
+impl LuaVM {
+    /// Lets scripts run `fuel` more instructions in all, or any number with
+    /// `None`. Past that they stop with `LuaError::OutOfFuel`.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.limits.fuel = fuel;
+    }
+    /// Adds to the instructions left, if they are limited.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(left) = &mut self.limits.fuel {
+            *left = left.saturating_add(fuel);
+        }
+    }
+    /// The instructions left, if they are limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.limits.fuel
+    }
+    /// Limits how many Lua functions can be running at once, counting those
+    /// of the coroutines waiting on others; `DEFAULT_MAX_CALL_DEPTH` unless
+    /// set. Calls past it fail with the usual "stack overflow" error, which
+    /// scripts can catch. Calls nested through Rust, such as metamethods
+    /// and `pcall`, are also held to `MAX_C_CALLS` whatever this is.
+    pub fn set_max_call_depth(&mut self, depth: Option<usize>) {
+        self.limits.max_call_depth = depth;
+    }
+    /// Stops scripts still running at `deadline` with
+    /// `LuaError::DeadlineExceeded`. The clock is read every thousand
+    /// instructions, so a script can overrun it by that much.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.limits.deadline = deadline;
+        self.limits.deadline_countdown = 0;
+    }
+    /// Whether the last run stopped by a budget can be resumed.
+    pub fn is_suspended(&self) -> bool {
+        self.limits.suspended.is_some()
+    }
+    /// Goes on with the run stopped by a budget, from the instruction it
+    /// stopped at. The results are those the stopped call would have had.
+    pub fn resume_run(&mut self) -> anyhow::Result<Vec<GCLuaValue>> {
+        let Some(depth) = self.limits.suspended.take() else {
+            bail!("no stopped run to resume");
+        };
+        self.run_from(depth)
+    }
+    /// Drops the run stopped by a budget, closing its upvalues.
+    pub fn abandon_run(&mut self) {
+        if let Some(depth) = self.limits.suspended.take() {
+            let base = self.state.frames[depth].base;
+            self.unwind(depth, base);
+        }
+    }
+
+    /// Charges an instruction about to run in the loop that stops at
+    /// `stop_depth`, failing if a budget has run out.
+    pub(super) fn charge_instruction(&mut self, stop_depth: usize) -> anyhow::Result<()> {
+        let error = if self.deadline_passed() {
+            LuaError::DeadlineExceeded
+        } else {
+            match &mut self.limits.fuel {
+                Some(0) => LuaError::OutOfFuel,
+                Some(fuel) => {
+                    *fuel -= 1;
+                    return Ok(());
+                }
+                None => return Ok(()),
+            }
+        };
+        // only the loop the host started can be left and taken up again
+        if self.state.nny == 1 && self.current_thread.borrow().is_main && self.limits.suspended.is_none() {
+            self.limits.suspended = Some(stop_depth);
+        }
+        Err(error.into())
+    }
+    fn deadline_passed(&mut self) -> bool {
+        let Some(deadline) = self.limits.deadline else {
+            return false;
+        };
+        if self.limits.deadline_countdown > 0 {
+            self.limits.deadline_countdown -= 1;
+            return false;
+        }
+        self.limits.deadline_countdown = DEADLINE_CHECK_INTERVAL;
+        Instant::now() >= deadline
+    }
+    /// Fails if entering another function would go past the call depth
+    /// limit.
+    pub(super) fn check_call_depth(&self) -> anyhow::Result<()> {
+        let depth = self.limits.frames_below + self.state.frames.len();
+        if self.limits.max_call_depth.is_some_and(|max| depth >= max) {
+            bail!("stack overflow");
+        }
+        Ok(())
+    }
+    /// Counts a call nested through Rust, failing with "C stack overflow"
+    /// past `MAX_C_CALLS`; each successful call must be matched by
+    /// `leave_c_call`.
+    pub(super) fn enter_c_call(&mut self) -> anyhow::Result<()> {
+        if self.limits.c_calls >= MAX_C_CALLS {
+            bail!("C stack overflow");
+        }
+        self.limits.c_calls += 1;
+        Ok(())
+    }
+    pub(super) fn leave_c_call(&mut self) {
+        self.limits.c_calls -= 1;
+    }
+}
@@ -0,0 +1,235 @@
+//! Instruction listings in the format of `luac -l` (print.c).
+
+use std::fmt::Write;
+
+use super::{
+    chunk_parser::{FunctionBlock, LuaConstant},
+    fmt_number,
+    instruction::VMOpcode,
+};
+
+/// Operand modes of the B and C fields, as in lopcodes.c.
+#[derive(PartialEq)]
+pub(crate) enum ArgMode {
+    /// Unused.
+    N,
+    /// Used as is.
+    U,
+    /// A register or a jump offset.
+    R,
+    /// A constant or a register.
+    K,
+}
+pub(crate) enum OpMode {
+    Abc,
+    Abx,
+    AsBx,
+}
+pub(crate) fn op_modes(op: &VMOpcode) -> (OpMode, ArgMode, ArgMode) {
+    use ArgMode::*;
+    use VMOpcode::*;
+    match op {
+        MOVE | LOADNIL | UNM | NOT | LEN => (OpMode::Abc, R, N),
+        LOADK | GETGLOBAL | SETGLOBAL => (OpMode::Abx, K, N),
+        LOADBOOL | NEWTABLE | CALL | TAILCALL | SETLIST => (OpMode::Abc, U, U),
+        GETUPVAL | SETUPVAL | RETURN | VARARG => (OpMode::Abc, U, N),
+        GETTABLE | SELF => (OpMode::Abc, R, K),
+        SETTABLE | ADD | SUB | MUL | DIV | MOD | POW | EQ | LT | LE => (OpMode::Abc, K, K),
+        CONCAT => (OpMode::Abc, R, R),
+        JMP | FORLOOP | FORPREP => (OpMode::AsBx, R, N),
+        TEST | TESTSET => (OpMode::Abc, R, U),
+        TFORLOOP => (OpMode::Abc, N, U),
+        CLOSE => (OpMode::Abc, N, N),
+        CLOSURE => (OpMode::Abx, U, N),
+    }
+}
+
+const BIT_RK: u32 = 1 << 8;
+
+fn rk(v: u32) -> i64 {
+    if v & BIT_RK != 0 {
+        -1 - (v & !BIT_RK) as i64
+    } else {
+        v as i64
+    }
+}
+
+/// A constant as `luac` prints it: numbers with `%.14g`, strings quoted and
+/// escaped.
+pub fn fmt_constant(c: &LuaConstant) -> String {
+    match c {
+        LuaConstant::LUA_TNIL => "nil".to_string(),
+        LuaConstant::LUA_TBOOLEAN(b) => b.to_string(),
+        LuaConstant::LUA_TNUMBER(n) => fmt_number(*n),
+        LuaConstant::LUA_TSTRING(s) => {
+            let mut out = String::from("\"");
+            for &c in s.as_bytes() {
+                match c {
+                    b'"' => out.push_str("\\\""),
+                    b'\\' => out.push_str("\\\\"),
+                    0x07 => out.push_str("\\a"),
+                    0x08 => out.push_str("\\b"),
+                    0x0c => out.push_str("\\f"),
+                    b'\n' => out.push_str("\\n"),
+                    b'\r' => out.push_str("\\r"),
+                    b'\t' => out.push_str("\\t"),
+                    0x0b => out.push_str("\\v"),
+                    0x20..=0x7e => out.push(c as char),
+                    _ => write!(out, "\\{:03}", c).unwrap(),
+                }
+            }
+            out.push('"');
+            out
+        }
+    }
+}
+
+/// Lists `func` and the functions nested in it, like `luac -l`.
+pub fn listing(func: &FunctionBlock) -> String {
+    let mut out = String::new();
+    list_function(&mut out, func);
+    out
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+fn list_function(out: &mut String, f: &FunctionBlock) {
+    let source = f.source_name.strip_prefix('@').or_else(|| f.source_name.strip_prefix('='));
+    let source = match source {
+        Some(s) => s,
+        None if f.source_name.starts_with('\x1b') => "(bstring)",
+        None => "(string)",
+    };
+    let n = f.list_instructions.len();
+    writeln!(
+        out,
+        "\n{} <{}:{},{}> ({} instruction{}, {} bytes at {:p})",
+        if f.line_def == 0 { "main" } else { "function" },
+        source,
+        f.line_def,
+        f.last_line_def,
+        n,
+        plural(n),
+        n * 4,
+        f
+    )
+    .unwrap();
+    let (params, slots, upvals) = (f.num_param as usize, f.max_stack_size as usize, f.num_upval as usize);
+    writeln!(
+        out,
+        "{}{} param{}, {} slot{}, {} upvalue{}, {} local{}, {} constant{}, {} function{}",
+        params,
+        if f.is_vararg != 0 { "+" } else { "" },
+        plural(params),
+        slots,
+        plural(slots),
+        upvals,
+        plural(upvals),
+        f.local_vars.len(),
+        plural(f.local_vars.len()),
+        f.list_const.len(),
+        plural(f.list_const.len()),
+        f.list_fnproto.len(),
+        plural(f.list_fnproto.len())
+    )
+    .unwrap();
+    list_code(out, f);
+    for p in &f.list_fnproto {
+        list_function(out, p);
+    }
+}
+
+fn list_code(out: &mut String, f: &FunctionBlock) {
+    let mut pc = 0;
+    while pc < f.list_instructions.len() {
+        write!(out, "\t{}\t", pc + 1).unwrap();
+        match f.line_info.get(pc) {
+            Some(line) if *line > 0 => write!(out, "[{}]\t", line).unwrap(),
+            _ => out.push_str("[-]\t"),
+        }
+        out.push_str(&instruction(f, pc));
+        out.push('\n');
+        let i = f.list_instructions[pc].to_u32();
+        // the count of a SETLIST whose C is 0 is not an instruction
+        if f.list_instructions[pc].opcode == VMOpcode::SETLIST && (i >> 14) & 0x1ff == 0 {
+            pc += 1;
+        }
+        pc += 1;
+    }
+}
+
+/// The instruction at `pc` as `luac -l` shows it, without the pc and line:
+/// the opcode, its operands and a comment naming constants or targets.
+pub fn instruction(f: &FunctionBlock, pc: usize) -> String {
+    let mut out = String::new();
+    let constant = |idx: u32| fmt_constant(&f.list_const[idx as usize]);
+    let i = f.list_instructions[pc].to_u32();
+    let op = &f.list_instructions[pc].opcode;
+    let a = (i >> 6) & 0xff;
+    let b = (i >> 23) & 0x1ff;
+    let c = (i >> 14) & 0x1ff;
+    let bx = i >> 14;
+    let sbx = bx as i64 - 131071;
+    write!(out, "{:<9}\t", format!("{:?}", op)).unwrap();
+    let (mode, b_mode, c_mode) = op_modes(op);
+    match mode {
+        OpMode::Abc => {
+            write!(out, "{}", a).unwrap();
+            if b_mode != ArgMode::N {
+                write!(out, " {}", rk(b)).unwrap();
+            }
+            if c_mode != ArgMode::N {
+                write!(out, " {}", rk(c)).unwrap();
+            }
+        }
+        OpMode::Abx if b_mode == ArgMode::K => write!(out, "{} {}", a, -1 - bx as i64).unwrap(),
+        OpMode::Abx => write!(out, "{} {}", a, bx).unwrap(),
+        OpMode::AsBx if *op == VMOpcode::JMP => write!(out, "{}", sbx).unwrap(),
+        OpMode::AsBx => write!(out, "{} {}", a, sbx).unwrap(),
+    }
+    match op {
+        VMOpcode::LOADK => write!(out, "\t; {}", constant(bx)).unwrap(),
+        VMOpcode::GETUPVAL | VMOpcode::SETUPVAL => {
+            let name = f.upvalue_names.get(b as usize).map(String::as_str).unwrap_or("-");
+            write!(out, "\t; {}", name).unwrap();
+        }
+        VMOpcode::GETGLOBAL | VMOpcode::SETGLOBAL => match &*f.list_const[bx as usize] {
+            LuaConstant::LUA_TSTRING(s) => write!(out, "\t; {}", s).unwrap(),
+            c => write!(out, "\t; {}", fmt_constant(c)).unwrap(),
+        },
+        VMOpcode::GETTABLE | VMOpcode::SELF if c & BIT_RK != 0 => {
+            write!(out, "\t; {}", constant(c & !BIT_RK)).unwrap();
+        }
+        VMOpcode::SETTABLE
+        | VMOpcode::ADD
+        | VMOpcode::SUB
+        | VMOpcode::MUL
+        | VMOpcode::DIV
+        | VMOpcode::POW
+        | VMOpcode::EQ
+        | VMOpcode::LT
+        | VMOpcode::LE
+            if (b | c) & BIT_RK != 0 =>
+        {
+            let operand = |v: u32| if v & BIT_RK != 0 { constant(v & !BIT_RK) } else { "-".to_string() };
+            write!(out, "\t; {} {}", operand(b), operand(c)).unwrap();
+        }
+        VMOpcode::JMP | VMOpcode::FORLOOP | VMOpcode::FORPREP => {
+            write!(out, "\t; to {}", sbx + pc as i64 + 2).unwrap();
+        }
+        VMOpcode::CLOSURE => write!(out, "\t; {:p}", &*f.list_fnproto[bx as usize]).unwrap(),
+        VMOpcode::SETLIST if c == 0 => {
+            let count = f.list_instructions.get(pc + 1).map_or(0, |i| i.to_u32());
+            write!(out, "\t; {}", count).unwrap();
+        }
+        VMOpcode::SETLIST => write!(out, "\t; {}", c).unwrap(),
+        _ => {}
+    }
+    out
+}
@@ -0,0 +1,260 @@
+//! Memory accounting. Strings, tables, closures, upvalues, userdata and
+//! threads note their size in a per-thread count when made, and a VM charges
+//! itself what was noted while it ran, so VMs sharing a thread keep separate
+//! accounts. Freeing is only seen when the VM measures what it can still
+//! reach, which it does when the count says it is over its limit; like Lua's
+//! own count, usage in between includes garbage.
+
+use std::{
+    cell::Cell,
+    collections::HashSet,
+    mem::{size_of, size_of_val},
+};
+
+use super::{
+    error::LuaError,
+    table::{GCLuaTable, LuaKey, LuaTable},
+    thread::{CallFrame, GCLuaThread, GCUpValue, LuaThread, ThreadState, UpValue},
+    userdata::{GCLuaUserData, LuaUserData, UserDataValue},
+    GCLuaFunction, GCLuaValue, LuaFunction, LuaVM, LuaValue,
+};
+
+/// Roughly what the collector adds to each allocation: its header and the
+/// borrow flag of the cell inside.
+const GC_OVERHEAD: usize = 5 * size_of::<usize>();
+
+thread_local! {
+    /// Bytes noted on this thread, ever; only differences mean anything.
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+}
+
+pub(crate) fn note_alloc(bytes: usize) {
+    ALLOCATED.with(|a| a.set(a.get().wrapping_add(bytes)));
+}
+fn allocated() -> usize {
+    ALLOCATED.with(Cell::get)
+}
+
+pub(crate) fn value_size(v: &LuaValue) -> usize {
+    let payload = match v {
+        LuaValue::String(s) => s.len(),
+        _ => 0,
+    };
+    GC_OVERHEAD + size_of::<LuaValue>() + payload
+}
+pub(crate) fn table_size(t: &LuaTable) -> usize {
+    GC_OVERHEAD + size_of::<LuaTable>() + t.entries().map(|(k, _)| entry_size(k)).sum::<usize>()
+}
+/// What one more key in a table costs: the entry and its index slot.
+pub(crate) fn entry_size(key: &LuaKey) -> usize {
+    let payload = match key {
+        LuaKey::String(s) => s.len(),
+        _ => 0,
+    };
+    size_of::<(LuaKey, GCLuaValue)>() + size_of::<(LuaKey, usize)>() + payload
+}
+pub(crate) fn function_size(f: &LuaFunction) -> usize {
+    GC_OVERHEAD + size_of::<LuaFunction>() + f.upvalues.len() * size_of::<GCUpValue>()
+}
+pub(crate) fn upvalue_size() -> usize {
+    GC_OVERHEAD + size_of::<UpValue>()
+}
+pub(crate) fn userdata_size(value: &dyn UserDataValue) -> usize {
+    GC_OVERHEAD + size_of::<LuaUserData>() + size_of_val(value)
+}
+pub(crate) fn thread_size() -> usize {
+    GC_OVERHEAD + size_of::<LuaThread>()
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Memory {
+    limit: Option<usize>,
+    /// Bytes reachable when last measured.
+    live: usize,
+    /// Bytes noted while running since then.
+    since: usize,
+    /// The per-thread count when `since` was last brought up to date.
+    mark: usize,
+    peak: usize,
+    /// Runs of the interpreter under way; allocations are charged while
+    /// there are any.
+    runs: usize,
+}
+
+impl LuaVM {
+    /// Fails allocations that would take the VM's memory past `bytes` with
+    /// the "not enough memory" error, or lifts the limit.
+    pub fn set_memory_limit(&mut self, bytes: Option<usize>) {
+        self.memory.limit = bytes;
+    }
+    /// Bytes in use as counted: what was reachable when last measured and
+    /// all allocated since, garbage included.
+    pub fn memory_used(&mut self) -> usize {
+        if self.memory.runs > 0 {
+            self.sync_memory();
+        }
+        self.memory.live + self.memory.since
+    }
+    /// The most `memory_used` has been.
+    pub fn memory_peak(&mut self) -> usize {
+        if self.memory.runs > 0 {
+            self.sync_memory();
+        }
+        self.memory.peak
+    }
+    /// Runs a full collection and measures the memory the VM still holds.
+    pub fn collect_garbage(&mut self) -> usize {
+        gc::force_collect();
+        self.remeasure_memory()
+    }
+    /// Measures the memory the VM still holds, without collecting first.
+    pub fn remeasure_memory(&mut self) -> usize {
+        if self.memory.runs > 0 {
+            self.sync_memory();
+        }
+        self.memory.live = self.measure();
+        self.memory.since = 0;
+        self.memory.live
+    }
+
+    /// Starts charging the VM with allocations, unless a run already is.
+    /// What the host allocates in between runs is left out until measured.
+    pub(super) fn enter_memory_count(&mut self) {
+        if self.memory.runs == 0 {
+            self.memory.mark = allocated();
+        }
+        self.memory.runs += 1;
+    }
+    pub(super) fn leave_memory_count(&mut self) {
+        self.sync_memory();
+        self.memory.runs -= 1;
+    }
+    fn sync_memory(&mut self) {
+        let now = allocated();
+        self.memory.since += now.wrapping_sub(self.memory.mark);
+        self.memory.mark = now;
+        self.memory.peak = self.memory.peak.max(self.memory.live + self.memory.since);
+    }
+    /// Fails if the memory limit is exceeded, once garbage is left out.
+    pub(super) fn check_memory(&mut self) -> anyhow::Result<()> {
+        let Some(limit) = self.memory.limit else {
+            return Ok(());
+        };
+        self.sync_memory();
+        if self.memory.live + self.memory.since <= limit {
+            return Ok(());
+        }
+        if self.remeasure_memory() > limit {
+            return Err(LuaError::OutOfMemory.into());
+        }
+        Ok(())
+    }
+
+    /// Bytes reachable from the VM's globals, registry, metatables and
+    /// stacks.
+    fn measure(&self) -> usize {
+        let mut walk = Walk::default();
+        walk.tables.push(self.globals.clone());
+        walk.tables.push(self.registry.clone());
+        walk.tables.extend(self.type_metatables.values().cloned());
+        walk.state(&self.state);
+        walk.threads.push(self.current_thread.clone());
+        walk.threads.extend(self.resumers.iter().cloned());
+        walk.values.extend(self.native_upvalues.iter().flatten().cloned());
+        walk.values.extend(self.pending_yield.iter().flatten().cloned());
+        walk.run()
+    }
+}
+
+/// A traversal of the GC heap, counting each object once.
+#[derive(Default)]
+struct Walk {
+    seen: HashSet<usize>,
+    bytes: usize,
+    values: Vec<GCLuaValue>,
+    tables: Vec<GCLuaTable>,
+    functions: Vec<GCLuaFunction>,
+    upvalues: Vec<GCUpValue>,
+    userdata: Vec<GCLuaUserData>,
+    threads: Vec<GCLuaThread>,
+}
+impl Walk {
+    fn first_visit(&mut self, ptr: usize) -> bool {
+        self.seen.insert(ptr)
+    }
+    fn state(&mut self, state: &ThreadState) {
+        self.bytes += state.stack.len() * size_of::<GCLuaValue>() + state.frames.len() * size_of::<CallFrame>();
+        self.values.extend(state.stack.iter().cloned());
+        for frame in &state.frames {
+            self.functions.push(frame.func.clone());
+            self.values.extend(frame.varargs.iter().cloned());
+        }
+        self.upvalues.extend(state.open_upvalues.iter().cloned());
+    }
+    fn run(mut self) -> usize {
+        loop {
+            if let Some(v) = self.values.pop() {
+                if !self.first_visit(v.ptr()) {
+                    continue;
+                }
+                let v = v.borrow();
+                self.bytes += value_size(&v);
+                match &*v {
+                    LuaValue::Table(t) => self.tables.push(t.clone()),
+                    LuaValue::Function(f) => self.functions.push(f.clone()),
+                    LuaValue::NativeFunction(f) => self.values.extend(f.upvalues.iter().cloned()),
+                    LuaValue::UserData(u) => self.userdata.push(u.clone()),
+                    LuaValue::Thread(t) => self.threads.push(t.clone()),
+                    _ => {}
+                }
+            } else if let Some(t) = self.tables.pop() {
+                if !self.first_visit(t.ptr()) {
+                    continue;
+                }
+                let t = t.borrow();
+                self.bytes += table_size(&t);
+                for (k, v) in t.entries() {
+                    if let LuaKey::Object(_, key) = k {
+                        self.values.push(key.clone());
+                    }
+                    self.values.push(v.clone());
+                }
+                self.tables.extend(t.metatable.clone());
+            } else if let Some(f) = self.functions.pop() {
+                if !self.first_visit(f.ptr()) {
+                    continue;
+                }
+                let f = f.borrow();
+                self.bytes += function_size(&f);
+                self.upvalues.extend(f.upvalues.iter().cloned());
+            } else if let Some(u) = self.upvalues.pop() {
+                if !self.first_visit(u.ptr()) {
+                    continue;
+                }
+                self.bytes += upvalue_size();
+                match &*u.borrow() {
+                    UpValue::Open(thread, _) => self.threads.push(thread.clone()),
+                    UpValue::Closed(v) => self.values.push(v.clone()),
+                }
+            } else if let Some(u) = self.userdata.pop() {
+                if !self.first_visit(u.ptr()) {
+                    continue;
+                }
+                let data = u.data();
+                self.bytes += u.size();
+                self.tables.extend(data.metatable.clone());
+                self.tables.extend(data.env.clone());
+            } else if let Some(t) = self.threads.pop() {
+                if !self.first_visit(t.ptr()) {
+                    continue;
+                }
+                self.bytes += thread_size();
+                let t = t.borrow();
+                self.functions.extend(t.body.clone());
+                self.state(&t.state);
+            } else {
+                return self.bytes;
+            }
+        }
+    }
+}
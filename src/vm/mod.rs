@@ -1,23 +1,67 @@
-use std::{any::Any, borrow::Borrow, fmt::Debug, sync::Arc, collections::HashMap, panic::{catch_unwind, AssertUnwindSafe}};
+use std::{collections::HashMap, fmt::Debug, panic::{catch_unwind, AssertUnwindSafe}, rc::Rc};
 
-use ahash::AHashMap;
+use anyhow::{anyhow, bail};
 use gc::{Finalize, Gc, GcCell, GcCellRef, Trace, GcCellRefMut};
 
 use self::{
-    chunk_parser::{FunctionBlock, LuaChunk, LuaConstant},
-    instruction::{InstParam, VMInst, VMOpcode, MASK_CBIT},
+    chunk_parser::{FunctionBlock, LuaChunk, VARARG_NEEDSARG},
+    convert::{FromLua, IntoLua},
+    debug::{Hook, HookEvent, TracebackLimits},
+    instruction::{VMInst, VMOpcode, MASK_CBIT},
+    limits::Limits,
+    memory::Memory,
+    table::{GCLuaTable, LuaKey, LuaTable},
+    thread::{CallFrame, GCLuaThread, GCUpValue, LuaThread, ReturnTo, ThreadState, ThreadStatus, UpValue},
+    trace::{TraceEvent, TraceSink},
+    string::LuaString,
+    userdata::GCLuaUserData,
 };
 
+pub mod cfg;
+pub mod dap;
 pub mod chunk_parser;
+pub mod convert;
+pub mod debug;
+pub mod debugger;
 pub mod instruction;
 pub mod decompiler;
+pub mod dot;
+pub mod error;
+pub mod hostio;
+pub mod json;
+pub mod limits;
+pub mod listing;
+pub mod memory;
+pub mod optimizer;
+pub mod sandbox;
+#[cfg(feature = "serde")]
+pub mod serialize;
+pub mod stdlib;
+pub mod string;
+pub mod table;
+pub mod thread;
+pub mod trace;
+pub mod userdata;
+pub mod verify;
+
+/// `SETLIST` flushes the array part of a table constructor in batches of this size.
+pub(crate) const FIELDS_PER_FLUSH: u32 = 50;
+/// Bound on `__index`/`__newindex` chains, like `MAXTAGLOOP` in the reference VM.
+const MAX_TAG_LOOP: usize = 100;
+
 #[derive(Debug, Trace, Finalize)]
 pub enum LuaValue {
     Nil,
     Number(f64),
     Boolean(bool),
-    String(String),
+    String(LuaString),
     Function(GCLuaFunction),
+    NativeFunction(LuaNativeFunction),
+    Table(GCLuaTable),
+    UserData(GCLuaUserData),
+    /// A bare pointer-sized value chosen by the host, compared by value.
+    LightUserData(usize),
+    Thread(GCLuaThread),
 }
 impl LuaValue {
     pub fn to_gc(self) -> GCLuaValue {
@@ -26,338 +70,1145 @@ impl LuaValue {
     pub fn as_string(&self, fmts: bool) -> String {
         match self {
             LuaValue::Nil => "nil".to_string(),
-            LuaValue::Number(v) => v.to_string(),
+            LuaValue::Number(v) => fmt_number(*v),
             LuaValue::Boolean(b) => b.to_string(),
             LuaValue::String(s) => {
                 if fmts {
-                    format!("\"{}\"", s.clone())
+                    crate::compiler::printer::quote_string(s.as_bytes())
                 } else {
-                    s.clone()
+                    s.to_string()
                 }
             },
-            _ => String::new(),
+            LuaValue::Function(_) | LuaValue::NativeFunction(_) => format!("function: {:#x}", self.identity()),
+            LuaValue::Table(_) => format!("table: {:#x}", self.identity()),
+            LuaValue::UserData(_) | LuaValue::LightUserData(_) => format!("userdata: {:#x}", self.identity()),
+            LuaValue::Thread(_) => format!("thread: {:#x}", self.identity()),
+        }
+    }
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LuaValue::Nil => "nil",
+            LuaValue::Number(_) => "number",
+            LuaValue::Boolean(_) => "boolean",
+            LuaValue::String(_) => "string",
+            LuaValue::Function(_) | LuaValue::NativeFunction(_) => "function",
+            LuaValue::Table(_) => "table",
+            LuaValue::UserData(_) | LuaValue::LightUserData(_) => "userdata",
+            LuaValue::Thread(_) => "thread",
+        }
+    }
+    /// Everything except `nil` and `false` is true.
+    pub fn truthy(&self) -> bool {
+        !matches!(self, LuaValue::Nil | LuaValue::Boolean(false))
+    }
+    /// Number coercion as done by arithmetic: numbers, and strings that parse as numbers.
+    pub fn to_number(&self) -> Option<f64> {
+        match self {
+            LuaValue::Number(n) => Some(*n),
+            LuaValue::String(s) => std::str::from_utf8(s.as_bytes()).ok().and_then(parse_number),
+            _ => None,
+        }
+    }
+    /// Address of the allocation behind a reference value, 0 for plain values.
+    pub fn identity(&self) -> usize {
+        match self {
+            LuaValue::Function(f) => f.ptr(),
+            LuaValue::NativeFunction(f) => f.ptr(),
+            LuaValue::Table(t) => t.ptr(),
+            LuaValue::UserData(u) => u.ptr(),
+            LuaValue::LightUserData(p) => *p,
+            LuaValue::Thread(t) => t.ptr(),
+            _ => 0,
+        }
+    }
+    pub fn raw_equals(&self, other: &LuaValue) -> bool {
+        match (self, other) {
+            (LuaValue::Nil, LuaValue::Nil) => true,
+            (LuaValue::Number(a), LuaValue::Number(b)) => a == b,
+            (LuaValue::Boolean(a), LuaValue::Boolean(b)) => a == b,
+            (LuaValue::String(a), LuaValue::String(b)) => a == b,
+            (LuaValue::LightUserData(a), LuaValue::LightUserData(b)) => a == b,
+            (LuaValue::Function(_), LuaValue::Function(_))
+            | (LuaValue::NativeFunction(_), LuaValue::NativeFunction(_))
+            | (LuaValue::Table(_), LuaValue::Table(_))
+            | (LuaValue::UserData(_), LuaValue::UserData(_))
+            | (LuaValue::Thread(_), LuaValue::Thread(_)) => self.identity() == other.identity(),
+            _ => false,
+        }
+    }
+}
+/// Formats a number the way Lua 5.1 does (`%.14g`).
+pub fn fmt_number(v: f64) -> String {
+    if v.is_nan() {
+        return if v.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if v.is_infinite() {
+        return if v < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    if v == v.trunc() && v.abs() < 1e14 {
+        if v == 0.0 && v.is_sign_negative() {
+            return "-0".to_string();
         }
+        return format!("{}", v as i64);
+    }
+    let sci = format!("{:.13e}", v);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    if (-4..14).contains(&exp) {
+        let fixed = format!("{:.*}", (13 - exp) as usize, v);
+        strip_zeros(&fixed).to_string()
+    } else {
+        format!(
+            "{}e{}{:02}",
+            strip_zeros(mantissa),
+            if exp < 0 { '-' } else { '+' },
+            exp.abs()
+        )
+    }
+}
+fn strip_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
     }
 }
+/// Parses a numeric string the way `lua_str2number` does: decimal with optional
+/// exponent, or hexadecimal integers, surrounded by optional whitespace.
+pub fn parse_number(s: &str) -> Option<f64> {
+    let t = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    if t.is_empty() {
+        return None;
+    }
+    let (neg, digits) = match t.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, t.strip_prefix('+').unwrap_or(t)),
+    };
+    if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        if hex.is_empty() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let v = hex.chars().fold(0f64, |acc, c| acc * 16.0 + c.to_digit(16).unwrap() as f64);
+        return Some(if neg { -v } else { v });
+    }
+    if !digits.chars().all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'))
+        || !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+    {
+        return None;
+    }
+    let v: f64 = digits.parse().ok()?;
+    Some(if neg { -v } else { v })
+}
 #[derive(Debug, Clone, Trace, Finalize)]
 pub struct GCLuaValue(Gc<GcCell<LuaValue>>);
 impl GCLuaValue {
     pub fn new(v: LuaValue) -> Self {
+        memory::note_alloc(memory::value_size(&v));
         Self(Gc::new(GcCell::new(v)))
     }
-    pub fn borrow(&self) -> GcCellRef<LuaValue> {
+    pub fn borrow(&self) -> GcCellRef<'_, LuaValue> {
         self.0.try_borrow().unwrap()
     }
+    pub fn is_nil(&self) -> bool {
+        matches!(*self.borrow(), LuaValue::Nil)
+    }
+    pub fn ptr(&self) -> usize {
+        &*self.0 as *const GcCell<LuaValue> as usize
+    }
 }
 #[derive(Debug, Clone, Trace, Finalize)]
 pub struct GCLuaFunction(Gc<GcCell<LuaFunction>>);
 impl GCLuaFunction {
     pub fn new(v: LuaFunction) -> Self {
+        memory::note_alloc(memory::function_size(&v));
         Self(Gc::new(GcCell::new(v)))
     }
-    pub fn borrow(&self) -> GcCellRef<LuaFunction> {
+    pub fn borrow(&self) -> GcCellRef<'_, LuaFunction> {
         self.0.try_borrow().unwrap()
     }
-    pub fn borrow_mut(&self) -> GcCellRefMut<LuaFunction> {
+    pub fn borrow_mut(&self) -> GcCellRefMut<'_, LuaFunction> {
         self.0.try_borrow_mut().unwrap()
     }
+    pub fn ptr(&self) -> usize {
+        &*self.0 as *const GcCell<LuaFunction> as usize
+    }
 }
 #[derive(Debug, Clone, Trace, Finalize)]
 pub struct LuaFunction {
     prototype: Gc<FunctionBlock>,
-    upvalues: HashMap<u32, GCLuaValue>,
-    idx: usize,
+    upvalues: Vec<GCUpValue>,
 }
 impl LuaFunction {
-    pub fn new(prototype: Gc<FunctionBlock>, upvalues: HashMap<u32, GCLuaValue>) -> Self {
-        Self { prototype, idx: 0, upvalues }
+    pub fn new(prototype: Gc<FunctionBlock>, upvalues: Vec<GCUpValue>) -> Self {
+        Self { prototype, upvalues }
     }
     pub fn to_gc(self) -> GCLuaFunction {
         GCLuaFunction::new(self)
     }
 }
+/// Signature of functions implemented in Rust and callable from Lua.
+pub type NativeFn = dyn Fn(&mut LuaVM, Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>>;
+/// A Rust function exposed to Lua. The closure is not traced, so it must not
+/// capture GC values; state that lives in the GC heap belongs in the registry.
+#[derive(Clone, Trace, Finalize)]
+pub struct LuaNativeFunction {
+    pub name: String,
+    /// Values bound to the function, read with `LuaVM::native_upvalue` while it runs.
+    pub upvalues: Vec<GCLuaValue>,
+    #[unsafe_ignore_trace]
+    func: Rc<NativeFn>,
+}
+impl LuaNativeFunction {
+    pub fn new<F>(name: &str, func: F) -> Self
+    where
+        F: Fn(&mut LuaVM, Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> + 'static,
+    {
+        Self::with_upvalues(name, Vec::new(), func)
+    }
+    pub fn with_upvalues<F>(name: &str, upvalues: Vec<GCLuaValue>, func: F) -> Self
+    where
+        F: Fn(&mut LuaVM, Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> + 'static,
+    {
+        Self { name: name.to_string(), upvalues, func: Rc::new(func) }
+    }
+    pub fn call(&self, vm: &mut LuaVM, args: Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> {
+        vm.native_upvalues.push(self.upvalues.clone());
+        let r = (self.func)(vm, args);
+        vm.native_upvalues.pop();
+        r
+    }
+    pub fn ptr(&self) -> usize {
+        Rc::as_ptr(&self.func) as *const () as usize
+    }
+}
+impl Debug for LuaNativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "LuaNativeFunction({})", self.name)
+    }
+}
+/// How a run of the interpreter loop ended.
+enum ExecOutcome {
+    Return(Vec<GCLuaValue>),
+    Yield(Vec<GCLuaValue>),
+}
+/// What the loop does after an instruction.
+enum Step {
+    Continue,
+    Done(ExecOutcome),
+}
 pub struct LuaVM {
-    registers: AHashMap<u32, GCLuaValue>,
-    globals: AHashMap<u32, GCLuaValue>,
-    constants: AHashMap<u32, Gc<LuaConstant>>,
-    currently_executing: Option<GCLuaFunction>,
-    base: u32,
-    top: Option<u32>,
-    last_op: Option<VMInst>,
+    globals: GCLuaTable,
+    registry: GCLuaTable,
+    native_upvalues: Vec<Vec<GCLuaValue>>,
+    /// Stack and frames of the running thread.
+    state: ThreadState,
+    current_thread: GCLuaThread,
+    /// Values passed to `coroutine.yield`, picked up once the native returns.
+    pending_yield: Option<Vec<GCLuaValue>>,
+    /// Where execution events go; `None` leaves tracing off.
+    trace: Option<Box<dyn TraceSink>>,
+    hook: Option<Hook>,
+    /// Instructions left before the next count hook.
+    hook_countdown: u32,
+    /// Set while a hook runs, so that it does not set off hooks itself.
+    in_hook: bool,
+    /// Metatables shared by all values of a type other than table and
+    /// userdata, keyed by type name.
+    type_metatables: HashMap<&'static str, GCLuaTable>,
+    traceback_limits: TracebackLimits,
+    limits: Limits,
+    memory: Memory,
+    /// Threads waiting on the running coroutine, outermost first.
+    resumers: Vec<GCLuaThread>,
+}
+impl Default for LuaVM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl LuaVM {
     pub fn new() -> Self {
-        let mut constants = AHashMap::new();
         Self {
-            registers: AHashMap::new(),
-            globals: AHashMap::new(),
-            constants,
-            currently_executing: None,
-            base: 0,
-            top: None,
-            last_op: None
-        }
-    }
-    pub fn process_chunk(&mut self, chunk: LuaChunk) -> Option<Vec<GCLuaValue>> {
-        self.process_func(GCLuaFunction::new(LuaFunction::new(Gc::new(chunk.func), HashMap::new())))
-    }
-    fn call_func(&mut self, func: GCLuaFunction, base_set: u32, param_count: Option<u32>) -> Option<Vec<GCLuaValue>> {
-        println!("Calling");
-        let base = self.base;
-        self.base = base_set;
-        // if let Some(c) = param_count {
-        //     for i in 0..c {
-        //         if self.try_copy_register(i).is_none() {
-        //             self.set_register(i, LuaValue::Nil.to_gc());
-        //         }
-        //     }
-        // }
-        let prev = self.currently_executing.clone().expect("Not executing? How");
-        let v = self.process_func(func);
-        println!("V: {:?}", v);
-        self.set_consts(&prev);
-        self.currently_executing = Some(prev);
-        //self.base = base;
-        v
-    }
-    fn set_consts(&mut self, func: &GCLuaFunction) {
-        self.constants = AHashMap::new();
-        for (idx, c) in func.borrow().prototype.list_const.iter().enumerate() {
-            self.constants.insert(idx as u32, c.clone());
-        }
-    }
-    fn process_func(&mut self, func: GCLuaFunction) -> Option<Vec<GCLuaValue>> {
-        self.currently_executing = Some(func.clone());
-        self.set_consts(&func);
-        let i = func.borrow().idx;
-        let len = func.borrow().prototype.list_instructions.len();
-        for i in i..len {
-            func.borrow_mut().idx = i;
-            let inst = func.borrow().prototype.list_instructions[i].clone();
-            let x = catch_unwind(AssertUnwindSafe(|| {
-                match inst.opcode {
-                    VMOpcode::LOADK => {
-                        let params = &inst.params;
-                        self.set_register(
-                            params[0].get_num_val(),
-                            self.get_constant(params[1].get_num_val()),
-                        );
-                    }
-                    VMOpcode::RETURN => {
-                        let b = inst.params[1].get_num_val();
-                        if b > 1 {
-                            println!("GR 1");
-                            let mut ra = inst.params[0].get_num_val();
-                            let mut returnval = Vec::new();
-                            println!("RA {} b-1 {}", ra, b - 1);
-                            if ra < b - 1 {
-                                while ra < b - 1 {
-                                    println!("Starting with register R({})", ra);
-                                    returnval.push(self.copy_register(ra));
-                                    ra += 1;
-                                }
-                            } else {
-                                returnval.push(self.copy_register(ra));
-                            }
-                            // for r in ra..(b - 1) + 1 {
-                            //     returnval.push(self.copy_register(r));
-                            // }
-                            return Some(returnval);
-                        } else if b == 0 {
-                            println!("Base: {}", self.base);
-                            let mut i = inst.params[0].get_num_val();
-                            let mut returnval = Vec::new();
-                            //i += 1;
-                            for i in i..self.top.unwrap() {
-                                returnval.push(self.copy_register(i));
-                            }
-                            // while let Some(v) = self.try_copy_register(i) {
-    
-                            //     i += 1;
-                            // }
-                            return Some(returnval);
-                        }
-                    }
-                    VMOpcode::ADD => {
-                        let (out, p1, p2) = self.get_abc(&inst);
-                        println!("p1 {:?} p2 {:?}", p1, p2);
-                        if let LuaValue::Number(a) = *p1.borrow() {
-                            if let LuaValue::Number(b) = *p2.borrow() {
-                                println!("Setting R({}) to {}", out, a + b);
-                                self.set_register(out, LuaValue::Number(a + b).to_gc());
-                            }
-                        };
-                    }
-                    VMOpcode::SUB => {
-                        let (out, p1, p2) = self.get_abc(&inst);
-                        if let LuaValue::Number(a) = *p1.borrow() {
-                            if let LuaValue::Number(b) = *p2.borrow() {
-                                self.set_register(out, LuaValue::Number(a - b).to_gc());
-                            }
-                        };
-                    }
-                    VMOpcode::MUL => {
-                        let (out, p1, p2) = self.get_abc(&inst);
-                        if let LuaValue::Number(a) = *p1.borrow() {
-                            if let LuaValue::Number(b) = *p2.borrow() {
-                                self.set_register(out, LuaValue::Number(a * b).to_gc());
-                            }
-                        };   
-                    }
-                    VMOpcode::MOD => {
-                        let (out, p1, p2) = self.get_abc(&inst);
-                        if let LuaValue::Number(a) = *p1.borrow() {
-                            if let LuaValue::Number(b) = *p2.borrow() {
-                                self.set_register(out, LuaValue::Number(a % b).to_gc());
-                            }
-                        };   
-                    }
-                    VMOpcode::POW => {
-                        let (out, p1, p2) = self.get_abc(&inst);
-                        if let LuaValue::Number(a) = *p1.borrow() {
-                            if let LuaValue::Number(b) = *p2.borrow() {
-                                self.set_register(out, LuaValue::Number(a.powf(b)).to_gc());
-                            }
-                        };     
-                    }
-                    VMOpcode::DIV => {
-                        let (out, p1, p2) = self.get_abc(&inst);
-                        if let LuaValue::Number(a) = *p1.borrow() {
-                            if let LuaValue::Number(b) = *p2.borrow() {
-                                self.set_register(out, LuaValue::Number(a / b).to_gc());
-                            }
-                        };     
-                    }
-                    VMOpcode::CLOSURE => {
-                        let reg = inst.params[0].get_num_val();
-                        let closure =
-                            func.borrow().prototype.list_fnproto[inst.params[1].get_num_val() as usize].clone();
-                        self.set_register(reg, LuaValue::Function(LuaFunction::new(closure, HashMap::new()).to_gc()).to_gc());
-                    }
-                    VMOpcode::MOVE => {
-                        let last_op = self.last_op.as_ref().unwrap().clone();
-                        if let VMOpcode::CLOSURE = self.last_op.as_ref().unwrap().opcode {
-                            let reg = last_op.params[0].get_num_val();
-                            let val = self.copy_register(reg);
-                            if let LuaValue::Function(f) = &*val.borrow() {
-                                let upvalue_to_set = inst.params[0].get_num_val();
-                                let mut fmut = f.borrow_mut(); 
-                                fmut.upvalues.remove(&upvalue_to_set);
-                                fmut.upvalues.insert(upvalue_to_set, self.copy_register(inst.params[1].get_num_val()));
-                            };
-                        }
-                        let b = self.copy_register(inst.params[1].get_num_val());
-                        self.set_register(inst.params[0].get_num_val(), b);
-                    }
-                    VMOpcode::SETGLOBAL => {
-                        let reg = inst.params[0].get_num_val();
-                        let global_idx = inst.params[1].get_num_val();
-                        let v = self.copy_register(reg);
-                        self.set_global(global_idx, v);
-                    }
-                    VMOpcode::GETGLOBAL => {
-                        let reg = inst.params[0].get_num_val();
-                        let global_idx = inst.params[1].get_num_val();
-                        let v = self.copy_global(global_idx);
-                        self.set_register(reg, v);
+            globals: GCLuaTable::default(),
+            registry: GCLuaTable::default(),
+            native_upvalues: Vec::new(),
+            state: ThreadState::default(),
+            current_thread: GCLuaThread::new(LuaThread::main()),
+            pending_yield: None,
+            trace: None,
+            hook: None,
+            hook_countdown: 0,
+            in_hook: false,
+            type_metatables: HashMap::new(),
+            traceback_limits: TracebackLimits::default(),
+            limits: Limits::default(),
+            memory: Memory::default(),
+            resumers: Vec::new(),
+        }
+    }
+    pub fn process_chunk(&mut self, chunk: LuaChunk) -> anyhow::Result<Vec<GCLuaValue>> {
+        self.process_chunk_with(chunk, Vec::new())
+    }
+    /// Runs the main function of `chunk` with `args` as its `...`.
+    pub fn process_chunk_with(&mut self, chunk: LuaChunk, args: Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> {
+        let main = self.load(chunk);
+        self.call_func(main, args)
+    }
+    /// The main function of `chunk`, ready to be called.
+    pub fn load(&mut self, chunk: LuaChunk) -> GCLuaFunction {
+        if let Some(sink) = self.trace.as_mut() {
+            sink.event(&TraceEvent::ChunkLoad { func: &chunk.func });
+        }
+        GCLuaFunction::new(LuaFunction::new(Gc::new(chunk.func), Vec::new()))
+    }
+    /// Sends execution events to `sink` from now on, or stops tracing.
+    pub fn set_trace(&mut self, sink: Option<Box<dyn TraceSink>>) {
+        self.trace = sink;
+    }
+    pub fn globals(&self) -> GCLuaTable {
+        self.globals.clone()
+    }
+    /// Table for host and library state that scripts cannot reach.
+    pub fn registry(&self) -> GCLuaTable {
+        self.registry.clone()
+    }
+    /// Upvalue `idx` of the native function currently running.
+    pub fn native_upvalue(&self, idx: usize) -> GCLuaValue {
+        self.native_upvalues
+            .last()
+            .and_then(|u| u.get(idx).cloned())
+            .unwrap_or_else(|| LuaValue::Nil.to_gc())
+    }
+    pub fn get_global(&self, name: &str) -> GCLuaValue {
+        self.globals.borrow().get_str(name).unwrap_or_else(|| LuaValue::Nil.to_gc())
+    }
+    pub fn set_global(&mut self, name: &str, v: GCLuaValue) {
+        self.globals.borrow_mut().set_str(name, v);
+    }
+    /// The thread currently running; the main thread outside of coroutines.
+    pub fn current_thread(&self) -> GCLuaThread {
+        self.current_thread.clone()
+    }
+    /// Calls any callable value, Lua or native, with the given arguments.
+    /// Code called this way cannot yield.
+    pub fn call_value(&mut self, func: GCLuaValue, args: Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> {
+        self.enter_c_call()?;
+        let r = self.call_value_nested(func, args);
+        self.leave_c_call();
+        r
+    }
+    fn call_value_nested(&mut self, func: GCLuaValue, mut args: Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> {
+        let callee = match &*func.borrow() {
+            LuaValue::Function(f) => Ok(f.clone()),
+            LuaValue::NativeFunction(f) => Err(Some(f.clone())),
+            _ => Err(None),
+        };
+        match callee {
+            Ok(f) => self.call_func(f, args),
+            Err(Some(native)) => {
+                self.trace_native(&native, args.len());
+                self.state.nny += 1;
+                let r = self.call_native(&native, args);
+                self.state.nny -= 1;
+                r
+            }
+            Err(None) => match self.get_metamethod(&func, "__call") {
+                Some(handler) => {
+                    args.insert(0, func);
+                    self.call_value(handler, args)
+                }
+                None => bail!("attempt to call a {} value", func.borrow().type_name()),
+            },
+        }
+    }
+    fn call_func(&mut self, func: GCLuaFunction, args: Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> {
+        let depth = self.state.frames.len();
+        let base = self.state.stack.len();
+        self.state.nny += 1;
+        let pushed = self.push_frame(func, base, args, ReturnTo::Host, 0);
+        self.state.nny -= 1;
+        if let Err(e) = pushed {
+            self.unwind(depth, base);
+            return Err(e);
+        }
+        self.run_from(depth)
+    }
+    /// Runs the frames above `depth`, the lowest of them entered from Rust,
+    /// until that one returns.
+    fn run_from(&mut self, depth: usize) -> anyhow::Result<Vec<GCLuaValue>> {
+        let base = self.state.frames[depth].base;
+        self.state.nny += 1;
+        self.enter_memory_count();
+        let r = self.execute(depth);
+        self.leave_memory_count();
+        self.state.nny -= 1;
+        match r {
+            Ok(ExecOutcome::Return(v)) => Ok(v),
+            Ok(ExecOutcome::Yield(_)) => unreachable!("yield below a Rust call"),
+            // kept for `resume_run`
+            Err(e) if self.limits.suspended == Some(depth) => Err(e),
+            Err(e) => {
+                self.unwind(depth, base);
+                Err(e)
+            }
+        }
+    }
+    /// Drops the frames from `depth` up and the stack from `base` up.
+    fn unwind(&mut self, depth: usize, base: usize) {
+        self.close_upvalues(base);
+        self.state.frames.truncate(depth);
+        self.state.stack.truncate(base);
+    }
+    /// Resumes a suspended coroutine, returning the values it yields or
+    /// returns. An error raised inside the coroutine comes back as `Err` and
+    /// leaves it dead.
+    pub fn resume(&mut self, thread: &GCLuaThread, args: Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> {
+        let status = thread.borrow().status;
+        match status {
+            ThreadStatus::Suspended => (),
+            ThreadStatus::Dead => bail!("cannot resume dead coroutine"),
+            ThreadStatus::Running | ThreadStatus::Normal => bail!("cannot resume non-suspended coroutine"),
+        }
+        self.enter_c_call()?;
+        let r = self.resume_nested(thread, args);
+        self.leave_c_call();
+        r
+    }
+    fn resume_nested(&mut self, thread: &GCLuaThread, args: Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> {
+        let prev = std::mem::replace(&mut self.current_thread, thread.clone());
+        let saved = std::mem::take(&mut thread.borrow_mut().state);
+        let prev_state = std::mem::replace(&mut self.state, saved);
+        {
+            let mut p = prev.borrow_mut();
+            p.state = prev_state;
+            p.status = ThreadStatus::Normal;
+        }
+        thread.borrow_mut().status = ThreadStatus::Running;
+        let below = prev.borrow().state.frames.len();
+        self.limits.frames_below += below;
+        self.resumers.push(prev.clone());
+        self.enter_memory_count();
+        let result = self.run_thread(thread, args);
+        self.leave_memory_count();
+        self.resumers.pop();
+        self.limits.frames_below -= below;
+        let prev_state = std::mem::take(&mut prev.borrow_mut().state);
+        let state = std::mem::replace(&mut self.state, prev_state);
+        {
+            let mut t = thread.borrow_mut();
+            t.state = state;
+            t.status = match result {
+                Ok(ExecOutcome::Yield(_)) => ThreadStatus::Suspended,
+                _ => ThreadStatus::Dead,
+            };
+        }
+        prev.borrow_mut().status = ThreadStatus::Running;
+        self.current_thread = prev;
+        match result? {
+            ExecOutcome::Return(v) | ExecOutcome::Yield(v) => Ok(v),
+        }
+    }
+    fn run_thread(&mut self, thread: &GCLuaThread, args: Vec<GCLuaValue>) -> anyhow::Result<ExecOutcome> {
+        let body = thread.borrow_mut().body.take();
+        if let Some(body) = body {
+            self.push_frame(body, 0, args, ReturnTo::Host, 0)?;
+            return self.execute(0);
+        }
+        match self.state.resume_target.take() {
+            Some(ReturnTo::Register { dest, wanted }) => {
+                self.place_results(dest, wanted, args);
+                self.execute(0)
+            }
+            _ => Ok(ExecOutcome::Return(args)),
+        }
+    }
+    /// Suspends the running coroutine with `values` once the calling native
+    /// function returns. Only natives called directly from Lua code can yield.
+    pub fn yield_values(&mut self, values: Vec<GCLuaValue>) -> anyhow::Result<()> {
+        if self.current_thread.borrow().is_main {
+            bail!("attempt to yield from outside a coroutine");
+        }
+        if self.state.nny > 0 {
+            bail!("attempt to yield across metamethod/C-call boundary");
+        }
+        self.pending_yield = Some(values);
+        Ok(())
+    }
+    /// Enters `func` with R(0) at `base`; `tail_calls` counts the frames it
+    /// replaces.
+    fn push_frame(&mut self, func: GCLuaFunction, base: usize, args: Vec<GCLuaValue>, ret: ReturnTo, tail_calls: u32) -> anyhow::Result<()> {
+        self.check_call_depth()?;
+        let (num_param, vararg_flags, max_stack_size) = {
+            let f = func.borrow();
+            (f.prototype.num_param as usize, f.prototype.is_vararg, f.prototype.max_stack_size as usize)
+        };
+        let nil = LuaValue::Nil.to_gc();
+        if self.state.stack.len() < base + max_stack_size {
+            self.state.stack.resize(base + max_stack_size, nil.clone());
+        }
+        let nargs = args.len();
+        let mut args = args.into_iter();
+        for i in 0..max_stack_size {
+            let v = if i < num_param { args.next() } else { None };
+            self.state.stack[base + i] = v.unwrap_or_else(|| nil.clone());
+        }
+        let varargs: Vec<GCLuaValue> = if vararg_flags != 0 { args.collect() } else { Vec::new() };
+        if vararg_flags & VARARG_NEEDSARG != 0 {
+            // the 5.0-style `arg` table, held in the register after the parameters
+            let mut arg = LuaTable::new();
+            for (i, v) in varargs.iter().enumerate() {
+                arg.set_int(i as i64 + 1, v.clone());
+            }
+            arg.set_str("n", LuaValue::Number(varargs.len() as f64).to_gc());
+            self.state.stack[base + num_param] = LuaValue::Table(GCLuaTable::new(arg)).to_gc();
+        }
+        self.state.frames.push(CallFrame { func, base, pc: 0, varargs, top: None, ret, tail_calls, hook_pc: None });
+        if let Some(sink) = self.trace.as_mut() {
+            let frame = self.state.frames.last().unwrap();
+            let depth = self.state.frames.len();
+            sink.event(&TraceEvent::Call { func: &frame.func.borrow().prototype, depth, args: nargs });
+        }
+        if self.hook_mask().is_some_and(|m| m.call) {
+            self.run_hook(HookEvent::Call)?;
+        }
+        Ok(())
+    }
+    fn execute(&mut self, stop_depth: usize) -> anyhow::Result<ExecOutcome> {
+        while self.state.frames.len() > stop_depth {
+            self.charge_instruction(stop_depth)?;
+            self.check_memory()?;
+            let (func, pc) = {
+                let frame = self.state.frames.last_mut().unwrap();
+                frame.pc += 1;
+                (frame.func.clone(), frame.pc - 1)
+            };
+            let inst = func.borrow().prototype.list_instructions.get(pc).cloned();
+            if let (Some(sink), Some(_)) = (self.trace.as_mut(), &inst) {
+                let f = func.borrow();
+                let base = self.state.frames.last().unwrap().base;
+                let end = (base + f.prototype.max_stack_size as usize).min(self.state.stack.len());
+                let depth = self.state.frames.len();
+                sink.event(&TraceEvent::Instruction { func: &f.prototype, pc, depth, registers: &self.state.stack[base..end] });
+            }
+            if self.hook.is_some() && inst.is_some() {
+                self.instruction_hook(pc).map_err(|e| self.locate(e))?;
+            }
+            let step = match inst {
+                Some(inst) => match catch_unwind(AssertUnwindSafe(|| self.step(&func, &inst))) {
+                    Ok(step) => step.map_err(|e| self.locate(e))?,
+                    Err(panic) => {
+                        let what = panic
+                            .downcast_ref::<&str>()
+                            .map(|s| s.to_string())
+                            .or_else(|| panic.downcast_ref::<String>().cloned())
+                            .unwrap_or_default();
+                        return Err(self.locate(anyhow!("internal error in {:?}: {}", inst.opcode, what)));
                     }
-                    VMOpcode::CALL => {
-                        let reg_idx = inst.params[0].get_num_val();
-                        let b = inst.params[1].get_num_val();
-                        let c = inst.params[2].get_num_val();
-                        let mut last_result = 0;
-                        if b >= 2 {
-                            let reg = self.copy_register(reg_idx);
-                            let reg = reg.borrow();
-                            if let LuaValue::Function(f) = &*reg {
-                                if let Some(params) = self.call_func(f.clone(), reg_idx + 1, Some(b - 1)) {
-                                    let mut idx = reg_idx;
-                                    for p in params {
-                                        last_result = idx;
-                                        self.set_register(idx, p);
-                                        idx += 1;
-                                    }
-                                }
-                            }
-                        } else {
-                            let reg = self.copy_register(reg_idx);
-                            let reg = reg.borrow();
-                            if let LuaValue::Function(f) = &*reg {
-                                if let Some(params) = self.call_func(f.clone(), self.base, None) {
-                                    let mut idx = reg_idx;
-                                    for p in params {
-                                        last_result = idx;
-                                        self.set_register(idx, p);
-                                        idx += 1;
-                                    }
-                                }
-                            }
-                        }
-                        if c == 0 {
-                            self.top = Some(last_result + 1);
-                        }
+                },
+                None => self.do_return(Vec::new())?,
+            };
+            if let Step::Done(outcome) = step {
+                return Ok(outcome);
+            }
+        }
+        Ok(ExecOutcome::Return(Vec::new()))
+    }
+    fn step(&mut self, func: &GCLuaFunction, inst: &VMInst) -> anyhow::Result<Step> {
+        match inst.opcode {
+            VMOpcode::LOADK => {
+                let params = &inst.params;
+                let value = self.get_constant(params[1].get_num_val());
+                self.set_register(params[0].get_num_val(), value);
+            }
+            VMOpcode::LOADBOOL => {
+                let reg = inst.params[0].get_num_val();
+                self.set_register(reg, LuaValue::Boolean(inst.params[1].get_num_val() != 0).to_gc());
+                if inst.params[2].get_num_val() != 0 {
+                    self.jump(1);
+                }
+            }
+            VMOpcode::LOADNIL => {
+                for reg in inst.params[0].get_num_val()..=inst.params[1].get_num_val() {
+                    self.set_register(reg, LuaValue::Nil.to_gc());
+                }
+            }
+            VMOpcode::RETURN => {
+                let a = inst.params[0].get_num_val();
+                let b = inst.params[1].get_num_val();
+                let end = if b == 0 { self.top().unwrap_or(a) } else { a + b - 1 };
+                let returnval = (a..end).map(|r| self.copy_register(r)).collect();
+                return self.do_return(returnval);
+            }
+            VMOpcode::ADD | VMOpcode::SUB | VMOpcode::MUL | VMOpcode::DIV | VMOpcode::MOD | VMOpcode::POW => {
+                let (out, p1, p2) = self.get_abc(inst);
+                let v = self.arith(&inst.opcode, &p1, &p2)?;
+                self.set_register(out, v);
+            }
+            VMOpcode::UNM => {
+                let v = self.copy_register(inst.params[1].get_num_val());
+                let v = self.arith(&inst.opcode, &v, &v)?;
+                self.set_register(inst.params[0].get_num_val(), v);
+            }
+            VMOpcode::NOT => {
+                let v = self.copy_register(inst.params[1].get_num_val()).borrow().truthy();
+                self.set_register(inst.params[0].get_num_val(), LuaValue::Boolean(!v).to_gc());
+            }
+            VMOpcode::LEN => {
+                let v = self.copy_register(inst.params[1].get_num_val());
+                let len = self.len(&v)?;
+                self.set_register(inst.params[0].get_num_val(), len);
+            }
+            VMOpcode::CONCAT => {
+                let mut out = Vec::new();
+                for reg in inst.params[1].get_num_val()..=inst.params[2].get_num_val() {
+                    let v = self.copy_register(reg);
+                    match &*v.borrow() {
+                        LuaValue::String(s) => out.extend_from_slice(s.as_bytes()),
+                        LuaValue::Number(n) => out.extend_from_slice(fmt_number(*n).as_bytes()),
+                        other => bail!("attempt to concatenate a {} value", other.type_name()),
+                    };
+                }
+                self.set_register(inst.params[0].get_num_val(), LuaValue::String(out.into()).to_gc());
+            }
+            VMOpcode::JMP => {
+                self.jump(inst.params[0].get_signed_val());
+            }
+            VMOpcode::EQ => {
+                let (a, p1, p2) = self.get_abc(inst);
+                if self.equals(&p1, &p2)? != (a != 0) {
+                    self.jump(1);
+                }
+            }
+            VMOpcode::LT => {
+                let (a, p1, p2) = self.get_abc(inst);
+                if self.less_than(&p1, &p2, false)? != (a != 0) {
+                    self.jump(1);
+                }
+            }
+            VMOpcode::LE => {
+                let (a, p1, p2) = self.get_abc(inst);
+                if self.less_than(&p1, &p2, true)? != (a != 0) {
+                    self.jump(1);
+                }
+            }
+            VMOpcode::TEST => {
+                let v = self.copy_register(inst.params[0].get_num_val()).borrow().truthy();
+                if v != (inst.params[1].get_num_val() != 0) {
+                    self.jump(1);
+                }
+            }
+            VMOpcode::TESTSET => {
+                let v = self.copy_register(inst.params[1].get_num_val());
+                if v.borrow().truthy() == (inst.params[2].get_num_val() != 0) {
+                    self.set_register(inst.params[0].get_num_val(), v);
+                } else {
+                    self.jump(1);
+                }
+            }
+            VMOpcode::FORPREP => {
+                let a = inst.params[0].get_num_val();
+                let init = self.for_number(a, "initial")?;
+                self.for_number(a + 1, "limit")?;
+                let step = self.for_number(a + 2, "step")?;
+                self.set_register(a, LuaValue::Number(init - step).to_gc());
+                self.jump(inst.params[1].get_signed_val());
+            }
+            VMOpcode::FORLOOP => {
+                let a = inst.params[0].get_num_val();
+                let step = self.for_number(a + 2, "step")?;
+                let idx = self.for_number(a, "initial")? + step;
+                let limit = self.for_number(a + 1, "limit")?;
+                if (step > 0.0 && idx <= limit) || (step <= 0.0 && limit <= idx) {
+                    self.jump(inst.params[1].get_signed_val());
+                    self.set_register(a, LuaValue::Number(idx).to_gc());
+                    self.set_register(a + 3, LuaValue::Number(idx).to_gc());
+                }
+            }
+            VMOpcode::TFORLOOP => {
+                let a = inst.params[0].get_num_val();
+                let c = inst.params[1].get_num_val();
+                let iter = self.copy_register(a);
+                let args = vec![self.copy_register(a + 1), self.copy_register(a + 2)];
+                let mut results = self.call_value(iter, args)?.into_iter();
+                for i in 0..c {
+                    self.set_register(a + 3 + i, results.next().unwrap_or_else(|| LuaValue::Nil.to_gc()));
+                }
+                let control = self.copy_register(a + 3);
+                if control.is_nil() {
+                    self.jump(1);
+                } else {
+                    self.set_register(a + 2, control);
+                }
+            }
+            VMOpcode::NEWTABLE => {
+                let reg = inst.params[0].get_num_val();
+                self.set_register(reg, LuaValue::Table(GCLuaTable::default()).to_gc());
+            }
+            VMOpcode::GETTABLE => {
+                let (out, table, key) = self.get_abc(inst);
+                if !self.has_fields(&table, "__index") {
+                    return Err(self.operand_error("index", inst.params[1].get_num_val(), &table));
+                }
+                let v = self.index(&table, &key)?;
+                self.set_register(out, v);
+            }
+            VMOpcode::SETTABLE => {
+                let (reg, key, value) = self.get_abc(inst);
+                let table = self.copy_register(reg);
+                if !self.has_fields(&table, "__newindex") {
+                    return Err(self.operand_error("index", reg, &table));
+                }
+                self.set_index(&table, key, value)?;
+            }
+            VMOpcode::SELF => {
+                let (out, object, key) = self.get_abc(inst);
+                if !self.has_fields(&object, "__index") {
+                    return Err(self.operand_error("index", inst.params[1].get_num_val(), &object));
+                }
+                self.set_register(out + 1, object.clone());
+                let method = self.index(&object, &key)?;
+                self.set_register(out, method);
+            }
+            VMOpcode::SETLIST => {
+                let a = inst.params[0].get_num_val();
+                let b = inst.params[1].get_num_val();
+                let mut c = inst.params[2].get_num_val();
+                if c == 0 {
+                    // the block number did not fit in C and is the next word
+                    let pc = self.state.frames.last().unwrap().pc;
+                    c = func.borrow().prototype.list_instructions.get(pc).map_or(0, VMInst::to_u32);
+                    self.jump(1);
+                    if c == 0 {
+                        bail!("SETLIST is missing its block number");
                     }
-                    VMOpcode::TAILCALL => {
-                        let reg_idx = inst.params[0].get_num_val();
-                        let b = inst.params[1].get_num_val();
-                        let mut last_result = 0;
-                        if b >= 2 {
-                            let reg = self.copy_register(reg_idx);
-                            let reg = reg.borrow();
-                            if let LuaValue::Function(f) = &*reg {
-                                if let Some(params) = self.call_func(f.clone(), reg_idx + 1, Some(b - 1)) {
-                                    let mut idx = reg_idx;
-                                    for p in params {
-                                        self.set_register(idx, p);
-                                        last_result = idx;
-                                        idx += 1;
-                                    }
-                                }
-                            }
-                        } else {
-                            println!("LSTH {}", reg_idx);
-                            let reg = self.copy_register(reg_idx);
-                            println!("AF");
-                            let reg = reg.borrow();
-                            if let LuaValue::Function(f) = &*reg {
-                                if let Some(params) = self.call_func(f.clone(), self.base, None) {
-                                    let mut idx = reg_idx;
-                                    for p in params {
-                                        self.set_register(idx, p);
-                                        last_result = idx;
-                                        idx += 1;
-                                    }
-                                }
-                            }
-                        }
-                        self.top = Some(last_result + 1);
+                }
+                let count = if b == 0 { self.top().unwrap_or(a + 1) - a - 1 } else { b };
+                let table = self.copy_register(a);
+                if let LuaValue::Table(t) = &*table.borrow() {
+                    let mut t = t.borrow_mut();
+                    for i in 1..=count {
+                        t.set_int(((c - 1) * FIELDS_PER_FLUSH + i) as i64, self.copy_register(a + i));
                     }
-                    VMOpcode::GETUPVAL => {
-                        let upvalue_num = inst.params[1].get_num_val();
-                        let register_num = inst.params[0].get_num_val();
-                        let upvalue = func.borrow().upvalues.get(&upvalue_num).unwrap().clone();
-                        self.set_register(register_num, upvalue);
+                };
+            }
+            VMOpcode::CLOSURE => {
+                let reg = inst.params[0].get_num_val();
+                let closure =
+                    func.borrow().prototype.list_fnproto[inst.params[1].get_num_val() as usize].clone();
+                // each upvalue is described by a MOVE (enclosing local) or
+                // GETUPVAL (enclosing upvalue) pseudo-instruction
+                let mut upvalues = Vec::with_capacity(closure.num_upval as usize);
+                for _ in 0..closure.num_upval {
+                    let pc = self.state.frames.last().unwrap().pc;
+                    let pseudo = func.borrow().prototype.list_instructions[pc].clone();
+                    self.jump(1);
+                    let idx = pseudo.params[1].get_num_val();
+                    match &pseudo.opcode {
+                        VMOpcode::MOVE => upvalues.push(self.find_upvalue(self.base() + idx as usize)),
+                        VMOpcode::GETUPVAL => upvalues.push(func.borrow().upvalues[idx as usize].clone()),
+                        other => bail!("unexpected {:?} in closure upvalue list", other),
                     }
-                    VMOpcode::SETUPVAL => {
-                        let upvalue_num = inst.params[1].get_num_val();
-                        let register_num = inst.params[0].get_num_val();
-                        let upvalue = &mut func.borrow_mut().upvalues;
-                        upvalue.remove(&upvalue_num);
-                        upvalue.insert(upvalue_num, self.copy_register(register_num));
+                }
+                self.set_register(reg, LuaValue::Function(LuaFunction::new(closure, upvalues).to_gc()).to_gc());
+            }
+            VMOpcode::MOVE => {
+                let b = self.copy_register(inst.params[1].get_num_val());
+                self.set_register(inst.params[0].get_num_val(), b);
+            }
+            VMOpcode::SETGLOBAL => {
+                let reg = inst.params[0].get_num_val();
+                let name = self.get_constant(inst.params[1].get_num_val());
+                let v = self.copy_register(reg);
+                let globals = LuaValue::Table(self.globals.clone()).to_gc();
+                self.set_index(&globals, name, v)?;
+            }
+            VMOpcode::GETGLOBAL => {
+                let reg = inst.params[0].get_num_val();
+                let name = self.get_constant(inst.params[1].get_num_val());
+                let globals = LuaValue::Table(self.globals.clone()).to_gc();
+                let v = self.index(&globals, &name)?;
+                self.set_register(reg, v);
+            }
+            VMOpcode::CALL | VMOpcode::TAILCALL => {
+                let reg_idx = inst.params[0].get_num_val();
+                let b = inst.params[1].get_num_val();
+                let nargs = if b == 0 { self.top().unwrap_or(reg_idx + 1) - reg_idx - 1 } else { b - 1 };
+                let args = (0..nargs).map(|i| self.copy_register(reg_idx + 1 + i)).collect();
+                let func = self.copy_register(reg_idx);
+                let callable = matches!(*func.borrow(), LuaValue::Function(_) | LuaValue::NativeFunction(_));
+                if !callable && self.get_metamethod(&func, "__call").is_none() {
+                    return Err(self.operand_error("call", reg_idx, &func));
+                }
+                let dest = self.base() + reg_idx as usize;
+                if inst.opcode == VMOpcode::TAILCALL {
+                    let callee = match &*func.borrow() {
+                        LuaValue::Function(f) => Some(f.clone()),
+                        _ => None,
+                    };
+                    if let Some(callee) = callee {
+                        let frame = self.state.frames.pop().unwrap();
+                        self.close_upvalues(frame.base);
+                        self.push_frame(callee, frame.base, args, frame.ret, frame.tail_calls + 1)?;
+                        return Ok(Step::Continue);
                     }
-                    _ => (),
+                    // anything else is called normally; the RETURN that
+                    // always follows a TAILCALL passes the results on
+                    return self.call_at(func, args, dest, None);
+                }
+                let c = inst.params[2].get_num_val();
+                let wanted = if c == 0 { None } else { Some(c as usize - 1) };
+                return self.call_at(func, args, dest, wanted);
+            }
+            VMOpcode::VARARG => {
+                let a = inst.params[0].get_num_val();
+                let b = inst.params[1].get_num_val();
+                let varargs = self.state.frames.last().unwrap().varargs.clone();
+                let n = if b == 0 { varargs.len() } else { b as usize - 1 };
+                let mut varargs = varargs.into_iter();
+                for i in 0..n {
+                    self.set_register(a + i as u32, varargs.next().unwrap_or_else(|| LuaValue::Nil.to_gc()));
+                }
+                if b == 0 {
+                    let top = self.base() + (a as usize) + n;
+                    self.state.frames.last_mut().unwrap().top = Some(top);
+                }
+            }
+            VMOpcode::CLOSE => {
+                let level = self.base() + inst.params[0].get_num_val() as usize;
+                self.close_upvalues(level);
+            }
+            VMOpcode::GETUPVAL => {
+                let upvalue_num = inst.params[1].get_num_val();
+                let register_num = inst.params[0].get_num_val();
+                let upvalue = func.borrow().upvalues[upvalue_num as usize].clone();
+                let v = self.get_upvalue(&upvalue);
+                self.set_register(register_num, v);
+            }
+            VMOpcode::SETUPVAL => {
+                let upvalue_num = inst.params[1].get_num_val();
+                let register_num = inst.params[0].get_num_val();
+                let upvalue = func.borrow().upvalues[upvalue_num as usize].clone();
+                let v = self.copy_register(register_num);
+                self.set_upvalue(&upvalue, v);
+            }
+        }
+        Ok(Step::Continue)
+    }
+    /// Calls `func` for a CALL instruction whose results go to `dest`. Lua
+    /// functions get a new frame instead of a nested interpreter loop.
+    fn call_at(&mut self, func: GCLuaValue, mut args: Vec<GCLuaValue>, dest: usize, wanted: Option<usize>) -> anyhow::Result<Step> {
+        let callee = match &*func.borrow() {
+            LuaValue::Function(f) => Ok(f.clone()),
+            LuaValue::NativeFunction(f) => Err(Some(f.clone())),
+            _ => Err(None),
+        };
+        match callee {
+            Ok(f) => {
+                self.push_frame(f, dest + 1, args, ReturnTo::Register { dest, wanted }, 0)?;
+                Ok(Step::Continue)
+            }
+            Err(Some(native)) => {
+                self.trace_native(&native, args.len());
+                let results = self.call_native(&native, args)?;
+                if let Some(values) = self.pending_yield.take() {
+                    self.state.resume_target = Some(ReturnTo::Register { dest, wanted });
+                    return Ok(Step::Done(ExecOutcome::Yield(values)));
+                }
+                self.place_results(dest, wanted, results);
+                Ok(Step::Continue)
+            }
+            Err(None) => match self.get_metamethod(&func, "__call") {
+                Some(handler) => {
+                    args.insert(0, func);
+                    self.call_at(handler, args, dest, wanted)
                 }
-                None
-            }));
-            if x.is_err() {
-                panic!("Panicked on {:?} instruction", inst.opcode);
+                None => bail!("attempt to call a {} value", func.borrow().type_name()),
+            },
+        }
+    }
+    fn trace_native(&mut self, native: &LuaNativeFunction, args: usize) {
+        if let Some(sink) = self.trace.as_mut() {
+            let depth = self.state.frames.len();
+            sink.event(&TraceEvent::NativeCall { name: &native.name, depth, args });
+        }
+    }
+    /// Runs a native function, with the call and return hooks around it.
+    fn call_native(&mut self, native: &LuaNativeFunction, args: Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> {
+        if self.hook_mask().is_some_and(|m| m.call) {
+            self.run_hook(HookEvent::Call)?;
+        }
+        let results = native.call(self, args)?;
+        if self.hook_mask().is_some_and(|m| m.ret) {
+            self.run_hook(HookEvent::Return)?;
+        }
+        Ok(results)
+    }
+    fn do_return(&mut self, results: Vec<GCLuaValue>) -> anyhow::Result<Step> {
+        if let Some(sink) = self.trace.as_mut() {
+            sink.event(&TraceEvent::Return { depth: self.state.frames.len(), results: results.len() });
+        }
+        if self.hook_mask().is_some_and(|m| m.ret) {
+            self.run_hook(HookEvent::Return)?;
+            for _ in 0..self.state.frames.last().unwrap().tail_calls {
+                self.run_hook(HookEvent::TailReturn)?;
+            }
+        }
+        let frame = self.state.frames.pop().unwrap();
+        self.close_upvalues(frame.base);
+        Ok(match frame.ret {
+            ReturnTo::Host => {
+                self.state.stack.truncate(frame.base);
+                Step::Done(ExecOutcome::Return(results))
             }
-            self.last_op = Some(inst.clone());
-            if let Some(x) = x.unwrap() {
-                return Some(x);
+            ReturnTo::Register { dest, wanted } => {
+                self.place_results(dest, wanted, results);
+                Step::Continue
+            }
+        })
+    }
+    /// Stores call results at absolute stack index `dest`, padding with nil
+    /// up to `wanted`; `None` keeps them all and sets the frame's top.
+    fn place_results(&mut self, dest: usize, wanted: Option<usize>, results: Vec<GCLuaValue>) {
+        let n = wanted.unwrap_or(results.len());
+        let mut results = results.into_iter();
+        for i in 0..n {
+            self.set_stack(dest + i, results.next().unwrap_or_else(|| LuaValue::Nil.to_gc()));
+        }
+        if wanted.is_none() {
+            if let Some(frame) = self.state.frames.last_mut() {
+                frame.top = Some(dest + n);
             }
         }
-        None
+    }
+    fn find_upvalue(&mut self, idx: usize) -> GCUpValue {
+        let open = &mut self.state.open_upvalues;
+        let pos = open.partition_point(|u| u.open_index().unwrap() < idx);
+        if let Some(u) = open.get(pos) {
+            if u.open_index() == Some(idx) {
+                return u.clone();
+            }
+        }
+        let uv = GCUpValue::new(UpValue::Open(self.current_thread.clone(), idx));
+        open.insert(pos, uv.clone());
+        uv
+    }
+    /// Closes every open upvalue at or above stack index `level`.
+    fn close_upvalues(&mut self, level: usize) {
+        let pos = self.state.open_upvalues.partition_point(|u| u.open_index().unwrap() < level);
+        for uv in self.state.open_upvalues.split_off(pos) {
+            let v = self.state.stack[uv.open_index().unwrap()].clone();
+            *uv.borrow_mut() = UpValue::Closed(v);
+        }
+    }
+    fn get_upvalue(&self, uv: &GCUpValue) -> GCLuaValue {
+        match &*uv.borrow() {
+            UpValue::Closed(v) => v.clone(),
+            UpValue::Open(thread, idx) if thread.ptr() == self.current_thread.ptr() => self.state.stack[*idx].clone(),
+            UpValue::Open(thread, idx) => thread.borrow().state.stack[*idx].clone(),
+        }
+    }
+    fn set_upvalue(&mut self, uv: &GCUpValue, v: GCLuaValue) {
+        let (thread, idx) = match &mut *uv.borrow_mut() {
+            UpValue::Closed(c) => {
+                *c = v;
+                return;
+            }
+            UpValue::Open(thread, idx) => (thread.clone(), *idx),
+        };
+        if thread.ptr() == self.current_thread.ptr() {
+            self.state.stack[idx] = v;
+        } else {
+            thread.borrow_mut().state.stack[idx] = v;
+        }
+    }
+    /// `obj[key]`, honouring `__index`.
+    pub fn index(&mut self, obj: &GCLuaValue, key: &GCLuaValue) -> anyhow::Result<GCLuaValue> {
+        let mut obj = obj.clone();
+        for _ in 0..MAX_TAG_LOOP {
+            let raw = match &*obj.borrow() {
+                LuaValue::Table(t) => Some(
+                    LuaKey::from_value(key)
+                        .ok()
+                        .and_then(|k| t.borrow().get(&k)),
+                ),
+                _ => None,
+            };
+            if let Some(Some(v)) = raw {
+                return Ok(v);
+            }
+            let handler = match self.get_metamethod(&obj, "__index") {
+                Some(h) => h,
+                None if raw.is_some() => return Ok(LuaValue::Nil.to_gc()),
+                None => bail!("attempt to index a {} value", obj.borrow().type_name()),
+            };
+            if matches!(*handler.borrow(), LuaValue::Function(_) | LuaValue::NativeFunction(_)) {
+                let v = self.call_value(handler, vec![obj, key.clone()])?;
+                return Ok(v.into_iter().next().unwrap_or_else(|| LuaValue::Nil.to_gc()));
+            }
+            obj = handler;
+        }
+        bail!("loop in gettable")
+    }
+    /// `obj[key] = value`, honouring `__newindex`.
+    pub fn set_index(&mut self, obj: &GCLuaValue, key: GCLuaValue, value: GCLuaValue) -> anyhow::Result<()> {
+        let mut obj = obj.clone();
+        for _ in 0..MAX_TAG_LOOP {
+            let table = match &*obj.borrow() {
+                LuaValue::Table(t) => Some(t.clone()),
+                _ => None,
+            };
+            let handler = self.get_metamethod(&obj, "__newindex");
+            if let Some(t) = &table {
+                let k = LuaKey::from_value(&key)?;
+                let present = t.borrow().get(&k).is_some();
+                if present || handler.is_none() {
+                    t.borrow_mut().set(k, value);
+                    return Ok(());
+                }
+            }
+            let handler = match handler {
+                Some(h) => h,
+                None => bail!("attempt to index a {} value", obj.borrow().type_name()),
+            };
+            if matches!(*handler.borrow(), LuaValue::Function(_) | LuaValue::NativeFunction(_)) {
+                self.call_value(handler, vec![obj, key, value])?;
+                return Ok(());
+            }
+            obj = handler;
+        }
+        bail!("loop in settable")
+    }
+    pub fn get_metatable(&self, v: &GCLuaValue) -> Option<GCLuaTable> {
+        match &*v.borrow() {
+            LuaValue::Table(t) => t.borrow().metatable.clone(),
+            LuaValue::UserData(u) => u.data().metatable.clone(),
+            other => self.type_metatables.get(other.type_name()).cloned(),
+        }
+    }
+    /// Sets the metatable of `v`, ignoring `__metatable`. Values other than
+    /// tables and full userdata share one metatable per type.
+    pub fn set_metatable(&mut self, v: &GCLuaValue, metatable: Option<GCLuaTable>) {
+        match &*v.borrow() {
+            LuaValue::Table(t) => t.borrow_mut().metatable = metatable,
+            LuaValue::UserData(u) => u.data_mut().metatable = metatable,
+            other => match metatable {
+                Some(mt) => {
+                    self.type_metatables.insert(other.type_name(), mt);
+                }
+                None => {
+                    self.type_metatables.remove(other.type_name());
+                }
+            },
+        }
+    }
+    pub fn get_metamethod(&self, v: &GCLuaValue, event: &str) -> Option<GCLuaValue> {
+        self.get_metatable(v)?.borrow().get_str(event)
+    }
+    /// Whether `v` is a table or has the metamethod `event` to stand in for one.
+    fn has_fields(&self, v: &GCLuaValue, event: &str) -> bool {
+        matches!(*v.borrow(), LuaValue::Table(_)) || self.get_metamethod(v, event).is_some()
+    }
+    /// Arithmetic on numbers or numeric strings, falling back to the operands'
+    /// `__add`-style metamethods. `UNM` passes its operand twice.
+    fn arith(&mut self, op: &VMOpcode, a: &GCLuaValue, b: &GCLuaValue) -> anyhow::Result<GCLuaValue> {
+        if let (Ok(x), Ok(y)) = (f64::from_lua(a), f64::from_lua(b)) {
+            let v = match op {
+                VMOpcode::ADD => x + y,
+                VMOpcode::SUB => x - y,
+                VMOpcode::MUL => x * y,
+                VMOpcode::DIV => x / y,
+                VMOpcode::MOD => x - (x / y).floor() * y,
+                VMOpcode::POW => x.powf(y),
+                VMOpcode::UNM => -x,
+                _ => unreachable!("{:?} is not arithmetic", op),
+            };
+            return Ok(v.into_lua());
+        }
+        let event = match op {
+            VMOpcode::ADD => "__add",
+            VMOpcode::SUB => "__sub",
+            VMOpcode::MUL => "__mul",
+            VMOpcode::DIV => "__div",
+            VMOpcode::MOD => "__mod",
+            VMOpcode::POW => "__pow",
+            _ => "__unm",
+        };
+        if let Some(h) = self.get_metamethod(a, event).or_else(|| self.get_metamethod(b, event)) {
+            let r = self.call_value(h, vec![a.clone(), b.clone()])?;
+            return Ok(r.into_iter().next().unwrap_or_else(|| LuaValue::Nil.to_gc()));
+        }
+        let bad = if f64::from_lua(a).is_err() { a } else { b };
+        bail!("attempt to perform arithmetic on a {} value", bad.borrow().type_name())
+    }
+    fn len(&mut self, v: &GCLuaValue) -> anyhow::Result<GCLuaValue> {
+        let len = match &*v.borrow() {
+            LuaValue::String(s) => Some(s.len()),
+            LuaValue::Table(t) => Some(t.borrow().len()),
+            _ => None,
+        };
+        if let Some(len) = len {
+            return Ok(LuaValue::Number(len as f64).to_gc());
+        }
+        match self.get_metamethod(v, "__len") {
+            Some(h) => Ok(self.call_value(h, vec![v.clone()])?.into_iter().next().unwrap_or_else(|| LuaValue::Nil.to_gc())),
+            None => bail!("attempt to get length of a {} value", v.borrow().type_name()),
+        }
+    }
+    /// `a == b`, honouring `__eq` for two tables or two userdata that share
+    /// the same handler.
+    pub fn equals(&mut self, a: &GCLuaValue, b: &GCLuaValue) -> anyhow::Result<bool> {
+        if a.borrow().raw_equals(&b.borrow()) {
+            return Ok(true);
+        }
+        let comparable = matches!(
+            (&*a.borrow(), &*b.borrow()),
+            (LuaValue::Table(_), LuaValue::Table(_)) | (LuaValue::UserData(_), LuaValue::UserData(_))
+        );
+        if !comparable {
+            return Ok(false);
+        }
+        match (self.get_metamethod(a, "__eq"), self.get_metamethod(b, "__eq")) {
+            (Some(h1), Some(h2)) if h1.borrow().raw_equals(&h2.borrow()) => {
+                let r = self.call_value(h1, vec![a.clone(), b.clone()])?;
+                Ok(r.first().map(|v| v.borrow().truthy()).unwrap_or(false))
+            }
+            _ => Ok(false),
+        }
+    }
+    /// Converts a value to a string the way `tostring` does, honouring
+    /// `__tostring`.
+    pub fn tostring(&mut self, v: &GCLuaValue) -> anyhow::Result<String> {
+        match self.get_metamethod(v, "__tostring") {
+            Some(h) => {
+                let r = self.call_value(h, vec![v.clone()])?;
+                Ok(r.first().map(|v| v.borrow().as_string(false)).unwrap_or_else(|| "nil".to_string()))
+            }
+            None => Ok(v.borrow().as_string(false)),
+        }
+    }
+    fn less_than(&mut self, a: &GCLuaValue, b: &GCLuaValue, or_equal: bool) -> anyhow::Result<bool> {
+        match (&*a.borrow(), &*b.borrow()) {
+            (LuaValue::Number(x), LuaValue::Number(y)) => return Ok(if or_equal { x <= y } else { x < y }),
+            (LuaValue::String(x), LuaValue::String(y)) => return Ok(if or_equal { x <= y } else { x < y }),
+            _ => (),
+        }
+        let event = if or_equal { "__le" } else { "__lt" };
+        if let Some(h) = self.get_metamethod(a, event).or_else(|| self.get_metamethod(b, event)) {
+            let r = self.call_value(h, vec![a.clone(), b.clone()])?;
+            return Ok(r.first().map(|v| v.borrow().truthy()).unwrap_or(false));
+        }
+        let (ta, tb) = (a.borrow().type_name(), b.borrow().type_name());
+        if ta == tb {
+            bail!("attempt to compare two {} values", ta)
+        } else {
+            bail!("attempt to compare {} with {}", ta, tb)
+        }
+    }
+    fn for_number(&mut self, reg: u32, what: &str) -> anyhow::Result<f64> {
+        match self.copy_register(reg).borrow().to_number() {
+            Some(n) => Ok(n),
+            None => bail!("'for' {} value must be a number", what),
+        }
     }
     fn get_abc(&mut self, inst: &VMInst) -> (u32, GCLuaValue, GCLuaValue) {
         let out = inst.params[0].get_num_val();
@@ -370,7 +1221,6 @@ impl LuaVM {
         let p1 = if p1_const {
             self.get_constant(p1loc & !MASK_CBIT)
         } else {
-            println!("P1 is reg {}", p1loc & !MASK_CBIT);
             self.copy_register(p1loc & !MASK_CBIT)
         };
 
@@ -381,45 +1231,39 @@ impl LuaVM {
         };
         (out, p1, p2)
     }
-    fn cast<'a, T: 'static>(&self, val: &'a LuaValue) -> &'a T {
-        let any = val as &dyn Any;
-        any.downcast_ref::<T>().unwrap()
-    }
-    fn get_constant(&self, idx: u32) -> GCLuaValue {
-        println!("getting constant: {}", idx);
-        self.constants.get(&idx).unwrap().clone().as_value()
-    }
-    fn copy_global(&self, idx: u32) -> GCLuaValue {
-        self.globals.get(&idx).unwrap().clone()
-    }
-    fn set_register(&mut self, mut idx: u32, val: GCLuaValue) {
-        idx += self.base;
-        self.registers.remove(&idx);
-        self.registers.insert(idx, val);
+    fn get_constant(&mut self, idx: u32) -> GCLuaValue {
+        let value = self.state.frames.last().unwrap().func.borrow().prototype.list_const[idx as usize].clone().as_value();
+        if let Some(sink) = self.trace.as_mut() {
+            sink.event(&TraceEvent::Constant { index: idx, depth: self.state.frames.len(), value: &value });
+        }
+        value
     }
-    fn get_register(&self, mut idx: u32) -> &GCLuaValue {
-        idx += self.base;
-        println!("getting register: {}", idx);
-        self.registers.get(&idx).as_ref().unwrap()
+    fn base(&self) -> usize {
+        self.state.frames.last().map(|f| f.base).unwrap_or(0)
     }
-    fn take_register(&mut self, mut idx: u32) -> GCLuaValue {
-        idx += self.base;
-        self.registers.remove(&idx).unwrap()
+    /// The current frame's top as a register number, if a multi-result
+    /// instruction set it.
+    fn top(&self) -> Option<u32> {
+        let frame = self.state.frames.last()?;
+        frame.top.map(|t| (t - frame.base) as u32)
     }
-    fn try_take_register(&mut self, mut idx: u32) -> Option<GCLuaValue> {
-        idx += self.base;
-        self.registers.remove(&idx)
+    fn jump(&mut self, offset: i32) {
+        let frame = self.state.frames.last_mut().unwrap();
+        frame.pc = (frame.pc as i32 + offset) as usize;
     }
-    fn copy_register(&mut self, mut idx: u32) -> GCLuaValue {
-        idx += self.base;
-        self.registers.get(&idx).unwrap().clone()
+    fn set_stack(&mut self, idx: usize, val: GCLuaValue) {
+        if idx >= self.state.stack.len() {
+            self.state.stack.resize(idx + 1, LuaValue::Nil.to_gc());
+        }
+        self.state.stack[idx] = val;
     }
-    fn try_copy_register(&mut self, mut idx: u32) -> Option<GCLuaValue> {
-        idx += self.base;
-        self.registers.get(&idx).cloned()
+    fn set_register(&mut self, idx: u32, val: GCLuaValue) {
+        self.set_stack(self.base() + idx as usize, val);
     }
-    fn set_global(&mut self, idx: u32, v: GCLuaValue) {
-        self.globals.remove(&idx);
-        self.globals.insert(idx, v);
+    fn copy_register(&mut self, idx: u32) -> GCLuaValue {
+        match self.state.stack.get(self.base() + idx as usize) {
+            Some(v) => v.clone(),
+            None => LuaValue::Nil.to_gc(),
+        }
     }
 }
@@ -0,0 +1,421 @@
+//! Optimization passes over compiled `FunctionBlock`s. Each pass rewrites the
+//! encoded instructions in place; removing instructions re-targets every jump
+//! and remaps line info and local variable ranges, so the result can be
+//! written back out with `LuaChunk::write`.
+
+use std::collections::HashMap;
+
+use gc::Gc;
+
+use super::{
+    cfg::{is_test, slots, Cfg, Slot},
+    chunk_parser::{FunctionBlock, LocalVar, LuaChunk, LuaConstant, VARARG_NEEDSARG},
+    string::LuaString,
+    instruction::{
+        arg_a, arg_b, arg_bx, arg_c, arg_sbx, create_abx, op_of, set_b, set_bx, set_c, set_sbx, VMInst, VMOpcode,
+        MASK_CBIT,
+    },
+};
+
+const MAXARG_BX: u32 = (1 << 18) - 1;
+
+/// Which passes `optimize` runs. They run in the order of the fields.
+#[derive(Debug, Clone)]
+pub struct Passes {
+    /// Replaces arithmetic on two number constants with a LOADK of the
+    /// result. Division by zero and NaN results are left for run time.
+    pub fold_constants: bool,
+    /// Points jumps that land on another JMP at its destination, and removes
+    /// jumps to the next instruction.
+    pub collapse_jumps: bool,
+    /// Removes instructions no path from the entry reaches.
+    pub remove_unreachable: bool,
+    /// Removes `MOVE a a`, and `MOVE b a` right after `MOVE a b`.
+    pub remove_moves: bool,
+    /// Removes LOADNILs of registers that are still nil from function entry.
+    pub remove_loadnils: bool,
+    /// Merges equal constants and drops unused ones.
+    pub dedup_constants: bool,
+}
+impl Default for Passes {
+    fn default() -> Self {
+        Self {
+            fold_constants: true,
+            collapse_jumps: true,
+            remove_unreachable: true,
+            remove_moves: true,
+            remove_loadnils: true,
+            dedup_constants: true,
+        }
+    }
+}
+impl Passes {
+    /// No passes at all.
+    pub fn none() -> Self {
+        Self {
+            fold_constants: false,
+            collapse_jumps: false,
+            remove_unreachable: false,
+            remove_moves: false,
+            remove_loadnils: false,
+            dedup_constants: false,
+        }
+    }
+}
+
+pub fn optimize_chunk(chunk: &LuaChunk, passes: &Passes) -> anyhow::Result<LuaChunk> {
+    Ok(LuaChunk { header: chunk.header.clone(), func: optimize(&chunk.func, passes)? })
+}
+
+/// Runs `passes` over `func` and the functions nested in it.
+pub fn optimize(func: &FunctionBlock, passes: &Passes) -> anyhow::Result<FunctionBlock> {
+    let mut code = Code::new(func);
+    if passes.fold_constants {
+        code.fold_constants();
+    }
+    if passes.collapse_jumps {
+        code.collapse_jumps();
+    }
+    if passes.remove_unreachable {
+        code.remove_unreachable();
+        // removed code can leave jumps that now land on the next instruction
+        if passes.collapse_jumps {
+            code.collapse_jumps();
+        }
+    }
+    if passes.remove_moves {
+        code.remove_moves();
+    }
+    if passes.remove_loadnils {
+        code.remove_loadnils();
+    }
+    if passes.dedup_constants {
+        code.dedup_constants();
+    }
+    let mut list_fnproto = Vec::new();
+    for p in &func.list_fnproto {
+        list_fnproto.push(Gc::new(optimize(p, passes)?));
+    }
+    let mut out = func.clone();
+    out.list_instructions = VMInst::decode_all(&code.code)?;
+    out.list_const = code.constants.into_iter().map(Gc::new).collect();
+    out.list_fnproto = list_fnproto;
+    out.line_info = code.lines;
+    out.local_vars = code.local_vars;
+    Ok(out)
+}
+
+fn is_arith(op: &VMOpcode) -> bool {
+    matches!(op, VMOpcode::ADD | VMOpcode::SUB | VMOpcode::MUL | VMOpcode::DIV | VMOpcode::MOD | VMOpcode::POW)
+}
+/// Ops whose B and C fields may name constants.
+fn rk_fields(op: &VMOpcode) -> (bool, bool) {
+    match op {
+        VMOpcode::GETTABLE | VMOpcode::SELF => (false, true),
+        VMOpcode::SETTABLE | VMOpcode::EQ | VMOpcode::LT | VMOpcode::LE => (true, true),
+        op if is_arith(op) => (true, true),
+        _ => (false, false),
+    }
+}
+
+/// An instruction field holding a constant index.
+enum Field {
+    Bx,
+    B,
+    C,
+}
+#[derive(Hash, PartialEq, Eq)]
+enum ConstKey {
+    Nil,
+    Boolean(bool),
+    Number(u64),
+    String(LuaString),
+}
+impl ConstKey {
+    fn of(c: &LuaConstant) -> Self {
+        match c {
+            LuaConstant::LUA_TNIL => ConstKey::Nil,
+            LuaConstant::LUA_TBOOLEAN(b) => ConstKey::Boolean(*b),
+            // 0 and -0 stay apart: they differ as divisors
+            LuaConstant::LUA_TNUMBER(n) => ConstKey::Number(n.to_bits()),
+            LuaConstant::LUA_TSTRING(s) => ConstKey::String(s.clone()),
+        }
+    }
+}
+
+struct Code {
+    code: Vec<u32>,
+    /// Empty in stripped chunks.
+    lines: Vec<u32>,
+    constants: Vec<LuaConstant>,
+    local_vars: Vec<LocalVar>,
+    /// Upvalue count of each nested function.
+    proto_upvals: Vec<u32>,
+    /// First register that does not hold a parameter, or `arg`, on entry.
+    first_free: u32,
+}
+impl Code {
+    fn new(func: &FunctionBlock) -> Self {
+        let needs_arg = (func.is_vararg & VARARG_NEEDSARG != 0) as u32;
+        Self {
+            code: func.list_instructions.iter().map(|i| i.to_u32()).collect(),
+            lines: func.line_info.clone(),
+            constants: func.list_const.iter().map(|c| (**c).clone()).collect(),
+            local_vars: func.local_vars.clone(),
+            proto_upvals: func.list_fnproto.iter().map(|p| p.num_upval as u32).collect(),
+            first_free: func.num_param as u32 + needs_arg,
+        }
+    }
+    fn slots(&self) -> Vec<Slot> {
+        slots(&self.code, &self.proto_upvals)
+    }
+    fn jump_target(&self, pc: usize) -> Option<usize> {
+        match op_of(self.code[pc]) {
+            VMOpcode::JMP | VMOpcode::FORLOOP | VMOpcode::FORPREP => {
+                Some((pc as i64 + 1 + arg_sbx(self.code[pc]) as i64) as usize)
+            }
+            _ => None,
+        }
+    }
+    /// Whether the instruction at `pc` conditionally skips the next one.
+    fn skips_next(&self, pc: usize) -> bool {
+        let i = self.code[pc];
+        let op = op_of(i);
+        is_test(&op) || (op == VMOpcode::LOADBOOL && arg_c(i) != 0)
+    }
+    /// Pcs reached other than by falling through from the previous instruction.
+    fn jump_targets(&self, slots: &[Slot]) -> Vec<bool> {
+        let mut targets = vec![false; self.code.len() + 1];
+        for pc in (0..self.code.len()).filter(|pc| slots[*pc] == Slot::Inst) {
+            if let Some(t) = self.jump_target(pc) {
+                targets[t.min(self.code.len())] = true;
+            }
+            if self.skips_next(pc) {
+                targets[(pc + 2).min(self.code.len())] = true;
+            }
+        }
+        targets
+    }
+    /// Whether removing the instruction at `pc` would change what its
+    /// predecessor skips.
+    fn is_skipped(&self, slots: &[Slot], pc: usize) -> bool {
+        pc > 0 && slots[pc - 1] == Slot::Inst && self.skips_next(pc - 1)
+    }
+    fn add_constant(&mut self, c: LuaConstant) -> u32 {
+        let key = ConstKey::of(&c);
+        if let Some(idx) = self.constants.iter().position(|k| ConstKey::of(k) == key) {
+            return idx as u32;
+        }
+        self.constants.push(c);
+        self.constants.len() as u32 - 1
+    }
+
+    /// Deletes the instructions marked dead, re-targeting jumps around them.
+    fn remove(&mut self, dead: &[bool]) {
+        let n = self.code.len();
+        let slots = self.slots();
+        let mut new_pc = Vec::with_capacity(n + 1);
+        let mut kept: usize = 0;
+        for d in dead {
+            new_pc.push(kept);
+            if !d {
+                kept += 1;
+            }
+        }
+        new_pc.push(kept);
+        for pc in (0..n).filter(|pc| !dead[*pc] && slots[*pc] == Slot::Inst) {
+            if let Some(t) = self.jump_target(pc) {
+                let offset = new_pc[t.min(n)] as i32 - (new_pc[pc] as i32 + 1);
+                set_sbx(&mut self.code[pc], offset);
+            }
+        }
+        for var in &mut self.local_vars {
+            var.start_pc = new_pc[(var.start_pc as usize).min(n)] as u32;
+            var.end_pc = new_pc[(var.end_pc as usize).min(n)] as u32;
+        }
+        let keep = |v: &Vec<u32>| v.iter().zip(dead).filter(|(_, d)| !**d).map(|(i, _)| *i).collect();
+        if self.lines.len() == n {
+            self.lines = keep(&self.lines);
+        }
+        self.code = keep(&self.code);
+    }
+
+    fn fold_constants(&mut self) {
+        let slots = self.slots();
+        for pc in (0..self.code.len()).filter(|pc| slots[*pc] == Slot::Inst) {
+            let i = self.code[pc];
+            let op = op_of(i);
+            let (b, c) = (arg_b(i), arg_c(i));
+            if !is_arith(&op) || b & MASK_CBIT == 0 || c & MASK_CBIT == 0 {
+                continue;
+            }
+            let number = |k: u32| match self.constants[(k & !MASK_CBIT) as usize] {
+                LuaConstant::LUA_TNUMBER(n) => Some(n),
+                _ => None,
+            };
+            let (Some(v1), Some(v2)) = (number(b), number(c)) else { continue };
+            let r = match op {
+                VMOpcode::ADD => v1 + v2,
+                VMOpcode::SUB => v1 - v2,
+                VMOpcode::MUL => v1 * v2,
+                VMOpcode::DIV if v2 != 0.0 => v1 / v2,
+                VMOpcode::MOD if v2 != 0.0 => v1 - (v1 / v2).floor() * v2,
+                VMOpcode::POW => v1.powf(v2),
+                _ => continue,
+            };
+            if r.is_nan() {
+                continue;
+            }
+            let k = self.add_constant(LuaConstant::LUA_TNUMBER(r));
+            if k <= MAXARG_BX {
+                self.code[pc] = create_abx(VMOpcode::LOADK, arg_a(i), k);
+            }
+        }
+    }
+
+    fn collapse_jumps(&mut self) {
+        let slots = self.slots();
+        let n = self.code.len();
+        let is_jmp = |code: &[u32], pc: usize| pc < n && slots[pc] == Slot::Inst && op_of(code[pc]) == VMOpcode::JMP;
+        let jumps: Vec<usize> = (0..n).filter(|pc| is_jmp(&self.code, *pc)).collect();
+        for pc in jumps {
+            let mut target = self.jump_target(pc).unwrap();
+            let mut steps = 0;
+            while is_jmp(&self.code, target) && steps < n {
+                target = self.jump_target(target).unwrap();
+                steps += 1;
+            }
+            // a chain that never leaves its jumps is an infinite loop; keep it
+            if steps < n {
+                set_sbx(&mut self.code[pc], target as i32 - (pc as i32 + 1));
+            }
+        }
+        // jumps to the next instruction, unless a test guards them
+        let dead: Vec<bool> = (0..n)
+            .map(|pc| is_jmp(&self.code, pc) && arg_sbx(self.code[pc]) == 0 && !self.is_skipped(&slots, pc))
+            .collect();
+        self.remove(&dead);
+    }
+
+    fn remove_unreachable(&mut self) {
+        let n = self.code.len();
+        if n == 0 {
+            return;
+        }
+        let cfg = Cfg::from_code(&self.code, &self.proto_upvals);
+        let mut dead = vec![false; n];
+        for (b, block) in cfg.blocks.iter().enumerate() {
+            dead[block.start..block.end].fill(!cfg.reachable[b]);
+        }
+        // the reference loader requires code to end with a RETURN
+        dead[n - 1] = false;
+        self.remove(&dead);
+    }
+
+    fn remove_moves(&mut self) {
+        let slots = self.slots();
+        let targets = self.jump_targets(&slots);
+        let mut dead = vec![false; self.code.len()];
+        for pc in 0..self.code.len() {
+            let i = self.code[pc];
+            if slots[pc] != Slot::Inst || op_of(i) != VMOpcode::MOVE || self.is_skipped(&slots, pc) {
+                continue;
+            }
+            let (a, b) = (arg_a(i), arg_b(i));
+            let undoes_previous = pc > 0 && !targets[pc] && !dead[pc - 1] && slots[pc - 1] == Slot::Inst && {
+                let p = self.code[pc - 1];
+                op_of(p) == VMOpcode::MOVE && arg_a(p) == b && arg_b(p) == a
+            };
+            dead[pc] = a == b || undoes_previous;
+        }
+        self.remove(&dead);
+    }
+
+    fn remove_loadnils(&mut self) {
+        let slots = self.slots();
+        let targets = self.jump_targets(&slots);
+        let mut dead = vec![false; self.code.len()];
+        // registers from `first_free` up start out nil; follow the code from
+        // the entry while that is the only way in
+        let mut written = vec![true; self.first_free as usize];
+        let is_nil = |written: &[bool], r: u32| written.get(r as usize).is_none_or(|w| !w);
+        for pc in 0..self.code.len() {
+            if targets[pc] || slots[pc] != Slot::Inst {
+                break;
+            }
+            let i = self.code[pc];
+            let a = arg_a(i);
+            let writes = match op_of(i) {
+                VMOpcode::LOADNIL => {
+                    let b = arg_b(i);
+                    dead[pc] = (a..=b).all(|r| is_nil(&written, r)) && !self.is_skipped(&slots, pc);
+                    continue;
+                }
+                VMOpcode::MOVE
+                | VMOpcode::LOADK
+                | VMOpcode::GETUPVAL
+                | VMOpcode::GETGLOBAL
+                | VMOpcode::GETTABLE
+                | VMOpcode::NEWTABLE
+                | VMOpcode::UNM
+                | VMOpcode::NOT
+                | VMOpcode::LEN
+                | VMOpcode::CONCAT => a..a + 1,
+                VMOpcode::LOADBOOL if arg_c(i) == 0 => a..a + 1,
+                op if is_arith(&op) => a..a + 1,
+                VMOpcode::SELF => a..a + 2,
+                VMOpcode::SETGLOBAL | VMOpcode::SETUPVAL | VMOpcode::SETTABLE => a..a,
+                // calls, jumps and the rest end the straight line
+                _ => break,
+            };
+            for r in writes {
+                if written.len() <= r as usize {
+                    written.resize(r as usize + 1, false);
+                }
+                written[r as usize] = true;
+            }
+        }
+        self.remove(&dead);
+    }
+
+    fn dedup_constants(&mut self) {
+        let slots = self.slots();
+        let mut used = vec![false; self.constants.len()];
+        let mut refs = Vec::new();
+        for pc in (0..self.code.len()).filter(|pc| slots[*pc] == Slot::Inst) {
+            let i = self.code[pc];
+            let op = op_of(i);
+            if matches!(op, VMOpcode::LOADK | VMOpcode::GETGLOBAL | VMOpcode::SETGLOBAL) {
+                refs.push((pc, Field::Bx, arg_bx(i)));
+            }
+            let (b_rk, c_rk) = rk_fields(&op);
+            if b_rk && arg_b(i) & MASK_CBIT != 0 {
+                refs.push((pc, Field::B, arg_b(i) & !MASK_CBIT));
+            }
+            if c_rk && arg_c(i) & MASK_CBIT != 0 {
+                refs.push((pc, Field::C, arg_c(i) & !MASK_CBIT));
+            }
+        }
+        for (_, _, k) in &refs {
+            used[*k as usize] = true;
+        }
+        let mut index = HashMap::new();
+        let mut constants = Vec::new();
+        let mut remap = vec![0; self.constants.len()];
+        for (k, c) in self.constants.iter().enumerate().filter(|(k, _)| used[*k]) {
+            remap[k] = *index.entry(ConstKey::of(c)).or_insert_with(|| {
+                constants.push(c.clone());
+                constants.len() as u32 - 1
+            });
+        }
+        for (pc, field, k) in refs {
+            let i = &mut self.code[pc];
+            match field {
+                Field::Bx => set_bx(i, remap[k as usize]),
+                Field::B => set_b(i, remap[k as usize] | MASK_CBIT),
+                Field::C => set_c(i, remap[k as usize] | MASK_CBIT),
+            }
+        }
+        self.constants = constants;
+    }
+}
@@ -0,0 +1,207 @@
+//! A preset for running untrusted scripts: a VM with only the library
+//! functions that cannot reach the host's files, processes or environment,
+//! nor the VM's own internals; `print` writes to the host's stdout. There
+//! is no `io`, no `debug`, only the clock and date parts of `os`, and
+//! `loadstring` takes source text only, since crafted bytecode can break
+//! out of the VM. Functions share the VM's globals, there being no
+//! per-function environments and so no `setfenv`; a sandbox is a VM of its
+//! own, so its scripts cannot reach the functions of another. Runaway
+//! recursion fails with a catchable error: Lua calls are held to the
+//! sandbox's call depth, and calls through metamethods, `pcall` and
+//! coroutines to `limits::MAX_C_CALLS`. That bound keeps the host's stack
+//! safe only when the thread running the VM has the stack it documents;
+//! see `Sandbox`. Other budgets are separate: see `LuaVM::set_fuel` and
+//! `LuaVM::set_memory_limit`.
+
+use std::{collections::BTreeSet, rc::Rc};
+
+use super::{
+    hostio::HostIo,
+    limits::DEFAULT_MAX_CALL_DEPTH,
+    stdlib::{base, boolean, nil},
+    string::LuaString,
+    table::{GCLuaTable, LuaKey},
+    GCLuaValue, LuaNativeFunction, LuaVM, LuaValue,
+};
+
+/// What a sandbox lets scripts have unless told otherwise.
+const SAFE_FUNCTIONS: &[&str] = &[
+    "_G",
+    "assert",
+    "error",
+    "getmetatable",
+    "ipairs",
+    "loadstring",
+    "next",
+    "pairs",
+    "pcall",
+    "print",
+    "rawequal",
+    "rawget",
+    "rawset",
+    "select",
+    "setmetatable",
+    "tonumber",
+    "tostring",
+    "type",
+    "unpack",
+    "coroutine",
+    "os.clock",
+    "os.date",
+    "os.difftime",
+    "os.time",
+];
+
+/// Which functions a sandboxed VM gets. Names are globals such as
+/// `"pairs"`, library functions such as `"os.time"`, or whole libraries
+/// such as `"coroutine"`.
+///
+/// Scripts can nest `MAX_C_CALLS` calls through Rust, which takes about
+/// 2.5 MB of stack in debug builds and under 512 KB in release builds. A
+/// 2 MB thread, the default for spawned threads and test threads, is not
+/// enough in debug builds: run sandboxes on the main thread or on one
+/// spawned with `std::thread::Builder::stack_size` of 4 MB or more.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    allowed: BTreeSet<String>,
+    functions: Vec<(String, LuaNativeFunction)>,
+    max_call_depth: usize,
+}
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Sandbox {
+    /// The safe subset of the standard libraries.
+    pub fn new() -> Self {
+        Self {
+            allowed: SAFE_FUNCTIONS.iter().map(|name| name.to_string()).collect(),
+            functions: Vec::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+        }
+    }
+    /// Lets scripts have the standard function or library `name` too.
+    pub fn allow(mut self, name: &str) -> Self {
+        self.allowed.insert(name.to_string());
+        self
+    }
+    /// Takes `name` away, with all of it if it is a library.
+    pub fn deny(mut self, name: &str) -> Self {
+        let prefix = format!("{}.", name);
+        self.allowed.retain(|n| n != name && !n.starts_with(&prefix));
+        self.functions.retain(|(n, _)| n != name && !n.starts_with(&prefix));
+        self
+    }
+    /// How many Lua functions scripts can have running at once;
+    /// `DEFAULT_MAX_CALL_DEPTH` unless set.
+    pub fn max_call_depth(mut self, depth: usize) -> Self {
+        self.max_call_depth = depth;
+        self
+    }
+    /// Adds a host function, under a global or library name.
+    pub fn function(mut self, name: &str, f: LuaNativeFunction) -> Self {
+        self.functions.push((name.to_string(), f));
+        self
+    }
+    fn allows(&self, name: &str) -> bool {
+        self.allowed.contains(name) || name.split_once('.').is_some_and(|(lib, _)| self.allowed.contains(lib))
+    }
+    /// A VM with the allowed functions, doing what I/O they do through
+    /// `host`.
+    pub fn build(&self, host: Rc<dyn HostIo>) -> LuaVM {
+        let mut vm = LuaVM::new();
+        vm.set_max_call_depth(Some(self.max_call_depth));
+        let globals = vm.open_base(host.clone());
+        vm.open_coroutine();
+        vm.open_os(host.clone());
+        vm.open_io(host);
+        vm.open_debug();
+        for (name, value) in string_keys(&globals) {
+            if self.allows(&name) {
+                continue;
+            }
+            let lib = match &*value.borrow() {
+                LuaValue::Table(t) if t.ptr() != globals.ptr() => Some(t.clone()),
+                _ => None,
+            };
+            let Some(lib) = lib else {
+                vm.set_global(&name, nil());
+                continue;
+            };
+            for (field, _) in string_keys(&lib) {
+                if !self.allows(&format!("{}.{}", name, field)) {
+                    lib.borrow_mut().set_str(&field, nil());
+                }
+            }
+            if lib.borrow().is_empty() {
+                vm.set_global(&name, nil());
+            }
+        }
+        if self.allows("loadstring") {
+            vm.set_global("loadstring", LuaValue::NativeFunction(base::loadstring(false)).to_gc());
+        }
+        protect_string_metatable(&mut vm);
+        for (name, f) in &self.functions {
+            vm.add_function(name, f.clone());
+        }
+        vm
+    }
+}
+
+impl LuaVM {
+    /// A VM for untrusted scripts, with the default `Sandbox`.
+    pub fn sandboxed(host: Rc<dyn HostIo>) -> LuaVM {
+        Sandbox::new().build(host)
+    }
+    /// Makes `f` callable as the global or library function `name`,
+    /// creating the library if need be.
+    pub fn add_function(&mut self, name: &str, f: LuaNativeFunction) {
+        let f = LuaValue::NativeFunction(f).to_gc();
+        let Some((lib, field)) = name.split_once('.') else {
+            self.set_global(name, f);
+            return;
+        };
+        let table = match &*self.get_global(lib).borrow() {
+            LuaValue::Table(t) => Some(t.clone()),
+            _ => None,
+        };
+        let table = table.unwrap_or_else(|| {
+            let t = GCLuaTable::default();
+            self.set_global(lib, LuaValue::Table(t.clone()).to_gc());
+            t
+        });
+        table.borrow_mut().set_str(field, f);
+    }
+    /// Removes the global or library function `name`.
+    pub fn remove_function(&mut self, name: &str) {
+        let Some((lib, field)) = name.split_once('.') else {
+            self.set_global(name, nil());
+            return;
+        };
+        if let LuaValue::Table(t) = &*self.get_global(lib).borrow() {
+            t.borrow_mut().set_str(field, nil());
+        }
+    }
+}
+
+/// The entries of `t` under string keys.
+fn string_keys(t: &GCLuaTable) -> Vec<(String, GCLuaValue)> {
+    t.borrow()
+        .entries()
+        .filter(|(_, v)| !v.is_nil())
+        .filter_map(|(k, v)| match k {
+            LuaKey::String(s) => Some((s.to_string(), v.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Hides the metatable all strings share, so that scripts cannot change
+/// how other code's strings behave: `getmetatable("")` gives `false`.
+fn protect_string_metatable(vm: &mut LuaVM) {
+    let probe = LuaValue::String(LuaString::default()).to_gc();
+    let mt = vm.get_metatable(&probe).unwrap_or_default();
+    mt.borrow_mut().set_str("__metatable", boolean(false));
+    vm.set_metatable(&probe, Some(mt));
+}
@@ -0,0 +1,633 @@
+//! Serde support (the `serde` feature): Rust values to Lua tables and back.
+//!
+//! Sequences become array tables and structs and maps become keyed tables.
+//! Enums use serde's default external tagging: a unit variant is the string
+//! `"Name"`, any other variant a one-entry table `{ Name = ... }`. Errors
+//! carry the path of the offending value, e.g. `config.servers[3].port`.
+use std::fmt::Display;
+
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor},
+    ser::{self, Serialize},
+};
+
+use super::{
+    table::{GCLuaTable, LuaKey, LuaTable},
+    GCLuaValue, LuaValue,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    /// Where in the value the problem is; empty for the root.
+    pub path: Option<String>,
+    pub message: String,
+}
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            Some(path) if !path.is_empty() => write!(f, "{}: {}", path, self.message),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+impl std::error::Error for Error {}
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error { path: None, message: msg.to_string() }
+    }
+}
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error { path: None, message: msg.to_string() }
+    }
+}
+
+/// Converts a Rust value into a Lua value.
+pub fn to_lua<T: Serialize + ?Sized>(value: &T) -> Result<GCLuaValue, Error> {
+    value.serialize(Serializer)
+}
+/// Reads a Lua value into a Rust value.
+pub fn from_lua<T: DeserializeOwned>(value: &GCLuaValue) -> Result<T, Error> {
+    T::deserialize(Deserializer::new(value.clone()))
+}
+/// Like `from_lua`, with error paths starting at `name`, usually the global
+/// the value was read from.
+pub fn from_lua_named<T: DeserializeOwned>(value: &GCLuaValue, name: &str) -> Result<T, Error> {
+    T::deserialize(Deserializer::with_path(value.clone(), name.to_string()))
+}
+
+pub struct Serializer;
+impl ser::Serializer for Serializer {
+    type Ok = GCLuaValue;
+    type Error = Error;
+    type SerializeSeq = SerializeTable;
+    type SerializeTuple = SerializeTable;
+    type SerializeTupleStruct = SerializeTable;
+    type SerializeTupleVariant = SerializeVariant;
+    type SerializeMap = SerializeTable;
+    type SerializeStruct = SerializeTable;
+    type SerializeStructVariant = SerializeVariant;
+
+    fn serialize_bool(self, v: bool) -> Result<GCLuaValue, Error> {
+        Ok(LuaValue::Boolean(v).to_gc())
+    }
+    fn serialize_i8(self, v: i8) -> Result<GCLuaValue, Error> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_i16(self, v: i16) -> Result<GCLuaValue, Error> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_i32(self, v: i32) -> Result<GCLuaValue, Error> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_i64(self, v: i64) -> Result<GCLuaValue, Error> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_u8(self, v: u8) -> Result<GCLuaValue, Error> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_u16(self, v: u16) -> Result<GCLuaValue, Error> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_u32(self, v: u32) -> Result<GCLuaValue, Error> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_u64(self, v: u64) -> Result<GCLuaValue, Error> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_f32(self, v: f32) -> Result<GCLuaValue, Error> {
+        self.serialize_f64(v as f64)
+    }
+    fn serialize_f64(self, v: f64) -> Result<GCLuaValue, Error> {
+        Ok(LuaValue::Number(v).to_gc())
+    }
+    fn serialize_char(self, v: char) -> Result<GCLuaValue, Error> {
+        Ok(LuaValue::String(v.to_string().into()).to_gc())
+    }
+    fn serialize_str(self, v: &str) -> Result<GCLuaValue, Error> {
+        Ok(LuaValue::String(v.into()).to_gc())
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<GCLuaValue, Error> {
+        Ok(LuaValue::String(v.into()).to_gc())
+    }
+    fn serialize_none(self) -> Result<GCLuaValue, Error> {
+        Ok(LuaValue::Nil.to_gc())
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<GCLuaValue, Error> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<GCLuaValue, Error> {
+        Ok(LuaValue::Nil.to_gc())
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<GCLuaValue, Error> {
+        Ok(LuaValue::Nil.to_gc())
+    }
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<GCLuaValue, Error> {
+        self.serialize_str(variant)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<GCLuaValue, Error> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<GCLuaValue, Error> {
+        let mut table = LuaTable::new();
+        table.set_str(variant, value.serialize(Serializer)?);
+        Ok(LuaValue::Table(GCLuaTable::new(table)).to_gc())
+    }
+    fn serialize_seq(self, _len: Option<usize>) -> Result<SerializeTable, Error> {
+        Ok(SerializeTable::default())
+    }
+    fn serialize_tuple(self, _len: usize) -> Result<SerializeTable, Error> {
+        Ok(SerializeTable::default())
+    }
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<SerializeTable, Error> {
+        Ok(SerializeTable::default())
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeVariant, Error> {
+        Ok(SerializeVariant { variant, inner: SerializeTable::default() })
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeTable, Error> {
+        Ok(SerializeTable::default())
+    }
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<SerializeTable, Error> {
+        Ok(SerializeTable::default())
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeVariant, Error> {
+        Ok(SerializeVariant { variant, inner: SerializeTable::default() })
+    }
+}
+
+#[derive(Default)]
+pub struct SerializeTable {
+    table: LuaTable,
+    len: i64,
+    key: Option<GCLuaValue>,
+}
+impl SerializeTable {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.len += 1;
+        self.table.set_int(self.len, value.serialize(Serializer)?);
+        Ok(())
+    }
+    fn finish(self) -> GCLuaValue {
+        LuaValue::Table(GCLuaTable::new(self.table)).to_gc()
+    }
+}
+impl ser::SerializeSeq for SerializeTable {
+    type Ok = GCLuaValue;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<GCLuaValue, Error> {
+        Ok(self.finish())
+    }
+}
+impl ser::SerializeTuple for SerializeTable {
+    type Ok = GCLuaValue;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<GCLuaValue, Error> {
+        Ok(self.finish())
+    }
+}
+impl ser::SerializeTupleStruct for SerializeTable {
+    type Ok = GCLuaValue;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<GCLuaValue, Error> {
+        Ok(self.finish())
+    }
+}
+impl ser::SerializeMap for SerializeTable {
+    type Ok = GCLuaValue;
+    type Error = Error;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(key.serialize(Serializer)?);
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.key.take().expect("serialize_value called before serialize_key");
+        let key = LuaKey::from_value(&key).map_err(<Error as ser::Error>::custom)?;
+        self.table.set(key, value.serialize(Serializer)?);
+        Ok(())
+    }
+    fn end(self) -> Result<GCLuaValue, Error> {
+        Ok(self.finish())
+    }
+}
+impl ser::SerializeStruct for SerializeTable {
+    type Ok = GCLuaValue;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.table.set_str(key, value.serialize(Serializer)?);
+        Ok(())
+    }
+    fn end(self) -> Result<GCLuaValue, Error> {
+        Ok(self.finish())
+    }
+}
+/// A tuple or struct variant: `{ Name = { ... } }`.
+pub struct SerializeVariant {
+    variant: &'static str,
+    inner: SerializeTable,
+}
+impl SerializeVariant {
+    fn finish(self) -> GCLuaValue {
+        let mut table = LuaTable::new();
+        table.set_str(self.variant, self.inner.finish());
+        LuaValue::Table(GCLuaTable::new(table)).to_gc()
+    }
+}
+impl ser::SerializeTupleVariant for SerializeVariant {
+    type Ok = GCLuaValue;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.inner.push(value)
+    }
+    fn end(self) -> Result<GCLuaValue, Error> {
+        Ok(self.finish())
+    }
+}
+impl ser::SerializeStructVariant for SerializeVariant {
+    type Ok = GCLuaValue;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        ser::SerializeStruct::serialize_field(&mut self.inner, key, value)
+    }
+    fn end(self) -> Result<GCLuaValue, Error> {
+        Ok(self.finish())
+    }
+}
+
+pub struct Deserializer {
+    value: GCLuaValue,
+    path: String,
+}
+impl Deserializer {
+    pub fn new(value: GCLuaValue) -> Self {
+        Self::with_path(value, String::new())
+    }
+    pub fn with_path(value: GCLuaValue, path: String) -> Self {
+        Self { value, path }
+    }
+    fn error(&self, message: String) -> Error {
+        Error { path: Some(self.path.clone()), message }
+    }
+    fn type_error(&self, expected: &str) -> Error {
+        self.error(format!("expected {}, got {}", expected, self.value.borrow().type_name()))
+    }
+    /// Gives errors raised by visitors (missing fields, unknown variants) the
+    /// path of the value being visited; errors from deeper values keep theirs.
+    fn at<T>(&self, r: Result<T, Error>) -> Result<T, Error> {
+        r.map_err(|mut e| {
+            if e.path.is_none() {
+                e.path = Some(self.path.clone());
+            }
+            e
+        })
+    }
+    fn child(&self, value: GCLuaValue, key: &LuaKey) -> Deserializer {
+        let path = match key {
+            LuaKey::String(s) if is_identifier(&s.to_str_lossy()) && self.path.is_empty() => s.to_string(),
+            LuaKey::String(s) if is_identifier(&s.to_str_lossy()) => format!("{}.{}", self.path, s),
+            _ => format!("{}[{}]", self.path, key.to_value().borrow().as_string(true)),
+        };
+        Deserializer::with_path(value, path)
+    }
+    fn table(&self) -> Option<GCLuaTable> {
+        match &*self.value.borrow() {
+            LuaValue::Table(t) => Some(t.clone()),
+            _ => None,
+        }
+    }
+    fn number(&self, expected: &str) -> Result<f64, Error> {
+        match &*self.value.borrow() {
+            LuaValue::Number(n) => Ok(*n),
+            _ => Err(self.type_error(expected)),
+        }
+    }
+    fn integer(&self, ty: &str, min: f64, max: f64) -> Result<f64, Error> {
+        let n = self.number("number")?;
+        // `max + 1.0` because MAX as f64 may already round up out of range
+        if n.fract() != 0.0 || n < min || n >= max + 1.0 {
+            return Err(self.error(format!("expected {}, got {}", ty, super::fmt_number(n))));
+        }
+        Ok(n)
+    }
+    fn seq_access(&self, table: &GCLuaTable) -> Result<SeqAccess, Error> {
+        match sequence(&table.borrow()) {
+            Some(items) => Ok(SeqAccess { items: items.into_iter(), index: 0, path: self.path.clone() }),
+            None => Err(self.error("expected array, got table with non-sequence keys".to_string())),
+        }
+    }
+    fn map_access(&self, table: &GCLuaTable) -> MapAccess {
+        let mut entries = Vec::new();
+        let table = table.borrow();
+        let mut key = None;
+        while let Ok(Some((k, v))) = table.next(key.as_ref()) {
+            entries.push((k.clone(), v));
+            key = Some(k);
+        }
+        MapAccess { entries: entries.into_iter(), value: None, parent: Deserializer::with_path(self.value.clone(), self.path.clone()) }
+    }
+}
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+/// The values `t[1]..t[n]` if every key is an integer in `1..=n`. Holes
+/// (nil from `None` elements) are allowed as long as at least half of the
+/// slots are used, the same density rule Lua uses for a table's array part.
+fn sequence(t: &LuaTable) -> Option<Vec<GCLuaValue>> {
+    let (mut count, mut max) = (0usize, 0usize);
+    let mut key = None;
+    while let Some((k, _)) = t.next(key.as_ref()).ok()? {
+        match &k {
+            LuaKey::Number(bits) => {
+                let n = f64::from_bits(*bits);
+                if n.fract() != 0.0 || n < 1.0 {
+                    return None;
+                }
+                max = max.max(n as usize);
+            }
+            _ => return None,
+        }
+        count += 1;
+        key = Some(k);
+    }
+    if max > count * 2 {
+        return None;
+    }
+    Some((1..=max as i64).map(|i| t.get_int(i).unwrap_or_else(|| LuaValue::Nil.to_gc())).collect())
+}
+
+macro_rules! deserialize_integer {
+    ($($method:ident => $visit:ident as $t:ty),*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                let n = self.integer(stringify!($t), <$t>::MIN as f64, <$t>::MAX as f64)?;
+                self.at(visitor.$visit(n as $t))
+            }
+        )*
+    };
+}
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let r = match &*self.value.borrow() {
+            LuaValue::Nil => visitor.visit_unit(),
+            LuaValue::Boolean(b) => visitor.visit_bool(*b),
+            LuaValue::Number(n) if n.fract() == 0.0 && n.abs() < 9007199254740992.0 => visitor.visit_i64(*n as i64),
+            LuaValue::Number(n) => visitor.visit_f64(*n),
+            LuaValue::String(s) => match std::str::from_utf8(s.as_bytes()) {
+                Ok(s) => visitor.visit_str(s),
+                Err(_) => visitor.visit_bytes(s.as_bytes()),
+            },
+            LuaValue::Table(t) => match sequence(&t.borrow()) {
+                Some(_) => visitor.visit_seq(self.seq_access(t)?),
+                None => visitor.visit_map(self.map_access(t)),
+            },
+            other => return Err(self.error(format!("cannot deserialize a {} value", other.type_name()))),
+        };
+        self.at(r)
+    }
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let b = match &*self.value.borrow() {
+            LuaValue::Boolean(b) => *b,
+            _ => return Err(self.type_error("boolean")),
+        };
+        self.at(visitor.visit_bool(b))
+    }
+    deserialize_integer!(
+        deserialize_i8 => visit_i8 as i8,
+        deserialize_i16 => visit_i16 as i16,
+        deserialize_i32 => visit_i32 as i32,
+        deserialize_i64 => visit_i64 as i64,
+        deserialize_u8 => visit_u8 as u8,
+        deserialize_u16 => visit_u16 as u16,
+        deserialize_u32 => visit_u32 as u32,
+        deserialize_u64 => visit_u64 as u64
+    );
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let n = self.number("number")?;
+        self.at(visitor.visit_f32(n as f32))
+    }
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let n = self.number("number")?;
+        self.at(visitor.visit_f64(n))
+    }
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let s = match &*self.value.borrow() {
+            LuaValue::String(s) => String::from_utf8(s.as_bytes().to_vec()),
+            _ => return Err(self.type_error("string")),
+        };
+        let s = s.map_err(|_| self.error("string is not UTF-8".to_string()))?;
+        self.at(visitor.visit_string(s))
+    }
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_byte_buf(visitor)
+    }
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let s = match &*self.value.borrow() {
+            LuaValue::String(s) => s.clone(),
+            _ => return Err(self.type_error("string")),
+        };
+        self.at(visitor.visit_byte_buf(s.into_bytes()))
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.value.is_nil() {
+            return self.at(visitor.visit_none());
+        }
+        visitor.visit_some(self)
+    }
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if !self.value.is_nil() {
+            return Err(self.type_error("nil"));
+        }
+        self.at(visitor.visit_unit())
+    }
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let table = self.table().ok_or_else(|| self.type_error("array"))?;
+        let access = self.seq_access(&table)?;
+        self.at(visitor.visit_seq(access))
+    }
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let table = self.table().ok_or_else(|| self.type_error("table"))?;
+        let access = self.map_access(&table);
+        self.at(visitor.visit_map(access))
+    }
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let (variant, value) = match &*self.value.borrow() {
+            LuaValue::String(s) => (s.clone(), None),
+            LuaValue::Table(t) => {
+                let t = t.borrow();
+                let only_entry = match t.next(None) {
+                    Ok(Some((key, value))) if matches!(t.next(Some(&key)), Ok(None)) => Some((key, value)),
+                    _ => None,
+                };
+                match &only_entry {
+                    Some((LuaKey::String(name), value)) => (name.clone(), Some(value.clone())),
+                    _ => return Err(self.error("expected enum variant, got table without exactly one string key".to_string())),
+                }
+            }
+            _ => return Err(self.type_error("enum variant")),
+        };
+        let r = visitor.visit_enum(EnumAccess { variant: variant.to_string(), value, path: self.path.clone() });
+        self.at(r)
+    }
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_any(visitor)
+    }
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+}
+
+struct SeqAccess {
+    items: std::vec::IntoIter<GCLuaValue>,
+    index: usize,
+    path: String,
+}
+impl<'de> de::SeqAccess<'de> for SeqAccess {
+    type Error = Error;
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        match self.items.next() {
+            Some(value) => {
+                self.index += 1;
+                let path = format!("{}[{}]", self.path, self.index);
+                seed.deserialize(Deserializer::with_path(value, path)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+struct MapAccess {
+    entries: std::vec::IntoIter<(LuaKey, GCLuaValue)>,
+    value: Option<(LuaKey, GCLuaValue)>,
+    parent: Deserializer,
+}
+impl<'de> de::MapAccess<'de> for MapAccess {
+    type Error = Error;
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                let key_value = key.to_value();
+                self.value = Some((key, value));
+                seed.deserialize(Deserializer::with_path(key_value, self.parent.path.clone())).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (key, value) = self.value.take().expect("next_value_seed called before next_key_seed");
+        seed.deserialize(self.parent.child(value, &key))
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+struct EnumAccess {
+    variant: String,
+    value: Option<GCLuaValue>,
+    path: String,
+}
+impl<'de> de::EnumAccess<'de> for EnumAccess {
+    type Error = Error;
+    type Variant = VariantAccess;
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, VariantAccess), Error> {
+        let path = if self.path.is_empty() { self.variant.clone() } else { format!("{}.{}", self.path, self.variant) };
+        let variant = seed.deserialize(self.variant.into_deserializer())?;
+        Ok((variant, VariantAccess { value: self.value, path }))
+    }
+}
+struct VariantAccess {
+    value: Option<GCLuaValue>,
+    path: String,
+}
+impl VariantAccess {
+    fn inner(self, expected: &str) -> Result<Deserializer, Error> {
+        match self.value {
+            Some(value) => Ok(Deserializer::with_path(value, self.path)),
+            None => Err(Error { path: Some(self.path), message: format!("expected {} variant, got unit variant", expected) }),
+        }
+    }
+}
+impl<'de> de::VariantAccess<'de> for VariantAccess {
+    type Error = Error;
+    fn unit_variant(self) -> Result<(), Error> {
+        match &self.value {
+            Some(v) if !v.is_nil() => Err(Error {
+                path: Some(self.path),
+                message: format!("expected unit variant, got {}", v.borrow().type_name()),
+            }),
+            _ => Ok(()),
+        }
+    }
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self.inner("newtype")?)
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self.inner("tuple")?, visitor)
+    }
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_map(self.inner("struct")?, visitor)
+    }
+}
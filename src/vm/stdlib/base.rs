@@ -0,0 +1,269 @@
+use std::rc::Rc;
+
+use anyhow::bail;
+
+use super::{
+    arg_error, boolean, check_any, check_bytes, check_number, check_string, check_table, is_none_or_nil, nil, number, opt_number,
+    opt_string, string,
+};
+use crate::{
+    compiler::compile,
+    vm::{
+        chunk_parser::LuaChunk,
+        error::{LuaError, RuntimeError},
+        hostio::HostIo,
+        table::LuaKey,
+        verify::verify,
+        GCLuaValue, LuaNativeFunction, LuaVM, LuaValue,
+    },
+};
+
+/// What the collector's tuning knobs report; this collector has none.
+const GC_PAUSE: f64 = 200.0;
+/// Most values `unpack` returns at once, `LUAI_MAXCSTACK`.
+const MAX_UNPACK: f64 = 8000.0;
+
+pub fn create(host: Rc<dyn HostIo>) -> Vec<LuaNativeFunction> {
+    let assert = LuaNativeFunction::new("assert", |_, args| {
+        if check_any(&args, 1, "assert")?.borrow().truthy() {
+            return Ok(args);
+        }
+        bail!(opt_string(&args, 2, "assert")?.unwrap_or_else(|| "assertion failed!".to_string()))
+    });
+    let collectgarbage = LuaNativeFunction::new("collectgarbage", |vm, args| {
+        let opt = opt_string(&args, 1, "collectgarbage")?.unwrap_or_else(|| "collect".to_string());
+        Ok(vec![match opt.as_str() {
+            "collect" => {
+                vm.collect_garbage();
+                number(0.0)
+            }
+            // one step does a whole cycle
+            "step" => {
+                vm.collect_garbage();
+                boolean(true)
+            }
+            "count" => number(vm.remeasure_memory() as f64 / 1024.0),
+            "stop" | "restart" => number(0.0),
+            "setpause" | "setstepmul" => number(GC_PAUSE),
+            _ => return Err(arg_error(1, "collectgarbage", &format!("invalid option '{}'", opt))),
+        }])
+    });
+    let error = LuaNativeFunction::new("error", |vm, args| {
+        let level = opt_number(&args, 2, "error")?.unwrap_or(1.0) as usize;
+        let message = args.first().map_or_else(|| "nil".to_string(), |v| v.borrow().as_string(false));
+        let is_string = matches!(args.first().map(|v| v.borrow()).as_deref(), Some(LuaValue::String(_) | LuaValue::Number(_)));
+        if is_string && level == 1 {
+            // placed at the caller's line like any error from a native
+            return Err(LuaError::Runtime(message).into());
+        }
+        let message = match vm.get_info(level.saturating_sub(1)).filter(|_| is_string && level > 1) {
+            Some(info) => match info.current_line {
+                Some(line) => format!("{}:{}: {}", info.short_src, line, message),
+                None => message,
+            },
+            None => message,
+        };
+        let cause = LuaError::Runtime(message.clone()).into();
+        Err(RuntimeError { message, traceback: vm.traceback(0), cause }.into())
+    });
+    let getmetatable = LuaNativeFunction::new("getmetatable", |vm, args| {
+        let value = check_any(&args, 1, "getmetatable")?;
+        let Some(mt) = vm.get_metatable(&value) else {
+            return Ok(vec![nil()]);
+        };
+        let protected = mt.borrow().get_str("__metatable");
+        Ok(vec![protected.unwrap_or_else(|| LuaValue::Table(mt).to_gc())])
+    });
+    let inext = LuaNativeFunction::new("inext", |_, args| {
+        let t = check_table(&args, 1, "ipairs")?;
+        let i = check_number(&args, 2, "ipairs")? as i64 + 1;
+        let v = t.borrow().get_int(i);
+        Ok(v.map_or_else(Vec::new, |v| vec![number(i as f64), v]))
+    });
+    let ipairs = LuaNativeFunction::with_upvalues("ipairs", vec![LuaValue::NativeFunction(inext).to_gc()], |vm, args| {
+        let t = check_table(&args, 1, "ipairs")?;
+        Ok(vec![vm.native_upvalue(0), LuaValue::Table(t).to_gc(), number(0.0)])
+    });
+    let loadstring = loadstring(true);
+    let next = LuaNativeFunction::new("next", |_, args| {
+        let t = check_table(&args, 1, "next")?;
+        let key = if is_none_or_nil(&args, 2) { None } else { Some(LuaKey::from_value(&args[1])?) };
+        let entry = t.borrow().next(key.as_ref())?;
+        Ok(entry.map_or_else(|| vec![nil()], |(k, v)| vec![k.to_value(), v]))
+    });
+    let pairs = LuaNativeFunction::with_upvalues("pairs", vec![LuaValue::NativeFunction(next.clone()).to_gc()], |vm, args| {
+        let t = check_table(&args, 1, "pairs")?;
+        Ok(vec![vm.native_upvalue(0), LuaValue::Table(t).to_gc(), nil()])
+    });
+    let pcall = LuaNativeFunction::new("pcall", |vm, mut args| {
+        let f = check_any(&args, 1, "pcall")?;
+        args.remove(0);
+        match vm.call_value(f, args) {
+            Ok(mut values) => {
+                values.insert(0, boolean(true));
+                Ok(values)
+            }
+            // fuel, deadlines and stops by the host are not the script's to catch
+            Err(e) if LuaError::is_catchable(&e) => Ok(vec![boolean(false), string(e.to_string())]),
+            Err(e) => Err(e),
+        }
+    });
+    let print = LuaNativeFunction::new("print", move |vm, args| {
+        let mut line = Vec::new();
+        for (i, v) in args.iter().enumerate() {
+            if i > 0 {
+                line.push(b'\t');
+            }
+            match &*v.borrow() {
+                LuaValue::String(s) if vm.get_metamethod(v, "__tostring").is_none() => {
+                    line.extend_from_slice(s.as_bytes());
+                    continue;
+                }
+                _ => (),
+            }
+            line.extend_from_slice(vm.tostring(v)?.as_bytes());
+        }
+        line.push(b'\n');
+        // like `fputs` in the reference `print`, write errors are ignored
+        let _ = host.stdout().write(&line);
+        Ok(Vec::new())
+    });
+    let rawequal = LuaNativeFunction::new("rawequal", |_, args| {
+        let a = check_any(&args, 1, "rawequal")?;
+        let b = check_any(&args, 2, "rawequal")?;
+        let equal = a.borrow().raw_equals(&b.borrow());
+        Ok(vec![boolean(equal)])
+    });
+    let rawget = LuaNativeFunction::new("rawget", |_, args| {
+        let t = check_table(&args, 1, "rawget")?;
+        let key = check_any(&args, 2, "rawget")?;
+        let v = LuaKey::from_value(&key).ok().and_then(|k| t.borrow().get(&k));
+        Ok(vec![v.unwrap_or_else(nil)])
+    });
+    let rawset = LuaNativeFunction::new("rawset", |_, args| {
+        let t = check_table(&args, 1, "rawset")?;
+        let key = check_any(&args, 2, "rawset")?;
+        let value = check_any(&args, 3, "rawset")?;
+        t.borrow_mut().set(LuaKey::from_value(&key)?, value);
+        Ok(vec![args[0].clone()])
+    });
+    let select = LuaNativeFunction::new("select", |_, args| {
+        let count = args.len().saturating_sub(1) as i64;
+        if matches!(args.first().map(|v| v.borrow()).as_deref(), Some(LuaValue::String(s)) if s == "#") {
+            return Ok(vec![number(count as f64)]);
+        }
+        let n = check_number(&args, 1, "select")? as i64;
+        let start = match n {
+            n if n < 0 => count + n,
+            n => n.min(count + 1) - 1,
+        };
+        if start < 0 {
+            return Err(arg_error(1, "select", "index out of range"));
+        }
+        Ok(args[1 + start as usize..].to_vec())
+    });
+    let setmetatable = LuaNativeFunction::new("setmetatable", |vm, args| {
+        check_table(&args, 1, "setmetatable")?;
+        let metatable = match args.get(1).map(|v| v.borrow()).as_deref() {
+            Some(LuaValue::Table(mt)) => Some(mt.clone()),
+            Some(LuaValue::Nil) => None,
+            _ => return Err(arg_error(2, "setmetatable", "nil or table expected")),
+        };
+        if is_protected(vm, &args[0]) {
+            bail!("cannot change a protected metatable");
+        }
+        vm.set_metatable(&args[0], metatable);
+        Ok(vec![args[0].clone()])
+    });
+    let tonumber = LuaNativeFunction::new("tonumber", |_, args| {
+        let base = opt_number(&args, 2, "tonumber")?.unwrap_or(10.0);
+        if base == 10.0 {
+            let v = check_any(&args, 1, "tonumber")?;
+            let n = v.borrow().to_number();
+            return Ok(vec![n.map_or_else(nil, number)]);
+        }
+        if !(2.0..=36.0).contains(&base) {
+            return Err(arg_error(2, "tonumber", "base out of range"));
+        }
+        let s = check_string(&args, 1, "tonumber")?;
+        let s = s.trim();
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let n = u64::from_str_radix(digits, base as u32).ok().map(|n| if negative { -(n as f64) } else { n as f64 });
+        Ok(vec![n.map_or_else(nil, number)])
+    });
+    let tostring = LuaNativeFunction::new("tostring", |vm, args| {
+        let v = check_any(&args, 1, "tostring")?;
+        if matches!(&*v.borrow(), LuaValue::String(_)) && vm.get_metamethod(&v, "__tostring").is_none() {
+            return Ok(vec![v]);
+        }
+        Ok(vec![string(vm.tostring(&v)?)])
+    });
+    let type_fn = LuaNativeFunction::new("type", |_, args| {
+        let v = check_any(&args, 1, "type")?;
+        let name = v.borrow().type_name();
+        Ok(vec![string(name)])
+    });
+    let unpack = LuaNativeFunction::new("unpack", |_, args| {
+        let t = check_table(&args, 1, "unpack")?;
+        let t = t.borrow();
+        let i = opt_number(&args, 2, "unpack")?.unwrap_or(1.0);
+        let j = match opt_number(&args, 3, "unpack")? {
+            Some(j) => j,
+            None => t.len() as f64,
+        };
+        if j - i >= MAX_UNPACK {
+            bail!("too many results to unpack");
+        }
+        let (i, j) = (i as i64, j as i64);
+        Ok((i..=j).map(|k| t.get_int(k).unwrap_or_else(nil)).collect())
+    });
+    vec![
+        assert,
+        collectgarbage,
+        error,
+        getmetatable,
+        ipairs,
+        loadstring,
+        next,
+        pairs,
+        pcall,
+        print,
+        rawequal,
+        rawget,
+        rawset,
+        select,
+        setmetatable,
+        tonumber,
+        tostring,
+        type_fn,
+        unpack,
+    ]
+}
+
+/// `loadstring`, which compiles source text and, if `binary` is set, loads
+/// precompiled chunks once they pass the verifier.
+pub(crate) fn loadstring(binary: bool) -> LuaNativeFunction {
+    LuaNativeFunction::new("loadstring", move |vm, args| {
+        let source = check_bytes(&args, 1, "loadstring")?;
+        let chunkname = opt_string(&args, 2, "loadstring")?.unwrap_or_else(|| source.to_string());
+        let chunk = if !source.starts_with("\x1bLua") {
+            compile(source.as_bytes(), &chunkname).map_err(Into::into)
+        } else if binary {
+            LuaChunk::from_reader(&mut source.as_bytes()).and_then(|chunk| verify(&chunk.func).map(|_| chunk))
+        } else {
+            return Ok(vec![nil(), string("attempt to load a binary chunk")]);
+        };
+        Ok(match chunk {
+            Ok(chunk) => vec![LuaValue::Function(vm.load(chunk)).to_gc()],
+            Err(e) => vec![nil(), string(e.to_string())],
+        })
+    })
+}
+
+/// Whether `vm` would refuse to let scripts replace `value`'s metatable.
+fn is_protected(vm: &LuaVM, value: &GCLuaValue) -> bool {
+    vm.get_metatable(value).is_some_and(|mt| mt.borrow().get_str("__metatable").is_some())
+}
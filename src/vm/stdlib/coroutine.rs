@@ -0,0 +1,73 @@
+use super::{arg_error, boolean, lib_table, nil, string};
+use crate::vm::{
+    error::LuaError,
+    table::GCLuaTable,
+    thread::{GCLuaThread, LuaThread},
+    GCLuaValue, LuaNativeFunction, LuaVM, LuaValue,
+};
+
+pub fn create() -> GCLuaTable {
+    let create = LuaNativeFunction::new("create", |_, args| {
+        let body = match args.first().map(|v| v.borrow()).as_deref() {
+            Some(LuaValue::Function(f)) => f.clone(),
+            _ => return Err(arg_error(1, "create", "Lua function expected")),
+        };
+        Ok(vec![LuaValue::Thread(GCLuaThread::new(LuaThread::new(body))).to_gc()])
+    });
+    let resume = LuaNativeFunction::new("resume", |vm, mut args| {
+        let co = check_thread(&args, "resume")?;
+        args.remove(0);
+        match vm.resume(&co, args) {
+            Ok(mut values) => {
+                values.insert(0, boolean(true));
+                Ok(values)
+            }
+            Err(e) if LuaError::is_catchable(&e) => Ok(vec![boolean(false), string(e.to_string())]),
+            Err(e) => Err(e),
+        }
+    });
+    let yield_ = LuaNativeFunction::new("yield", |vm, args| {
+        vm.yield_values(args)?;
+        Ok(Vec::new())
+    });
+    let status = LuaNativeFunction::new("status", |vm, args| {
+        let co = check_thread(&args, "status")?;
+        Ok(vec![string(status_of(vm, &co))])
+    });
+    let running = LuaNativeFunction::new("running", |vm, _| {
+        let current = vm.current_thread();
+        if current.borrow().is_main {
+            return Ok(vec![nil()]);
+        }
+        Ok(vec![LuaValue::Thread(current).to_gc()])
+    });
+    let wrap = LuaNativeFunction::new("wrap", |_, args| {
+        let body = match args.first().map(|v| v.borrow()).as_deref() {
+            Some(LuaValue::Function(f)) => f.clone(),
+            _ => return Err(arg_error(1, "wrap", "Lua function expected")),
+        };
+        let co = LuaValue::Thread(GCLuaThread::new(LuaThread::new(body))).to_gc();
+        let f = LuaNativeFunction::with_upvalues("wrap", vec![co], |vm, args| {
+            let co = match &*vm.native_upvalue(0).borrow() {
+                LuaValue::Thread(t) => t.clone(),
+                _ => unreachable!(),
+            };
+            vm.resume(&co, args)
+        });
+        Ok(vec![LuaValue::NativeFunction(f).to_gc()])
+    });
+    lib_table(vec![create, resume, yield_, status, running, wrap])
+}
+
+fn check_thread(args: &[GCLuaValue], fname: &str) -> anyhow::Result<GCLuaThread> {
+    match args.first().map(|v| v.borrow()).as_deref() {
+        Some(LuaValue::Thread(t)) => Ok(t.clone()),
+        _ => Err(arg_error(1, fname, "coroutine expected")),
+    }
+}
+fn status_of(vm: &LuaVM, co: &GCLuaThread) -> &'static str {
+    if co.ptr() == vm.current_thread().ptr() {
+        return "running";
+    }
+    co.borrow().status.as_str()
+}
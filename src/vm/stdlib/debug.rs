@@ -0,0 +1,246 @@
+use super::{
+    arg_error, check_any, check_function, check_number, check_string, is_none_or_nil, lib_table, nil, number, opt_number,
+    string,
+};
+use crate::vm::{
+    debug::{DebugInfo, HookEvent, HookMask, HOOK_KEY},
+    table::{GCLuaTable, LuaTable},
+    thread::GCLuaThread,
+    GCLuaValue, LuaNativeFunction, LuaVM, LuaValue,
+};
+
+pub fn create() -> GCLuaTable {
+    let getinfo = LuaNativeFunction::new("getinfo", |vm, args| {
+        let (thread, args) = split_thread(vm, args);
+        let options = match args.get(1) {
+            Some(_) => check_string(&args, 2, "getinfo")?,
+            None => "flnSu".to_string(),
+        };
+        if let Some(c) = options.chars().find(|c| !"SlunfL".contains(*c)) {
+            return Err(arg_error(2, "getinfo", &format!("invalid option '{}'", c)));
+        }
+        let info = match args.first().map(|v| v.borrow()).as_deref() {
+            Some(LuaValue::Function(_) | LuaValue::NativeFunction(_)) => vm.function_info(&args[0]),
+            Some(LuaValue::Number(_)) => {
+                let level = check_number(&args, 1, "getinfo")?;
+                if level < 0.0 {
+                    None
+                } else {
+                    at_level(vm, &thread, level as usize, |vm, level| vm.get_info(level), |vm| running_info(vm, "getinfo"))
+                }
+            }
+            _ => return Err(arg_error(1, "getinfo", "function or level expected")),
+        };
+        Ok(vec![info.map_or_else(nil, |info| info_table(&info, &options))])
+    });
+    let getlocal = LuaNativeFunction::new("getlocal", |vm, args| {
+        let (thread, args) = split_thread(vm, args);
+        let level = check_level(vm, &thread, &args, "getlocal")?;
+        let n = check_number(&args, 2, "getlocal")? as usize;
+        Ok(match at_level(vm, &thread, level, |vm, level| vm.get_local(level, n), |_| None) {
+            Some((name, value)) => vec![string(name), value],
+            None => vec![nil()],
+        })
+    });
+    let setlocal = LuaNativeFunction::new("setlocal", |vm, args| {
+        let (thread, args) = split_thread(vm, args);
+        let level = check_level(vm, &thread, &args, "setlocal")?;
+        let n = check_number(&args, 2, "setlocal")? as usize;
+        let value = check_any(&args, 3, "setlocal")?;
+        let name = at_level(vm, &thread, level, |vm, level| vm.set_local(level, n, value), |_| None);
+        Ok(vec![name.map_or_else(nil, string)])
+    });
+    let getupvalue = LuaNativeFunction::new("getupvalue", |vm, args| {
+        let func = check_function(&args, 1, "getupvalue")?;
+        let n = check_number(&args, 2, "getupvalue")? as usize;
+        Ok(match vm.upvalue_of(&func, n) {
+            Some((name, value)) => vec![string(name), value],
+            None => Vec::new(),
+        })
+    });
+    let setupvalue = LuaNativeFunction::new("setupvalue", |vm, args| {
+        let func = check_function(&args, 1, "setupvalue")?;
+        let n = check_number(&args, 2, "setupvalue")? as usize;
+        let value = check_any(&args, 3, "setupvalue")?;
+        Ok(vm.set_upvalue_of(&func, n, value).map(string).into_iter().collect())
+    });
+    let sethook = LuaNativeFunction::new("sethook", |vm, args| {
+        // hooks belong to the VM, so a thread argument changes nothing
+        let (_, args) = split_thread(vm, args);
+        if is_none_or_nil(&args, 1) {
+            vm.clear_hook();
+            return Ok(Vec::new());
+        }
+        let mask = check_string(&args, 2, "sethook")?;
+        let func = check_function(&args, 1, "sethook")?;
+        let count = opt_number(&args, 3, "sethook")?.unwrap_or(0.0).max(0.0) as u32;
+        let mask = HookMask { call: mask.contains('c'), ret: mask.contains('r'), line: mask.contains('l'), count };
+        vm.set_hook(mask, call_lua_hook);
+        vm.registry().borrow_mut().set_str(HOOK_KEY, func);
+        Ok(Vec::new())
+    });
+    let gethook = LuaNativeFunction::new("gethook", |vm, _| {
+        let Some(mask) = vm.hook_mask() else {
+            return Ok(vec![nil()]);
+        };
+        let hook = vm.registry().borrow().get_str(HOOK_KEY).filter(|f| !f.is_nil());
+        let mut chars = String::new();
+        for (on, c) in [(mask.call, 'c'), (mask.ret, 'r'), (mask.line, 'l')] {
+            if on {
+                chars.push(c);
+            }
+        }
+        Ok(vec![hook.unwrap_or_else(|| string("external hook")), string(chars), number(mask.count as f64)])
+    });
+    let traceback = LuaNativeFunction::new("traceback", |vm, args| {
+        let (thread, args) = split_thread(vm, args);
+        let message = match args.first().map(|v| v.borrow()).as_deref() {
+            None | Some(LuaValue::Nil) => None,
+            Some(LuaValue::String(_) | LuaValue::Number(_)) => Some(check_string(&args, 1, "traceback")?),
+            Some(_) => return Ok(vec![args[0].clone()]),
+        };
+        let level = match opt_number(&args, 2, "traceback")? {
+            Some(level) => level.max(0.0) as usize,
+            None if thread.is_some() => 0,
+            None => 1,
+        };
+        let mut out = message.map(|m| m + "\n").unwrap_or_default();
+        out += &match &thread {
+            Some(thread) => vm.on_thread(thread, |vm| vm.traceback(level)),
+            None if level == 0 => {
+                let own = match vm.stack_depth().checked_sub(1).and_then(|top| vm.call_name(top)) {
+                    Some((name, _)) => format!("\n\t[C]: in function '{}'", name),
+                    None => "\n\t[C]: ?".to_string(),
+                };
+                vm.traceback(0).replacen("stack traceback:", &format!("stack traceback:{}", own), 1)
+            }
+            None => vm.traceback(level - 1),
+        };
+        Ok(vec![string(out)])
+    });
+    let getmetatable = LuaNativeFunction::new("getmetatable", |vm, args| {
+        let value = check_any(&args, 1, "getmetatable")?;
+        Ok(vec![vm.get_metatable(&value).map_or_else(nil, |mt| LuaValue::Table(mt).to_gc())])
+    });
+    let setmetatable = LuaNativeFunction::new("setmetatable", |vm, args| {
+        let value = check_any(&args, 1, "setmetatable")?;
+        let metatable = match args.get(1).map(|v| v.borrow()).as_deref() {
+            Some(LuaValue::Table(t)) => Some(t.clone()),
+            Some(LuaValue::Nil) => None,
+            _ => return Err(arg_error(2, "setmetatable", "nil or table expected")),
+        };
+        vm.set_metatable(&value, metatable);
+        Ok(vec![LuaValue::Boolean(true).to_gc()])
+    });
+    let getregistry = LuaNativeFunction::new("getregistry", |vm, _| Ok(vec![LuaValue::Table(vm.registry()).to_gc()]));
+    lib_table(vec![
+        getinfo,
+        getlocal,
+        setlocal,
+        getupvalue,
+        setupvalue,
+        sethook,
+        gethook,
+        traceback,
+        getmetatable,
+        setmetatable,
+        getregistry,
+    ])
+}
+
+/// Calls the function `debug.sethook` stored, with the event name and, for
+/// line events, the line.
+fn call_lua_hook(vm: &mut LuaVM, event: HookEvent) -> anyhow::Result<()> {
+    let Some(hook) = vm.registry().borrow().get_str(HOOK_KEY) else {
+        return Ok(());
+    };
+    let line = match event {
+        HookEvent::Line(line) => number(line as f64),
+        _ => nil(),
+    };
+    vm.call_value(hook, vec![string(event.name()), line])?;
+    Ok(())
+}
+
+/// Takes a leading thread argument off `args`. The running thread counts as
+/// none, since its levels start at the native being called.
+fn split_thread(vm: &LuaVM, mut args: Vec<GCLuaValue>) -> (Option<GCLuaThread>, Vec<GCLuaValue>) {
+    let thread = match args.first().map(|v| v.borrow()).as_deref() {
+        Some(LuaValue::Thread(t)) => Some(t.clone()),
+        _ => None,
+    };
+    if thread.is_none() {
+        return (None, args);
+    }
+    args.remove(0);
+    (thread.filter(|t| t.ptr() != vm.current_thread().ptr()), args)
+}
+/// Runs `f` with the VM level for Lua level `level`. In the running thread
+/// level 0 is the native function itself, which `native` describes.
+fn at_level<R>(
+    vm: &mut LuaVM,
+    thread: &Option<GCLuaThread>,
+    level: usize,
+    f: impl FnOnce(&mut LuaVM, usize) -> Option<R>,
+    native: impl FnOnce(&LuaVM) -> Option<R>,
+) -> Option<R> {
+    match thread {
+        Some(thread) => vm.on_thread(thread, |vm| f(vm, level)),
+        None if level == 0 => native(vm),
+        None => f(vm, level - 1),
+    }
+}
+fn check_level(vm: &mut LuaVM, thread: &Option<GCLuaThread>, args: &[GCLuaValue], fname: &str) -> anyhow::Result<usize> {
+    let level = check_number(args, 1, fname)?;
+    let depth = match thread {
+        Some(thread) => vm.on_thread(thread, |vm| vm.stack_depth()),
+        None => vm.stack_depth() + 1,
+    };
+    if level < 0.0 || level as usize >= depth {
+        return Err(arg_error(1, fname, "level out of range"));
+    }
+    Ok(level as usize)
+}
+/// Level 0 of the running thread: the native function `fname` itself.
+fn running_info(vm: &LuaVM, fname: &str) -> Option<DebugInfo> {
+    let native = LuaValue::NativeFunction(LuaNativeFunction::new(fname, |_, _| Ok(Vec::new()))).to_gc();
+    let mut info = vm.function_info(&native)?;
+    if let Some((name, what)) = vm.stack_depth().checked_sub(1).and_then(|top| vm.call_name(top)) {
+        info.name = Some(name);
+        info.name_what = what;
+    }
+    Some(info)
+}
+/// The table `debug.getinfo` returns, with the fields `options` selects.
+fn info_table(info: &DebugInfo, options: &str) -> GCLuaValue {
+    let line = |l: Option<u32>| number(l.map_or(-1.0, |l| l as f64));
+    let mut t = LuaTable::new();
+    if options.contains('S') {
+        t.set_str("source", string(info.source.clone()));
+        t.set_str("short_src", string(info.short_src.clone()));
+        t.set_str("linedefined", line(info.line_defined));
+        t.set_str("lastlinedefined", line(info.last_line_defined));
+        t.set_str("what", string(info.what));
+    }
+    if options.contains('l') {
+        t.set_str("currentline", line(info.current_line));
+    }
+    if options.contains('u') {
+        t.set_str("nups", number(info.nups as f64));
+    }
+    if options.contains('n') {
+        t.set_str("name", info.name.clone().map_or_else(nil, string));
+        t.set_str("namewhat", string(info.name_what));
+    }
+    if options.contains('f') {
+        t.set_str("func", info.func.clone());
+    }
+    if options.contains('L') {
+        let mut lines = LuaTable::new();
+        for l in &info.active_lines {
+            lines.set_int(*l as i64, LuaValue::Boolean(true).to_gc());
+        }
+        t.set_str("activelines", LuaValue::Table(GCLuaTable::new(lines)).to_gc());
+    }
+    LuaValue::Table(GCLuaTable::new(t)).to_gc()
+}
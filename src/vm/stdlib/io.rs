@@ -0,0 +1,462 @@
+use std::{fmt::Debug, io::SeekFrom, rc::Rc};
+
+use anyhow::bail;
+use gc::{Finalize, Trace};
+
+use super::{
+    arg_error, boolean, check_number, check_string, io_failure, is_none_or_nil, lib_table, nil,
+    number, opt_number, opt_string, string,
+};
+use crate::vm::{
+    fmt_number,
+    hostio::{HostFile, HostIo, OpenMode, EINVAL},
+    parse_number,
+    table::GCLuaTable,
+    userdata::{check_userdata, GCLuaUserData, LuaUserData},
+    GCLuaValue, LuaNativeFunction, LuaVM, LuaValue,
+};
+
+/// An open (or closed) file as seen by scripts. Reads go through a small
+/// buffer so that `*l` and `*n` can look ahead.
+#[derive(Trace, Finalize)]
+pub struct LuaFile {
+    #[unsafe_ignore_trace]
+    handle: Option<Box<dyn HostFile>>,
+    buffer: Vec<u8>,
+    /// stdin/stdout/stderr cannot be closed by scripts.
+    standard: bool,
+}
+impl Debug for LuaFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LuaFile")
+            .field("open", &self.handle.is_some())
+            .field("standard", &self.standard)
+            .finish()
+    }
+}
+impl LuaFile {
+    pub fn new(handle: Box<dyn HostFile>) -> Self {
+        Self { handle: Some(handle), buffer: Vec::new(), standard: false }
+    }
+    pub fn is_closed(&self) -> bool {
+        self.handle.is_none()
+    }
+    fn handle(&mut self) -> anyhow::Result<&mut Box<dyn HostFile>> {
+        match &mut self.handle {
+            Some(h) => Ok(h),
+            None => bail!("attempt to use a closed file"),
+        }
+    }
+    /// Pulls more bytes into the read buffer, returning how many arrived.
+    fn fill(&mut self) -> std::io::Result<usize> {
+        let mut chunk = [0u8; 4096];
+        let n = match &mut self.handle {
+            Some(h) => h.read(&mut chunk)?,
+            None => 0,
+        };
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+    fn peek(&mut self) -> std::io::Result<Option<u8>> {
+        if self.buffer.is_empty() && self.fill()? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.buffer[0]))
+    }
+    fn read_line(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        let mut scanned = 0;
+        loop {
+            if let Some(pos) = self.buffer[scanned..].iter().position(|b| *b == b'\n') {
+                let mut line: Vec<u8> = self.buffer.drain(..scanned + pos + 1).collect();
+                line.pop();
+                return Ok(Some(line));
+            }
+            scanned = self.buffer.len();
+            if self.fill()? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Ok(Some(self.buffer.drain(..).collect()));
+            }
+        }
+    }
+    fn read_all(&mut self) -> std::io::Result<Vec<u8>> {
+        while self.fill()? > 0 {}
+        Ok(self.buffer.drain(..).collect())
+    }
+    fn read_chars(&mut self, n: usize) -> std::io::Result<Option<Vec<u8>>> {
+        while self.buffer.len() < n {
+            if self.fill()? == 0 {
+                break;
+            }
+        }
+        if n > 0 && self.buffer.is_empty() {
+            return Ok(None);
+        }
+        if n == 0 {
+            // `read(0)` only tests for end of file
+            return Ok(self.peek()?.map(|_| Vec::new()));
+        }
+        let n = n.min(self.buffer.len());
+        Ok(Some(self.buffer.drain(..n).collect()))
+    }
+    fn read_number(&mut self) -> std::io::Result<Option<f64>> {
+        while let Some(b) = self.peek()? {
+            if !b.is_ascii_whitespace() {
+                break;
+            }
+            self.buffer.remove(0);
+        }
+        let mut text = String::new();
+        while let Some(b) = self.peek()? {
+            let hex = text.trim_start_matches(['+', '-']).len() > 1 && text.contains(['x', 'X']);
+            let accept = b.is_ascii_digit()
+                || b == b'.'
+                || (hex && b.is_ascii_hexdigit())
+                || (matches!(b, b'x' | b'X') && matches!(text.trim_start_matches(['+', '-']), "0"))
+                || (!hex && matches!(b, b'e' | b'E') && !text.is_empty())
+                || (matches!(b, b'+' | b'-') && (text.is_empty() || (!hex && text.ends_with(['e', 'E']))));
+            if !accept {
+                break;
+            }
+            text.push(b as char);
+            self.buffer.remove(0);
+        }
+        Ok(parse_number(&text))
+    }
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<std::io::Result<()>> {
+        let buffered = self.buffer.len() as i64;
+        let handle = self.handle()?;
+        if buffered > 0 {
+            // give back read-ahead so the write lands where the script expects
+            let _ = handle.seek(SeekFrom::Current(-buffered));
+        }
+        self.buffer.clear();
+        Ok(self.handle()?.write(data))
+    }
+    fn seek(&mut self, pos: SeekFrom) -> anyhow::Result<std::io::Result<u64>> {
+        let buffered = self.buffer.len() as i64;
+        let pos = match pos {
+            SeekFrom::Current(off) => SeekFrom::Current(off - buffered),
+            p => p,
+        };
+        let r = self.handle()?.seek(pos);
+        if r.is_ok() {
+            self.buffer.clear();
+        }
+        Ok(r)
+    }
+    fn close(&mut self) -> std::io::Result<()> {
+        match self.handle.take() {
+            Some(mut h) => h.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Registry keys for the file metatable and the default input/output files.
+const FILE_META: &str = "FILE*";
+const DEFAULT_INPUT: &str = "io.input";
+const DEFAULT_OUTPUT: &str = "io.output";
+
+fn file_value(vm: &LuaVM, file: LuaFile) -> GCLuaValue {
+    let meta = match vm.registry().borrow().get_str(FILE_META).as_ref().map(|v| v.borrow()).as_deref() {
+        Some(LuaValue::Table(t)) => Some(t.clone()),
+        _ => None,
+    };
+    LuaValue::UserData(GCLuaUserData::new(LuaUserData::new(file, meta))).to_gc()
+}
+fn default_file(vm: &LuaVM, key: &str) -> GCLuaValue {
+    vm.registry().borrow().get_str(key).unwrap_or_else(nil)
+}
+/// The `LuaFile` userdata in argument `n`, or a "bad argument" error.
+fn check_file(args: &[GCLuaValue], n: usize, fname: &str) -> anyhow::Result<GCLuaUserData> {
+    check_userdata::<LuaFile>(args, n, fname, FILE_META)
+}
+/// Like `check_file` but also rejects closed files.
+fn check_open_file(args: &[GCLuaValue], n: usize, fname: &str) -> anyhow::Result<GCLuaUserData> {
+    let file = check_file(args, n, fname)?;
+    if with_file(&file, |f| f.is_closed()) {
+        bail!("attempt to use a closed file");
+    }
+    Ok(file)
+}
+fn with_file<R>(file: &GCLuaUserData, f: impl FnOnce(&mut LuaFile) -> R) -> R {
+    f(&mut file.borrow_mut::<LuaFile>().unwrap())
+}
+
+/// `file:read(...)`; `formats` are the arguments after the file itself.
+fn read_formats(file: &GCLuaUserData, formats: &[GCLuaValue], fname: &str, first_arg: usize) -> anyhow::Result<Vec<GCLuaValue>> {
+    let formats = if formats.is_empty() { vec![string("*l")] } else { formats.to_vec() };
+    let mut out = Vec::new();
+    for (i, format) in formats.iter().enumerate() {
+        let n = first_arg + i;
+        let read = with_file(file, |f| -> anyhow::Result<std::io::Result<Option<GCLuaValue>>> {
+            let format_ref = format.borrow();
+            Ok(match &*format_ref {
+                LuaValue::Number(count) => f.read_chars(*count as usize).map(|s| s.map(string)),
+                LuaValue::String(s) if s.starts_with("*n") => f.read_number().map(|v| v.map(number)),
+                LuaValue::String(s) if s.starts_with("*l") => f.read_line().map(|v| v.map(string)),
+                LuaValue::String(s) if s.starts_with("*a") => f.read_all().map(|v| Some(string(v))),
+                _ => return Err(arg_error(n, fname, "invalid format")),
+            })
+        })?;
+        match read {
+            Ok(Some(v)) => out.push(v),
+            Ok(None) => {
+                out.push(nil());
+                break;
+            }
+            Err(e) => return Ok(io_failure(&e, None)),
+        }
+    }
+    Ok(out)
+}
+/// Writes `values` to `file`, answering `true` as Lua 5.1 does (5.2 gives
+/// back the file).
+fn write_values(file: &GCLuaUserData, values: &[GCLuaValue], fname: &str, first_arg: usize) -> anyhow::Result<Vec<GCLuaValue>> {
+    for (i, v) in values.iter().enumerate() {
+        let bytes = match &*v.borrow() {
+            LuaValue::String(s) => s.as_bytes().to_vec(),
+            LuaValue::Number(n) => fmt_number(*n).into_bytes(),
+            other => {
+                return Err(arg_error(first_arg + i, fname, &format!("string expected, got {}", other.type_name())))
+            }
+        };
+        if let Err(e) = with_file(file, |f| f.write(&bytes))? {
+            return Ok(io_failure(&e, None));
+        }
+    }
+    Ok(vec![boolean(true)])
+}
+fn close_file(file: &GCLuaUserData) -> Vec<GCLuaValue> {
+    if with_file(file, |f| f.standard) {
+        return vec![nil(), string("cannot close standard file")];
+    }
+    match with_file(file, |f| f.close()) {
+        Ok(()) => vec![boolean(true)],
+        Err(e) => io_failure(&e, None),
+    }
+}
+/// Iterator returned by `io.lines`/`file:lines`. When `close_at_eof` is set the
+/// file was opened by `io.lines(name)` and is closed once it runs dry.
+fn lines_iterator(file: GCLuaValue, close_at_eof: bool) -> GCLuaValue {
+    let upvalues = vec![file, boolean(close_at_eof)];
+    LuaValue::NativeFunction(LuaNativeFunction::with_upvalues("lines", upvalues, |vm, _| {
+        let file = userdata_of(&vm.native_upvalue(0));
+        let close_at_eof = vm.native_upvalue(1).borrow().truthy();
+        if with_file(&file, |f| f.is_closed()) {
+            bail!("file is already closed");
+        }
+        match with_file(&file, |f| f.read_line()) {
+            Ok(Some(line)) => Ok(vec![string(line)]),
+            Ok(None) => {
+                if close_at_eof {
+                    let _ = with_file(&file, |f| f.close());
+                }
+                Ok(vec![nil()])
+            }
+            Err(e) => bail!("{}", super::strerror(&e)),
+        }
+    }))
+    .to_gc()
+}
+fn open_file(vm: &LuaVM, host: &dyn HostIo, path: &str, mode: &str) -> std::io::Result<GCLuaValue> {
+    let mode = match OpenMode::parse(mode) {
+        Some(m) => m,
+        None => return Err(std::io::Error::from_raw_os_error(EINVAL)),
+    };
+    let handle = host.open(path, mode)?;
+    Ok(file_value(vm, LuaFile::new(handle)))
+}
+fn userdata_of(v: &GCLuaValue) -> GCLuaUserData {
+    match &*v.borrow() {
+        LuaValue::UserData(u) => u.clone(),
+        _ => unreachable!("default files are always userdata"),
+    }
+}
+
+pub fn create(vm: &mut LuaVM, host: Rc<dyn HostIo>) -> GCLuaTable {
+    let meta = GCLuaTable::default();
+    let methods = file_methods();
+    meta.borrow_mut().set_str("__index", LuaValue::Table(methods).to_gc());
+    meta.borrow_mut().set_str(
+        "__tostring",
+        LuaValue::NativeFunction(LuaNativeFunction::new("tostring", |_, args| {
+            let file = check_file(&args, 1, "tostring")?;
+            if with_file(&file, |f| f.is_closed()) {
+                Ok(vec![string("file (closed)")])
+            } else {
+                Ok(vec![string(format!("file ({:#x})", file.ptr()))])
+            }
+        }))
+        .to_gc(),
+    );
+    vm.registry().borrow_mut().set_str(FILE_META, LuaValue::Table(meta).to_gc());
+
+    let standard = |vm: &LuaVM, handle: Box<dyn HostFile>| {
+        let mut f = LuaFile::new(handle);
+        f.standard = true;
+        file_value(vm, f)
+    };
+    let stdin = standard(vm, host.stdin());
+    let stdout = standard(vm, host.stdout());
+    let stderr = standard(vm, host.stderr());
+    {
+        let registry = vm.registry();
+        let mut registry = registry.borrow_mut();
+        registry.set_str(DEFAULT_INPUT, stdin.clone());
+        registry.set_str(DEFAULT_OUTPUT, stdout.clone());
+    }
+
+    let h = host.clone();
+    let open = LuaNativeFunction::new("open", move |vm, args| {
+        let path = check_string(&args, 1, "open")?;
+        let mode = opt_string(&args, 2, "open")?.unwrap_or_else(|| "r".to_string());
+        match open_file(vm, &*h, &path, &mode) {
+            Ok(f) => Ok(vec![f]),
+            Err(e) => Ok(io_failure(&e, Some(&path))),
+        }
+    });
+    let h = host.clone();
+    let tmpfile = LuaNativeFunction::new("tmpfile", move |vm, _| {
+        let opened = h.tmpname().and_then(|path| open_file(vm, &*h, &path, "w+"));
+        match opened {
+            Ok(f) => Ok(vec![f]),
+            Err(e) => Ok(io_failure(&e, None)),
+        }
+    });
+    let close = LuaNativeFunction::new("close", |vm, args| {
+        if is_none_or_nil(&args, 1) {
+            return Ok(close_file(&userdata_of(&default_file(vm, DEFAULT_OUTPUT))));
+        }
+        Ok(close_file(&check_file(&args, 1, "close")?))
+    });
+    let read = LuaNativeFunction::new("read", |vm, args| {
+        let file = check_open_file(&[default_file(vm, DEFAULT_INPUT)], 1, "read")?;
+        read_formats(&file, &args, "read", 1)
+    });
+    let write = LuaNativeFunction::new("write", |vm, args| {
+        let file = check_open_file(&[default_file(vm, DEFAULT_OUTPUT)], 1, "write")?;
+        write_values(&file, &args, "write", 1)
+    });
+    let h = host.clone();
+    let lines = LuaNativeFunction::new("lines", move |vm, args| {
+        if is_none_or_nil(&args, 1) {
+            let input = default_file(vm, DEFAULT_INPUT);
+            check_open_file(std::slice::from_ref(&input), 1, "lines")?;
+            return Ok(vec![lines_iterator(input, false)]);
+        }
+        let path = check_string(&args, 1, "lines")?;
+        match open_file(vm, &*h, &path, "r") {
+            Ok(f) => Ok(vec![lines_iterator(f, true)]),
+            Err(e) => bail!("{}: {}", path, super::strerror(&e)),
+        }
+    });
+    let h = host.clone();
+    let input = LuaNativeFunction::new("input", move |vm, args| {
+        select_default(vm, &*h, &args, "input", "r", DEFAULT_INPUT)
+    });
+    let h = host;
+    let output = LuaNativeFunction::new("output", move |vm, args| {
+        select_default(vm, &*h, &args, "output", "w", DEFAULT_OUTPUT)
+    });
+    let type_fn = LuaNativeFunction::new("type", |_, args| {
+        if args.is_empty() {
+            return Err(arg_error(1, "type", "value expected"));
+        }
+        Ok(vec![match check_file(&args, 1, "type") {
+            Ok(f) if with_file(&f, |f| f.is_closed()) => string("closed file"),
+            Ok(_) => string("file"),
+            Err(_) => nil(),
+        }])
+    });
+    let popen = LuaNativeFunction::new("popen", |_, _| bail!("'popen' not supported"));
+
+    let lib = lib_table(vec![open, tmpfile, close, read, write, lines, input, output, type_fn, popen]);
+    {
+        let mut l = lib.borrow_mut();
+        l.set_str("stdin", stdin);
+        l.set_str("stdout", stdout);
+        l.set_str("stderr", stderr);
+    }
+    lib
+}
+
+/// Shared body of `io.input` and `io.output`.
+fn select_default(
+    vm: &mut LuaVM,
+    host: &dyn HostIo,
+    args: &[GCLuaValue],
+    fname: &str,
+    mode: &str,
+    key: &str,
+) -> anyhow::Result<Vec<GCLuaValue>> {
+    if !is_none_or_nil(args, 1) {
+        let new = if let Some(LuaValue::String(_) | LuaValue::Number(_)) = args.first().map(|v| v.borrow()).as_deref() {
+            let path = check_string(args, 1, fname)?;
+            match open_file(vm, host, &path, mode) {
+                Ok(f) => f,
+                Err(e) => bail!("{}: {}", path, super::strerror(&e)),
+            }
+        } else {
+            check_file(args, 1, fname)?;
+            args[0].clone()
+        };
+        vm.registry().borrow_mut().set_str(key, new);
+    }
+    Ok(vec![default_file(vm, key)])
+}
+
+fn file_methods() -> GCLuaTable {
+    let read = LuaNativeFunction::new("read", |_, args| {
+        let file = check_open_file(&args, 1, "read")?;
+        read_formats(&file, &args[1..], "read", 2)
+    });
+    let write = LuaNativeFunction::new("write", |_, args| {
+        let file = check_open_file(&args, 1, "write")?;
+        write_values(&file, &args[1..], "write", 2)
+    });
+    let lines = LuaNativeFunction::new("lines", |_, args| {
+        check_open_file(&args, 1, "lines")?;
+        Ok(vec![lines_iterator(args[0].clone(), false)])
+    });
+    let close = LuaNativeFunction::new("close", |_, args| {
+        let file = check_open_file(&args, 1, "close")?;
+        Ok(close_file(&file))
+    });
+    let flush = LuaNativeFunction::new("flush", |_, args| {
+        let file = check_open_file(&args, 1, "flush")?;
+        match with_file(&file, |f| f.handle().map(|h| h.flush()))? {
+            Ok(()) => Ok(vec![args[0].clone()]),
+            Err(e) => Ok(io_failure(&e, None)),
+        }
+    });
+    let seek = LuaNativeFunction::new("seek", |_: &mut LuaVM, args| {
+        let file = check_open_file(&args, 1, "seek")?;
+        let whence = opt_string(&args, 2, "seek")?.unwrap_or_else(|| "cur".to_string());
+        let offset = opt_number(&args, 3, "seek")?.unwrap_or(0.0) as i64;
+        let pos = match whence.as_str() {
+            "set" => SeekFrom::Start(offset.max(0) as u64),
+            "cur" => SeekFrom::Current(offset),
+            "end" => SeekFrom::End(offset),
+            _ => return Err(arg_error(2, "seek", &format!("invalid option '{}'", whence))),
+        };
+        match with_file(&file, |f| f.seek(pos))? {
+            Ok(p) => Ok(vec![number(p as f64)]),
+            Err(e) => Ok(io_failure(&e, None)),
+        }
+    });
+    let setvbuf = LuaNativeFunction::new("setvbuf", |_, args| {
+        check_open_file(&args, 1, "setvbuf")?;
+        let mode = check_string(&args, 2, "setvbuf")?;
+        if !matches!(mode.as_str(), "no" | "full" | "line") {
+            return Err(arg_error(2, "setvbuf", &format!("invalid option '{}'", mode)));
+        }
+        if !is_none_or_nil(&args, 3) {
+            check_number(&args, 3, "setvbuf")?;
+        }
+        Ok(vec![boolean(true)])
+    });
+    lib_table(vec![read, write, lines, close, flush, seek, setvbuf])
+}
@@ -0,0 +1,166 @@
+//! Standard libraries. Nothing here is loaded by `LuaVM::new`; hosts opt in
+//! to each library, so untrusted scripts only see what they were given.
+use std::rc::Rc;
+
+use anyhow::anyhow;
+
+use super::{
+    hostio::HostIo,
+    string::LuaString,
+    table::{GCLuaTable, LuaTable},
+    GCLuaValue, LuaNativeFunction, LuaVM, LuaValue,
+};
+
+pub mod base;
+pub mod coroutine;
+pub mod debug;
+pub mod io;
+pub mod os;
+
+impl LuaVM {
+    /// Registers the base functions as globals, and `_G`; `print` writes to
+    /// `host`'s stdout.
+    pub fn open_base(&mut self, host: Rc<dyn HostIo>) -> GCLuaTable {
+        self.set_global("_G", LuaValue::Table(self.globals.clone()).to_gc());
+        for f in base::create(host) {
+            let name = f.name.clone();
+            self.set_global(&name, LuaValue::NativeFunction(f).to_gc());
+        }
+        self.globals.clone()
+    }
+    /// Registers the `os` library, routed through `host`.
+    pub fn open_os(&mut self, host: Rc<dyn HostIo>) -> GCLuaTable {
+        let lib = os::create(host);
+        self.set_global("os", LuaValue::Table(lib.clone()).to_gc());
+        lib
+    }
+    /// Registers the `coroutine` library.
+    pub fn open_coroutine(&mut self) -> GCLuaTable {
+        let lib = coroutine::create();
+        self.set_global("coroutine", LuaValue::Table(lib.clone()).to_gc());
+        lib
+    }
+    /// Registers the `debug` library, which can reach into any function's
+    /// locals and upvalues; not for untrusted scripts.
+    pub fn open_debug(&mut self) -> GCLuaTable {
+        let lib = debug::create();
+        self.set_global("debug", LuaValue::Table(lib.clone()).to_gc());
+        lib
+    }
+    /// Registers the `io` library, routed through `host`.
+    pub fn open_io(&mut self, host: Rc<dyn HostIo>) -> GCLuaTable {
+        let lib = io::create(self, host);
+        self.set_global("io", LuaValue::Table(lib.clone()).to_gc());
+        lib
+    }
+}
+
+/// Builds a library table out of named native functions.
+pub(crate) fn lib_table(functions: Vec<LuaNativeFunction>) -> GCLuaTable {
+    let mut table = LuaTable::new();
+    for f in functions {
+        let name = f.name.clone();
+        table.set_str(&name, LuaValue::NativeFunction(f).to_gc());
+    }
+    GCLuaTable::new(table)
+}
+
+pub(crate) fn nil() -> GCLuaValue {
+    LuaValue::Nil.to_gc()
+}
+pub(crate) fn string(s: impl Into<LuaString>) -> GCLuaValue {
+    LuaValue::String(s.into()).to_gc()
+}
+pub(crate) fn number(n: f64) -> GCLuaValue {
+    LuaValue::Number(n).to_gc()
+}
+pub(crate) fn boolean(b: bool) -> GCLuaValue {
+    LuaValue::Boolean(b).to_gc()
+}
+
+pub(crate) fn arg_error(n: usize, fname: &str, msg: &str) -> anyhow::Error {
+    anyhow!("bad argument #{} to '{}' ({})", n, fname, msg)
+}
+fn type_error(args: &[GCLuaValue], n: usize, fname: &str, expected: &str) -> anyhow::Error {
+    let got = match args.get(n - 1) {
+        Some(v) => v.borrow().type_name(),
+        None => "no value",
+    };
+    arg_error(n, fname, &format!("{} expected, got {}", expected, got))
+}
+/// True when argument `n` (1-based) is absent or nil.
+pub(crate) fn is_none_or_nil(args: &[GCLuaValue], n: usize) -> bool {
+    args.get(n - 1).map(|v| v.is_nil()).unwrap_or(true)
+}
+/// Argument `n` as text, for names and options; see `check_bytes`.
+pub(crate) fn check_string(args: &[GCLuaValue], n: usize, fname: &str) -> anyhow::Result<String> {
+    check_bytes(args, n, fname).map(|s| s.to_string())
+}
+/// Argument `n` as a string, byte for byte.
+pub(crate) fn check_bytes(args: &[GCLuaValue], n: usize, fname: &str) -> anyhow::Result<LuaString> {
+    match args.get(n - 1).map(|v| v.borrow()).as_deref() {
+        Some(LuaValue::String(s)) => return Ok(s.clone()),
+        Some(LuaValue::Number(v)) => return Ok(super::fmt_number(*v).into()),
+        _ => (),
+    }
+    Err(type_error(args, n, fname, "string"))
+}
+pub(crate) fn opt_string(args: &[GCLuaValue], n: usize, fname: &str) -> anyhow::Result<Option<String>> {
+    if is_none_or_nil(args, n) {
+        return Ok(None);
+    }
+    check_string(args, n, fname).map(Some)
+}
+pub(crate) fn check_number(args: &[GCLuaValue], n: usize, fname: &str) -> anyhow::Result<f64> {
+    match args.get(n - 1).and_then(|v| v.borrow().to_number()) {
+        Some(v) => Ok(v),
+        None => Err(type_error(args, n, fname, "number")),
+    }
+}
+pub(crate) fn opt_number(args: &[GCLuaValue], n: usize, fname: &str) -> anyhow::Result<Option<f64>> {
+    if is_none_or_nil(args, n) {
+        return Ok(None);
+    }
+    check_number(args, n, fname).map(Some)
+}
+pub(crate) fn check_table(args: &[GCLuaValue], n: usize, fname: &str) -> anyhow::Result<GCLuaTable> {
+    match args.get(n - 1).map(|v| v.borrow()).as_deref() {
+        Some(LuaValue::Table(t)) => Ok(t.clone()),
+        _ => Err(type_error(args, n, fname, "table")),
+    }
+}
+pub(crate) fn check_any(args: &[GCLuaValue], n: usize, fname: &str) -> anyhow::Result<GCLuaValue> {
+    args.get(n - 1).cloned().ok_or_else(|| arg_error(n, fname, "value expected"))
+}
+pub(crate) fn check_function(args: &[GCLuaValue], n: usize, fname: &str) -> anyhow::Result<GCLuaValue> {
+    match args.get(n - 1).map(|v| v.borrow()).as_deref() {
+        Some(LuaValue::Function(_) | LuaValue::NativeFunction(_)) => Ok(args[n - 1].clone()),
+        _ => Err(arg_error(n, fname, "function expected")),
+    }
+}
+/// Result triple used by functions that fail softly: `nil, message, errno`.
+pub(crate) fn io_failure(e: &std::io::Error, path: Option<&str>) -> Vec<GCLuaValue> {
+    let msg = match path {
+        Some(p) => format!("{}: {}", p, strerror(e)),
+        None => strerror(e),
+    };
+    vec![nil(), string(msg), number(errno(e) as f64)]
+}
+/// The C `strerror` text for an io error, without Rust's "(os error N)" suffix.
+pub(crate) fn strerror(e: &std::io::Error) -> String {
+    let s = e.to_string();
+    match s.find(" (os error") {
+        Some(idx) => s[..idx].to_string(),
+        None => s,
+    }
+}
+pub(crate) fn errno(e: &std::io::Error) -> i32 {
+    use std::io::ErrorKind;
+    e.raw_os_error().unwrap_or(match e.kind() {
+        ErrorKind::NotFound => 2,
+        ErrorKind::PermissionDenied => 13,
+        ErrorKind::AlreadyExists => 17,
+        ErrorKind::InvalidInput => 22,
+        _ => 5,
+    })
+}
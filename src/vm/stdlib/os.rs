@@ -0,0 +1,271 @@
+use std::rc::Rc;
+
+use anyhow::bail;
+
+use super::{
+    boolean, check_number, check_string, check_table, io_failure, is_none_or_nil,
+    lib_table, nil, number, opt_number, opt_string, string,
+};
+use crate::vm::{
+    error::LuaError,
+    hostio::HostIo,
+    table::{GCLuaTable, LuaTable},
+    LuaNativeFunction, LuaValue,
+};
+
+pub fn create(host: Rc<dyn HostIo>) -> GCLuaTable {
+    let h = host.clone();
+    let time = LuaNativeFunction::new("time", move |_, args| {
+        if is_none_or_nil(&args, 1) {
+            return Ok(vec![number(h.time().floor())]);
+        }
+        let t = check_table(&args, 1, "time")?;
+        let t = time_from_table(&*h, &t.borrow())?;
+        Ok(vec![number(t as f64)])
+    });
+    let h = host.clone();
+    let clock = LuaNativeFunction::new("clock", move |_, _| Ok(vec![number(h.clock())]));
+    let h = host.clone();
+    let date = LuaNativeFunction::new("date", move |_, args| {
+        let format = opt_string(&args, 1, "date")?.unwrap_or_else(|| "%c".to_string());
+        let t = match opt_number(&args, 2, "date")? {
+            Some(t) => t.floor() as i64,
+            None => h.time().floor() as i64,
+        };
+        let (utc, format) = match format.strip_prefix('!') {
+            Some(f) => (true, f),
+            None => (false, format.as_str()),
+        };
+        let offset = if utc { 0 } else { h.utc_offset(t) };
+        let dt = DateTime::from_unix(t + offset);
+        if format.starts_with("*t") {
+            return Ok(vec![LuaValue::Table(dt.to_table()).to_gc()]);
+        }
+        Ok(vec![string(dt.strftime(format, utc, offset))])
+    });
+    let h = host.clone();
+    let getenv = LuaNativeFunction::new("getenv", move |_, args| {
+        let name = check_string(&args, 1, "getenv")?;
+        Ok(vec![h.getenv(&name).map(string).unwrap_or_else(nil)])
+    });
+    let difftime = LuaNativeFunction::new("difftime", |_, args| {
+        let t2 = check_number(&args, 1, "difftime")?;
+        let t1 = opt_number(&args, 2, "difftime")?.unwrap_or(0.0);
+        Ok(vec![number(t2 - t1)])
+    });
+    let h = host.clone();
+    let tmpname = LuaNativeFunction::new("tmpname", move |_, _| match h.tmpname() {
+        Ok(name) => Ok(vec![string(name)]),
+        Err(_) => bail!("unable to generate a unique filename"),
+    });
+    let h = host.clone();
+    let remove = LuaNativeFunction::new("remove", move |_, args| {
+        let path = check_string(&args, 1, "remove")?;
+        match h.remove(&path) {
+            Ok(()) => Ok(vec![boolean(true)]),
+            Err(e) => Ok(io_failure(&e, Some(&path))),
+        }
+    });
+    let h = host.clone();
+    let rename = LuaNativeFunction::new("rename", move |_, args| {
+        let from = check_string(&args, 1, "rename")?;
+        let to = check_string(&args, 2, "rename")?;
+        match h.rename(&from, &to) {
+            Ok(()) => Ok(vec![boolean(true)]),
+            Err(e) => Ok(io_failure(&e, Some(&from))),
+        }
+    });
+    let h = host;
+    let exit = LuaNativeFunction::new("exit", move |_, args| {
+        let code = match args.first().map(|v| v.borrow()).as_deref() {
+            None | Some(LuaValue::Nil) | Some(LuaValue::Boolean(true)) => 0,
+            Some(LuaValue::Boolean(false)) => 1,
+            _ => check_number(&args, 1, "exit")? as i32,
+        };
+        h.exit(code);
+        Err(LuaError::Exit(code).into())
+    });
+    lib_table(vec![time, clock, date, getenv, difftime, tmpname, remove, rename, exit])
+}
+
+/// `os.time{...}`: the table is read as local time and normalised the way
+/// `mktime` does, so out-of-range fields carry into the next unit.
+fn time_from_table(host: &dyn HostIo, t: &LuaTable) -> anyhow::Result<i64> {
+    let field = |name: &str, default: Option<i64>| -> anyhow::Result<i64> {
+        match t.get_str(name).and_then(|v| v.borrow().to_number()) {
+            Some(v) => Ok(v as i64),
+            None => match default {
+                Some(d) => Ok(d),
+                None => bail!("field '{}' missing in date table", name),
+            },
+        }
+    };
+    let sec = field("sec", Some(0))?;
+    let min = field("min", Some(0))?;
+    let hour = field("hour", Some(12))?;
+    let day = field("day", None)?;
+    let month = field("month", None)? - 1;
+    let year = field("year", None)?;
+    let year = year + month.div_euclid(12);
+    let month = month.rem_euclid(12) + 1;
+    let days = days_from_civil(year, month, 1) + day - 1;
+    let local = days * 86400 + hour * 3600 + min * 60 + sec;
+    let offset = host.utc_offset(local - host.utc_offset(local));
+    Ok(local - offset)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (if m <= 2 { yoe + era * 400 + 1 } else { yoe + era * 400 }, m, d)
+}
+
+const DAY_NAMES: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+const MONTH_NAMES: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June", "July", "August", "September", "October",
+    "November", "December",
+];
+
+/// Broken-down time, the equivalent of C's `struct tm`.
+struct DateTime {
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    min: i64,
+    sec: i64,
+    /// 0 = Sunday
+    wday: i64,
+    /// 0-based day of the year
+    yday: i64,
+}
+impl DateTime {
+    fn from_unix(t: i64) -> Self {
+        let days = t.div_euclid(86400);
+        let secs = t.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            min: secs / 60 % 60,
+            sec: secs % 60,
+            wday: (days + 4).rem_euclid(7),
+            yday: days - days_from_civil(year, 1, 1),
+        }
+    }
+    fn to_table(&self) -> GCLuaTable {
+        let mut t = LuaTable::new();
+        let mut set = |k: &str, v: i64| t.set_str(k, number(v as f64));
+        set("year", self.year);
+        set("month", self.month);
+        set("day", self.day);
+        set("hour", self.hour);
+        set("min", self.min);
+        set("sec", self.sec);
+        set("wday", self.wday + 1);
+        set("yday", self.yday + 1);
+        t.set_str("isdst", boolean(false));
+        GCLuaTable::new(t)
+    }
+    fn hour12(&self) -> i64 {
+        match self.hour % 12 {
+            0 => 12,
+            h => h,
+        }
+    }
+    /// ISO 8601 week-based year and week number.
+    fn iso_week(&self) -> (i64, i64) {
+        let wday = (self.wday + 6) % 7;
+        let week = (self.yday - wday + 10) / 7;
+        if week < 1 {
+            let prev = DateTime::from_unix((days_from_civil(self.year, 1, 1) - 1) * 86400);
+            return (self.year - 1, prev.iso_week().1);
+        }
+        let days_in_year = days_from_civil(self.year + 1, 1, 1) - days_from_civil(self.year, 1, 1);
+        if week == 53 && self.yday - wday + 3 >= days_in_year {
+            return (self.year + 1, 1);
+        }
+        (self.year, week)
+    }
+    /// C-locale `strftime`. Unknown conversions are copied through verbatim.
+    fn strftime(&self, format: &str, utc: bool, offset: i64) -> String {
+        let mut out = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            let spec = match chars.next() {
+                Some(s) => s,
+                None => {
+                    out.push('%');
+                    break;
+                }
+            };
+            let day = DAY_NAMES[self.wday as usize];
+            let month = MONTH_NAMES[self.month as usize - 1];
+            let s = match spec {
+                'a' => day[..3].to_string(),
+                'A' => day.to_string(),
+                'b' | 'h' => month[..3].to_string(),
+                'B' => month.to_string(),
+                'c' => self.strftime("%a %b %e %H:%M:%S %Y", utc, offset),
+                'C' => format!("{:02}", self.year.div_euclid(100)),
+                'd' => format!("{:02}", self.day),
+                'D' | 'x' => self.strftime("%m/%d/%y", utc, offset),
+                'e' => format!("{:2}", self.day),
+                'F' => self.strftime("%Y-%m-%d", utc, offset),
+                'g' => format!("{:02}", self.iso_week().0.rem_euclid(100)),
+                'G' => self.iso_week().0.to_string(),
+                'H' => format!("{:02}", self.hour),
+                'I' => format!("{:02}", self.hour12()),
+                'j' => format!("{:03}", self.yday + 1),
+                'm' => format!("{:02}", self.month),
+                'M' => format!("{:02}", self.min),
+                'n' => "\n".to_string(),
+                'p' => if self.hour < 12 { "AM" } else { "PM" }.to_string(),
+                'r' => self.strftime("%I:%M:%S %p", utc, offset),
+                'R' => self.strftime("%H:%M", utc, offset),
+                'S' => format!("{:02}", self.sec),
+                't' => "\t".to_string(),
+                'T' | 'X' => self.strftime("%H:%M:%S", utc, offset),
+                'u' => (((self.wday + 6) % 7) + 1).to_string(),
+                'U' => format!("{:02}", (self.yday + 7 - self.wday) / 7),
+                'V' => format!("{:02}", self.iso_week().1),
+                'w' => self.wday.to_string(),
+                'W' => format!("{:02}", (self.yday + 7 - (self.wday + 6) % 7) / 7),
+                'y' => format!("{:02}", self.year.rem_euclid(100)),
+                'Y' => self.year.to_string(),
+                'z' => {
+                    let sign = if offset < 0 { '-' } else { '+' };
+                    let abs = offset.abs();
+                    format!("{}{:02}{:02}", sign, abs / 3600, abs / 60 % 60)
+                }
+                'Z' => if utc || offset == 0 { "UTC" } else { "" }.to_string(),
+                '%' => "%".to_string(),
+                other => format!("%{}", other),
+            };
+            out.push_str(&s);
+        }
+        out
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+};
+
+use anyhow::bail;
+use gc::{Finalize, Gc, GcCell, GcCellRef, GcCellRefMut, Trace};
+
+use super::{memory, string::LuaString, GCLuaValue, LuaValue};
+
+/// A hashable view of a `LuaValue` used as a table key. Reference types are
+/// keyed by the address of their GC allocation, and keep the value alive.
+#[derive(Debug, Clone, Trace, Finalize)]
+pub enum LuaKey {
+    Boolean(bool),
+    Number(u64),
+    String(LuaString),
+    LightUserData(usize),
+    Object(usize, GCLuaValue),
+}
+impl LuaKey {
+    pub fn from_value(value: &GCLuaValue) -> anyhow::Result<Self> {
+        Ok(match &*value.borrow() {
+            LuaValue::Nil => bail!("table index is nil"),
+            LuaValue::Boolean(b) => LuaKey::Boolean(*b),
+            LuaValue::Number(n) => {
+                if n.is_nan() {
+                    bail!("table index is NaN");
+                }
+                LuaKey::number(*n)
+            }
+            LuaValue::String(s) => LuaKey::String(s.clone()),
+            LuaValue::LightUserData(p) => LuaKey::LightUserData(*p),
+            v => LuaKey::Object(v.identity(), value.clone()),
+        })
+    }
+    pub fn number(n: f64) -> Self {
+        // -0.0 and 0.0 are the same key
+        LuaKey::Number(if n == 0.0 { 0f64.to_bits() } else { n.to_bits() })
+    }
+    pub fn string(s: &str) -> Self {
+        LuaKey::String(s.into())
+    }
+    pub fn to_value(&self) -> GCLuaValue {
+        match self {
+            LuaKey::Boolean(b) => LuaValue::Boolean(*b).to_gc(),
+            LuaKey::Number(n) => LuaValue::Number(f64::from_bits(*n)).to_gc(),
+            LuaKey::String(s) => LuaValue::String(s.clone()).to_gc(),
+            LuaKey::LightUserData(p) => LuaValue::LightUserData(*p).to_gc(),
+            LuaKey::Object(_, v) => v.clone(),
+        }
+    }
+}
+impl PartialEq for LuaKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (LuaKey::Boolean(a), LuaKey::Boolean(b)) => a == b,
+            (LuaKey::Number(a), LuaKey::Number(b)) => a == b,
+            (LuaKey::String(a), LuaKey::String(b)) => a == b,
+            (LuaKey::LightUserData(a), LuaKey::LightUserData(b)) => a == b,
+            (LuaKey::Object(a, _), LuaKey::Object(b, _)) => a == b,
+            _ => false,
+        }
+    }
+}
+impl Eq for LuaKey {}
+impl Hash for LuaKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            LuaKey::Boolean(b) => b.hash(state),
+            LuaKey::Number(n) => n.hash(state),
+            LuaKey::String(s) => s.hash(state),
+            LuaKey::LightUserData(p) => p.hash(state),
+            LuaKey::Object(addr, _) => addr.hash(state),
+        }
+    }
+}
+
+/// Lua table. Entries are kept in insertion order so that `next` is cheap and
+/// traversal order is deterministic; assigning nil leaves a tombstone which is
+/// only compacted away when a new key is inserted.
+#[derive(Debug, Default, Trace, Finalize)]
+pub struct LuaTable {
+    entries: Vec<(LuaKey, GCLuaValue)>,
+    index: HashMap<LuaKey, usize, ahash::RandomState>,
+    tombstones: usize,
+    pub metatable: Option<GCLuaTable>,
+}
+impl LuaTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn get(&self, key: &LuaKey) -> Option<GCLuaValue> {
+        let idx = *self.index.get(key)?;
+        let v = &self.entries[idx].1;
+        if v.is_nil() {
+            None
+        } else {
+            Some(v.clone())
+        }
+    }
+    pub fn get_str(&self, key: &str) -> Option<GCLuaValue> {
+        self.get(&LuaKey::string(key))
+    }
+    pub fn get_int(&self, key: i64) -> Option<GCLuaValue> {
+        self.get(&LuaKey::number(key as f64))
+    }
+    pub fn set(&mut self, key: LuaKey, value: GCLuaValue) {
+        if let Some(idx) = self.index.get(&key) {
+            let slot = &mut self.entries[*idx].1;
+            match (slot.is_nil(), value.is_nil()) {
+                (true, false) => self.tombstones -= 1,
+                (false, true) => self.tombstones += 1,
+                _ => (),
+            }
+            *slot = value;
+            return;
+        }
+        if value.is_nil() {
+            return;
+        }
+        if self.tombstones > 8 && self.tombstones * 2 > self.entries.len() {
+            self.compact();
+        }
+        memory::note_alloc(memory::entry_size(&key));
+        self.index.insert(key.clone(), self.entries.len());
+        self.entries.push((key, value));
+    }
+    pub fn set_str(&mut self, key: &str, value: GCLuaValue) {
+        self.set(LuaKey::string(key), value);
+    }
+    pub fn set_int(&mut self, key: i64, value: GCLuaValue) {
+        self.set(LuaKey::number(key as f64), value);
+    }
+    fn compact(&mut self) {
+        self.entries.retain(|(_, v)| !v.is_nil());
+        self.index.clear();
+        for (idx, (k, _)) in self.entries.iter().enumerate() {
+            self.index.insert(k.clone(), idx);
+        }
+        self.tombstones = 0;
+    }
+    /// Border of the array part, as returned by the `#` operator.
+    pub fn len(&self) -> usize {
+        let mut n = 0;
+        while self.get_int(n as i64 + 1).is_some() {
+            n += 1;
+        }
+        n
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.len() == self.tombstones
+    }
+    /// All entries, tombstones included.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &(LuaKey, GCLuaValue)> {
+        self.entries.iter()
+    }
+    /// Traversal step for `next`: the entry following `key`, or the first
+    /// entry when `key` is `None`.
+    pub fn next(&self, key: Option<&LuaKey>) -> anyhow::Result<Option<(LuaKey, GCLuaValue)>> {
+        let start = match key {
+            None => 0,
+            Some(k) => match self.index.get(k) {
+                Some(idx) => idx + 1,
+                None => bail!("invalid key to 'next'"),
+            },
+        };
+        Ok(self.entries[start.min(self.entries.len())..]
+            .iter()
+            .find(|(_, v)| !v.is_nil())
+            .cloned())
+    }
+}
+#[derive(Debug, Clone, Trace, Finalize)]
+pub struct GCLuaTable(Gc<GcCell<LuaTable>>);
+impl GCLuaTable {
+    pub fn new(v: LuaTable) -> Self {
+        memory::note_alloc(memory::table_size(&v));
+        Self(Gc::new(GcCell::new(v)))
+    }
+    pub fn borrow(&self) -> GcCellRef<'_, LuaTable> {
+        self.0.try_borrow().unwrap()
+    }
+    pub fn borrow_mut(&self) -> GcCellRefMut<'_, LuaTable> {
+        self.0.try_borrow_mut().unwrap()
+    }
+    pub fn ptr(&self) -> usize {
+        &*self.0 as *const GcCell<LuaTable> as usize
+    }
+}
+impl Default for GCLuaTable {
+    fn default() -> Self {
+        Self::new(LuaTable::new())
+    }
+}
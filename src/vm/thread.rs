@@ -0,0 +1,130 @@
+use gc::{Finalize, Gc, GcCell, GcCellRef, GcCellRefMut, Trace};
+
+use super::{memory, GCLuaFunction, GCLuaValue};
+
+/// Where the results of a finished call go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReturnTo {
+    /// Hand them back to the Rust caller of `LuaVM::execute`.
+    Host,
+    /// Store them in the caller's stack at `dest`; `wanted` is `None` for
+    /// "all results" (C == 0), which also sets the caller's top.
+    Register { dest: usize, wanted: Option<usize> },
+}
+/// One activation of a Lua function.
+#[derive(Debug, Clone, Trace, Finalize)]
+pub struct CallFrame {
+    pub func: GCLuaFunction,
+    /// Absolute stack index of R(0).
+    pub base: usize,
+    /// Index of the next instruction to run.
+    pub pc: usize,
+    pub varargs: Vec<GCLuaValue>,
+    /// End of the values produced by the last multi-result CALL or VARARG.
+    pub top: Option<usize>,
+    #[unsafe_ignore_trace]
+    pub ret: ReturnTo,
+    /// Frames this one replaced through tail calls.
+    pub tail_calls: u32,
+    /// Instruction the line hook last saw run in this frame.
+    pub hook_pc: Option<usize>,
+}
+/// A captured local. Open upvalues still live in a thread's stack; they are
+/// closed (copied out) when the frame owning the slot returns.
+#[derive(Debug, Trace, Finalize)]
+pub enum UpValue {
+    Open(GCLuaThread, usize),
+    Closed(GCLuaValue),
+}
+#[derive(Debug, Clone, Trace, Finalize)]
+pub struct GCUpValue(Gc<GcCell<UpValue>>);
+impl GCUpValue {
+    pub fn new(v: UpValue) -> Self {
+        memory::note_alloc(memory::upvalue_size());
+        Self(Gc::new(GcCell::new(v)))
+    }
+    pub fn ptr(&self) -> usize {
+        &*self.0 as *const GcCell<UpValue> as usize
+    }
+    pub fn borrow(&self) -> GcCellRef<'_, UpValue> {
+        self.0.try_borrow().unwrap()
+    }
+    pub fn borrow_mut(&self) -> GcCellRefMut<'_, UpValue> {
+        self.0.try_borrow_mut().unwrap()
+    }
+    pub fn open_index(&self) -> Option<usize> {
+        match &*self.borrow() {
+            UpValue::Open(_, idx) => Some(*idx),
+            UpValue::Closed(_) => None,
+        }
+    }
+}
+/// Execution state of a thread: its value stack and call frames. The
+/// running thread's state lives in `LuaVM`; the others keep theirs here.
+#[derive(Debug, Default, Trace, Finalize)]
+pub struct ThreadState {
+    pub stack: Vec<GCLuaValue>,
+    pub frames: Vec<CallFrame>,
+    /// Open upvalues, sorted by stack index.
+    pub open_upvalues: Vec<GCUpValue>,
+    /// Where the values passed to the next `resume` go: the destination of
+    /// the CALL that yielded.
+    #[unsafe_ignore_trace]
+    pub resume_target: Option<ReturnTo>,
+    /// Number of Rust-level calls (natives calling back into Lua,
+    /// metamethods) on this thread; yielding is only allowed at zero.
+    pub nny: usize,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadStatus {
+    Suspended,
+    Running,
+    /// Resumed another coroutine and is waiting for it.
+    Normal,
+    Dead,
+}
+impl ThreadStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThreadStatus::Suspended => "suspended",
+            ThreadStatus::Running => "running",
+            ThreadStatus::Normal => "normal",
+            ThreadStatus::Dead => "dead",
+        }
+    }
+}
+/// A coroutine, or the main thread.
+#[derive(Debug, Trace, Finalize)]
+pub struct LuaThread {
+    #[unsafe_ignore_trace]
+    pub status: ThreadStatus,
+    /// The function a coroutine runs, until its first resume.
+    pub body: Option<GCLuaFunction>,
+    pub state: ThreadState,
+    pub is_main: bool,
+}
+impl LuaThread {
+    pub fn main() -> Self {
+        Self { status: ThreadStatus::Running, body: None, state: ThreadState::default(), is_main: true }
+    }
+    pub fn new(body: GCLuaFunction) -> Self {
+        Self { status: ThreadStatus::Suspended, body: Some(body), state: ThreadState::default(), is_main: false }
+    }
+}
+#[derive(Debug, Clone, Trace, Finalize)]
+pub struct GCLuaThread(Gc<GcCell<LuaThread>>);
+impl GCLuaThread {
+    pub fn new(v: LuaThread) -> Self {
+        memory::note_alloc(memory::thread_size());
+        Self(Gc::new(GcCell::new(v)))
+    }
+    pub fn borrow(&self) -> GcCellRef<'_, LuaThread> {
+        self.0.try_borrow().unwrap()
+    }
+    pub fn borrow_mut(&self) -> GcCellRefMut<'_, LuaThread> {
+        self.0.try_borrow_mut().unwrap()
+    }
+    pub fn ptr(&self) -> usize {
+        &*self.0 as *const GcCell<LuaThread> as usize
+    }
+}
@@ -0,0 +1,144 @@
+//! Execution tracing. A `TraceSink` set with `LuaVM::set_trace` is told what
+//! the VM does as it does it; with none set the VM only checks for one.
+
+use std::{fmt::Write as _, io::Write};
+
+use super::{chunk_parser::FunctionBlock, json::quote, listing, GCLuaValue};
+
+/// Something the VM did.
+pub enum TraceEvent<'a> {
+    /// A chunk was handed to the VM to run.
+    ChunkLoad { func: &'a FunctionBlock },
+    /// The instruction at `pc` of `func` is about to run, in a frame
+    /// `depth` deep whose registers hold `registers`.
+    Instruction { func: &'a FunctionBlock, pc: usize, depth: usize, registers: &'a [GCLuaValue] },
+    /// A Lua function was entered, making a frame `depth` deep.
+    Call { func: &'a FunctionBlock, depth: usize, args: usize },
+    /// A native function was called from a frame `depth` deep.
+    NativeCall { name: &'a str, depth: usize, args: usize },
+    /// The frame `depth` deep returned.
+    Return { depth: usize, results: usize },
+    /// Constant `index` of the function running `depth` deep was read.
+    Constant { index: u32, depth: usize, value: &'a GCLuaValue },
+}
+
+/// Receives trace events.
+pub trait TraceSink {
+    fn event(&mut self, event: &TraceEvent);
+}
+
+/// Where a function comes from, as `source:line`.
+fn location(func: &FunctionBlock) -> String {
+    format!("{}:{}", func.source_name, func.line_def)
+}
+
+/// The listing of one instruction, on one line without tabs.
+fn text_of(func: &FunctionBlock, pc: usize) -> String {
+    listing::instruction(func, pc).replace('\t', " ")
+}
+
+fn line(func: &FunctionBlock, pc: usize) -> Option<u32> {
+    func.line_info.get(pc).copied()
+}
+
+/// Writes events as text, one per line, indented by frame depth.
+pub struct TextSink<W: Write> {
+    out: W,
+}
+impl<W: Write> TextSink<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+impl<W: Write> TraceSink for TextSink<W> {
+    fn event(&mut self, event: &TraceEvent) {
+        let text = match event {
+            TraceEvent::ChunkLoad { func } => {
+                format!("load {} ({} instructions)", func.source_name, func.list_instructions.len())
+            }
+            TraceEvent::Instruction { func, pc, depth, registers } => {
+                let mut text = format!("{:indent$}[{}]", "", pc + 1, indent = depth * 2);
+                if let Some(line) = line(func, *pc) {
+                    write!(text, " line {}", line).unwrap();
+                }
+                write!(text, "  {}  |", text_of(func, *pc)).unwrap();
+                for (r, v) in registers.iter().enumerate() {
+                    write!(text, " r{}={}", r, v.borrow().as_string(true)).unwrap();
+                }
+                text
+            }
+            TraceEvent::Call { func, depth, args } => {
+                format!("{:indent$}call {} with {} args", "", location(func), args, indent = depth * 2)
+            }
+            TraceEvent::NativeCall { name, depth, args } => {
+                format!("{:indent$}call native {} with {} args", "", name, args, indent = depth * 2)
+            }
+            TraceEvent::Return { depth, results } => {
+                format!("{:indent$}return {} results", "", results, indent = depth * 2)
+            }
+            TraceEvent::Constant { index, depth, value } => {
+                format!("{:indent$}constant {} = {}", "", index, value.borrow().as_string(true), indent = depth * 2)
+            }
+        };
+        // a trace that cannot be written is dropped rather than failing the script
+        let _ = writeln!(self.out, "{}", text);
+    }
+}
+
+/// Writes events as JSON objects, one per line, with an `event` field naming
+/// the kind.
+pub struct JsonSink<W: Write> {
+    out: W,
+}
+impl<W: Write> JsonSink<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<W: Write> TraceSink for JsonSink<W> {
+    fn event(&mut self, event: &TraceEvent) {
+        let json = match event {
+            TraceEvent::ChunkLoad { func } => format!(
+                r#"{{"event":"load","source":{},"instructions":{}}}"#,
+                quote(&func.source_name),
+                func.list_instructions.len()
+            ),
+            TraceEvent::Instruction { func, pc, depth, registers } => {
+                let registers: Vec<String> = registers.iter().map(|v| quote(&v.borrow().as_string(true))).collect();
+                format!(
+                    r#"{{"event":"instruction","function":{},"pc":{},"line":{},"depth":{},"op":"{:?}","text":{},"registers":[{}]}}"#,
+                    quote(&location(func)),
+                    pc + 1,
+                    line(func, *pc).map_or("null".to_string(), |l| l.to_string()),
+                    depth,
+                    func.list_instructions[*pc].opcode,
+                    quote(&text_of(func, *pc)),
+                    registers.join(",")
+                )
+            }
+            TraceEvent::Call { func, depth, args } => format!(
+                r#"{{"event":"call","function":{},"depth":{},"args":{}}}"#,
+                quote(&location(func)),
+                depth,
+                args
+            ),
+            TraceEvent::NativeCall { name, depth, args } => format!(
+                r#"{{"event":"native_call","name":{},"depth":{},"args":{}}}"#,
+                quote(name),
+                depth,
+                args
+            ),
+            TraceEvent::Return { depth, results } => {
+                format!(r#"{{"event":"return","depth":{},"results":{}}}"#, depth, results)
+            }
+            TraceEvent::Constant { index, depth, value } => format!(
+                r#"{{"event":"constant","index":{},"depth":{},"value":{}}}"#,
+                index,
+                depth,
+                quote(&value.borrow().as_string(true))
+            ),
+        };
+        let _ = writeln!(self.out, "{}", json);
+    }
+}
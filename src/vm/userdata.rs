@@ -0,0 +1,317 @@
+use std::{
+    any::{Any, TypeId},
+    fmt::Debug,
+    marker::PhantomData,
+    rc::Rc,
+};
+
+use anyhow::anyhow;
+use gc::{Finalize, Gc, GcCell, GcCellRef, GcCellRefMut, Trace};
+
+use super::{
+    memory,
+    stdlib::arg_error,
+    table::{GCLuaTable, LuaTable},
+    GCLuaValue, LuaNativeFunction, LuaVM, LuaValue,
+};
+
+/// Anything a host wants to hand to scripts as a full userdata.
+pub trait UserDataValue: Trace + Any + Debug {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+impl<T: Trace + Any + Debug> UserDataValue for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+/// Rust code run when a userdata is collected, standing in for `__gc`. It
+/// runs in the middle of a collection, so it gets no VM and must not touch
+/// GC values reachable from the userdata.
+#[derive(Clone)]
+pub struct Finalizer(Rc<FinalizerFn>);
+type FinalizerFn = dyn Fn(&dyn Any);
+impl Debug for Finalizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Finalizer")
+    }
+}
+/// A full userdata. The value has a cell of its own, so that a method
+/// holding it can still have its metatable looked up.
+#[derive(Debug, Trace)]
+pub struct LuaUserData {
+    value: GcCell<Box<dyn UserDataValue>>,
+    meta: GcCell<UserDataMeta>,
+    #[unsafe_ignore_trace]
+    type_id: TypeId,
+    size: usize,
+    #[unsafe_ignore_trace]
+    finalizer: Option<Finalizer>,
+}
+#[derive(Debug, Default, Trace, Finalize)]
+pub struct UserDataMeta {
+    pub metatable: Option<GCLuaTable>,
+    /// Environment table, as set with `setfenv`.
+    pub env: Option<GCLuaTable>,
+}
+impl Finalize for LuaUserData {
+    fn finalize(&self) {
+        if let (Some(f), Ok(value)) = (&self.finalizer, self.value.try_borrow()) {
+            (f.0)((**value).as_any());
+        }
+    }
+}
+impl LuaUserData {
+    pub fn new<T: UserDataValue>(value: T, metatable: Option<GCLuaTable>) -> Self {
+        Self {
+            size: memory::userdata_size(&value),
+            type_id: TypeId::of::<T>(),
+            value: GcCell::new(Box::new(value)),
+            meta: GcCell::new(UserDataMeta { metatable, env: None }),
+            finalizer: None,
+        }
+    }
+}
+#[derive(Debug, Clone, Trace, Finalize)]
+pub struct GCLuaUserData(Gc<LuaUserData>);
+impl GCLuaUserData {
+    pub fn new(v: LuaUserData) -> Self {
+        memory::note_alloc(v.size);
+        Self(Gc::new(v))
+    }
+    /// Metatable and environment.
+    pub fn data(&self) -> GcCellRef<'_, UserDataMeta> {
+        self.0.meta.try_borrow().unwrap()
+    }
+    pub fn data_mut(&self) -> GcCellRefMut<'_, UserDataMeta> {
+        self.0.meta.try_borrow_mut().unwrap()
+    }
+    /// Borrows the wrapped value as a `T`, or `None` if it holds another type.
+    /// Panics if the value is mutably borrowed.
+    pub fn borrow<T: 'static>(&self) -> Option<GcCellRef<'_, T>> {
+        if !self.is::<T>() {
+            return None;
+        }
+        Some(GcCellRef::map(self.0.value.borrow(), |v| (**v).as_any().downcast_ref::<T>().unwrap()))
+    }
+    /// Panics if the value is borrowed.
+    pub fn borrow_mut<T: 'static>(&self) -> Option<GcCellRefMut<'_, Box<dyn UserDataValue>, T>> {
+        if !self.is::<T>() {
+            return None;
+        }
+        Some(GcCellRefMut::map(self.0.value.borrow_mut(), |v| (**v).as_any_mut().downcast_mut::<T>().unwrap()))
+    }
+    /// `borrow` of a value known to be a `T`, as a Lua error if a method
+    /// running further up already holds it mutably.
+    fn checked_borrow<T: 'static>(&self) -> anyhow::Result<GcCellRef<'_, T>> {
+        let value = self.0.value.try_borrow().map_err(|_| anyhow!("userdata already borrowed"))?;
+        Ok(GcCellRef::map(value, |v| (**v).as_any().downcast_ref::<T>().unwrap()))
+    }
+    fn checked_borrow_mut<T: 'static>(&self) -> anyhow::Result<GcCellRefMut<'_, Box<dyn UserDataValue>, T>> {
+        let value = self.0.value.try_borrow_mut().map_err(|_| anyhow!("userdata already borrowed"))?;
+        Ok(GcCellRefMut::map(value, |v| (**v).as_any_mut().downcast_mut::<T>().unwrap()))
+    }
+    pub fn is<T: 'static>(&self) -> bool {
+        self.0.type_id == TypeId::of::<T>()
+    }
+    /// Bytes the userdata was counted as when made.
+    pub fn size(&self) -> usize {
+        self.0.size
+    }
+    pub fn ptr(&self) -> usize {
+        &*self.0 as *const LuaUserData as usize
+    }
+}
+
+/// Describes the methods and metamethods of a Rust type exposed as userdata.
+/// `register` turns it into a metatable stored in the registry under the
+/// type's name, in the manner of `luaL_newmetatable`.
+pub struct UserDataType<T: UserDataValue> {
+    name: String,
+    methods: Vec<LuaNativeFunction>,
+    metamethods: Vec<(String, LuaNativeFunction)>,
+    index: Option<LuaNativeFunction>,
+    finalizer: Option<Finalizer>,
+    _marker: PhantomData<fn(T)>,
+}
+impl<T: UserDataValue> UserDataType<T> {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            methods: Vec::new(),
+            metamethods: Vec::new(),
+            index: None,
+            finalizer: None,
+            _marker: PhantomData,
+        }
+    }
+    /// Adds a method called as `obj:name(...)`. `args` excludes the object,
+    /// which stays borrowed while `f` runs: calling a `method_mut` of it from
+    /// inside, or anything of it from inside a `method_mut`, is an error.
+    pub fn method<F>(mut self, name: &str, f: F) -> Self
+    where
+        F: Fn(&mut LuaVM, &T, Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> + 'static,
+    {
+        let (fname, type_name) = (name.to_string(), self.name.clone());
+        self.methods.push(LuaNativeFunction::new(name, move |vm, mut args| {
+            let ud = check_userdata::<T>(&args, 1, &fname, &type_name)?;
+            args.remove(0);
+            let this = ud.checked_borrow::<T>()?;
+            f(vm, &this, args)
+        }));
+        self
+    }
+    /// Like `method`, with mutable access to the object.
+    pub fn method_mut<F>(mut self, name: &str, f: F) -> Self
+    where
+        F: Fn(&mut LuaVM, &mut T, Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> + 'static,
+    {
+        let (fname, type_name) = (name.to_string(), self.name.clone());
+        self.methods.push(LuaNativeFunction::new(name, move |vm, mut args| {
+            let ud = check_userdata::<T>(&args, 1, &fname, &type_name)?;
+            args.remove(0);
+            let mut this = ud.checked_borrow_mut::<T>()?;
+            f(vm, &mut this, args)
+        }));
+        self
+    }
+    /// Sets any metamethod (`__add`, `__len`, `__call`, ...) to a native
+    /// function receiving the raw operands.
+    pub fn metamethod<F>(mut self, event: &str, f: F) -> Self
+    where
+        F: Fn(&mut LuaVM, Vec<GCLuaValue>) -> anyhow::Result<Vec<GCLuaValue>> + 'static,
+    {
+        self.metamethods.push((event.to_string(), LuaNativeFunction::new(event, f)));
+        self
+    }
+    /// Fallback for `__index` lookups of keys that are not methods.
+    pub fn index<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut LuaVM, &T, GCLuaValue) -> anyhow::Result<GCLuaValue> + 'static,
+    {
+        let type_name = self.name.clone();
+        self.index = Some(LuaNativeFunction::new("__index", move |vm, args| {
+            let ud = check_userdata::<T>(&args, 1, "__index", &type_name)?;
+            let key = args.get(1).cloned().unwrap_or_else(|| LuaValue::Nil.to_gc());
+            let this = ud.checked_borrow::<T>()?;
+            Ok(vec![f(vm, &this, key)?])
+        }));
+        self
+    }
+    pub fn tostring<F>(self, f: F) -> Self
+    where
+        F: Fn(&T) -> String + 'static,
+    {
+        let type_name = self.name.clone();
+        self.metamethod("__tostring", move |_, args| {
+            let ud = check_userdata::<T>(&args, 1, "tostring", &type_name)?;
+            let s = f(&*ud.checked_borrow::<T>()?);
+            Ok(vec![LuaValue::String(s.into()).to_gc()])
+        })
+    }
+    /// `__eq`; comparing against a userdata of another type is false.
+    pub fn eq<F>(self, f: F) -> Self
+    where
+        F: Fn(&T, &T) -> bool + 'static,
+    {
+        self.metamethod("__eq", move |_, args| {
+            let operand = |n: usize| match args.get(n).map(|v| v.borrow()).as_deref() {
+                Some(LuaValue::UserData(u)) if u.is::<T>() => Some(u.clone()),
+                _ => None,
+            };
+            let equal = match (operand(0), operand(1)) {
+                (Some(a), Some(b)) => f(&*a.checked_borrow::<T>()?, &*b.checked_borrow::<T>()?),
+                _ => false,
+            };
+            Ok(vec![LuaValue::Boolean(equal).to_gc()])
+        })
+    }
+    /// `__gc`: called with the value when the userdata is collected.
+    pub fn gc<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&T) + 'static,
+    {
+        self.finalizer = Some(Finalizer(Rc::new(move |v: &dyn Any| {
+            if let Some(v) = v.downcast_ref::<T>() {
+                f(v)
+            }
+        })));
+        self
+    }
+    pub fn register(self, vm: &mut LuaVM) -> UserDataClass<T> {
+        let mut methods = LuaTable::new();
+        for m in self.methods {
+            let name = m.name.clone();
+            methods.set_str(&name, LuaValue::NativeFunction(m).to_gc());
+        }
+        let methods = LuaValue::Table(GCLuaTable::new(methods)).to_gc();
+        let index = match self.index {
+            // methods win over the fallback, as with a method table chained
+            // in front of an `__index` function
+            Some(fallback) => LuaValue::NativeFunction(LuaNativeFunction::with_upvalues(
+                "__index",
+                vec![methods, LuaValue::NativeFunction(fallback).to_gc()],
+                |vm, args| {
+                    let key = args.get(1).cloned().unwrap_or_else(|| LuaValue::Nil.to_gc());
+                    let found = vm.index(&vm.native_upvalue(0), &key)?;
+                    if !found.is_nil() {
+                        return Ok(vec![found]);
+                    }
+                    vm.call_value(vm.native_upvalue(1), args)
+                },
+            ))
+            .to_gc(),
+            None => methods,
+        };
+        let meta = GCLuaTable::default();
+        meta.borrow_mut().set_str("__index", index);
+        for (event, f) in self.metamethods {
+            meta.borrow_mut().set_str(&event, LuaValue::NativeFunction(f).to_gc());
+        }
+        vm.registry().borrow_mut().set_str(&self.name, LuaValue::Table(meta).to_gc());
+        UserDataClass { name: self.name, finalizer: self.finalizer, _marker: PhantomData }
+    }
+}
+/// Handle to a registered userdata type. It holds no GC values, so native
+/// functions may capture it.
+pub struct UserDataClass<T: UserDataValue> {
+    name: String,
+    finalizer: Option<Finalizer>,
+    _marker: PhantomData<fn(T)>,
+}
+impl<T: UserDataValue> Clone for UserDataClass<T> {
+    fn clone(&self) -> Self {
+        Self { name: self.name.clone(), finalizer: self.finalizer.clone(), _marker: PhantomData }
+    }
+}
+impl<T: UserDataValue> UserDataClass<T> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn metatable(&self, vm: &LuaVM) -> Option<GCLuaTable> {
+        match vm.registry().borrow().get_str(&self.name).as_ref().map(|v| v.borrow()).as_deref() {
+            Some(LuaValue::Table(t)) => Some(t.clone()),
+            _ => None,
+        }
+    }
+    /// Wraps `value` in a new userdata of this type.
+    pub fn create(&self, vm: &LuaVM, value: T) -> GCLuaValue {
+        let mut ud = LuaUserData::new(value, self.metatable(vm));
+        ud.finalizer = self.finalizer.clone();
+        LuaValue::UserData(GCLuaUserData::new(ud)).to_gc()
+    }
+}
+/// The userdata of type `T` in argument `n`, or a "bad argument" error
+/// naming `type_name`.
+pub fn check_userdata<T: 'static>(args: &[GCLuaValue], n: usize, fname: &str, type_name: &str) -> anyhow::Result<GCLuaUserData> {
+    if let Some(LuaValue::UserData(u)) = args.get(n - 1).map(|v| v.borrow()).as_deref() {
+        if u.is::<T>() {
+            return Ok(u.clone());
+        }
+    }
+    let got = args.get(n - 1).map(|v| v.borrow().type_name()).unwrap_or("no value");
+    Err(arg_error(n, fname, &format!("{} expected, got {}", type_name, got)))
+}
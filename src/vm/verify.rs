@@ -0,0 +1,203 @@
+//! Structural checks on bytecode before it is run, after `luaG_checkcode`
+//! in ldebug.c: every register, constant, upvalue and jump an instruction
+//! names must exist, and instructions that come in pairs must be paired.
+
+use anyhow::{bail, Context};
+
+use super::{
+    cfg::is_test,
+    chunk_parser::{FunctionBlock, LuaConstant, VARARG_HASARG, VARARG_ISVARARG, VARARG_NEEDSARG},
+    instruction::{arg_a, arg_b, arg_bx, arg_c, arg_sbx, op_of, VMOpcode, MASK_CBIT},
+    listing::{op_modes, ArgMode, OpMode},
+};
+
+/// Most registers a function may use, `MAXSTACK` in the reference VM.
+const MAX_STACK: u32 = 250;
+
+/// Checks `func` and the functions nested in it.
+pub fn verify(func: &FunctionBlock) -> anyhow::Result<()> {
+    Verifier::new(func).check()
+}
+
+struct Verifier<'a> {
+    f: &'a FunctionBlock,
+    code: Vec<u32>,
+}
+impl<'a> Verifier<'a> {
+    fn new(f: &'a FunctionBlock) -> Self {
+        Self { f, code: f.list_instructions.iter().map(|i| i.to_u32()).collect() }
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        let f = self.f;
+        if f.max_stack_size as u32 > MAX_STACK {
+            bail!("stack size {} is over {}", f.max_stack_size, MAX_STACK);
+        }
+        if f.num_param as u32 + (f.is_vararg & VARARG_HASARG) as u32 > f.max_stack_size as u32 {
+            bail!("parameters do not fit in the stack");
+        }
+        if f.is_vararg & VARARG_NEEDSARG != 0 && f.is_vararg & VARARG_HASARG == 0 {
+            bail!("vararg flags need `arg` without having it");
+        }
+        if f.upvalue_names.len() > f.num_upval as usize {
+            bail!("{} upvalue names for {} upvalues", f.upvalue_names.len(), f.num_upval);
+        }
+        if !f.line_info.is_empty() && f.line_info.len() != self.code.len() {
+            bail!("{} line numbers for {} instructions", f.line_info.len(), self.code.len());
+        }
+        if self.code.last().map(|i| op_of(*i)) != Some(VMOpcode::RETURN) {
+            bail!("code does not end in RETURN");
+        }
+        let mut pc = 0;
+        while pc < self.code.len() {
+            pc = self.instruction(pc).with_context(|| format!("pc {}", pc + 1))?;
+        }
+        for (i, proto) in f.list_fnproto.iter().enumerate() {
+            verify(proto).with_context(|| format!("function {} defined at line {}", i, proto.line_def))?;
+        }
+        Ok(())
+    }
+
+    fn reg(&self, r: u32) -> anyhow::Result<()> {
+        if r >= self.f.max_stack_size as u32 {
+            bail!("register {} is out of the stack of {}", r, self.f.max_stack_size);
+        }
+        Ok(())
+    }
+    fn constant(&self, k: u32) -> anyhow::Result<&LuaConstant> {
+        match self.f.list_const.get(k as usize) {
+            Some(c) => Ok(c),
+            None => bail!("constant {} is out of {}", k, self.f.list_const.len()),
+        }
+    }
+    fn arg(&self, v: u32, mode: ArgMode) -> anyhow::Result<()> {
+        match mode {
+            ArgMode::N if v != 0 => bail!("unused operand is {}", v),
+            ArgMode::R => self.reg(v),
+            ArgMode::K if v & MASK_CBIT != 0 => self.constant(v & !MASK_CBIT).map(drop),
+            ArgMode::K => self.reg(v),
+            _ => Ok(()),
+        }
+    }
+    fn upvalue(&self, idx: u32) -> anyhow::Result<()> {
+        if idx >= self.f.num_upval as u32 {
+            bail!("upvalue {} is out of {}", idx, self.f.num_upval);
+        }
+        Ok(())
+    }
+    /// What follows an instruction leaving results open to the top of the
+    /// stack must take all of them.
+    fn open_user(&self, pc: usize) -> anyhow::Result<()> {
+        let next = self.code.get(pc + 1).copied();
+        match next.map(|i| (op_of(i), arg_b(i))) {
+            Some((VMOpcode::CALL | VMOpcode::TAILCALL | VMOpcode::RETURN | VMOpcode::SETLIST, 0)) => Ok(()),
+            _ => bail!("open results are not used up by the next instruction"),
+        }
+    }
+    /// Whether `pc` holds data for the instruction before it rather than an
+    /// instruction.
+    fn is_data(&self, pc: usize) -> bool {
+        pc > 0 && {
+            let prev = self.code[pc - 1];
+            op_of(prev) == VMOpcode::SETLIST && arg_c(prev) == 0
+        }
+    }
+
+    /// Checks the instruction at `pc` and returns where the next one is.
+    fn instruction(&self, pc: usize) -> anyhow::Result<usize> {
+        let i = self.code[pc];
+        let op = op_of(i);
+        let (a, b, c) = (arg_a(i), arg_b(i), arg_c(i));
+        let (mode, b_mode, c_mode) = op_modes(&op);
+        self.reg(a)?;
+        match mode {
+            OpMode::Abc => {
+                self.arg(b, b_mode)?;
+                self.arg(c, c_mode)?;
+            }
+            OpMode::Abx if b_mode == ArgMode::K => {
+                self.constant(arg_bx(i))?;
+            }
+            OpMode::Abx => {}
+            OpMode::AsBx => {
+                let dest = pc as i64 + 1 + arg_sbx(i) as i64;
+                if dest < 0 || dest >= self.code.len() as i64 || self.is_data(dest as usize) {
+                    bail!("jump to {} is out of the code", dest + 1);
+                }
+            }
+        }
+        if is_test(&op) && self.code.get(pc + 1).map(|i| op_of(*i)) != Some(VMOpcode::JMP) {
+            bail!("{:?} is not followed by a JMP", op);
+        }
+        let mut next = pc + 1;
+        match op {
+            VMOpcode::LOADBOOL if c != 0 && (pc + 2 >= self.code.len() || self.is_data(pc + 2)) => {
+                bail!("LOADBOOL skips out of the code")
+            }
+            VMOpcode::LOADNIL => self.reg(b)?,
+            VMOpcode::GETUPVAL | VMOpcode::SETUPVAL => self.upvalue(b)?,
+            VMOpcode::GETGLOBAL | VMOpcode::SETGLOBAL
+                if !matches!(self.constant(arg_bx(i))?, LuaConstant::LUA_TSTRING(_)) =>
+            {
+                bail!("global name is not a string")
+            }
+            VMOpcode::SELF => self.reg(a + 1)?,
+            VMOpcode::CONCAT if b >= c => bail!("CONCAT of an empty range"),
+            VMOpcode::TFORLOOP if c < 1 => bail!("TFORLOOP with no variables"),
+            VMOpcode::TFORLOOP => self.reg(a + 2 + c)?,
+            VMOpcode::FORLOOP | VMOpcode::FORPREP => self.reg(a + 3)?,
+            VMOpcode::CALL | VMOpcode::TAILCALL => {
+                if b != 0 {
+                    self.reg(a + b - 1)?;
+                }
+                match c {
+                    0 => self.open_user(pc)?,
+                    1 => {}
+                    _ => self.reg(a + c - 2)?,
+                }
+            }
+            VMOpcode::RETURN if b > 1 => self.reg(a + b - 2)?,
+            VMOpcode::SETLIST => {
+                if b > 0 {
+                    self.reg(a + b)?;
+                }
+                if c == 0 {
+                    if pc + 1 >= self.code.len() {
+                        bail!("SETLIST is missing its block number");
+                    }
+                    next += 1;
+                }
+            }
+            VMOpcode::CLOSURE => {
+                let Some(proto) = self.f.list_fnproto.get(arg_bx(i) as usize) else {
+                    bail!("function {} is out of {}", arg_bx(i), self.f.list_fnproto.len());
+                };
+                let nup = proto.num_upval as usize;
+                if pc + nup >= self.code.len() {
+                    bail!("CLOSURE is missing its upvalues");
+                }
+                for p in pc + 1..=pc + nup {
+                    let u = self.code[p];
+                    match op_of(u) {
+                        VMOpcode::GETUPVAL => self.upvalue(arg_b(u))?,
+                        VMOpcode::MOVE => self.reg(arg_b(u))?,
+                        _ => bail!("upvalue {} of CLOSURE is not a MOVE or GETUPVAL", p - pc),
+                    }
+                }
+                next += nup;
+            }
+            VMOpcode::VARARG => {
+                if self.f.is_vararg & VARARG_ISVARARG == 0 || self.f.is_vararg & VARARG_NEEDSARG != 0 {
+                    bail!("VARARG in a function without `...`");
+                }
+                match b {
+                    0 => self.open_user(pc)?,
+                    1 => {}
+                    _ => self.reg(a + b - 2)?,
+                }
+            }
+            _ => {}
+        }
+        Ok(next)
+    }
+}
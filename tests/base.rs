@@ -0,0 +1,179 @@
+//! The base library, checked against what Lua 5.1 gives for the same calls.
+
+use std::rc::Rc;
+
+use luatest::{
+    compiler::compile,
+    vm::{hostio::MemoryHostIo, LuaVM},
+};
+
+fn vm() -> (LuaVM, Rc<MemoryHostIo>) {
+    let host = Rc::new(MemoryHostIo::new());
+    let mut vm = LuaVM::new();
+    vm.open_base(host.clone());
+    (vm, host)
+}
+fn run(vm: &mut LuaVM, source: &str) -> Vec<String> {
+    let chunk = compile(source.as_bytes(), "=base").unwrap();
+    let results = vm.process_chunk(chunk).unwrap();
+    results.iter().map(|v| v.borrow().as_string(false)).collect()
+}
+/// The message of the error `source` raises, caught by `pcall`.
+fn error(vm: &mut LuaVM, source: &str) -> String {
+    let results = run(vm, &format!("return pcall(function() {} end)", source));
+    assert_eq!(results[0], "false", "{} did not fail", source);
+    results[1].clone()
+}
+
+#[test]
+fn select() {
+    let (mut vm, _) = vm();
+    assert_eq!(run(&mut vm, "return select('#')"), ["0"]);
+    assert_eq!(run(&mut vm, "return select('#', nil, nil)"), ["2"]);
+    assert_eq!(run(&mut vm, "return select(2, 'a', 'b', 'c')"), ["b", "c"]);
+    assert_eq!(run(&mut vm, "return select(-1, 'a', 'b', 'c')"), ["c"]);
+    assert_eq!(run(&mut vm, "return select(-3, 'a', 'b', 'c')"), ["a", "b", "c"]);
+    assert!(run(&mut vm, "return select(5, 'a')").is_empty());
+    assert_eq!(error(&mut vm, "select(-4, 'a', 'b', 'c')"), "base:1: bad argument #1 to 'select' (index out of range)");
+    assert_eq!(error(&mut vm, "select(0, 'a')"), "base:1: bad argument #1 to 'select' (index out of range)");
+    assert_eq!(error(&mut vm, "select('x')"), "base:1: bad argument #1 to 'select' (number expected, got string)");
+}
+
+#[test]
+fn unpack() {
+    let (mut vm, _) = vm();
+    assert_eq!(run(&mut vm, "return unpack({1, 2, 3})"), ["1", "2", "3"]);
+    assert_eq!(run(&mut vm, "return unpack({1, 2, 3}, 2)"), ["2", "3"]);
+    assert_eq!(run(&mut vm, "return unpack({1, 2, 3}, 2, 5)"), ["2", "3", "nil", "nil"]);
+    assert_eq!(run(&mut vm, "return unpack({1, 2, 3}, -1, 1)"), ["nil", "nil", "1"]);
+    assert!(run(&mut vm, "return unpack({})").is_empty());
+    assert!(run(&mut vm, "return unpack({1}, 3, 2)").is_empty());
+    assert_eq!(error(&mut vm, "unpack({}, 1, 1e8)"), "base:1: too many results to unpack");
+    assert_eq!(error(&mut vm, "unpack(1)"), "base:1: bad argument #1 to 'unpack' (table expected, got number)");
+}
+
+#[test]
+fn tonumber() {
+    let (mut vm, _) = vm();
+    assert_eq!(run(&mut vm, "return tonumber('10'), tonumber(' 0x1F '), tonumber('1e2'), tonumber(7)"), ["10", "31", "100", "7"]);
+    assert_eq!(run(&mut vm, "return tonumber('z'), tonumber(''), tonumber({}), tonumber('1 2')"), ["nil", "nil", "nil", "nil"]);
+    assert_eq!(run(&mut vm, "return tonumber('ff', 16), tonumber('FF', 16), tonumber('777', 8), tonumber('zz', 36)"), ["255", "255", "511", "1295"]);
+    assert_eq!(run(&mut vm, "return tonumber('-101', 2), tonumber(' 11 ', 2), tonumber('12', 2), tonumber('8', 8)"), ["-5", "3", "nil", "nil"]);
+    assert_eq!(run(&mut vm, "return tonumber(10, 16)"), ["16"]);
+    assert_eq!(error(&mut vm, "tonumber('1', 1)"), "base:1: bad argument #2 to 'tonumber' (base out of range)");
+    assert_eq!(error(&mut vm, "tonumber('1', 37)"), "base:1: bad argument #2 to 'tonumber' (base out of range)");
+    assert_eq!(error(&mut vm, "tonumber()"), "base:1: bad argument #1 to 'tonumber' (value expected)");
+}
+
+#[test]
+fn error_levels() {
+    let (mut vm, _) = vm();
+    let source = "
+        local function lib(level) error('bad', level) end
+        local function caller(level)
+            lib(level)
+        end
+        local function outer(level)
+            caller(level)
+        end
+        local results = {}
+        for _, level in ipairs({1, 2, 3, 0}) do
+            local ok, e = pcall(outer, level)
+            results[#results + 1] = e
+        end
+        return unpack(results)
+    ";
+    assert_eq!(run(&mut vm, source), ["base:2: bad", "base:4: bad", "base:7: bad", "bad"]);
+    assert_eq!(run(&mut vm, "return pcall(error, 'plain', 0)"), ["false", "plain"]);
+    assert_eq!(run(&mut vm, "return pcall(error)"), ["false", "nil"]);
+    assert_eq!(run(&mut vm, "return pcall(error, 42)"), ["false", "42"]);
+    assert_eq!(error(&mut vm, "assert(false)"), "base:1: assertion failed!");
+    assert_eq!(error(&mut vm, "assert(nil, 'custom')"), "base:1: custom");
+    assert_eq!(run(&mut vm, "return assert(1, 'unused', 3)"), ["1", "unused", "3"]);
+}
+
+#[test]
+fn raw_access() {
+    let (mut vm, _) = vm();
+    let source = "
+        local log = {}
+        local mt = {
+            __index = function(t, k) return 'meta ' .. k end,
+            __newindex = function(t, k, v) log[#log + 1] = k end,
+            __eq = function() return true end,
+        }
+        local a, b = setmetatable({}, mt), setmetatable({}, mt)
+        rawset(a, 'x', 1)
+        a.y = 2
+        return a.x, a.y, rawget(a, 'y'), rawget(a, 'x'), #log, log[1], a == b, rawequal(a, b), rawequal(a, a)
+    ";
+    assert_eq!(run(&mut vm, source), ["1", "meta y", "nil", "1", "1", "y", "true", "false", "true"]);
+    assert_eq!(run(&mut vm, "return rawequal('a', 'a'), rawequal(1, '1'), rawget({10}, 1)"), ["true", "false", "10"]);
+    assert_eq!(run(&mut vm, "local t = {} return rawset(t, 1, 2) == t"), ["true"]);
+    assert_eq!(error(&mut vm, "rawset({}, nil, 1)"), "base:1: table index is nil");
+    assert_eq!(error(&mut vm, "rawget('s', 1)"), "base:1: bad argument #1 to 'rawget' (table expected, got string)");
+}
+
+#[test]
+fn protected_metatables() {
+    let (mut vm, _) = vm();
+    let source = "
+        local mt = {__metatable = 'mine'}
+        local t = setmetatable({}, mt)
+        local ok, e = pcall(setmetatable, t, {})
+        local ok2 = pcall(setmetatable, t, nil)
+        return getmetatable(t), ok, e, ok2
+    ";
+    assert_eq!(run(&mut vm, source), ["mine", "false", "cannot change a protected metatable", "false"]);
+    assert_eq!(run(&mut vm, "local mt = {} return getmetatable(setmetatable({}, mt)) == mt"), ["true"]);
+    assert_eq!(run(&mut vm, "return getmetatable({}), getmetatable(1)"), ["nil", "nil"]);
+    assert_eq!(error(&mut vm, "setmetatable({}, 1)"), "base:1: bad argument #2 to 'setmetatable' (nil or table expected)");
+}
+
+#[test]
+fn iteration() {
+    let (mut vm, _) = vm();
+    let source = "
+        local s = ''
+        for i, v in ipairs({'a', 'b', nil, 'd'}) do s = s .. i .. v end
+        local n = 0
+        for k, v in pairs({1, 2, x = 3, y = 4}) do n = n + v end
+        return s, n, next({}), next({5})
+    ";
+    assert_eq!(run(&mut vm, source), ["1a2b", "10", "nil", "1", "5"]);
+    assert_eq!(error(&mut vm, "next({}, 'missing')"), "base:1: invalid key to 'next'");
+}
+
+#[test]
+fn tostring_and_type() {
+    let (mut vm, _) = vm();
+    let source = "return tostring(nil), tostring(true), tostring(12), tostring(1.5), tostring('s'), type(print), type(nil)";
+    assert_eq!(run(&mut vm, source), ["nil", "true", "12", "1.5", "s", "function", "nil"]);
+    let source = "return tostring(setmetatable({}, {__tostring = function() return 'custom' end}))";
+    assert_eq!(run(&mut vm, source), ["custom"]);
+    assert_eq!(run(&mut vm, "return #tostring('\\255\\0')"), ["2"]);
+    assert!(run(&mut vm, "return tostring({})")[0].starts_with("table: 0x"));
+    assert_eq!(error(&mut vm, "type()"), "base:1: bad argument #1 to 'type' (value expected)");
+}
+
+#[test]
+fn numbers_format_like_14_significant_digits() {
+    let (mut vm, _) = vm();
+    let source = "return tostring(1e14 - 1), tostring(1e14), tostring(-1e14), tostring(-(1e14 - 1)), \
+                  tostring(123456789012345), tostring(2^53), tostring(1e15)";
+    assert_eq!(
+        run(&mut vm, source),
+        ["99999999999999", "1e+14", "-1e+14", "-99999999999999", "1.2345678901234e+14", "9.007199254741e+15", "1e+15"]
+    );
+    let source = "return tostring(0.1), tostring(1/3), tostring(1e-5), tostring(-0), tostring(2^63)";
+    assert_eq!(run(&mut vm, source), ["0.1", "0.33333333333333", "1e-05", "-0", "9.2233720368548e+18"]);
+}
+
+#[test]
+fn print_writes_to_host_stdout() {
+    let (mut vm, host) = vm();
+    run(&mut vm, "print('a', 1, nil, true) print() print(setmetatable({}, {__tostring = function() return 'obj' end}))");
+    assert_eq!(host.stdout_contents(), b"a\t1\tnil\ttrue\n\nobj\n");
+    run(&mut vm, "print('\\255\\0x')");
+    assert_eq!(host.stdout_contents(), b"a\t1\tnil\ttrue\n\nobj\n\xff\0x\n");
+}
@@ -0,0 +1,109 @@
+//! Control-flow graphs of compiled code: blocks and their edges, dominators,
+//! post-dominators and loops.
+
+use luatest::{
+    compiler::compile,
+    vm::cfg::{Cfg, EdgeKind},
+};
+
+fn cfg(source: &str) -> Cfg {
+    Cfg::new(&compile(source.as_bytes(), "=cfg").unwrap().func)
+}
+/// Each block as its pc range and successors, `t`/`f` marking conditional
+/// edges.
+fn blocks(cfg: &Cfg) -> Vec<String> {
+    cfg.blocks
+        .iter()
+        .map(|b| {
+            let succs: Vec<String> = b
+                .succs
+                .iter()
+                .map(|e| match e.kind {
+                    EdgeKind::Always => e.target.to_string(),
+                    EdgeKind::True => format!("t{}", e.target),
+                    EdgeKind::False => format!("f{}", e.target),
+                })
+                .collect();
+            format!("{}..{} -> {}", b.start, b.end, succs.join(" "))
+        })
+        .collect()
+}
+
+#[test]
+fn if_else() {
+    // 0 VARARG  1 TEST  2 JMP  3 LOADK  4 JMP  5 LOADK  6 RETURN  7 RETURN
+    let cfg = cfg("local x = ...\nif x then x = 1 else x = 2 end\nreturn x");
+    // TEST's condition holding means x is false, so its true edge is the else
+    assert_eq!(blocks(&cfg), ["0..3 -> t2 f1", "3..5 -> 3", "5..6 -> 3", "6..7 -> ", "7..8 -> "]);
+    assert_eq!(cfg.blocks[3].preds, [1, 2]);
+    assert_eq!(cfg.idom, [None, Some(0), Some(0), Some(0), None]);
+    assert_eq!(cfg.ipdom, [Some(3), Some(3), Some(3), None, None]);
+    // the compiler's trailing RETURN cannot be reached
+    assert_eq!(cfg.reachable, [true, true, true, true, false]);
+    assert!(cfg.loops.is_empty());
+    assert!(cfg.dominates(0, 3) && !cfg.dominates(1, 3) && cfg.dominates(3, 3));
+    assert!(cfg.post_dominates(3, 0) && !cfg.post_dominates(1, 0));
+    assert_eq!(cfg.block_of(4), Some(1));
+    assert_eq!(cfg.block_of(8), None);
+}
+
+#[test]
+fn numeric_for_with_if() {
+    // 0-3 LOADK  4 FORPREP  5 MOD  6 EQ  7 JMP  8 ADD  9 JMP  10 SUB
+    // 11 FORLOOP  12 RETURN  13 RETURN
+    let source = "
+local s = 0
+for i = 1, 10 do
+  if i % 2 == 0 then s = s + i else s = s - 1 end
+end
+return s";
+    let cfg = cfg(source);
+    assert_eq!(
+        blocks(&cfg),
+        ["0..5 -> 4", "5..8 -> t3 f2", "8..10 -> 4", "10..11 -> 4", "11..12 -> t1 f5", "12..13 -> ", "13..14 -> "]
+    );
+    // FORPREP jumps straight to the FORLOOP, which therefore heads the loop
+    assert_eq!(cfg.idom, [None, Some(4), Some(1), Some(1), Some(0), Some(4), None]);
+    assert_eq!(cfg.ipdom[..6], [Some(4), Some(4), Some(4), Some(4), Some(5), None]);
+    assert_eq!(cfg.loops.len(), 1);
+    let l = &cfg.loops[0];
+    assert_eq!((l.header, &l.latches[..], &l.body[..]), (4, &[2, 3][..], &[1, 2, 3, 4][..]));
+    assert!(cfg.is_back_edge(2, 4) && cfg.is_back_edge(3, 4));
+    assert!(!cfg.is_back_edge(4, 1) && !cfg.is_back_edge(0, 4));
+}
+
+#[test]
+fn while_with_break() {
+    // 0 LOADK  1 LT  2 JMP  3 ADD  4 EQ  5 JMP  6 JMP (break)  7 JMP (dead)
+    // 8 RETURN  9 RETURN
+    let source = "
+local n = 0
+while n < 10 do
+  n = n + 1
+  if n == 5 then break end
+end
+return n";
+    let cfg = cfg(source);
+    assert_eq!(blocks(&cfg), ["0..1 -> 1", "1..3 -> t5 f2", "3..6 -> t1 f3", "6..7 -> 5", "7..8 -> 1", "8..9 -> ", "9..10 -> "]);
+    // the loop's own closing jump is dead, since the `if` jumps back first
+    assert_eq!(cfg.reachable, [true, true, true, true, false, true, false]);
+    assert_eq!(cfg.blocks[1].preds, [0, 2, 4]);
+    assert_eq!(cfg.idom, [None, Some(0), Some(1), Some(2), None, Some(1), None]);
+    assert_eq!(cfg.ipdom[..4], [Some(1), Some(5), Some(5), Some(5)]);
+    // the break leaves the loop, so it is not part of the body
+    assert_eq!(cfg.loops.len(), 1);
+    let l = &cfg.loops[0];
+    assert_eq!((l.header, &l.latches[..], &l.body[..]), (1, &[2][..], &[1, 2][..]));
+    assert!(cfg.exits.iter().all(|e| *e));
+}
+
+#[test]
+fn infinite_loop_never_exits() {
+    // 0 JMP -1 to itself, then the RETURN nothing reaches
+    let cfg = cfg("while true do end");
+    assert_eq!(blocks(&cfg), ["0..1 -> 0", "1..2 -> "]);
+    assert_eq!(cfg.exits, [false, true]);
+    assert_eq!(cfg.ipdom, [None, None]);
+    assert_eq!(cfg.loops.len(), 1);
+    assert_eq!((cfg.loops[0].header, &cfg.loops[0].latches[..]), (0, &[0][..]));
+}
@@ -0,0 +1,135 @@
+//! Compares the compiler's instruction listings against golden listings in
+//! `luac -l` format, and runs the compiled chunks. Each `tests/compiler/*.lst`
+//! is the listing of the `.lua` beside it; `luac -l x.lua` from Lua 5.1,
+//! run in that directory, gives the same text up to function addresses.
+
+use std::{fs, path::Path};
+
+use luatest::{
+    compiler::compile,
+    vm::{
+        chunk_parser::{LuaChunk, LuaConstant},
+        convert::FromLua,
+        listing::listing,
+        optimizer::{optimize_chunk, Passes},
+        string::LuaString,
+        verify::verify,
+        LuaVM,
+    },
+};
+
+/// Replaces the function addresses `luac -l` prints, which differ per run.
+fn normalise(listing: &str) -> String {
+    let mut out = String::new();
+    let mut rest = listing;
+    while let Some(idx) = rest.find("0x") {
+        out.push_str(&rest[..idx]);
+        out.push_str("0x00000000");
+        rest = rest[idx + 2..].trim_start_matches(|c: char| c.is_ascii_hexdigit());
+    }
+    out.push_str(rest);
+    out
+}
+
+fn compile_file(name: &str) -> LuaChunk {
+    let path = Path::new("tests/compiler").join(name);
+    let source = fs::read(&path).unwrap();
+    compile(&source, &format!("@{}", name)).unwrap()
+}
+
+#[test]
+fn listings_match_golden() {
+    let mut checked = 0;
+    for entry in fs::read_dir("tests/compiler").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|e| e != "lua") {
+            continue;
+        }
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let expected = fs::read_to_string(path.with_extension("lst")).unwrap();
+        let actual = listing(&compile_file(name).func);
+        assert_eq!(normalise(&actual), normalise(&expected), "listing of {} differs", name);
+        checked += 1;
+    }
+    assert!(checked > 0);
+}
+
+#[test]
+fn bytecode_round_trips() {
+    for name in ["arith.lua", "control.lua", "closures.lua", "flow.lua", "tables.lua"] {
+        let chunk = compile_file(name);
+        let bytes = chunk.to_bytes().unwrap();
+        let read = LuaChunk::from_reader(&mut &bytes[..]).unwrap();
+        assert_eq!(normalise(&listing(&read.func)), normalise(&listing(&chunk.func)), "{}", name);
+        assert_eq!(read.to_bytes().unwrap(), bytes, "{}", name);
+    }
+}
+
+fn run(name: &str) -> Vec<f64> {
+    let mut vm = LuaVM::new();
+    let results = vm.process_chunk(compile_file(name)).unwrap();
+    results.iter().map(|v| f64::from_lua(v).unwrap()).collect()
+}
+
+#[test]
+fn compiled_chunks_run() {
+    assert_eq!(run("control.lua"), [63.0, 0.0, 6.0]);
+    assert_eq!(run("closures.lua"), [17.0, 42.0, 6.0, 2.0, 2.0]);
+    let mut vm = LuaVM::new();
+    let results = vm.process_chunk(compile_file("arith.lua")).unwrap();
+    let numbers: Vec<f64> = results[..3].iter().map(|v| f64::from_lua(v).unwrap()).collect();
+    assert_eq!(numbers, [12.5, 91.0, f64::NEG_INFINITY]);
+    assert_eq!(String::from_lua(&results[3]).unwrap(), "x10y3");
+    assert!(bool::from_lua(&results[4]).unwrap());
+}
+
+#[test]
+fn constructors_past_511_flushes() {
+    // block 512 on takes a word of its own after SETLIST; block 560 is not
+    // an instruction at all
+    let items: Vec<String> = (1..=28000).map(|i| i.to_string()).collect();
+    let source = format!("local t = {{{}}}\nreturn #t, t[28000], t[25551], t[25601]", items.join(", "));
+    let chunk = compile(source.as_bytes(), "=big").unwrap();
+    verify(&chunk.func).unwrap();
+    let bytes = chunk.to_bytes().unwrap();
+    let read = LuaChunk::from_reader(&mut &bytes[..]).unwrap();
+    assert_eq!(read.to_bytes().unwrap(), bytes);
+    let optimized = optimize_chunk(&chunk, &Passes::default()).unwrap();
+    for chunk in [read, optimized] {
+        let results = LuaVM::new().process_chunk(chunk).unwrap();
+        let numbers: Vec<f64> = results.iter().map(|v| f64::from_lua(v).unwrap()).collect();
+        assert_eq!(numbers, [28000.0, 28000.0, 25551.0, 25601.0]);
+    }
+}
+
+#[test]
+fn limit_errors() {
+    let locals: Vec<String> = (0..201).map(|i| format!("local v{} = {}", i, i)).collect();
+    let err = compile(locals.join("\n").as_bytes(), "=limits").err().unwrap();
+    assert_eq!(err.to_string(), "limits:201: main function has more than 200 local variables");
+}
+
+#[test]
+fn string_constants_keep_their_bytes() {
+    let chunk = compile(br#"local s = "\255\128" return #s, #"\255\0a", s"#, "=bytes").unwrap();
+    let k: Vec<&[u8]> = chunk
+        .func
+        .list_const
+        .iter()
+        .filter_map(|k| match &**k {
+            LuaConstant::LUA_TSTRING(s) => Some(s.as_bytes()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(k, [&b"\xff\x80"[..], &b"\xff\0a"[..]]);
+    // dumped as a size_t length counting the trailing NUL, then the bytes
+    let bytes = chunk.to_bytes().unwrap();
+    assert!(bytes.windows(11).any(|w| w == b"\x03\0\0\0\0\0\0\0\xff\x80\0"));
+
+    let mut vm = LuaVM::new();
+    let read = LuaChunk::from_reader(&mut &bytes[..]).unwrap();
+    let results = vm.process_chunk(read).unwrap();
+    assert_eq!(f64::from_lua(&results[0]).unwrap(), 2.0);
+    assert_eq!(f64::from_lua(&results[1]).unwrap(), 3.0);
+    assert_eq!(LuaString::from_lua(&results[2]).unwrap().as_bytes(), b"\xff\x80");
+}
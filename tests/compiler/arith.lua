@@ -0,0 +1,8 @@
+local a, b = 10, 3
+local c = a + b * 2 - (a - b) / 2
+local d = a % b + a ^ 2 + -a
+local e = 2 * 3 + 4 ^ 0.5 - 1 / 0
+local s = "x" .. a .. "y" .. b
+local t = not a, #s
+local eq = a == b or a ~= b and a < b or a <= b or a > 1 and a >= b
+return c, d, e, s, eq
@@ -0,0 +1,33 @@
+local function counter(start)
+  local n = start
+  return function(step)
+    n = n + (step or 1)
+    return n
+  end
+end
+local c = counter(10)
+c()
+c(5)
+local obj = { v = 1 }
+function obj:get(x) return self.v + x end
+function obj.set(self, v) self.v = v end
+obj:set(41)
+local function ipairs1(t)
+  return function(t, i)
+    i = i + 1
+    if t[i] then return i, t[i] end
+  end, t, 0
+end
+local function sum(...)
+  local total = 0
+  for _, v in ipairs1({ ... }) do total = total + v end
+  return total, select and 1
+end
+local function count(...)
+  return arg.n
+end
+local fs = {}
+for i = 1, 3 do
+  fs[i] = function() return i end
+end
+return c(), obj:get(1), sum(1, 2, 3), count(4, 5), fs[2]()
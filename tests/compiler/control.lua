@@ -0,0 +1,29 @@
+local n = 0
+for i = 1, 10 do
+  if i % 2 == 0 then
+    n = n + i
+  elseif i == 5 then
+    n = n - 1
+  else
+    n = n + 1
+  end
+end
+for i = 10, 1, -2 do n = n + i end
+local j = 0
+while j < 5 do
+  j = j + 1
+  if j == 4 then break end
+end
+repeat
+  local k = j
+  j = j - 1
+until k < 2
+local t = { 1, 2, 3 }
+local function iter(t, i)
+  i = i + 1
+  local v = t[i]
+  if v then return i, v end
+end
+local sum = 0
+for _, v in iter, t, 0 do sum = sum + v end
+return n, j, sum
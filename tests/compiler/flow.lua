@@ -0,0 +1,27 @@
+local function pick(x)
+  if x > 2 then
+    return "big"
+  elseif x > 1 then
+    return "mid"
+  else
+    return "small"
+  end
+end
+local fs = {}
+local i = 0
+repeat
+  local j = i
+  fs[#fs + 1] = function() return j end
+  i = i + 1
+until j >= 3
+local n = 0
+while true do
+  n = n + 1
+  if n > 5 then
+    if n % 2 == 0 then break end
+  end
+end
+local a, b
+local x = not not (a and b or n)
+local y = (n == 6) and "yes" or "no"
+return pick(3) .. pick(2) .. pick(1), fs[3](), n, x, y
@@ -0,0 +1,11 @@
+local t = { 1, 2, 3, x = 1, ["y"] = 2, [10] = 3; 4 }
+local u = { f(), g() }
+local w = { ... }
+local i, a = 1, {}
+a[i], i = i + 1, 20
+a.b.c, a[1], a[i] = 1, 2
+local x, y, z = 1
+x, y = y, x
+local big = { 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25,
+  26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52 }
+return #t, t.x + t.y + t[10], #big, a[2]
@@ -0,0 +1,127 @@
+//! Conversions between Rust and Lua values, on their own and through typed
+//! native functions.
+
+use std::collections::HashMap;
+
+use luatest::{
+    compiler::compile,
+    vm::{
+        convert::{FromLua, FromLuaError, IntoLua, Variadic},
+        table::GCLuaTable,
+        GCLuaValue, LuaNativeFunction, LuaVM, LuaValue,
+    },
+};
+
+fn eval(vm: &mut LuaVM, source: &str) -> anyhow::Result<Vec<GCLuaValue>> {
+    let chunk = compile(source.as_bytes(), "=convert").unwrap();
+    vm.process_chunk(chunk)
+}
+fn value(source: &str) -> GCLuaValue {
+    eval(&mut LuaVM::new(), &format!("return {}", source)).unwrap().remove(0)
+}
+fn invalid(message: &str) -> FromLuaError {
+    FromLuaError::Invalid(message.to_string())
+}
+
+#[test]
+fn integers() {
+    assert_eq!(i32::from_lua(&value("2147483647")), Ok(i32::MAX));
+    assert_eq!(i32::from_lua(&value("-2147483648")), Ok(i32::MIN));
+    assert_eq!(i32::from_lua(&value("2147483648")), Err(invalid("number has no integer representation")));
+    assert_eq!(i32::from_lua(&value("-2147483649")), Err(invalid("number has no integer representation")));
+    assert_eq!(u8::from_lua(&value("256")), Err(invalid("number has no integer representation")));
+    assert_eq!(u32::from_lua(&value("-1")), Err(invalid("number has no integer representation")));
+    // 2^63 is i64::MAX as f64 rounded up, and does not fit
+    assert_eq!(i64::from_lua(&value("2^63")), Err(invalid("number has no integer representation")));
+    assert_eq!(i64::from_lua(&value("-2^63")), Ok(i64::MIN));
+    assert_eq!(i64::from_lua(&value("1.5")), Err(invalid("number has no integer representation")));
+    assert_eq!(i64::from_lua(&value("0/0")), Err(invalid("number has no integer representation")));
+    assert_eq!(i64::from_lua(&value("'12'")), Ok(12));
+    assert_eq!(
+        i64::from_lua(&value("{}")),
+        Err(FromLuaError::Expected { expected: "number".to_string(), got: "table" })
+    );
+    assert_eq!(f64::from_lua(&value("1.5")), Ok(1.5));
+    assert_eq!(i64::from_missing().unwrap_err().to_string(), "number expected, got no value");
+    assert_eq!(7u16.into_lua().borrow().as_string(false), "7");
+}
+
+#[test]
+fn options_and_strings() {
+    assert_eq!(Option::<i64>::from_lua(&value("nil")), Ok(None));
+    assert_eq!(Option::<i64>::from_lua(&value("3")), Ok(Some(3)));
+    assert_eq!(Option::<i64>::from_lua(&value("false")).unwrap_err().to_string(), "number expected, got boolean");
+    assert_eq!(Option::<i64>::from_missing(), Ok(None));
+    assert!(None::<i64>.into_lua().is_nil());
+    assert_eq!(Some("x").into_lua().borrow().as_string(false), "x");
+
+    assert_eq!(String::from_lua(&value("'text'")), Ok("text".to_string()));
+    assert_eq!(String::from_lua(&value("1e15")), Ok("1e+15".to_string()));
+    assert_eq!(String::from_lua(&value("'\\255'")), Err(invalid("string is not UTF-8")));
+    assert_eq!(bool::from_lua(&value("0")), Ok(true));
+    assert_eq!(bool::from_lua(&value("nil")), Ok(false));
+}
+
+#[test]
+fn vectors() {
+    assert_eq!(Vec::<i64>::from_lua(&value("{1, 2, 3}")), Ok(vec![1, 2, 3]));
+    assert_eq!(Vec::<i64>::from_lua(&value("{}")), Ok(vec![]));
+    // only the array part is read
+    assert_eq!(Vec::<i64>::from_lua(&value("{1, 2, x = 'skipped', [5] = 5}")), Ok(vec![1, 2]));
+    assert_eq!(
+        Vec::<i64>::from_lua(&value("{1, 2.5}")),
+        Err(invalid("invalid element #2: number has no integer representation"))
+    );
+    assert_eq!(Vec::<Vec<String>>::from_lua(&value("{{'a'}, {'b', 'c'}}")), Ok(vec![vec!["a".to_string()], vec!["b".to_string(), "c".to_string()]]));
+    assert_eq!(Vec::<i64>::from_lua(&value("'s'")).unwrap_err().to_string(), "table expected, got string");
+
+    let v = vec![10, 20].into_lua();
+    assert_eq!(Vec::<i64>::from_lua(&v), Ok(vec![10, 20]));
+    match &*v.borrow() {
+        LuaValue::Table(t) => assert_eq!(t.borrow().len(), 2),
+        other => panic!("{:?}", other),
+    };
+}
+
+#[test]
+fn maps() {
+    let map = HashMap::<String, i64>::from_lua(&value("{a = 1, b = 2}")).unwrap();
+    assert_eq!(map, [("a".to_string(), 1), ("b".to_string(), 2)].into_iter().collect());
+    let map = HashMap::<i64, bool>::from_lua(&value("{true, false}")).unwrap();
+    assert_eq!(map, [(1, true), (2, false)].into_iter().collect());
+    assert_eq!(
+        HashMap::<String, i64>::from_lua(&value("{a = 'x'}")),
+        Err(invalid("invalid value for key \"a\": number expected, got string"))
+    );
+    assert_eq!(
+        HashMap::<i64, i64>::from_lua(&value("{a = 1}")),
+        Err(invalid("invalid key: number expected, got string"))
+    );
+
+    let original: HashMap<String, Vec<i64>> = [("k".to_string(), vec![1, 2])].into_iter().collect();
+    assert_eq!(HashMap::from_lua(&original.clone().into_lua()), Ok(original));
+}
+
+#[test]
+fn typed_functions() {
+    let mut vm = LuaVM::new();
+    let add = LuaNativeFunction::typed("add", |_, (a, b, rest): (i64, Option<i64>, Variadic<f64>)| {
+        Ok(a + b.unwrap_or(0) + rest.iter().sum::<f64>() as i64)
+    });
+    vm.set_global("add", add.into_lua());
+    let results = eval(&mut vm, "return add(1), add(1, 2), add(1, nil, 3, 4)").unwrap();
+    let results: Vec<i64> = results.iter().map(|v| i64::from_lua(v).unwrap()).collect();
+    assert_eq!(results, [1, 3, 8]);
+    let e = eval(&mut vm, "add()").unwrap_err();
+    assert_eq!(e.to_string(), "convert:1: bad argument #1 to 'add' (number expected, got no value)");
+    let e = eval(&mut vm, "add(1, 2, 'x')").unwrap_err();
+    assert_eq!(e.to_string(), "convert:1: bad argument #3 to 'add' (number expected, got string)");
+    let e = eval(&mut vm, "add(2^40 + 0.5)").unwrap_err();
+    assert_eq!(e.to_string(), "convert:1: bad argument #1 to 'add' (number has no integer representation)");
+
+    let f = eval(&mut vm, "return function(a, b) return a .. b, #a end").unwrap().remove(0);
+    let (joined, n): (String, u8) = vm.call(f.clone(), ("ab", 3)).unwrap();
+    assert_eq!((joined.as_str(), n), ("ab3", 2));
+    let e = vm.call::<(String, GCLuaTable)>(f, ("ab", 3)).map(|_| ()).unwrap_err();
+    assert_eq!(e.to_string(), "bad result #2 (table expected, got number)");
+}
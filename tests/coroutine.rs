@@ -0,0 +1,158 @@
+//! Coroutines: yielding out of nested Lua calls, the status of each thread
+//! along the way, and the boundaries a yield cannot cross.
+
+use std::rc::Rc;
+
+use luatest::{
+    compiler::compile,
+    vm::{hostio::MemoryHostIo, GCLuaValue, LuaNativeFunction, LuaVM, LuaValue},
+};
+
+fn vm() -> LuaVM {
+    let mut vm = LuaVM::new();
+    vm.open_base(Rc::new(MemoryHostIo::new()));
+    vm.open_coroutine();
+    vm
+}
+fn eval(vm: &mut LuaVM, source: &str) -> anyhow::Result<Vec<GCLuaValue>> {
+    let chunk = compile(source.as_bytes(), "=co").unwrap();
+    vm.process_chunk(chunk)
+}
+fn run(vm: &mut LuaVM, source: &str) -> Vec<String> {
+    eval(vm, source).unwrap().iter().map(|v| v.borrow().as_string(false)).collect()
+}
+
+#[test]
+fn yield_across_nested_lua_calls() {
+    let mut vm = vm();
+    let source = "
+        local function leaf(x) local got = coroutine.yield(x * 2) return got + 1 end
+        local function middle(x) return leaf(x + 1) * 10 end
+        local co = coroutine.create(function(a) local r = middle(a) local s = coroutine.yield(r) return 'end', s end)
+        local _, first = coroutine.resume(co, 1)
+        local _, second = coroutine.resume(co, 5)
+        local ok, a, b = coroutine.resume(co, 'last')
+        return first, second, ok, a, b
+    ";
+    assert_eq!(run(&mut vm, source), ["4", "60", "true", "end", "last"]);
+    // several values each way, and locals kept across yields
+    let source = "
+        local co = coroutine.wrap(function(...)
+            local n = select('#', ...)
+            local a, b = coroutine.yield(n, ...)
+            for i = 1, 3 do a = a + coroutine.yield(a) end
+            return a, b
+        end)
+        local r = {co(7, 8, 9)}
+        local s = {co(1, 'b')}
+        co(10) co(100)
+        local t = {co(1000)}
+        return #r, r[1], r[4], s[1], t[1], t[2]
+    ";
+    assert_eq!(run(&mut vm, source), ["4", "3", "9", "1", "1111", "b"]);
+    // generators written the usual way
+    let source = "
+        local function range(n) return coroutine.wrap(function() for i = 1, n do coroutine.yield(i) end end) end
+        local sum = 0
+        for i in range(10) do sum = sum + i end
+        return sum
+    ";
+    assert_eq!(run(&mut vm, source), ["55"]);
+}
+
+#[test]
+fn status_transitions() {
+    let mut vm = vm();
+    let source = "
+        local log = {}
+        local outer
+        local inner = coroutine.create(function()
+            log[#log + 1] = coroutine.status(outer)
+            log[#log + 1] = coroutine.status(coroutine.running())
+            coroutine.yield()
+        end)
+        outer = coroutine.create(function()
+            log[#log + 1] = coroutine.status(outer)
+            coroutine.resume(inner)
+            log[#log + 1] = coroutine.status(inner)
+            coroutine.yield()
+        end)
+        log[#log + 1] = coroutine.status(outer)
+        coroutine.resume(outer)
+        log[#log + 1] = coroutine.status(outer)
+        coroutine.resume(outer)
+        log[#log + 1] = coroutine.status(outer)
+        return unpack(log)
+    ";
+    assert_eq!(run(&mut vm, source), ["suspended", "running", "normal", "running", "suspended", "suspended", "dead"]);
+    assert_eq!(run(&mut vm, "return coroutine.running()"), ["nil"]);
+    let source = "local co co = coroutine.create(function() return coroutine.running() == co end) return coroutine.resume(co)";
+    assert_eq!(run(&mut vm, source), ["true", "true"]);
+    // an error leaves the coroutine dead
+    let source = "
+        local co = coroutine.create(function() error('boom') end)
+        local ok, e = coroutine.resume(co)
+        return ok, e, coroutine.status(co)
+    ";
+    assert_eq!(run(&mut vm, source), ["false", "co:2: boom", "dead"]);
+}
+
+#[test]
+fn resuming_what_cannot_be_resumed() {
+    let mut vm = vm();
+    let source = "
+        local co = coroutine.create(function() return 1 end)
+        local a = {coroutine.resume(co)}
+        local b = {coroutine.resume(co)}
+        return a[1], a[2], b[1], b[2]
+    ";
+    assert_eq!(run(&mut vm, source), ["true", "1", "false", "cannot resume dead coroutine"]);
+    let source = "local co co = coroutine.create(function() return coroutine.resume(co) end) return coroutine.resume(co)";
+    assert_eq!(run(&mut vm, source), ["true", "false", "cannot resume non-suspended coroutine"]);
+    let source = "local f = coroutine.wrap(function() end) f() return pcall(f)";
+    assert_eq!(run(&mut vm, source), ["false", "cannot resume dead coroutine"]);
+    assert_eq!(
+        run(&mut vm, "return pcall(coroutine.resume, 1)"),
+        ["false", "bad argument #1 to 'resume' (coroutine expected)"]
+    );
+    assert_eq!(
+        run(&mut vm, "return pcall(coroutine.create, print)"),
+        ["false", "bad argument #1 to 'create' (Lua function expected)"]
+    );
+    assert_eq!(run(&mut vm, "return pcall(coroutine.yield, 1)"), ["false", "attempt to yield from outside a coroutine"]);
+}
+
+#[test]
+fn yield_across_a_native_call() {
+    let mut vm = vm();
+    // a host function calling back into Lua
+    let each = LuaNativeFunction::new("each", |vm, args| {
+        let f = args[0].clone();
+        for i in 1..=3 {
+            vm.call_value(f.clone(), vec![LuaValue::Number(i as f64).to_gc()])?;
+        }
+        Ok(Vec::new())
+    });
+    vm.set_global("each", LuaValue::NativeFunction(each).to_gc());
+    let source = "
+        local seen = 0
+        local co = coroutine.create(function() each(function(i) seen = seen + i end) coroutine.yield('after') end)
+        local a, b = coroutine.resume(co)
+        return a, b, seen
+    ";
+    assert_eq!(run(&mut vm, source), ["true", "after", "6"]);
+    let source = "
+        local co = coroutine.create(function() each(function(i) coroutine.yield(i) end) end)
+        local ok, e = coroutine.resume(co)
+        return ok, e, coroutine.status(co)
+    ";
+    assert_eq!(run(&mut vm, source), ["false", "co:2: attempt to yield across metamethod/C-call boundary", "dead"]);
+    // metamethods and pcall are called from Rust too
+    let source = "
+        local t = setmetatable({}, {__index = function(t, k) return coroutine.yield(k) end})
+        return coroutine.resume(coroutine.create(function() return t.x end))
+    ";
+    assert_eq!(run(&mut vm, source), ["false", "co:2: attempt to yield across metamethod/C-call boundary"]);
+    let source = "return coroutine.resume(coroutine.create(function() return pcall(coroutine.yield, 1) end))";
+    assert_eq!(run(&mut vm, source), ["true", "false", "attempt to yield across metamethod/C-call boundary"]);
+}
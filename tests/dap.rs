@@ -0,0 +1,284 @@
+//! Drives `luatest dap` through scripted Debug Adapter Protocol sessions over
+//! its stdin and stdout, against the scripts in `tests/dap`.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+};
+
+use luatest::vm::json::Json;
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/dap/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    seq: usize,
+    /// Events read while waiting for something else.
+    events: Vec<Json>,
+}
+impl Client {
+    fn start() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_luatest"))
+            .arg("dap")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Self { child, stdin, stdout, seq: 0, events: Vec::new() }
+    }
+    fn read(&mut self) -> Json {
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            assert!(self.stdout.read_line(&mut line).unwrap() > 0, "adapter closed its output");
+            match line.trim_end() {
+                "" => break,
+                header => length = header.strip_prefix("Content-Length: ").unwrap().parse().unwrap(),
+            }
+        }
+        let mut body = vec![0; length];
+        self.stdout.read_exact(&mut body).unwrap();
+        Json::parse(std::str::from_utf8(&body).unwrap()).unwrap()
+    }
+    /// Sends a request and returns its response, successful or not.
+    fn try_request(&mut self, command: &str, arguments: Json) -> Json {
+        self.seq += 1;
+        let text = Json::object([
+            ("seq", self.seq.into()),
+            ("type", "request".into()),
+            ("command", command.into()),
+            ("arguments", arguments),
+        ])
+        .to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", text.len(), text).unwrap();
+        self.stdin.flush().unwrap();
+        loop {
+            let message = self.read();
+            if message.get("type").and_then(Json::as_str) == Some("event") {
+                self.events.push(message);
+                continue;
+            }
+            assert_eq!(message.get("request_seq").and_then(Json::as_f64), Some(self.seq as f64));
+            assert_eq!(message.get("command").and_then(Json::as_str), Some(command));
+            return message;
+        }
+    }
+    /// Sends a request that must succeed and returns the response body.
+    fn request(&mut self, command: &str, arguments: Json) -> Json {
+        let response = self.try_request(command, arguments);
+        assert_eq!(response.get("success"), Some(&Json::Bool(true)), "{} failed: {}", command, response);
+        response.get("body").cloned().unwrap_or(Json::Null)
+    }
+    /// The body of the next event called `name`, skipping other events.
+    fn event(&mut self, name: &str) -> Json {
+        loop {
+            let event = match self.events.is_empty() {
+                true => self.read(),
+                false => self.events.remove(0),
+            };
+            assert_eq!(event.get("type").and_then(Json::as_str), Some("event"), "unexpected {}", event);
+            if event.get("event").and_then(Json::as_str) == Some(name) {
+                return event.get("body").cloned().unwrap_or(Json::Null);
+            }
+        }
+    }
+    /// Initializes, launches `script` with `launch` as extra arguments, sets
+    /// line breakpoints in it and finishes configuration.
+    fn launch(&mut self, script: &str, launch: &[(&str, Json)], lines: &[u32]) {
+        let capabilities = self.request("initialize", Json::object([("adapterID", "luatest".into())]));
+        assert_eq!(capabilities.get("supportsConfigurationDoneRequest"), Some(&Json::Bool(true)));
+        self.event("initialized");
+        let mut args = Json::object([("program", fixture(script).into())]);
+        for (key, value) in launch {
+            args.set(key, value.clone());
+        }
+        self.request("launch", args);
+        let breakpoints: Vec<Json> = lines.iter().map(|l| Json::object([("line", (*l).into())])).collect();
+        let source = Json::object([("path", fixture(script).into())]);
+        let set = self.request("setBreakpoints", Json::object([("source", source), ("breakpoints", breakpoints.into())]));
+        assert_eq!(set.get("breakpoints").and_then(Json::as_array).map(<[Json]>::len), Some(lines.len()));
+        self.request("configurationDone", Json::object([]));
+    }
+    fn frames(&mut self) -> Vec<Json> {
+        let body = self.request("stackTrace", Json::object([("threadId", 1u32.into())]));
+        body.get("stackFrames").and_then(Json::as_array).unwrap().to_vec()
+    }
+    /// Waits for a stop and returns its reason and the line stopped at.
+    fn stopped(&mut self) -> (String, u32) {
+        let body = self.event("stopped");
+        let reason = body.get("reason").and_then(Json::as_str).unwrap().to_string();
+        let line = self.frames()[0].get("line").and_then(Json::as_f64).unwrap() as u32;
+        (reason, line)
+    }
+    /// The variables under `reference` as name and value pairs, with the
+    /// reference of each.
+    fn variables(&mut self, reference: f64) -> Vec<(String, String, f64)> {
+        let body = self.request("variables", Json::object([("variablesReference", reference.into())]));
+        let field = |v: &Json, k: &str| v.get(k).cloned().unwrap();
+        body.get("variables")
+            .and_then(Json::as_array)
+            .unwrap()
+            .iter()
+            .map(|v| {
+                let name = field(v, "name").as_str().unwrap().to_string();
+                let value = field(v, "value").as_str().unwrap().to_string();
+                (name, value, field(v, "variablesReference").as_f64().unwrap())
+            })
+            .collect()
+    }
+    /// The reference of scope `name` of frame `frame_id`.
+    fn scope(&mut self, frame_id: u32, name: &str) -> f64 {
+        let body = self.request("scopes", Json::object([("frameId", frame_id.into())]));
+        let scopes = body.get("scopes").and_then(Json::as_array).unwrap().to_vec();
+        let scope = scopes.iter().find(|s| s.get("name").and_then(Json::as_str) == Some(name)).unwrap();
+        scope.get("variablesReference").and_then(Json::as_f64).unwrap()
+    }
+    fn evaluate(&mut self, frame_id: u32, expression: &str) -> Json {
+        self.try_request(
+            "evaluate",
+            Json::object([("expression", expression.into()), ("frameId", frame_id.into()), ("context", "repl".into())]),
+        )
+    }
+    /// Disconnects and checks the adapter exits cleanly.
+    fn finish(mut self) {
+        self.request("disconnect", Json::object([]));
+        drop(self.stdin);
+        assert!(self.child.wait().unwrap().success());
+    }
+}
+
+fn value(pairs: &[(String, String, f64)], name: &str) -> String {
+    pairs.iter().find(|(n, _, _)| n == name).map(|(_, v, _)| v.clone()).unwrap_or_default()
+}
+
+#[test]
+fn breakpoints_stack_and_variables() {
+    let mut client = Client::start();
+    client.launch("sample.lua", &[], &[3]);
+    let stop = client.event("stopped");
+    assert_eq!(stop.get("reason").and_then(Json::as_str), Some("breakpoint"));
+    assert_eq!(stop.get("hitBreakpointIds"), Some(&Json::Array(vec![Json::Number(1.0)])));
+
+    let frames = client.frames();
+    assert_eq!(frames.len(), 2);
+    let field = |i: usize, k: &str| frames[i].get(k).cloned().unwrap();
+    assert_eq!(field(0, "name"), Json::from("area"));
+    assert_eq!(field(0, "line"), Json::Number(3.0));
+    assert_eq!(field(0, "source").get("path"), Some(&Json::from(fixture("sample.lua"))));
+    assert_eq!(field(1, "name"), Json::from("main chunk"));
+    assert_eq!(field(1, "line"), Json::Number(7.0));
+
+    let locals = client.scope(1, "Locals");
+    let locals = client.variables(locals);
+    assert_eq!(value(&locals, "w"), "3");
+    assert_eq!(value(&locals, "h"), "4");
+    let main_locals = client.scope(2, "Locals");
+    assert_eq!(value(&client.variables(main_locals), "greeting"), "\"hello\"");
+
+    // tables expand, and so do the tables inside them
+    let globals = client.scope(1, "Globals");
+    let globals = client.variables(globals);
+    let (_, _, point) = globals.iter().find(|(n, _, _)| n == "point").unwrap();
+    let point = client.variables(*point);
+    let names: Vec<&str> = point.iter().map(|(n, _, _)| n.as_str()).collect();
+    assert_eq!(names, ["tags", "x", "y"]);
+    let tags = client.variables(point[0].2);
+    let tags: Vec<(&str, &str)> = tags.iter().map(|(n, v, _)| (n.as_str(), v.as_str())).collect();
+    assert_eq!(tags, [("[1]", "\"a\""), ("[2]", "\"b\"")]);
+
+    let sum = client.evaluate(1, "w * h + 1");
+    assert_eq!(sum.get("body").and_then(|b| b.get("result")), Some(&Json::from("13")));
+    let table = client.evaluate(2, "point");
+    let reference = table.get("body").and_then(|b| b.get("variablesReference")).and_then(Json::as_f64).unwrap();
+    assert_eq!(value(&client.variables(reference), "x"), "3");
+    let error = client.evaluate(1, "nothing.here");
+    assert_eq!(error.get("success"), Some(&Json::Bool(false)));
+    let message = error.get("message").and_then(Json::as_str).unwrap();
+    assert!(message.contains("attempt to index global 'nothing'"), "{}", message);
+
+    client.request("continue", Json::object([("threadId", 1u32.into())]));
+    let output = client.event("output");
+    assert_eq!(output.get("category"), Some(&Json::from("stdout")));
+    let mut written = output.get("output").and_then(Json::as_str).unwrap().to_string();
+    loop {
+        let next = client.read();
+        match next.get("event").and_then(Json::as_str) {
+            Some("output") => written += next.get("body").and_then(|b| b.get("output")).and_then(Json::as_str).unwrap(),
+            Some("exited") => {
+                assert_eq!(next.get("body").and_then(|b| b.get("exitCode")), Some(&Json::Number(0.0)));
+                break;
+            }
+            _ => panic!("unexpected {}", next),
+        }
+    }
+    assert_eq!(written, "hello 12\n");
+    client.event("terminated");
+    client.finish();
+}
+
+#[test]
+fn stepping() {
+    let mut client = Client::start();
+    client.launch("sample.lua", &[("stopOnEntry", true.into())], &[]);
+    assert_eq!(client.stopped(), ("entry".to_string(), 1));
+    client.request("next", Json::object([("threadId", 1u32.into())]));
+    // a function is made at the line of its `end`, as in luac
+    assert_eq!(client.stopped(), ("step".to_string(), 5));
+    client.request("next", Json::object([("threadId", 1u32.into())]));
+    assert_eq!(client.stopped(), ("step".to_string(), 6));
+    client.request("next", Json::object([("threadId", 1u32.into())]));
+    assert_eq!(client.stopped(), ("step".to_string(), 7));
+    client.request("stepIn", Json::object([("threadId", 1u32.into())]));
+    assert_eq!(client.stopped(), ("step".to_string(), 3));
+    assert_eq!(client.frames().len(), 2);
+    client.request("stepOut", Json::object([("threadId", 1u32.into())]));
+    assert_eq!(client.stopped(), ("step".to_string(), 8));
+    assert_eq!(client.frames().len(), 1);
+    client.request("continue", Json::object([("threadId", 1u32.into())]));
+    client.event("terminated");
+    client.finish();
+}
+
+#[test]
+fn function_breakpoint_and_disconnect_while_stopped() {
+    let mut client = Client::start();
+    client.request("initialize", Json::object([("adapterID", "luatest".into())]));
+    client.request("launch", Json::object([("program", fixture("sample.lua").into())]));
+    let names = vec![Json::object([("name", "area".into())])];
+    client.request("setFunctionBreakpoints", Json::object([("breakpoints", names.into())]));
+    client.request("configurationDone", Json::object([]));
+    assert_eq!(client.stopped(), ("function breakpoint".to_string(), 3));
+    // the script is stopped before it writes anything, and never goes on
+    client.finish();
+}
+
+#[test]
+fn runtime_error_is_reported() {
+    let mut client = Client::start();
+    client.launch("error.lua", &[], &[]);
+    let output = client.event("output");
+    assert_eq!(output.get("category"), Some(&Json::from("stderr")));
+    let text = output.get("output").and_then(Json::as_str).unwrap();
+    assert!(text.contains("error.lua:3: attempt to index upvalue 't' (a nil value)"), "{}", text);
+    assert!(text.contains("stack traceback:"), "{}", text);
+    assert_eq!(client.event("exited").get("exitCode"), Some(&Json::Number(1.0)));
+    client.event("terminated");
+    client.finish();
+}
+
+#[test]
+fn requests_needing_a_stop_fail_while_configuring() {
+    let mut client = Client::start();
+    client.request("initialize", Json::object([]));
+    let response = client.try_request("stackTrace", Json::object([("threadId", 1u32.into())]));
+    assert_eq!(response.get("success"), Some(&Json::Bool(false)));
+    let threads = client.request("threads", Json::object([]));
+    assert_eq!(threads.get("threads").and_then(Json::as_array).map(<[Json]>::len), Some(1));
+    client.finish();
+}
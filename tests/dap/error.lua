@@ -0,0 +1,5 @@
+local t = nil
+local function boom()
+  return t.field
+end
+boom()
@@ -0,0 +1,9 @@
+local greeting = "hello"
+local function area(w, h)
+  local size = w * h
+  return size
+end
+point = { x = 3, y = 4, tags = { "a", "b" } }
+local total = area(point.x, point.y)
+io.write(greeting, " ", total, "\n")
+return total
@@ -0,0 +1,246 @@
+//! The debug library and the hook API behind it: what `getinfo` reports,
+//! how callers' names are found, hooks and tracebacks.
+
+use std::{cell::RefCell, rc::Rc};
+
+use luatest::{
+    compiler::compile,
+    vm::{
+        debug::{HookEvent, HookMask, TracebackLimits},
+        error::RuntimeError,
+        hostio::MemoryHostIo,
+        GCLuaValue, LuaVM,
+    },
+};
+
+fn vm() -> LuaVM {
+    let mut vm = LuaVM::new();
+    vm.open_base(Rc::new(MemoryHostIo::new()));
+    vm.open_debug();
+    vm
+}
+fn eval(vm: &mut LuaVM, source: &str) -> anyhow::Result<Vec<GCLuaValue>> {
+    let chunk = compile(source.as_bytes(), "@test.lua").unwrap();
+    vm.process_chunk(chunk)
+}
+fn run(vm: &mut LuaVM, source: &str) -> Vec<String> {
+    eval(vm, source).unwrap().iter().map(|v| v.borrow().as_string(false)).collect()
+}
+
+#[test]
+fn getinfo_fields() {
+    let mut vm = vm();
+    let source = "local function f(a, b)
+  local up = a
+  local i = debug.getinfo(1, 'nSlu')
+  return i
+end
+local i = f(1, 2)
+return i.source, i.short_src, i.what, i.currentline, i.linedefined, i.lastlinedefined, i.nups, i.name, i.namewhat";
+    assert_eq!(run(&mut vm, source), ["@test.lua", "test.lua", "Lua", "3", "1", "5", "0", "f", "local"]);
+
+    let source = "local up = 1
+local function g() return up end
+local i = debug.getinfo(g)
+return i.what, i.currentline, i.linedefined, i.nups, i.name, i.namewhat, i.func == g";
+    assert_eq!(run(&mut vm, source), ["Lua", "-1", "2", "1", "nil", "", "true"]);
+    let source = "local i = debug.getinfo(1) return i.what, i.currentline, i.linedefined, i.short_src";
+    assert_eq!(run(&mut vm, source), ["main", "1", "0", "test.lua"]);
+    let source = "local i = debug.getinfo(print) return i.what, i.short_src, i.source, i.currentline, i.linedefined";
+    assert_eq!(run(&mut vm, source), ["C", "[C]", "=[C]", "-1", "-1"]);
+    // only the fields asked for
+    assert_eq!(run(&mut vm, "local i = debug.getinfo(1, 'l') return i.currentline, i.source, i.name"), ["1", "nil", "nil"]);
+    let source = "local function h()\n\n  return 1\nend\nlocal l = debug.getinfo(h, 'L').activelines return l[1], l[2], l[3], l[4]";
+    assert_eq!(run(&mut vm, source), ["nil", "nil", "true", "true"]);
+    assert_eq!(run(&mut vm, "return debug.getinfo(50)"), ["nil"]);
+    assert_eq!(
+        run(&mut vm, "return pcall(debug.getinfo, 1, 'x')"),
+        ["false", "bad argument #2 to 'getinfo' (invalid option 'x')"]
+    );
+
+    // the same from Rust, at the level the hook sees
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let log = seen.clone();
+    vm.set_hook(HookMask { call: true, ..Default::default() }, move |vm, _| {
+        if let Some(info) = vm.get_info(0) {
+            log.borrow_mut().push((info.name.unwrap_or_default(), info.name_what, info.line_defined, info.what));
+        }
+        Ok(())
+    });
+    run(&mut vm, "local function inner() end\nlocal t = {m = function() inner() end}\nt.m()");
+    vm.clear_hook();
+    let seen = seen.borrow();
+    assert_eq!(seen[0], (String::new(), "", Some(0), "main"));
+    assert_eq!(seen[1], ("m".to_string(), "field", Some(2), "Lua"));
+    assert_eq!(seen[2], ("inner".to_string(), "upvalue", Some(1), "Lua"));
+}
+
+#[test]
+fn callers_names() {
+    let mut vm = vm();
+    let source = "
+        local function who() local i = debug.getinfo(2, 'n') return tostring(i.name) .. ' ' .. i.namewhat end
+        local function name() local i = debug.getinfo(1, 'n') return tostring(i.name) .. ' ' .. i.namewhat end
+        gname = name
+        local lname = name
+        local t = {field = name}
+        function t:meth() local r = name() return r end
+        local obj = setmetatable({}, {__index = {m = name}})
+        local function via_upvalue() local r = lname() return r end
+        local results = {
+            gname(),
+            lname(),
+            t.field(),
+            t:meth(),
+            obj:m(),
+            via_upvalue(),
+            t['fi' .. 'eld'](),
+            (function() local r = name() return r end)(),
+        }
+        return unpack(results)
+    ";
+    assert_eq!(
+        run(&mut vm, source),
+        [
+            "gname global",
+            "lname local",
+            "field field",
+            "name upvalue",
+            "m method",
+            "lname upvalue",
+            "? field",
+            "name upvalue"
+        ]
+    );
+    // a tail call leaves no caller to ask
+    let source = "local function name() local i = debug.getinfo(1, 'n') return tostring(i.name), i.namewhat end local function f() return name() end return f()";
+    assert_eq!(run(&mut vm, source), ["nil", ""]);
+    // errors name the variable too
+    assert_eq!(eval(&mut vm, "local t = {} t.x.y = 1").unwrap_err().to_string(), "test.lua:1: attempt to index field 'x' (a nil value)");
+    assert_eq!(eval(&mut vm, "undefined()").unwrap_err().to_string(), "test.lua:1: attempt to call global 'undefined' (a nil value)");
+    assert_eq!(eval(&mut vm, "local o = {} o:nope()").unwrap_err().to_string(), "test.lua:1: attempt to call method 'nope' (a nil value)");
+}
+
+#[test]
+fn hooks() {
+    let mut vm = vm();
+    // count hooks from Rust, every 10 instructions
+    let counts = Rc::new(RefCell::new(0));
+    let c = counts.clone();
+    vm.set_hook(HookMask { count: 10, ..Default::default() }, move |_, event| {
+        assert_eq!(event, HookEvent::Count);
+        *c.borrow_mut() += 1;
+        Ok(())
+    });
+    // 2 LOADKs and FORPREP, 100 ADDs and FORLOOPs, RETURN
+    run(&mut vm, "local n = 0 for i = 1, 100 do n = n + i end");
+    assert_eq!(*counts.borrow(), 20);
+    assert_eq!(vm.hook_mask(), Some(HookMask { count: 10, ..Default::default() }));
+    vm.clear_hook();
+
+    // and from Lua, with lines, calls and returns
+    let source = "local log = {}
+local function f()
+  return 1
+end
+debug.sethook(function(event, line) log[#log + 1] = event .. (line and ':' .. line or '') end, 'crl')
+f()
+debug.sethook()
+local s = log[1] for i = 2, #log do s = s .. ' ' .. log[i] end
+return s";
+    assert_eq!(
+        run(&mut vm, source),
+        ["return line:6 call line:3 return line:7 call"]
+    );
+    let source = "local n = 0
+debug.sethook(function(e) n = n + 1 end, '', 1)
+local a = 1
+local b = 2
+debug.sethook()
+return n, debug.gethook()";
+    // one each for LOADK, LOADK, GETGLOBAL, GETTABLE and the CALL that clears it
+    assert_eq!(run(&mut vm, source), ["5", "nil"]);
+    let source = "local h = function() end debug.sethook(h, 'l', 3) local f, m, c = debug.gethook() debug.sethook() return f == h, m, c";
+    assert_eq!(run(&mut vm, source), ["true", "l", "3"]);
+    // a failing hook fails the code it interrupted
+    vm.set_hook(HookMask { line: true, ..Default::default() }, |_, event| match event {
+        HookEvent::Line(2) => Err(anyhow::anyhow!("stopped at 2")),
+        _ => Ok(()),
+    });
+    assert_eq!(eval(&mut vm, "local a = 1\nlocal b = 2\nlocal c = 3").unwrap_err().to_string(), "test.lua:2: stopped at 2");
+    vm.clear_hook();
+}
+
+#[test]
+fn tracebacks() {
+    let mut vm = vm();
+    let source = "local function a() error('deep') end
+local function b() a() end
+function c() b() end
+c()";
+    let e = eval(&mut vm, source).unwrap_err();
+    let e = e.downcast_ref::<RuntimeError>().unwrap();
+    assert_eq!(e.message, "test.lua:1: deep");
+    assert_eq!(
+        e.traceback,
+        "stack traceback:\n\ttest.lua:1: in function 'a'\n\ttest.lua:2: in function 'b'\n\ttest.lua:3: in function 'c'\n\ttest.lua:4: in main chunk"
+    );
+    let source = "local function f() return debug.traceback('msg', 1) end\nlocal t = f()\nreturn t";
+    assert_eq!(run(&mut vm, source), ["msg\nstack traceback:\n\ttest.lua:1: in function 'f'\n\ttest.lua:2: in main chunk"]);
+    let source = "local function f() return debug.traceback() end local t = (function() return f() end)() return t";
+    assert_eq!(
+        run(&mut vm, source),
+        ["stack traceback:\n\ttest.lua:1: in function <test.lua:1>\n\t(tail call): ?\n\ttest.lua:1: in main chunk"]
+    );
+
+    // 30 levels of `r`, the chunk below them: the innermost 12, then the
+    // outermost 10
+    let source = "local function r(n) if n == 0 then local t = debug.traceback() return t end local t = r(n - 1) return t end\nlocal t = r(29)\nreturn t";
+    let trace = run(&mut vm, source).remove(0);
+    let lines: Vec<&str> = trace.lines().collect();
+    assert_eq!(lines.len(), 1 + 12 + 1 + 10);
+    assert_eq!(lines[0], "stack traceback:");
+    assert!(lines[1..13].iter().all(|l| *l == "\ttest.lua:1: in function 'r'"), "{}", trace);
+    assert_eq!(lines[13], "\t...");
+    assert!(lines[14..23].iter().all(|l| *l == "\ttest.lua:1: in function 'r'"), "{}", trace);
+    assert_eq!(lines[23], "\ttest.lua:2: in main chunk");
+    // 22 levels fit without eliding
+    let trace = run(&mut vm, &source.replace("r(29)", "r(20)")).remove(0);
+    assert_eq!(trace.lines().count(), 1 + 22);
+    assert!(!trace.contains("..."));
+
+    vm.set_traceback_limits(TracebackLimits { head: 2, tail: 1 });
+    let trace = run(&mut vm, source).remove(0);
+    assert_eq!(
+        trace,
+        "stack traceback:\n\ttest.lua:1: in function 'r'\n\ttest.lua:1: in function 'r'\n\t...\n\ttest.lua:2: in main chunk"
+    );
+}
+
+#[test]
+fn locals_and_upvalues() {
+    let mut vm = vm();
+    let source = "
+        local function f(a, b)
+            local c = a + b
+            local names, values = {}, {}
+            for i = 1, 3 do
+                local name, value = debug.getlocal(1, i)
+                names[i], values[i] = name, value
+            end
+            debug.setlocal(1, 3, 100)
+            return names[1], names[2], names[3], values[3], c, debug.getlocal(1, 50)
+        end
+        return f(1, 2)
+    ";
+    assert_eq!(run(&mut vm, source), ["a", "b", "c", "3", "100", "nil"]);
+    let source = "
+        local x, y = 1, 2
+        local function g() return x + y end
+        local n1, v1 = debug.getupvalue(g, 2)
+        local set = debug.setupvalue(g, 1, 10)
+        return n1, v1, set, g(), x, debug.getupvalue(g, 3)
+    ";
+    assert_eq!(run(&mut vm, source), ["y", "2", "x", "12", "10"]);
+    assert_eq!(run(&mut vm, "local mt = {} return debug.getmetatable(setmetatable({}, mt)) == mt, debug.getregistry() ~= nil"), ["true", "true"]);
+}
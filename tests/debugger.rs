@@ -0,0 +1,199 @@
+//! The debugger driven by scripted handlers: where it stops for breakpoints
+//! and steps, and what a paused frame lets a handler see.
+
+use std::{cell::RefCell, rc::Rc};
+
+use luatest::{
+    compiler::compile,
+    vm::{
+        debugger::{BreakpointKind, DebugHandler, Debugger, Pause, Resume, StopReason},
+        error::LuaError,
+        hostio::MemoryHostIo,
+        GCLuaValue, LuaVM,
+    },
+};
+
+const PROGRAM: &str = "local function add(a, b)
+  local sum = a + b
+  return sum
+end
+local function twice(x)
+  local y = add(x, x)
+  local z = add(y, 1)
+  return z
+end
+local r = twice(3)
+result = r
+return r";
+
+/// Answers each pause with what `f` says.
+struct Script<F>(F);
+impl<F: FnMut(&mut Pause) -> anyhow::Result<Resume>> DebugHandler for Script<F> {
+    fn paused(&mut self, pause: &mut Pause) -> anyhow::Result<Resume> {
+        (self.0)(pause)
+    }
+}
+/// A handler that logs each stop as "reason line" and answers with
+/// `resumes` in turn, then continues.
+fn stepper(resumes: Vec<Resume>) -> (Debugger, Rc<RefCell<Vec<String>>>) {
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let stops = log.clone();
+    let mut resumes = resumes.into_iter();
+    let debugger = Debugger::new(Script(move |pause: &mut Pause| {
+        let (_, line) = pause.location(0).unwrap();
+        let reason = match pause.reason {
+            StopReason::Entry => "entry".to_string(),
+            StopReason::Step => "step".to_string(),
+            StopReason::Breakpoint(id) => format!("bp{}", id),
+        };
+        stops.borrow_mut().push(format!("{} {}", reason, line));
+        Ok(resumes.next().unwrap_or(Resume::Continue))
+    }));
+    (debugger, log)
+}
+fn run(debugger: &Debugger, source: &str, chunk_name: &str) -> anyhow::Result<Vec<GCLuaValue>> {
+    let mut vm = LuaVM::new();
+    vm.open_base(Rc::new(MemoryHostIo::new()));
+    debugger.attach(&mut vm);
+    vm.process_chunk(compile(source.as_bytes(), chunk_name).unwrap())
+}
+fn result(values: anyhow::Result<Vec<GCLuaValue>>) -> String {
+    values.unwrap()[0].borrow().as_string(false)
+}
+
+#[test]
+fn line_breakpoints() {
+    let (debugger, stops) = stepper(vec![]);
+    let id = debugger.breakpoints().add(BreakpointKind::Line { source: "prog.lua".to_string(), line: 2 });
+    debugger.breakpoints().add(BreakpointKind::Line { source: "other.lua".to_string(), line: 7 });
+    assert_eq!(result(run(&debugger, PROGRAM, "@scripts/prog.lua")), "7");
+    assert_eq!(*stops.borrow(), [format!("bp{} 2", id), format!("bp{} 2", id)]);
+
+    stops.borrow_mut().clear();
+    assert!(debugger.breakpoints().remove(id));
+    assert!(!debugger.breakpoints().remove(id));
+    debugger.breakpoints().add(BreakpointKind::Line { source: "@prog.lua".to_string(), line: 10 });
+    debugger.breakpoints().clear_source("other.lua");
+    assert_eq!(debugger.breakpoints().iter().count(), 1);
+    result(run(&debugger, PROGRAM, "@prog.lua"));
+    assert_eq!(*stops.borrow(), ["bp3 10"]);
+    // a different file of the same name is not a match
+    stops.borrow_mut().clear();
+    result(run(&debugger, PROGRAM, "@notprog.lua"));
+    assert!(stops.borrow().is_empty());
+}
+
+#[test]
+fn function_breakpoints() {
+    let (debugger, stops) = stepper(vec![]);
+    debugger.breakpoints().add(BreakpointKind::Function("add".to_string()));
+    assert_eq!(result(run(&debugger, PROGRAM, "@prog.lua")), "7");
+    // the stop is at the function's first line, once per call
+    assert_eq!(*stops.borrow(), ["bp1 2", "bp1 2"]);
+    stops.borrow_mut().clear();
+    // names are what the caller used; natives are never stopped in
+    debugger.breakpoints().add(BreakpointKind::Function("print".to_string()));
+    result(run(&debugger, "local function other() return 1 end\nprint(other())\nlocal add = other\nlocal v = add()\nreturn v", "@f.lua"));
+    assert_eq!(*stops.borrow(), ["bp1 1"]);
+}
+
+#[test]
+fn stepping() {
+    // over the calls, a line at a time
+    let (debugger, stops) = stepper(vec![Resume::StepOver; 10]);
+    debugger.stop_on_entry();
+    assert_eq!(result(run(&debugger, PROGRAM, "@prog.lua")), "7");
+    assert_eq!(*stops.borrow(), ["entry 4", "step 9", "step 10", "step 11", "step 12"]);
+
+    // into `twice`, into the first `add`, then out of both
+    let (debugger, stops) = stepper(vec![Resume::StepInto, Resume::StepInto, Resume::StepOut, Resume::StepOut]);
+    debugger.breakpoints().add(BreakpointKind::Line { source: "prog.lua".to_string(), line: 10 });
+    assert_eq!(result(run(&debugger, PROGRAM, "@prog.lua")), "7");
+    assert_eq!(*stops.borrow(), ["bp1 10", "step 6", "step 2", "step 7", "step 11"]);
+
+    // stepping over a line that hits a breakpoint stops there instead
+    let (debugger, stops) = stepper(vec![Resume::StepOver, Resume::StepOver, Resume::StepOver]);
+    debugger.breakpoints().add(BreakpointKind::Line { source: "prog.lua".to_string(), line: 6 });
+    debugger.breakpoints().add(BreakpointKind::Line { source: "prog.lua".to_string(), line: 3 });
+    result(run(&debugger, PROGRAM, "@prog.lua"));
+    assert_eq!(*stops.borrow(), ["bp1 6", "bp2 3", "step 7", "bp2 3"]);
+}
+
+#[test]
+fn abort() {
+    let (debugger, _) = stepper(vec![Resume::Abort]);
+    debugger.stop_on_entry();
+    let e = run(&debugger, PROGRAM, "@prog.lua").unwrap_err();
+    assert_eq!(e.downcast_ref::<LuaError>(), Some(&LuaError::Interrupted("stopped by the debugger".to_string())));
+}
+
+#[test]
+fn evaluate_scoping() {
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let log = seen.clone();
+    let debugger = Debugger::new(Script(move |pause: &mut Pause| {
+        let mut eval = |level: usize, expr: &str| match pause.evaluate(level, expr) {
+            Ok(values) => values.iter().map(|v| v.borrow().as_string(false)).collect::<Vec<_>>().join(", "),
+            Err(e) => format!("error: {}", e),
+        };
+        let mut out = vec![
+            eval(0, "a, b, sum"),
+            eval(0, "k"),
+            eval(0, "shadow"),
+            eval(1, "x, y, shadow"),
+            eval(2, "r"),
+            eval(0, "(function() a = 100 return a end)()"),
+            eval(0, "a"),
+            eval(0, "limit * 2"),
+            eval(9, "1"),
+            eval(0, "a +"),
+        ];
+        out.push(pause.locals(0).into_iter().map(|(name, _)| name).collect::<Vec<_>>().join(" "));
+        out.push(pause.upvalues(0).into_iter().map(|(name, _)| name).collect::<Vec<_>>().join(" "));
+        out.push(pause.lookup(0, "k").borrow().as_string(false));
+        out.push(pause.lookup(0, "limit").borrow().as_string(false));
+        out.push(pause.frames().iter().map(|f| f.name.clone().unwrap_or("?".to_string())).collect::<Vec<_>>().join(" "));
+        log.borrow_mut().extend(out);
+        Ok(Resume::Continue)
+    }));
+    debugger.breakpoints().add(BreakpointKind::Line { source: "scope.lua".to_string(), line: 5 });
+    let source = "limit = 21
+local k, shadow = 'up', 'outer'
+local function add(a, b)
+  local sum = a + b + #k
+  return sum
+end
+local function twice(x)
+  local shadow = 'inner'
+  local y = add(x, x)
+  return y
+end
+local r = twice(3)
+return r";
+    let mut vm = LuaVM::new();
+    vm.open_base(Rc::new(MemoryHostIo::new()));
+    debugger.attach(&mut vm);
+    vm.process_chunk(compile(source.as_bytes(), "@scope.lua").unwrap()).unwrap();
+    assert_eq!(
+        *seen.borrow(),
+        [
+            "3, 3, 8",
+            "up",
+            // not an upvalue of `add`
+            "nil",
+            "3, nil, inner",
+            "nil",
+            "100",
+            // locals are copied in, so the frame keeps its own
+            "3",
+            "42",
+            "error: no frame at level 9",
+            "error: (eval):2: unexpected symbol near '<eof>'",
+            "a b sum",
+            "k",
+            "up",
+            "21",
+            "add twice ?",
+        ]
+    );
+}
@@ -0,0 +1,377 @@
+//! The decompiler on the cases each part of it was written for. Every
+//! source is also recompiled from what the decompiler made of it and must
+//! print and return the same as the original.
+
+use std::rc::Rc;
+
+use gc::Gc;
+use luatest::{
+    compiler::{
+        compile,
+        printer::{long_string, number, quote_string, PrintOptions},
+    },
+    vm::{
+        chunk_parser::{FunctionBlock, LuaChunk},
+        convert::FromLua,
+        decompiler::LuaDecompiler,
+        hostio::MemoryHostIo,
+        string::LuaString,
+        LuaVM,
+    },
+};
+
+/// What running `chunk` prints, and then a line with its results or the
+/// error it stopped with.
+fn run(chunk: LuaChunk) -> String {
+    let host = Rc::new(MemoryHostIo::new());
+    let mut vm = LuaVM::new();
+    vm.open_base(host.clone());
+    let ending = match vm.process_chunk(chunk) {
+        Ok(results) => {
+            let results: Vec<String> = results.iter().map(|v| vm.tostring(v).unwrap()).collect();
+            format!("==> {}", results.join("\t"))
+        }
+        Err(e) => format!("==> error: {}", e),
+    };
+    String::from_utf8_lossy(&host.stdout_contents()).into_owned() + &ending
+}
+
+/// `chunk` decompiled, after checking that the result behaves like it.
+fn round_trip(chunk: LuaChunk) -> String {
+    round_trip_with(chunk, &PrintOptions::default())
+}
+fn round_trip_with(chunk: LuaChunk, options: &PrintOptions) -> String {
+    let source = LuaDecompiler::new(chunk.clone()).run_with(options).unwrap();
+    let recompiled = compile(source.as_bytes(), "=dec").unwrap_or_else(|e| panic!("{}\n{}", e, source));
+    assert_eq!(run(recompiled), run(chunk), "{}", source);
+    source
+}
+
+fn decompile(source: &str) -> String {
+    round_trip(compile(source.as_bytes(), "=src").unwrap())
+}
+
+#[test]
+fn control_structures() {
+    let source = "local function sign(x)
+  local r
+  if x < 0 then r = -1 elseif x == 0 then r = 0 else r = 1 end
+  return r
+end
+print(sign(-5), sign(0), sign(7))
+local i = 0
+while i < 10 do
+  i = i + 1
+  if i == 5 then break end
+end
+repeat i = i - 2 until i < 0
+local s = 0
+for j = 10, 1, -3 do s = s + j end
+for k, v in ipairs({4, 5, 6}) do s = s + k * v end
+return i, s";
+    let out = decompile(source);
+    assert!(out.contains("    if x < 0 then\n        r = -1\n    elseif x == 0 then\n        r = 0\n    else\n        r = 1\n    end\n"), "{}", out);
+    assert!(out.contains("while i < 10 do\n    i = i + 1\n    if i == 5 then\n        break\n    end\nend\n"), "{}", out);
+    assert!(out.contains("repeat\n    i = i - 2\nuntil i < 0\n"), "{}", out);
+    assert!(out.contains("for j = 10, 1, -3 do\n    s = s + j\nend\n"), "{}", out);
+    assert!(out.contains("for k, v in ipairs({4, 5, 6}) do\n    s = s + k * v\nend\nreturn i, s"), "{}", out);
+}
+
+#[test]
+fn loops_nest_and_break_from_the_inner_one() {
+    let out = decompile(
+        "local n = 0
+for i = 1, 5 do
+  local j = 0
+  while true do
+    j = j + 1
+    if j > i then break end
+    n = n + j
+  end
+end
+return n",
+    );
+    assert_eq!(
+        out,
+        "local n = 0
+for i = 1, 5 do
+    local j = 0
+    while true do
+        j = j + 1
+        if i < j then
+            break
+        end
+        n = n + j
+    end
+end
+return n"
+    );
+}
+
+#[test]
+fn conditional_expressions() {
+    let out = decompile(
+        "local function pick(a, b)
+  return a and b or 'c', not (a == b), a ~= nil and b == nil
+end
+print(pick(1, 2))
+print(pick(nil, 2))
+print(pick(1, false))
+local x = pick(1, 2) and 'yes' or 'no'
+return x",
+    );
+    assert!(out.contains("return a and b or \"c\", a ~= b, a ~= nil and b == nil\n"), "{}", out);
+    assert!(out.contains("local x = pick(1, 2) and \"yes\" or \"no\"\n"), "{}", out);
+}
+
+#[test]
+fn calls_and_constructors() {
+    let out = decompile(
+        "local o = {n = 0}
+function o.add(self, k) self.n = self.n + k return self end
+o:add(1):add(2)
+local function three() return 1, 2, 3 end
+local t = {0, three()}
+local u = {three(), three(), x = o['n'], [10] = 'ten'}
+print(#t, #u, u.x, u[10], o.n)
+return three()",
+    );
+    assert!(out.contains("function o:add(k)\n") && out.contains("o:add(1):add(2)\n"), "{}", out);
+    assert!(out.contains("local t = {0, three()}\n"), "{}", out);
+    assert!(out.contains("local u = {x = o.n, [10] = \"ten\", three(), (three())}\n"), "{}", out);
+    assert!(out.ends_with("return three()"), "{}", out);
+}
+
+/// `source` compiled without its line numbers and names, as `luac -s`
+/// leaves it.
+fn stripped(source: &str) -> LuaChunk {
+    fn strip(f: &mut FunctionBlock) {
+        f.line_info.clear();
+        f.local_vars.clear();
+        f.upvalue_names.clear();
+        for proto in &mut f.list_fnproto {
+            let mut p = (**proto).clone();
+            strip(&mut p);
+            *proto = Gc::new(p);
+        }
+    }
+    let mut chunk = compile(source.as_bytes(), "=src").unwrap();
+    strip(&mut chunk.func);
+    chunk
+}
+
+#[test]
+fn names_and_scopes_come_from_the_debug_info() {
+    let out = decompile(
+        "local total, count = 0, 0
+local function add(value, weight)
+  local scaled = value * weight
+  total = total + scaled
+  count = count + 1
+end
+do
+  local w = 2
+  add(3, w)
+end
+local w = 5
+add(1, w)
+return total, count, w",
+    );
+    assert_eq!(
+        out,
+        "local total, count = 0, 0
+local function add(value, weight)
+    local scaled = value * weight
+    total = total + scaled
+    count = count + 1
+end
+do
+    local w = 2
+    add(3, w)
+end
+local w = 5
+add(1, w)
+return total, count, w"
+    );
+}
+
+#[test]
+fn stripped_chunks_get_generated_names() {
+    let source = "local a, b = 1, 2
+local function f(x, ...)
+  local y = x + a
+  return function(z) return y + z + b end, ...
+end
+local g = f(3, 'extra')
+a = g(4)
+for i = 1, 3 do a = a + i end
+for k, v in pairs({10}) do a = a + v end
+return a, select(2, f(0, 'x'))";
+    let out = round_trip(stripped(source));
+    assert!(out.starts_with("local lv_0 = 1\nlocal lv_1 = 2\n"), "{}", out);
+    assert!(!out.contains("local y") && !out.contains(" x "), "{}", out);
+    assert!(out.contains("for lv_"), "{}", out);
+}
+
+#[test]
+fn folded_locals_of_stripped_chunks_free_their_registers() {
+    // nothing says where `c` or `f` go out of scope; the loops reuse their
+    // registers
+    let out = round_trip(stripped(
+        "local function f(x) return x end
+do local c = f(3) a = c end
+for i = 1, 2 do a = a + i end
+return a",
+    ));
+    assert_eq!(out, "local function lv_1(lv_0)\n    return lv_0\nend\na = lv_1(3)\nfor lv_2 = 1, 2 do\n    a = a + lv_2\nend\nreturn a");
+}
+
+#[test]
+fn local_functions() {
+    let source = "local function fact(n)
+    if n <= 1 then
+        return 1
+    end
+    return n * fact(n - 1)
+end
+local function noop()
+end
+f = \"global\"
+local f = function()
+    return f
+end
+return fact(10), noop(), f()";
+    // `f` in the last function is the global, so it cannot be `local function`
+    assert_eq!(decompile(source), source);
+}
+
+#[test]
+fn function_names() {
+    let source = "t = {a = {b = {}}}
+function t.a.b:c(x)
+    return self == t.a.b, x
+end
+function t.a.f(...)
+    return select(\"#\", ...), ...
+end
+function h()
+    return t.a.b:c(5)
+end
+return h(), t.a.f(1, nil, 3)";
+    assert_eq!(decompile(source), source);
+}
+
+#[test]
+fn nested_closures_and_upvalues() {
+    let source = "local function counter()
+    local n = 0
+    return function()
+        n = n + 1
+        return n
+    end, function()
+        return n
+    end
+end
+local inc, get = counter()
+inc()
+inc()
+local function outer(a)
+    return function(b)
+        return function(c)
+            return a .. b .. c
+        end
+    end
+end
+local fs = {}
+for i = 1, 3 do
+    local j = i * 10
+    fs[i] = function()
+        return i + j
+    end
+end
+return get(), outer(\"x\")(\"y\")(\"z\"), fs[1](), fs[3]()";
+    assert_eq!(decompile(source), source);
+}
+
+#[test]
+fn varargs() {
+    let source = "local function va(...)
+    local a, b = ...
+    local t = {...}
+    return #t, a, b, ...
+end
+print(va())
+return va(1, nil, 3)";
+    assert_eq!(decompile(source), source);
+}
+
+#[test]
+fn parentheses_only_where_precedence_needs_them() {
+    let source = "local a, b, c = 2, 3, 4
+return (a + b) * c, a - (b - c), a - b - c, a ^ b ^ c, (a ^ b) ^ c, -a ^ b, (-a) ^ b, \
+a .. b .. c, (a .. b) .. c, not (a == b), not a == b, (a or b) and c, a or b and c, \
+#{a} + 1, - -a, -(a + b), a / (b * c), a * b / c, a % (b % c)";
+    assert_eq!(
+        decompile(source),
+        "local a, b, c = 2, 3, 4
+return (a + b) * c, a - (b - c), a - b - c, a ^ b ^ c, (a ^ b) ^ c, -a ^ b, (-a) ^ b, a .. b .. c, (a .. b) .. c, \
+a ~= b, not a == b, (a or b) and c, a or b and c, #{a} + 1, - -a, -(a + b), a / (b * c), a * b / c, a % (b % c)"
+    );
+}
+
+#[test]
+fn string_literals_read_back_as_the_same_bytes() {
+    let strings: [&[u8]; 9] =
+        [b"a\"b\\c\n\r\t", b"\x00\x01\x7f\xff", b"\x001", b"]]", b"a]]b]=]c", b"\nx", b"x\ry", b"[[x", b""];
+    assert_eq!(quote_string(strings[0]), r#""a\"b\\c\n\r\t""#);
+    assert_eq!(quote_string(strings[1]), r#""\0\1\127\255""#);
+    // a digit after an escape would be read as part of it
+    assert_eq!(quote_string(strings[2]), r#""\0001""#);
+    assert_eq!(long_string(strings[3]).unwrap(), "[=[]]]=]");
+    assert_eq!(long_string(strings[4]).unwrap(), "[==[a]]b]=]c]==]");
+    // the newline after the opening bracket is skipped, so it takes another
+    assert_eq!(long_string(strings[5]).unwrap(), "[[\n\nx]]");
+    assert_eq!(long_string(strings[6]), None);
+    assert_eq!(long_string(strings[7]).unwrap(), "[=[[[x]=]");
+    for s in strings {
+        let mut literals = vec![quote_string(s)];
+        literals.extend(long_string(s));
+        for literal in literals {
+            let chunk = compile(format!("return {}", literal).as_bytes(), "=lit").unwrap();
+            let results = LuaVM::new().process_chunk(chunk).unwrap();
+            assert_eq!(LuaString::from_lua(&results[0]).unwrap().as_bytes(), s, "{}", literal);
+        }
+    }
+}
+
+#[test]
+fn number_literals_read_back_as_the_same_number() {
+    let numbers = [0.0, 1.5, -0.25, 1e16, 1e-6, 123456789012.0, 0.1, 2f64.powi(53) + 2.0, f64::MAX, f64::INFINITY, -f64::INFINITY];
+    let literals: Vec<String> = numbers.iter().map(|n| number(*n)).collect();
+    assert_eq!(literals[..5], ["0", "1.5", "-0.25", "1e16", "1e-6"]);
+    assert_eq!(literals[9..], ["1/0", "-1/0"]);
+    let chunk = compile(format!("return {}, 0/0", literals.join(", ")).as_bytes(), "=num").unwrap();
+    let results = LuaVM::new().process_chunk(chunk).unwrap();
+    for (n, v) in numbers.iter().zip(&results) {
+        assert_eq!(f64::from_lua(v).unwrap(), *n);
+    }
+    assert!(f64::from_lua(&results[numbers.len()]).unwrap().is_nan());
+    assert_eq!(number(f64::NAN), "0/0");
+}
+
+#[test]
+fn print_options() {
+    let source = "local x = ...
+if x then
+    print(\"one\\ntwo\\nthree\")
+end";
+    let chunk = || compile(source.as_bytes(), "=src").unwrap();
+    assert_eq!(round_trip(chunk()), "local x = ...\nif x then\n    print([[one\ntwo\nthree]])\nend");
+    let options = PrintOptions { indent_width: 2, long_strings: false, pc_comments: true };
+    assert_eq!(
+        round_trip_with(chunk(), &options),
+        "local x = ... -- pc 1\nif x then -- pc 2\n  print(\"one\\ntwo\\nthree\") -- pc 4\nend"
+    );
+    let options = PrintOptions { indent_width: 0, ..PrintOptions::default() };
+    assert_eq!(round_trip_with(chunk(), &options), "local x = ...\nif x then\nprint([[one\ntwo\nthree]])\nend");
+}
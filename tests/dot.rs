@@ -0,0 +1,71 @@
+//! Graphviz output for control-flow graphs, from the library and from
+//! `luatest cfg --dot`.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use luatest::{compiler::compile, vm::dot::to_dot};
+
+fn dot(source: &str) -> String {
+    to_dot(&compile(source.as_bytes(), "=dot").unwrap().func)
+}
+fn lines(dot: &str) -> Vec<&str> {
+    dot.lines().map(str::trim).collect()
+}
+
+#[test]
+fn branches_are_labelled() {
+    let out = dot("local x = ...\nif x then x = 1 else x = 2 end\nreturn x");
+    assert!(out.starts_with("digraph lua {\n\tcompound=true;\n"));
+    assert!(out.ends_with("\t}\n}\n"));
+    let lines = lines(&out);
+    assert!(lines.contains(&"label=\"main <dot:0,0>\";"));
+    assert!(lines.contains(&"f0_b0 [label=\"1    [1] VARARG    0 2\\l2    [2] TEST      0 0 0\\l3    [2] JMP       2 ; to 6\\l\"];"));
+    assert!(lines.contains(&"f0_b0 -> f0_b2 [label=\"true\", color=darkgreen, fontcolor=darkgreen];"));
+    assert!(lines.contains(&"f0_b0 -> f0_b1 [label=\"false\", color=red3, fontcolor=red3];"));
+    assert!(lines.contains(&"f0_b1 -> f0_b3;"));
+    // unreachable blocks are greyed out
+    assert!(lines.contains(&"f0_b4 [label=\"8    [3] RETURN    0 1\\l\", style=dashed, fontcolor=gray50, color=gray50];"));
+}
+
+#[test]
+fn loops_and_closures() {
+    let out = dot("local function f(a) if a then return 1 end return 2 end\nfor i = 1, 3 do f(i) end");
+    let lines = lines(&out);
+    // the loop header is drawn thicker and its back edge in blue
+    assert!(lines.contains(&"f0_b2 [label=\"9    [2] FORLOOP   1 -4 ; to 6\\l\", penwidth=2];"));
+    assert!(lines.contains(&"f0_b1 -> f0_b2 [penwidth=2, constraint=false, color=blue, fontcolor=blue];"));
+    assert!(lines.contains(&"f0_b2 -> f0_b1 [label=\"true\", color=darkgreen, fontcolor=darkgreen];"));
+    // the nested function is a cluster of its own, linked from its CLOSURE
+    assert!(lines.contains(&"subgraph cluster_f0_0 {"));
+    assert!(lines.contains(&"label=\"function <dot:1,1>\";"));
+    assert!(lines.contains(&"f0_0_b0 -> f0_0_b2 [label=\"true\", color=darkgreen, fontcolor=darkgreen];"));
+    assert!(lines.contains(&"f0_b0 -> f0_0_b0 [lhead=cluster_f0_0, style=dashed, label=\"closure 0\"];"));
+}
+
+#[test]
+fn labels_are_escaped() {
+    let out = dot("local s = 'say \"hi\"\\\\'");
+    assert!(out.contains("LOADK     0 -1 ; \\\"say \\\\\\\"hi\\\\\\\"\\\\\\\\\\\"\\l"), "{}", out);
+}
+
+#[test]
+fn cfg_command() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_luatest"))
+        .args(["cfg", "--dot", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"while x do x = x - 1 end").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let out = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(out, dot("while x do x = x - 1 end").replace("<dot:", "<stdin:"));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_luatest")).args(["cfg", "x.lua"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("luatest: cfg needs a format, --dot\n"));
+}
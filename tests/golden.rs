@@ -0,0 +1,166 @@
+//! Snapshot tests over the fixtures in `tests/golden`. Each `NAME.luac` is a
+//! Lua 5.1 bytecode file; `NAME.dec.lua` is what the decompiler makes of it
+//! and `NAME.out` what running it writes to stdout, followed by a line of
+//! its results. The decompiled source must also run to the same output.
+//!
+//! `BLESS=1 cargo test --test golden` rewrites the expectations from what
+//! the code does now, and compiles `NAME.lua` into a missing `NAME.luac`.
+//! The bytecode here came from our compiler; `luac -o NAME.luac NAME.lua`
+//! from Lua 5.1 gives the same instructions.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use luatest::{
+    compiler::compile,
+    vm::{chunk_parser::LuaChunk, decompiler::LuaDecompiler, hostio::MemoryHostIo, LuaVM},
+};
+
+const DIR: &str = "tests/golden";
+
+fn blessing() -> bool {
+    env::var_os("BLESS").is_some_and(|v| v != "0")
+}
+
+/// What running `chunk` writes through `io`, and then a line with its
+/// results separated by tabs, or the error it stopped with.
+fn run(chunk: LuaChunk) -> String {
+    let host = Rc::new(MemoryHostIo::new());
+    let mut vm = LuaVM::new();
+    vm.open_io(host.clone());
+    let ending = match vm.process_chunk(chunk) {
+        Ok(results) => {
+            let results: Vec<String> = results.iter().map(|v| vm.tostring(v).unwrap()).collect();
+            format!("==> {}\n", results.join("\t"))
+        }
+        Err(e) => format!("==> error: {}\n", e),
+    };
+    String::from_utf8_lossy(&host.stdout_contents()).into_owned() + &ending
+}
+
+/// A unified diff from `expected` to `actual`, with three lines of context.
+fn unified_diff(expected: &str, actual: &str) -> String {
+    let (old, new): (Vec<&str>, Vec<&str>) = (expected.lines().collect(), actual.lines().collect());
+    // lcs[i][j] is the longest common subsequence of old[i..] and new[j..]
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    // each line as its marker and the line number on either side
+    let mut script = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            script.push((' ', old[i], i, j));
+            (i, j) = (i + 1, j + 1);
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] > lcs[i + 1][j]) {
+            script.push(('+', new[j], i, j));
+            j += 1;
+        } else {
+            script.push(('-', old[i], i, j));
+            i += 1;
+        }
+    }
+    let mut out = String::from("--- expected\n+++ actual\n");
+    let changed: Vec<usize> = (0..script.len()).filter(|k| script[*k].0 != ' ').collect();
+    let mut k = 0;
+    while k < changed.len() {
+        let start = changed[k].saturating_sub(3);
+        let mut end = changed[k] + 1;
+        while k < changed.len() && changed[k] <= end + 6 {
+            end = changed[k] + 1;
+            k += 1;
+        }
+        let end = (end + 3).min(script.len());
+        let hunk = &script[start..end];
+        let olds = hunk.iter().filter(|l| l.0 != '+').count();
+        let news = hunk.iter().filter(|l| l.0 != '-').count();
+        out += &format!("@@ -{},{} +{},{} @@\n", hunk[0].2 + 1, olds, hunk[0].3 + 1, news);
+        for (marker, line, _, _) in hunk {
+            out += &format!("{}{}\n", marker, line);
+        }
+    }
+    out
+}
+
+/// Compares `actual` with the file at `path`, or writes it there when
+/// blessing.
+fn check(path: &Path, actual: &str, failures: &mut Vec<String>) {
+    if blessing() {
+        fs::write(path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(path).unwrap_or_default();
+    if expected != actual {
+        failures.push(format!("{}\n{}", path.display(), unified_diff(&expected, actual)));
+    }
+}
+
+fn fixtures() -> Vec<PathBuf> {
+    let mut names: Vec<PathBuf> = fs::read_dir(DIR)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "luac" || e == "lua") && !p.to_str().unwrap().ends_with(".dec.lua"))
+        .map(|p| p.with_extension(""))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+#[test]
+fn golden_fixtures() {
+    let mut failures = Vec::new();
+    let fixtures = fixtures();
+    assert!(!fixtures.is_empty());
+    for fixture in &fixtures {
+        let bytecode = fixture.with_extension("luac");
+        if blessing() && !bytecode.exists() {
+            let name = fixture.with_extension("lua");
+            let source = fs::read(&name).unwrap();
+            let chunk = compile(&source, &format!("@{}", name.file_name().unwrap().to_str().unwrap())).unwrap();
+            fs::write(&bytecode, chunk.to_bytes().unwrap()).unwrap();
+        }
+        let bytes = fs::read(&bytecode).unwrap_or_else(|e| panic!("{}: {}", bytecode.display(), e));
+        let read = || LuaChunk::from_reader(&mut &bytes[..]).unwrap();
+
+        let decompiled = match LuaDecompiler::new(read()).run() {
+            Ok(source) => source + "\n",
+            Err(e) => format!("-- error: {}\n", e),
+        };
+        check(&fixture.with_extension("dec.lua"), &decompiled, &mut failures);
+
+        let output = run(read());
+        check(&fixture.with_extension("out"), &output, &mut failures);
+
+        match compile(decompiled.as_bytes(), "=decompiled") {
+            Ok(recompiled) => {
+                let rerun = run(recompiled);
+                if rerun != output {
+                    failures.push(format!(
+                        "{} decompiled runs differently\n{}",
+                        fixture.display(),
+                        unified_diff(&output, &rerun)
+                    ));
+                }
+            }
+            Err(e) => failures.push(format!("{} decompiled does not compile: {}", fixture.display(), e)),
+        }
+    }
+    assert!(failures.is_empty(), "{} mismatches:\n{}", failures.len(), failures.join("\n"));
+}
+
+#[test]
+fn diff_shows_changed_lines_in_context() {
+    let expected = "a\nb\nc\nd\ne\nf\ng\nh\n";
+    let actual = "a\nb\nc\nd\nE\nf\ng\nh\ni\n";
+    assert_eq!(
+        unified_diff(expected, actual),
+        "--- expected\n+++ actual\n@@ -2,7 +2,8 @@\n b\n c\n d\n-e\n+E\n f\n g\n h\n+i\n"
+    );
+}
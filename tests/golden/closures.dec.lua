@@ -0,0 +1,37 @@
+local function fib(n)
+    if n < 2 then
+        return n
+    end
+    return fib(n - 1) + fib(n - 2)
+end
+local function counter()
+    local count = 0
+    return function(step)
+        count = count + (step or 1)
+        return count
+    end
+end
+local tick = counter()
+tick()
+tick(10)
+Account = {balance = 0}
+function Account.new(balance)
+    return {balance = balance, deposit = Account.deposit}
+end
+function Account:deposit(amount)
+    self.balance = self.balance + amount
+    return self
+end
+local acct = Account.new(5):deposit(7)
+local function count(...)
+    local packed = {...}
+    return #packed
+end
+local fns = {}
+for i = 1, 3 do
+    local double = i * 2
+    fns[i] = function()
+        return double + i
+    end
+end
+return fib(10), tick(), acct.balance, count(1, nil, 3), fns[1]() + fns[3]()
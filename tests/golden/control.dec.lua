@@ -0,0 +1,41 @@
+local function classify(n)
+    if n < 0 then
+        return "negative"
+    end
+    if n == 0 then
+        return "zero"
+    end
+    if n % 2 == 0 then
+        return "even"
+    end
+    return "odd"
+end
+local total = 0
+for i = 10, 1, -3 do
+    total = total + i
+end
+local n = 0
+while true do
+    n = n + 1
+    if 4 <= n and 0 < total then
+        break
+    end
+end
+local steps = 0
+repeat
+    local half = n / 2
+    steps = steps + 1
+    n = half
+until n < 1
+local function range(max)
+    return function(_, i)
+        if i < max then
+            return i + 1
+        end
+    end, nil, 0
+end
+local squares = 0
+for i in range(4) do
+    squares = squares + i * i
+end
+return classify(-2), classify(0), classify(6), classify(7), total, steps, squares
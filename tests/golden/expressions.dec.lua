@@ -0,0 +1,13 @@
+local a, b, c = 7, 2, "x"
+local t = {n = 3}
+local sum = a + b * t.n - (a - b) / 4
+local parts = c .. a .. "-" .. b
+local flag = b < a and b ~= 0 or nil
+local pick = t.missing and 1 or 2 < t.n and "big" or "small"
+local neg = -a ^ 2 + -b % 5
+local text = [[quote " backslash \ tab	]]
+local lines = [[one
+two
+three
+]]
+return sum, parts, flag, pick, neg, not not t.missing, text, lines, #lines
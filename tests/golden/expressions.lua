@@ -0,0 +1,10 @@
+local a, b, c = 7, 2, "x"
+local t = {n = 3}
+local sum = a + b * t.n - (a - b) / 4
+local parts = c .. a .. "-" .. b
+local flag = a > b and b ~= 0 or nil
+local pick = t.missing and 1 or t.n > 2 and "big" or "small"
+local neg = -a ^ 2 + (-b) % 5
+local text = "quote \" backslash \\ tab\t"
+local lines = "one\ntwo\nthree\n"
+return sum, parts, flag, pick, neg, not not t.missing, text, lines, #lines
@@ -0,0 +1,7 @@
+local names = {"alpha", "beta", "gamma"}
+for i = 1, #names do
+  io.write(i, ": ", names[i], "\n")
+end
+local out = io.stdout
+out:write("done", "\n")
+return #names
@@ -0,0 +1,16 @@
+local list = {10, 20, 30, 40}
+local point = {x = 1, y = 2, [3] = "three", ["two words"] = true}
+local nested = {inner = {list = {1, 2}}, 5}
+nested.inner.list[3] = 3
+point.x, point.y = point.y, point.x
+local grid = {}
+for i = 1, 3 do
+  grid[i] = {}
+  for j = 1, 3 do
+    grid[i][j] = i * j
+  end
+end
+local stack = {}
+stack[#stack + 1] = "a"
+stack[#stack + 1] = "b"
+return #list, point.x, point.y, point[3], point["two words"], #nested.inner.list, nested[1], grid[2][3], stack[#stack]
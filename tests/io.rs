@@ -0,0 +1,185 @@
+//! Runs the `io` and `os` libraries against `MemoryHostIo`, so that files,
+//! the clock and the time zone are all under the test's control.
+
+use std::rc::Rc;
+
+use luatest::{
+    compiler::compile,
+    vm::{convert::FromLua, hostio::MemoryHostIo, string::LuaString, GCLuaValue, LuaVM},
+};
+
+/// 2024-02-29 12:34:56 UTC, a Thursday.
+const LEAP_DAY: f64 = 1709210096.0;
+
+fn vm() -> (LuaVM, Rc<MemoryHostIo>) {
+    let host = Rc::new(MemoryHostIo::new());
+    let mut vm = LuaVM::new();
+    vm.open_base(host.clone());
+    vm.open_io(host.clone());
+    vm.open_os(host.clone());
+    (vm, host)
+}
+fn eval(vm: &mut LuaVM, source: &str) -> Vec<GCLuaValue> {
+    let chunk = compile(source.as_bytes(), "=io").unwrap();
+    vm.process_chunk(chunk).unwrap()
+}
+fn run(vm: &mut LuaVM, source: &str) -> Vec<String> {
+    eval(vm, source).iter().map(|v| v.borrow().as_string(false)).collect()
+}
+
+#[test]
+fn open_modes() {
+    let (mut vm, host) = vm();
+    host.set_file("a.txt", "hello");
+    assert_eq!(run(&mut vm, "return io.open('missing.txt')"), ["nil", "missing.txt: No such file or directory", "2"]);
+    assert_eq!(run(&mut vm, "return io.open('a.txt', 'rw')"), ["nil", "a.txt: Invalid argument", "22"]);
+    assert_eq!(run(&mut vm, "return io.type(io.open('a.txt')), io.type(42)"), ["file", "nil"]);
+
+    run(&mut vm, "local f = io.open('a.txt', 'a') f:write(' world', 1) f:close()");
+    assert_eq!(host.file("a.txt").unwrap(), b"hello world1");
+    run(&mut vm, "local f = io.open('b.txt', 'w') f:write('new') f:close()");
+    assert_eq!(host.file("b.txt").unwrap(), b"new");
+    run(&mut vm, "local f = io.open('a.txt', 'w') f:close()");
+    assert_eq!(host.file("a.txt").unwrap(), b"");
+
+    host.set_file("c.txt", "abcdef");
+    let source = "local f = io.open('c.txt', 'r+') local s = f:read(2) f:write('XY') f:close() return s";
+    assert_eq!(run(&mut vm, source), ["ab"]);
+    assert_eq!(host.file("c.txt").unwrap(), b"abXYef");
+    let source = "local f = io.open('d.txt', 'w') local r = f:write('a', 1) f:close() return r, io.write('b')";
+    assert_eq!(run(&mut vm, source), ["true", "true"]);
+    assert_eq!(host.file("d.txt").unwrap(), b"a1");
+    assert_eq!(
+        run(&mut vm, "local f = io.open('c.txt') return f:write('x')"),
+        ["nil", "Bad file descriptor", "9"]
+    );
+}
+
+#[test]
+fn read_formats() {
+    let (mut vm, host) = vm();
+    host.set_file("data", "first line\n  42 -1.5e2 0x10\nrest\nof it");
+    let source = "
+        local f = io.open('data')
+        local line = f:read()
+        local a, b, c = f:read('*n', '*n', '*n')
+        local nl = f:read('*l')
+        local four = f:read(4)
+        local zero = f:read(0)
+        local all = f:read('*a')
+        return line, a, b, c, nl, four, zero, all, f:read('*a'), f:read('*l'), f:read(0), f:read(1)
+    ";
+    assert_eq!(
+        run(&mut vm, source),
+        ["first line", "42", "-150", "16", "", "rest", "", "\nof it", "", "nil", "nil", "nil"]
+    );
+    host.set_file("bad", "abc");
+    assert_eq!(run(&mut vm, "local f = io.open('bad') return f:read('*n'), f:read('*a')"), ["nil", "abc"]);
+    assert_eq!(
+        run(&mut vm, "return pcall(io.open('bad').read, io.open('bad'), '*x')"),
+        ["false", "bad argument #2 to 'read' (invalid format)"]
+    );
+}
+
+#[test]
+fn reads_and_writes_keep_bytes() {
+    let (mut vm, host) = vm();
+    let data = b"\xff\x00\x80\n\xfe\r\n\xc3";
+    host.set_file("bin", &data[..]);
+    let values = eval(&mut vm, "local f = io.open('bin', 'rb') return f:read('*l', 2, '*a')");
+    let bytes: Vec<Vec<u8>> = values.iter().map(|v| LuaString::from_lua(v).unwrap().into_bytes()).collect();
+    assert_eq!(bytes, [&b"\xff\x00\x80"[..], b"\xfe\r", b"\n\xc3"]);
+    assert_eq!(run(&mut vm, "return #io.open('bin'):read('*a')"), ["8"]);
+
+    run(&mut vm, "local f = io.open('copy', 'wb') f:write(io.open('bin'):read('*a'), '\\0\\255') f:close()");
+    assert_eq!(host.file("copy").unwrap(), b"\xff\x00\x80\n\xfe\r\n\xc3\x00\xff");
+    let lines = eval(&mut vm, "local t = {} for l in io.lines('bin') do t[#t + 1] = l end return unpack(t)");
+    let lines: Vec<Vec<u8>> = lines.iter().map(|v| LuaString::from_lua(v).unwrap().into_bytes()).collect();
+    assert_eq!(lines, [&b"\xff\x00\x80"[..], b"\xfe\r", b"\xc3"]);
+}
+
+#[test]
+fn lines() {
+    let (mut vm, host) = vm();
+    host.set_file("l.txt", "one\ntwo\n\nfour");
+    let source = "local s, n = '', 0 for l in io.lines('l.txt') do s, n = s .. '<' .. l .. '>', n + 1 end return s, n";
+    assert_eq!(run(&mut vm, source), ["<one><two><><four>", "4"]);
+
+    let source = "
+        local f = io.open('l.txt')
+        local first = f:read()
+        local rest = {}
+        for l in f:lines() do rest[#rest + 1] = l end
+        return first, #rest, io.type(f), f:close(), io.type(f)
+    ";
+    assert_eq!(run(&mut vm, source), ["one", "3", "file", "true", "closed file"]);
+    assert_eq!(
+        run(&mut vm, "return pcall(io.lines, 'missing')"),
+        ["false", "missing: No such file or directory"]
+    );
+    host.set_stdin("x\ny\n");
+    assert_eq!(run(&mut vm, "local n = 0 for l in io.lines() do n = n + #l end return n"), ["2"]);
+}
+
+#[test]
+fn seek_and_close() {
+    let (mut vm, host) = vm();
+    host.set_file("s", "0123456789");
+    let source = "
+        local f = io.open('s', 'r+')
+        local size = f:seek('end')
+        local start = f:seek('set', 2)
+        local two = f:read(3)
+        local here = f:seek()
+        local back = f:seek('cur', -1)
+        local again = f:read(1)
+        f:seek('set', 8)
+        f:write('xy')
+        return size, start, two, here, back, again, f:seek('cur')
+    ";
+    assert_eq!(run(&mut vm, source), ["10", "2", "234", "5", "4", "4", "10"]);
+    assert_eq!(host.file("s").unwrap(), b"01234567xy");
+    assert_eq!(run(&mut vm, "return io.open('s'):seek('set', -1)"), ["0"]);
+    assert_eq!(run(&mut vm, "return io.open('s'):seek('cur', -1)"), ["nil", "Invalid argument", "22"]);
+    assert_eq!(
+        run(&mut vm, "return pcall(io.open('s').seek, io.open('s'), 'top')"),
+        ["false", "bad argument #2 to 'seek' (invalid option 'top')"]
+    );
+
+    let source = "
+        local f = io.open('s')
+        f:close()
+        return tostring(f), pcall(f.read, f)
+    ";
+    assert_eq!(run(&mut vm, source), ["file (closed)", "false", "attempt to use a closed file"]);
+    assert_eq!(run(&mut vm, "return io.stdout:close()"), ["nil", "cannot close standard file"]);
+    run(&mut vm, "io.write('to ', 'stdout ', 1)");
+    assert_eq!(host.stdout_contents(), b"to stdout 1");
+}
+
+#[test]
+fn dates() {
+    let (mut vm, host) = vm();
+    host.set_time(LEAP_DAY);
+    assert_eq!(run(&mut vm, "return os.time()"), ["1709210096"]);
+    assert_eq!(run(&mut vm, "return os.date('!%Y-%m-%d %H:%M:%S %A %j')"), ["2024-02-29 12:34:56 Thursday 060"]);
+    assert_eq!(run(&mut vm, "return os.date('!%c')"), ["Thu Feb 29 12:34:56 2024"]);
+    assert_eq!(run(&mut vm, "return os.date('!%x %X %p %%', 0)"), ["01/01/70 00:00:00 AM %"]);
+
+    // local time is UTC+2 here
+    host.set_utc_offset(7200);
+    assert_eq!(run(&mut vm, "return os.date('%H:%M %z')"), ["14:34 +0200"]);
+    let source = "local t = os.date('*t') return t.year, t.month, t.day, t.hour, t.wday, t.yday, t.isdst";
+    assert_eq!(run(&mut vm, source), ["2024", "2", "29", "14", "5", "60", "false"]);
+    assert_eq!(run(&mut vm, "return os.time(os.date('*t')) == os.time()"), ["true"]);
+    // fields out of range carry over, as with mktime
+    let source = "return os.time{year = 2024, month = 2, day = 30, hour = 14, min = 34, sec = 56} - os.time()";
+    assert_eq!(run(&mut vm, source), ["86400"]);
+    assert_eq!(run(&mut vm, "return os.time{year = 2024, month = 13, day = 1, hour = 0}"), ["1735682400"]);
+    assert_eq!(
+        run(&mut vm, "return pcall(os.time, {year = 2024})"),
+        ["false", "field 'day' missing in date table"]
+    );
+
+    assert_eq!(run(&mut vm, "return os.difftime(os.time(), os.time() - 90), os.difftime(5)"), ["90", "5"]);
+}
@@ -0,0 +1,89 @@
+//! Checks the lexer's string tokens byte for byte, and its error messages
+//! against those of Lua 5.1's `luac`.
+
+use luatest::compiler::lexer::{Lexer, Token};
+
+fn tokens(src: &[u8]) -> Vec<Token> {
+    let mut lexer = Lexer::new(src, "lex");
+    let mut out = Vec::new();
+    loop {
+        match lexer.next_token().unwrap() {
+            (Token::Eof, _) => return out,
+            (token, _) => out.push(token),
+        }
+    }
+}
+fn string(src: &[u8]) -> Vec<u8> {
+    match &tokens(src)[..] {
+        [Token::String(s)] => s.clone(),
+        other => panic!("expected one string, got {:?}", other),
+    }
+}
+fn error(src: &[u8]) -> String {
+    let mut lexer = Lexer::new(src, "lex");
+    loop {
+        match lexer.next_token() {
+            Ok((Token::Eof, _)) => panic!("no error lexing {:?}", String::from_utf8_lossy(src)),
+            Ok(_) => (),
+            Err(e) => return e.to_string(),
+        }
+    }
+}
+
+#[test]
+fn decimal_escapes() {
+    assert_eq!(string(br#""\0""#), b"\0");
+    assert_eq!(string(br#""\255""#), b"\xff");
+    assert_eq!(string(br#""\65\066\0067""#), b"AB\x067");
+    // at most three digits are read
+    assert_eq!(string(br#""\1234""#), b"{4");
+    assert_eq!(string(br#""a\0b\255c""#), b"a\0b\xffc");
+    assert_eq!(string(b"'\xff\x80'"), b"\xff\x80");
+}
+
+#[test]
+fn other_escapes() {
+    assert_eq!(string(br#""\a\b\f\n\r\t\v""#), b"\x07\x08\x0c\n\r\t\x0b");
+    assert_eq!(string(br#""\\\"\'\q""#), b"\\\"'q");
+    assert_eq!(string(b"'a\\\nb'"), b"a\nb");
+    assert_eq!(string(b"'a\\\r\nb'"), b"a\nb");
+}
+
+#[test]
+fn long_strings() {
+    assert_eq!(string(b"[[abc]]"), b"abc");
+    // a first newline is dropped, and every line break becomes `\n`
+    assert_eq!(string(b"[[\nx\r\ny\rz]]"), b"x\ny\nz");
+    assert_eq!(string(b"[[\\n\\0]]"), b"\\n\\0");
+    assert_eq!(string(b"[==[a]]b]=]c]==]"), b"a]]b]=]c");
+    assert_eq!(string(b"[=[ [[x]] ]=]"), b" [[x]] ");
+    assert_eq!(string(b"[=[\xff\0]=]"), b"\xff\0");
+    assert_eq!(tokens(b"x --[==[ a\n]] b ]==] y"), [Token::Name("x".into()), Token::Name("y".into())]);
+    assert_eq!(tokens(b"a[b]"), [Token::Name("a".into()), Token::Sym("["), Token::Name("b".into()), Token::Sym("]")]);
+}
+
+#[test]
+fn lexical_errors() {
+    assert_eq!(error(br#"x = "\256""#), r#"lex:1: escape sequence too large near '"\256'"#);
+    assert_eq!(error(b"x = 'abc"), "lex:1: unfinished string near '<eof>'");
+    assert_eq!(error(b"x = 'abc\ny'"), "lex:1: unfinished string near ''abc'");
+    assert_eq!(error(b"x = [[abc"), "lex:1: unfinished long string near '<eof>'");
+    assert_eq!(error(b"--[==[ abc ]=]"), "lex:1: unfinished long comment near '<eof>'");
+    assert_eq!(error(b"x = [[a [[b]] ]]"), "lex:1: nesting of [[...]] is deprecated near '[['");
+    assert_eq!(error(b"x = [==x"), "lex:1: invalid long string delimiter near '[=='");
+    assert_eq!(error(b"x = 3x"), "lex:1: malformed number near '3x'");
+    assert_eq!(error(b"\n\nx = 1.2.3"), "lex:3: malformed number near '1.2.3'");
+    assert_eq!(error(b"x = 0xA.5"), "lex:1: malformed number near '0xA.5'");
+    assert_eq!(error(b"x = 1e"), "lex:1: malformed number near '1e'");
+    assert_eq!(error(b"x = 1e+"), "lex:1: malformed number near '1e+'");
+    assert_eq!(error(b"x = 3..2"), "lex:1: malformed number near '3..2'");
+    assert_eq!(error(b"x = 0x"), "lex:1: malformed number near '0x'");
+}
+
+#[test]
+fn numerals() {
+    use Token::{Number, Sym};
+    assert_eq!(tokens(b"3 .5 1.5e-3 2E+2 0xFF"), [Number(3.0), Number(0.5), Number(0.0015), Number(200.0), Number(255.0)]);
+    // a sign after a hex `e` is an operator, and spaces part a number from `..`
+    assert_eq!(tokens(b"0x1e+5 3 ..2"), [Number(30.0), Sym("+"), Number(5.0), Number(3.0), Sym(".."), Number(2.0)]);
+}
@@ -0,0 +1,174 @@
+//! Instruction, call depth and deadline budgets, and the fixed bound on
+//! calls nested through Rust that keeps scripts from overflowing the host's
+//! stack.
+
+use std::{
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use luatest::{
+    compiler::compile,
+    vm::{
+        error::LuaError,
+        hostio::MemoryHostIo,
+        limits::{DEFAULT_MAX_CALL_DEPTH, MAX_C_CALLS},
+        GCLuaValue, LuaVM,
+    },
+};
+
+fn vm() -> LuaVM {
+    let mut vm = LuaVM::new();
+    vm.open_base(Rc::new(MemoryHostIo::new()));
+    vm.open_coroutine();
+    vm
+}
+fn eval(vm: &mut LuaVM, source: &str) -> anyhow::Result<Vec<GCLuaValue>> {
+    let chunk = compile(source.as_bytes(), "=limits").unwrap();
+    vm.process_chunk(chunk)
+}
+fn run(vm: &mut LuaVM, source: &str) -> Vec<String> {
+    eval(vm, source).unwrap().iter().map(|v| v.borrow().as_string(false)).collect()
+}
+/// Runs `f` with as much stack as a main thread gets, which nesting
+/// `MAX_C_CALLS` deep needs in debug builds; test threads get 2 MB.
+fn on_main_sized_stack(f: impl FnOnce() + Send + 'static) {
+    let thread = std::thread::Builder::new().stack_size(8 << 20).spawn(f).unwrap();
+    if let Err(panic) = thread.join() {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+#[test]
+fn lua_recursion_is_bounded_by_default() {
+    let mut vm = vm();
+    let source = "
+        local depth = 0
+        local function f() depth = depth + 1 return 1 + f() end
+        local ok, e = pcall(f)
+        return ok, e, depth
+    ";
+    let results = run(&mut vm, source);
+    assert_eq!(results[..2], ["false", "limits:3: stack overflow"]);
+    // the chunk is running too; natives such as pcall take no frame
+    assert_eq!(results[2], (DEFAULT_MAX_CALL_DEPTH - 1).to_string());
+    assert_eq!(run(&mut vm, "return 1"), ["1"]);
+}
+
+#[test]
+fn call_depth_can_be_set() {
+    let mut vm = vm();
+    vm.set_max_call_depth(Some(10));
+    let source = "local function f(n) if n == 0 then return 0 end return 1 + f(n - 1) end return pcall(f, 8), pcall(f, 9)";
+    assert_eq!(run(&mut vm, source), ["true", "false", "limits:1: stack overflow"]);
+    // tail calls reuse their frame
+    let source = "local function f(n) if n == 0 then return 'done' end return f(n - 1) end return f(1000)";
+    assert_eq!(run(&mut vm, source), ["done"]);
+    // frames of the coroutines waiting on others count too
+    let source = "
+        local function f(n) if n == 0 then return coroutine.yield(1) end return 1 + f(n - 1) end
+        local a, b = coroutine.resume(coroutine.create(f), 8)
+        local c, d = coroutine.resume(coroutine.create(f), 9)
+        return a, b, c, d
+    ";
+    assert_eq!(run(&mut vm, source), ["true", "1", "false", "limits:2: stack overflow"]);
+    vm.set_max_call_depth(None);
+    let source = "local function f(n) if n == 0 then return 0 end return 1 + f(n - 1) end return f(50000)";
+    assert_eq!(run(&mut vm, source), ["50000"]);
+}
+
+#[test]
+fn metamethod_recursion_stops_at_the_c_limit() {
+    on_main_sized_stack(metamethod_recursion);
+}
+fn metamethod_recursion() {
+    let mut vm = vm();
+    let source = "return pcall(function() return setmetatable({}, {__index = function(t, k) return t[k] end}).x end)";
+    assert_eq!(run(&mut vm, source), ["false", "limits:1: C stack overflow"]);
+    let source = "
+        local t = setmetatable({}, {__index = function(t, k) if k == 0 then return 0 end return t[k - 1] + 1 end})
+        return t[150]
+    ";
+    assert_eq!(run(&mut vm, source), ["150"]);
+    let source = "local t = setmetatable({}, {__newindex = function(t, k, v) t[k] = v end}) t.x = 1";
+    let e = eval(&mut vm, source).unwrap_err();
+    assert_eq!(e.to_string(), "limits:1: C stack overflow");
+    // Lua code calling through `__call` takes Lua frames, not Rust stack
+    let source = "local t = setmetatable({}, {__call = function(t) return 1 + t() end}) return pcall(t)";
+    assert_eq!(run(&mut vm, source), ["false", "limits:1: stack overflow"]);
+}
+
+#[test]
+fn pcall_recursion_stops_at_the_c_limit() {
+    on_main_sized_stack(pcall_recursion);
+}
+fn pcall_recursion() {
+    let mut vm = vm();
+    let source = "
+        local depth = 0
+        local function f() depth = depth + 1 return pcall(f) end
+        local results = {f()}
+        return depth, results[#results - 1], results[#results]
+    ";
+    let results = run(&mut vm, source);
+    assert_eq!(results[1..], ["false", "C stack overflow"]);
+    // each pcall that got to call f took one
+    assert_eq!(results[0], (MAX_C_CALLS + 1).to_string());
+    let source = "local function h() return coroutine.resume(coroutine.create(h)) end local r = {h()} return r[#r]";
+    assert_eq!(run(&mut vm, source), ["C stack overflow"]);
+    let source = "local function w() return coroutine.wrap(w)() end return pcall(w)";
+    assert_eq!(run(&mut vm, source), ["false", "limits:1: C stack overflow"]);
+    // the count is given back on the way out
+    assert_eq!(run(&mut vm, "local function f(n) if n == 0 then return 'ok' end return select(2, pcall(f, n - 1)) end return f(150)"), ["ok"]);
+}
+
+#[test]
+fn fuel_runs_out_and_can_be_topped_up() {
+    let mut vm = vm();
+    vm.set_fuel(Some(1000));
+    let e = eval(&mut vm, "local n = 0 while true do n = n + 1 end").unwrap_err();
+    assert_eq!(e.downcast_ref::<LuaError>(), Some(&LuaError::OutOfFuel));
+    assert_eq!(vm.fuel(), Some(0));
+    // the run is kept and picks up where it stopped
+    let source = "local n = 0 for i = 1, 1000 do n = n + i end return n";
+    vm.abandon_run();
+    vm.set_fuel(Some(100));
+    assert!(eval(&mut vm, source).is_err());
+    assert!(vm.is_suspended());
+    vm.add_fuel(100_000);
+    let results = vm.resume_run().unwrap();
+    assert_eq!(results[0].borrow().as_string(false), "500500");
+    assert!(!vm.is_suspended());
+    assert!(vm.resume_run().is_err());
+
+    vm.set_fuel(None);
+    vm.add_fuel(10);
+    assert_eq!(vm.fuel(), None);
+    assert_eq!(run(&mut vm, source), ["500500"]);
+}
+
+#[test]
+fn fuel_cannot_be_caught() {
+    let mut vm = vm();
+    vm.set_fuel(Some(10_000));
+    let e = eval(&mut vm, "return pcall(function() while true do end end)").unwrap_err();
+    assert_eq!(e.downcast_ref::<LuaError>(), Some(&LuaError::OutOfFuel));
+    // runs stopped inside a coroutine cannot be resumed
+    vm.set_fuel(Some(10_000));
+    let e = eval(&mut vm, "return coroutine.resume(coroutine.create(function() while true do end end))").unwrap_err();
+    assert_eq!(e.downcast_ref::<LuaError>(), Some(&LuaError::OutOfFuel));
+    vm.abandon_run();
+    vm.set_fuel(None);
+    assert_eq!(run(&mut vm, "return 'still usable'"), ["still usable"]);
+}
+
+#[test]
+fn deadline_stops_a_loop() {
+    let mut vm = vm();
+    vm.set_deadline(Some(Instant::now() + Duration::from_millis(20)));
+    let e = eval(&mut vm, "while true do end").unwrap_err();
+    assert_eq!(e.downcast_ref::<LuaError>(), Some(&LuaError::DeadlineExceeded));
+    vm.abandon_run();
+    vm.set_deadline(None);
+    assert_eq!(run(&mut vm, "return 1 + 1"), ["2"]);
+}
@@ -0,0 +1,75 @@
+//! Memory accounting: the limit, the peak and `collectgarbage("count")`.
+
+use std::rc::Rc;
+
+use luatest::{
+    compiler::compile,
+    vm::{error::LuaError, hostio::MemoryHostIo, GCLuaValue, LuaVM},
+};
+
+fn vm() -> LuaVM {
+    let mut vm = LuaVM::new();
+    vm.open_base(Rc::new(MemoryHostIo::new()));
+    vm
+}
+fn eval(vm: &mut LuaVM, source: &str) -> anyhow::Result<Vec<GCLuaValue>> {
+    let chunk = compile(source.as_bytes(), "=memory").unwrap();
+    vm.process_chunk(chunk)
+}
+fn run(vm: &mut LuaVM, source: &str) -> Vec<String> {
+    eval(vm, source).unwrap().iter().map(|v| v.borrow().as_string(false)).collect()
+}
+fn number(vm: &mut LuaVM, source: &str) -> f64 {
+    run(vm, source)[0].parse().unwrap()
+}
+
+#[test]
+fn limit_raises_not_enough_memory() {
+    let mut vm = vm();
+    vm.set_memory_limit(Some(128 * 1024));
+    let e = eval(&mut vm, "keep = {} for i = 1, 1e6 do keep[i] = {i} end").unwrap_err();
+    assert_eq!(e.downcast_ref::<LuaError>(), Some(&LuaError::OutOfMemory));
+    assert_eq!(e.to_string(), "not enough memory");
+    // what the script holds still counts, so the next run fails too
+    assert!(eval(&mut vm, "local t = {} for i = 1, 1e6 do t[i] = {} end").is_err());
+    run(&mut vm, "keep = nil");
+    vm.collect_garbage();
+    assert!(vm.memory_used() <= 128 * 1024);
+    assert_eq!(run(&mut vm, "local t = {} for i = 1, 100 do t[i] = {} end return #t"), ["100"]);
+    // garbage alone does not trip the limit
+    assert_eq!(run(&mut vm, "for i = 1, 1e5 do local t = {i, i} end return 'done'"), ["done"]);
+}
+
+#[test]
+fn peak_only_grows() {
+    let mut vm = vm();
+    let start = vm.memory_peak();
+    run(&mut vm, "big = {} for i = 1, 2000 do big[i] = {} end");
+    let grown = vm.memory_peak();
+    assert!(grown > start + 2000 * 32, "{} -> {}", start, grown);
+    assert!(vm.memory_used() <= grown);
+    run(&mut vm, "big = nil");
+    let high = vm.memory_peak();
+    assert!(high >= grown);
+    let after = vm.collect_garbage();
+    assert!(after < high);
+    assert_eq!(vm.memory_peak(), high);
+    run(&mut vm, "local small = {1, 2, 3}");
+    assert_eq!(vm.memory_peak(), high);
+}
+
+#[test]
+fn count_drops_after_a_collect() {
+    let mut vm = vm();
+    let base = number(&mut vm, "return collectgarbage('count')");
+    let full = number(&mut vm, "data = {} for i = 1, 1000 do data[i] = {i} end return collectgarbage('count')");
+    assert!(full > base + 50.0, "{} -> {}", base, full);
+    let freed = number(&mut vm, "data = nil collectgarbage() return collectgarbage('count')");
+    assert!(freed < full / 4.0, "{} -> {}", full, freed);
+    assert!(freed >= base * 0.5);
+    assert_eq!(run(&mut vm, "return collectgarbage('step'), collectgarbage('collect')"), ["true", "0"]);
+    assert_eq!(
+        run(&mut vm, "return pcall(collectgarbage, 'bogus')"),
+        ["false", "bad argument #1 to 'collectgarbage' (invalid option 'bogus')"]
+    );
+}
@@ -0,0 +1,230 @@
+//! Runs bytecode before and after each optimization pass and compares the
+//! results, and checks what the passes remove on hand-written code.
+
+use std::fs;
+
+use gc::Gc;
+use luatest::{
+    compiler::compile,
+    vm::{
+        chunk_parser::{ChunkHeader, FunctionBlock, LuaChunk, LuaConstant},
+        instruction::{VMInst, VMOpcode},
+        listing::listing,
+        optimizer::{optimize, optimize_chunk, Passes},
+        LuaVM,
+    },
+};
+
+const K: u32 = 256;
+
+fn abc(op: VMOpcode, a: u32, b: u32, c: u32) -> u32 {
+    op.to_num() | (a << 6) | (b << 23) | (c << 14)
+}
+fn abx(op: VMOpcode, a: u32, bx: u32) -> u32 {
+    op.to_num() | (a << 6) | (bx << 14)
+}
+fn jmp(sbx: i32) -> u32 {
+    abx(VMOpcode::JMP, 0, (sbx + 131071) as u32)
+}
+fn num(n: f64) -> LuaConstant {
+    LuaConstant::LUA_TNUMBER(n)
+}
+
+fn function(code: &[u32], constants: Vec<LuaConstant>) -> FunctionBlock {
+    FunctionBlock {
+        source_name: "=test".to_string(),
+        line_def: 0,
+        last_line_def: 0,
+        num_upval: 0,
+        num_param: 0,
+        is_vararg: 2,
+        max_stack_size: 4,
+        list_instructions: code.iter().map(|i| VMInst::from_u32(*i).unwrap()).collect(),
+        list_const: constants.into_iter().map(Gc::new).collect(),
+        list_fnproto: Vec::new(),
+        line_info: (1..=code.len() as u32).collect(),
+        local_vars: Vec::new(),
+        upvalue_names: Vec::new(),
+    }
+}
+
+fn run(func: &FunctionBlock) -> Vec<String> {
+    let chunk = LuaChunk { header: ChunkHeader::default(), func: func.clone() };
+    let results = LuaVM::new().process_chunk(chunk).unwrap();
+    results.iter().map(|v| format!("{:?}", v.borrow())).collect()
+}
+
+fn ops(func: &FunctionBlock) -> Vec<VMOpcode> {
+    func.list_instructions.iter().map(|i| i.opcode.clone()).collect()
+}
+
+/// Optimizes with only the passes `enable` turns on, checking the result
+/// still computes the same values.
+fn check(func: &FunctionBlock, enable: fn(&mut Passes)) -> FunctionBlock {
+    let mut passes = Passes::none();
+    enable(&mut passes);
+    let optimized = optimize(func, &passes).unwrap();
+    assert_eq!(run(&optimized), run(func), "{}", listing(&optimized));
+    optimized
+}
+
+#[test]
+fn folds_constant_arithmetic() {
+    let func = function(
+        &[
+            abc(VMOpcode::ADD, 0, K, K | 1),
+            abc(VMOpcode::DIV, 1, K, K | 2),
+            abc(VMOpcode::POW, 2, K | 3, K | 4),
+            abc(VMOpcode::RETURN, 0, 4, 0),
+            abc(VMOpcode::RETURN, 0, 1, 0),
+        ],
+        vec![num(2.0), num(3.0), num(0.0), num(-8.0), num(0.5)],
+    );
+    let folded = check(&func, |p| p.fold_constants = true);
+    // 2 / 0 and a NaN result are left for run time
+    assert_eq!(ops(&folded), [VMOpcode::LOADK, VMOpcode::DIV, VMOpcode::POW, VMOpcode::RETURN, VMOpcode::RETURN]);
+    let compacted = check(&func, |p| {
+        p.fold_constants = true;
+        p.dedup_constants = true;
+    });
+    assert_eq!(compacted.list_const.len(), 5);
+}
+
+#[test]
+fn collapses_jumps_and_removes_unreachable_code() {
+    let func = function(
+        &[
+            jmp(1),
+            abx(VMOpcode::LOADK, 0, 0),
+            jmp(1),
+            abx(VMOpcode::LOADK, 0, 1),
+            abx(VMOpcode::LOADK, 0, 2),
+            abc(VMOpcode::RETURN, 0, 2, 0),
+            abc(VMOpcode::RETURN, 0, 1, 0),
+        ],
+        vec![num(1.0), num(2.0), num(3.0)],
+    );
+    let collapsed = check(&func, |p| p.collapse_jumps = true);
+    assert!(listing(&collapsed).contains("JMP      \t3\t; to 5"));
+    let reduced = check(&func, |p| {
+        p.collapse_jumps = true;
+        p.remove_unreachable = true;
+    });
+    // the first jump now lands on the next instruction, so goes too
+    assert_eq!(ops(&reduced), [VMOpcode::LOADK, VMOpcode::RETURN, VMOpcode::RETURN]);
+    assert_eq!(reduced.line_info, [5, 6, 7]);
+}
+
+#[test]
+fn removes_redundant_moves() {
+    let func = function(
+        &[
+            abx(VMOpcode::LOADK, 0, 0),
+            abc(VMOpcode::MOVE, 0, 0, 0),
+            abc(VMOpcode::MOVE, 1, 0, 0),
+            abc(VMOpcode::MOVE, 0, 1, 0),
+            // skipped by the LOADBOOL, so it has to stay
+            abc(VMOpcode::LOADBOOL, 2, 1, 1),
+            abc(VMOpcode::MOVE, 2, 2, 0),
+            abc(VMOpcode::RETURN, 0, 4, 0),
+            abc(VMOpcode::RETURN, 0, 1, 0),
+        ],
+        vec![num(1.0)],
+    );
+    let reduced = check(&func, |p| p.remove_moves = true);
+    assert_eq!(
+        ops(&reduced),
+        [VMOpcode::LOADK, VMOpcode::MOVE, VMOpcode::LOADBOOL, VMOpcode::MOVE, VMOpcode::RETURN, VMOpcode::RETURN]
+    );
+}
+
+#[test]
+fn removes_loadnils_of_untouched_registers() {
+    let func = function(
+        &[
+            abx(VMOpcode::LOADK, 0, 0),
+            abc(VMOpcode::LOADNIL, 1, 2, 0),
+            abc(VMOpcode::LOADNIL, 0, 1, 0),
+            abc(VMOpcode::RETURN, 0, 4, 0),
+            abc(VMOpcode::RETURN, 0, 1, 0),
+        ],
+        vec![num(1.0)],
+    );
+    let reduced = check(&func, |p| p.remove_loadnils = true);
+    assert_eq!(ops(&reduced), [VMOpcode::LOADK, VMOpcode::LOADNIL, VMOpcode::RETURN, VMOpcode::RETURN]);
+}
+
+#[test]
+fn deduplicates_constants() {
+    let s = |s: &str| LuaConstant::LUA_TSTRING(s.into());
+    let func = function(
+        &[
+            abx(VMOpcode::LOADK, 0, 0),
+            abx(VMOpcode::LOADK, 1, 1),
+            abc(VMOpcode::ADD, 2, K | 2, K | 3),
+            abc(VMOpcode::DIV, 3, K | 2, K | 5),
+            abc(VMOpcode::RETURN, 0, 5, 0),
+            abc(VMOpcode::RETURN, 0, 1, 0),
+        ],
+        vec![s("a"), s("a"), num(1.0), num(1.0), s("unused"), num(-0.0)],
+    );
+    let reduced = check(&func, |p| p.dedup_constants = true);
+    let constants: Vec<String> = reduced.list_const.iter().map(|c| format!("{:?}", **c)).collect();
+    assert_eq!(constants, ["LUA_TSTRING(\"a\")", "LUA_TNUMBER(1.0)", "LUA_TNUMBER(-0.0)"]);
+}
+
+#[test]
+fn compiled_corpus_keeps_its_results() {
+    let all: [fn(&mut Passes); 7] = [
+        |p| p.fold_constants = true,
+        |p| p.collapse_jumps = true,
+        |p| p.remove_unreachable = true,
+        |p| p.remove_moves = true,
+        |p| p.remove_loadnils = true,
+        |p| p.dedup_constants = true,
+        |p| *p = Passes::default(),
+    ];
+    for name in ["arith.lua", "control.lua", "closures.lua", "flow.lua"] {
+        let source = fs::read(format!("tests/compiler/{}", name)).unwrap();
+        let chunk = compile(&source, &format!("@{}", name)).unwrap();
+        let expected = run(&chunk.func);
+        for enable in all {
+            let mut passes = Passes::none();
+            enable(&mut passes);
+            let optimized = optimize_chunk(&chunk, &passes).unwrap();
+            // what gets run is the re-encoded bytecode
+            let bytes = optimized.to_bytes().unwrap();
+            let read = LuaChunk::from_reader(&mut &bytes[..]).unwrap();
+            assert_eq!(run(&read.func), expected, "{} with {:?}", name, passes);
+        }
+    }
+}
+
+#[test]
+fn removes_code_after_return() {
+    let source = fs::read("tests/compiler/flow.lua").unwrap();
+    let chunk = compile(&source, "@flow.lua").unwrap();
+    let optimized = optimize_chunk(&chunk, &Passes::default()).unwrap();
+    let pick = &optimized.func.list_fnproto[0];
+    // the jumps out of each branch follow a RETURN
+    assert_eq!(pick.list_instructions.len(), chunk.func.list_fnproto[0].list_instructions.len() - 2);
+}
+
+#[test]
+fn keeps_loadnils_of_parameters() {
+    // parameters, and the `arg` table of a 5.0-style vararg function, hold
+    // values on entry
+    let source = "local function f(a) a = nil return a end
+local function g(...) arg = nil return arg end
+return f(5), g(1, 2)";
+    let chunk = compile(source.as_bytes(), "=nil").unwrap();
+    let expected = run(&chunk.func);
+    assert_eq!(expected, ["Nil", "Nil"]);
+    let mut passes = Passes::none();
+    passes.remove_loadnils = true;
+    for passes in [passes, Passes::default()] {
+        let optimized = optimize_chunk(&chunk, &passes).unwrap();
+        assert_eq!(run(&optimized.func), expected, "{}", listing(&optimized.func.list_fnproto[0]));
+        assert!(ops(&optimized.func.list_fnproto[0]).contains(&VMOpcode::LOADNIL));
+    }
+}
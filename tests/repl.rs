@@ -0,0 +1,52 @@
+//! `luatest repl` fed from a pipe: prompts, echoed values, continuation
+//! lines, `:dis` and errors.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// What the REPL writes to stdout and stderr given `input`.
+fn repl(input: &str) -> (String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_luatest"))
+        .arg("repl")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn expressions_are_echoed() {
+    let (out, err) = repl("x = 21\n=x * 2\n=1, 'two', nil, true\nprint('printed')\n=nil\n");
+    assert_eq!(out, "> > 42\n> 1\t\"two\"\tnil\ttrue\n> printed\n> nil\n> \n");
+    assert_eq!(err, "");
+}
+
+#[test]
+fn unfinished_input_continues() {
+    let (out, err) = repl("function f(a)\n  local b = a + 1\n  return b\nend\n=f(\n41)\nt = {\n'a',\n'b'}\n=#t\n");
+    assert_eq!(out, "> >> >> >> > >> 42\n> >> >> > 2\n> \n");
+    assert_eq!(err, "");
+    // end of input in the middle of a chunk ends the session
+    let (out, _) = repl("for i = 1, 3 do\n");
+    assert_eq!(out, "> >> ");
+}
+
+#[test]
+fn disassembly_and_errors() {
+    let (out, err) = repl(":dis\n=1, 's'\n:dis\nerror('boom')\nlocal = 1\n=1 +\n2\n");
+    let listing = "
+main <stdin:0,0> (4 instructions, 16 bytes at 0x";
+    assert!(out.starts_with("> nothing entered yet\n> 1\t\"s\"\n> "), "{}", out);
+    assert!(out.contains(listing), "{}", out);
+    assert!(out.contains("\t1\t[1]\tLOADK    \t0 -1\t; 1\n\t2\t[1]\tLOADK    \t1 -2\t; \"s\"\n"), "{}", out);
+    assert!(out.ends_with("> > > >> 3\n> \n"), "{}", out);
+    // errors go to stderr and the session goes on
+    assert_eq!(err, "stdin:1: boom\nstack traceback:\n\tstdin:1: in main chunk\nstdin:1: '<name>' expected near '='\n");
+}
@@ -0,0 +1,164 @@
+//! Runs the classic ways out of a Lua 5.1 sandbox against `LuaVM::sandboxed`
+//! and checks that each is closed.
+
+use std::rc::Rc;
+
+use luatest::{
+    compiler::compile,
+    vm::{
+        error::LuaError,
+        hostio::MemoryHostIo,
+        sandbox::Sandbox,
+        LuaNativeFunction, LuaVM, LuaValue,
+    },
+};
+
+fn sandbox() -> (LuaVM, Rc<MemoryHostIo>) {
+    let host = Rc::new(MemoryHostIo::new());
+    (LuaVM::sandboxed(host.clone()), host)
+}
+fn run(vm: &mut LuaVM, source: &str) -> Vec<String> {
+    let chunk = compile(source.as_bytes(), "=sandbox").unwrap();
+    let results = vm.process_chunk(chunk).unwrap();
+    results.iter().map(|v| v.borrow().as_string(false)).collect()
+}
+
+#[test]
+fn only_safe_functions_are_there() {
+    let (mut vm, _) = sandbox();
+    let missing = [
+        "io", "debug", "os.execute", "os.exit", "os.getenv", "os.remove", "os.rename", "os.tmpname", "require",
+        "package", "module", "dofile", "loadfile", "load", "getfenv", "setfenv", "collectgarbage", "newproxy",
+        "string",
+    ];
+    for name in missing {
+        let source = format!("return {}", name);
+        assert_eq!(run(&mut vm, &source), ["nil"], "{} should be missing", name);
+    }
+    let present = ["pairs", "pcall", "loadstring", "coroutine.wrap", "os.time", "os.clock", "_G"];
+    for name in present {
+        let source = format!("return type({})", name);
+        assert_ne!(run(&mut vm, &source), ["nil"], "{} should be there", name);
+    }
+    assert_eq!(run(&mut vm, "local n = 0 for _ in pairs(os) do n = n + 1 end return n"), ["4"]);
+}
+
+#[test]
+fn bytecode_cannot_be_loaded() {
+    let (mut vm, _) = sandbox();
+    let bytes = compile(b"return 1", "=b").unwrap().to_bytes().unwrap();
+    vm.set_global("bytecode", LuaValue::String(bytes.into()).to_gc());
+    assert_eq!(run(&mut vm, "return loadstring(bytecode)"), ["nil", "attempt to load a binary chunk"]);
+    assert_eq!(run(&mut vm, r#"return loadstring("\27Lua\81\0\1\4\4\4\8\0")"#), ["nil", "attempt to load a binary chunk"]);
+    // source text still loads
+    assert_eq!(run(&mut vm, "return loadstring('return 1 + 1')()"), ["2"]);
+}
+
+#[test]
+fn loaded_code_stays_inside() {
+    let (mut vm, _) = sandbox();
+    assert_eq!(run(&mut vm, "return loadstring('return io, debug, os.execute')()"), ["nil", "nil", "nil"]);
+    assert_eq!(run(&mut vm, "return loadstring('return _G')() == _G"), ["true"]);
+    assert_eq!(
+        run(&mut vm, "return loadstring('x =', '=chunk')"),
+        ["nil", "chunk:1: unexpected symbol near '<eof>'"]
+    );
+}
+
+#[test]
+fn string_metatable_is_protected() {
+    let (mut vm, _) = sandbox();
+    assert_eq!(run(&mut vm, "return getmetatable('')"), ["false"]);
+    assert_eq!(
+        run(&mut vm, "return pcall(function() getmetatable('').__index = {} end)"),
+        ["false", "sandbox:1: attempt to index a boolean value"]
+    );
+    assert_eq!(
+        run(&mut vm, "return pcall(setmetatable, '', {})"),
+        ["false", "bad argument #1 to 'setmetatable' (table expected, got string)"]
+    );
+    let mt = vm.get_metatable(&LuaValue::String("".into()).to_gc()).unwrap();
+    assert!(mt.borrow().get_str("__index").is_none());
+}
+
+#[test]
+fn protected_metatables_stay_put() {
+    let (mut vm, _) = sandbox();
+    let source = "
+        local t = setmetatable({}, {__metatable = 'locked'})
+        local ok, e = pcall(setmetatable, t, {})
+        return getmetatable(t), ok, e
+    ";
+    assert_eq!(run(&mut vm, source), ["locked", "false", "cannot change a protected metatable"]);
+}
+
+#[test]
+fn fuel_cannot_be_caught() {
+    let (mut vm, _) = sandbox();
+    vm.set_fuel(Some(10_000));
+    let chunk = compile(b"return pcall(function() while true do end end)", "=sandbox").unwrap();
+    let e = vm.process_chunk(chunk).unwrap_err();
+    assert_eq!(e.downcast_ref::<LuaError>(), Some(&LuaError::OutOfFuel));
+}
+
+#[test]
+fn memory_errors_can_be_caught() {
+    // as in Lua: the script gets "not enough memory" back and, once what
+    // it held is collected, can go on
+    let (mut vm, _) = sandbox();
+    vm.set_memory_limit(Some(256 * 1024));
+    let source = "
+        local ok, e = pcall(function() local t = {} for i = 1, 1e7 do t[i] = {} end end)
+        return ok, e
+    ";
+    assert_eq!(run(&mut vm, source), ["false", "not enough memory"]);
+    assert_eq!(run(&mut vm, "local t = {} for i = 1, 100 do t[i] = {} end return #t"), ["100"]);
+}
+
+/// Runs `f` with as much stack as a main thread gets, which nesting
+/// `MAX_C_CALLS` deep needs in debug builds; test threads get 2 MB.
+fn on_main_sized_stack(f: impl FnOnce() + Send + 'static) {
+    let thread = std::thread::Builder::new().stack_size(8 << 20).spawn(f).unwrap();
+    if let Err(panic) = thread.join() {
+        std::panic::resume_unwind(panic);
+    }
+}
+
+#[test]
+fn recursion_cannot_overflow_the_host_stack() {
+    on_main_sized_stack(|| {
+        let (mut vm, _) = sandbox();
+        let source = "return pcall(function() return setmetatable({}, {__index = function(t, k) return t[k] end}).x end)";
+        assert_eq!(run(&mut vm, source), ["false", "sandbox:1: C stack overflow"]);
+        let source = "local function f() return pcall(f) end local r = {f()} return r[#r - 1], r[#r]";
+        assert_eq!(run(&mut vm, source), ["false", "C stack overflow"]);
+        let source = "local t = setmetatable({}, {__lt = function(a, b) return a < b end}) return pcall(function() return t < t end)";
+        assert_eq!(run(&mut vm, source), ["false", "sandbox:1: C stack overflow"]);
+        let source = "local function h() return coroutine.resume(coroutine.create(h)) end local r = {h()} return r[#r]";
+        assert_eq!(run(&mut vm, source), ["C stack overflow"]);
+        let source = "local function f(n) return 1 + f(n + 1) end return pcall(f, 1)";
+        assert_eq!(run(&mut vm, source), ["false", "sandbox:1: stack overflow"]);
+        let source = "local function f(n) return 1 + loadstring('return ...')(f(n + 1)) end return pcall(f, 1)";
+        assert_eq!(run(&mut vm, source), ["false", "sandbox:1: stack overflow"]);
+        // the sandbox is still usable afterwards
+        assert_eq!(run(&mut vm, "return 1 + 1"), ["2"]);
+    });
+    let host = Rc::new(MemoryHostIo::new());
+    let mut vm = Sandbox::new().max_call_depth(50).build(host);
+    let source = "local function f(n) if n == 0 then return 0 end return 1 + f(n - 1) end return pcall(f, 40), pcall(f, 60)";
+    assert_eq!(run(&mut vm, source), ["true", "false", "sandbox:1: stack overflow"]);
+}
+
+#[test]
+fn host_picks_the_functions() {
+    let host = Rc::new(MemoryHostIo::new());
+    host.set_env("HOME", "/sandbox");
+    let greet = LuaNativeFunction::new("greet", |_, _| Ok(vec![LuaValue::String("hi".into()).to_gc()]));
+    let mut vm = Sandbox::new().allow("os.getenv").deny("coroutine").deny("pairs").function("host.greet", greet).build(host);
+    assert_eq!(run(&mut vm, "return os.getenv('HOME'), coroutine, pairs, host.greet()"), ["/sandbox", "nil", "nil", "hi"]);
+
+    vm.remove_function("os.time");
+    vm.remove_function("host.greet");
+    vm.add_function("shout", LuaNativeFunction::new("shout", |_, _| Ok(Vec::new())));
+    assert_eq!(run(&mut vm, "return os.time, host.greet, type(shout)"), ["nil", "nil", "function"]);
+}
@@ -0,0 +1,138 @@
+//! Serde round trips between Rust values and Lua tables, and the paths
+//! errors point at. Needs the `serde` feature.
+#![cfg(feature = "serde")]
+
+use std::collections::BTreeMap;
+
+use luatest::{
+    compiler::compile,
+    vm::{
+        serialize::{from_lua, from_lua_named, to_lua},
+        GCLuaValue, LuaVM,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Config {
+    name: String,
+    debug: bool,
+    ratio: f64,
+    servers: Vec<Server>,
+    tags: BTreeMap<String, u32>,
+    fallback: Option<Box<Server>>,
+    mode: Mode,
+}
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Server {
+    host: String,
+    port: u16,
+}
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+enum Mode {
+    Off,
+    Fixed(u8),
+    Range { low: i32, high: i32 },
+}
+
+fn eval(vm: &mut LuaVM, source: &str) -> GCLuaValue {
+    let chunk = compile(source.as_bytes(), "=serde").unwrap();
+    vm.process_chunk(chunk).unwrap().remove(0)
+}
+fn config() -> Config {
+    Config {
+        name: "main".to_string(),
+        debug: true,
+        ratio: 0.25,
+        servers: vec![
+            Server { host: "a".to_string(), port: 80 },
+            Server { host: "b".to_string(), port: 8080 },
+        ],
+        tags: [("x".to_string(), 1), ("y".to_string(), 2)].into_iter().collect(),
+        fallback: None,
+        mode: Mode::Range { low: -1, high: 9 },
+    }
+}
+
+#[test]
+fn nested_tables_round_trip() {
+    let mut vm = LuaVM::new();
+    let value = to_lua(&config()).unwrap();
+    assert_eq!(from_lua::<Config>(&value).unwrap(), config());
+    vm.set_global("config", value);
+    let source = "return config.name, config.servers[2].port, #config.servers, config.tags.y, config.fallback, config.mode.Range.low";
+    let chunk = compile(source.as_bytes(), "=serde").unwrap();
+    let results: Vec<String> = vm.process_chunk(chunk).unwrap().iter().map(|v| v.borrow().as_string(false)).collect();
+    assert_eq!(results, ["main", "8080", "2", "2", "nil", "-1"]);
+
+    // written by hand in Lua
+    let source = "
+        return {
+            name = 'lua', debug = false, ratio = 1.5,
+            servers = {{host = 'h', port = 1}},
+            tags = {},
+            fallback = {host = 'f', port = 2},
+            mode = {Fixed = 7},
+        }
+    ";
+    let config: Config = from_lua(&eval(&mut vm, source)).unwrap();
+    assert_eq!(config.servers, [Server { host: "h".to_string(), port: 1 }]);
+    assert_eq!(config.fallback, Some(Box::new(Server { host: "f".to_string(), port: 2 })));
+    assert_eq!(config.mode, Mode::Fixed(7));
+    assert!(config.tags.is_empty());
+    assert_eq!(from_lua::<Mode>(&eval(&mut vm, "return 'Off'")).unwrap(), Mode::Off);
+}
+
+#[test]
+fn arrays_and_maps() {
+    let mut vm = LuaVM::new();
+    assert_eq!(from_lua::<Vec<u8>>(&eval(&mut vm, "return {1, 2, 3}")).unwrap(), [1, 2, 3]);
+    assert_eq!(from_lua::<Vec<Option<u8>>>(&eval(&mut vm, "return {1, nil, 3}")).unwrap(), [Some(1), None, Some(3)]);
+    // an empty table reads as either
+    assert!(from_lua::<Vec<u8>>(&eval(&mut vm, "return {}")).unwrap().is_empty());
+    assert!(from_lua::<BTreeMap<String, u8>>(&eval(&mut vm, "return {}")).unwrap().is_empty());
+    let map: BTreeMap<u8, String> = from_lua(&eval(&mut vm, "return {[1] = 'a', [5] = 'e'}")).unwrap();
+    assert_eq!(map, [(1, "a".to_string()), (5, "e".to_string())].into_iter().collect());
+    assert_eq!(
+        from_lua::<Vec<u8>>(&eval(&mut vm, "return {1, 2, x = 3}")).unwrap_err().to_string(),
+        "expected array, got table with non-sequence keys"
+    );
+    assert_eq!(
+        from_lua::<Vec<u8>>(&eval(&mut vm, "return {[1] = 1, [10] = 2}")).unwrap_err().to_string(),
+        "expected array, got table with non-sequence keys"
+    );
+    // Rust sequences and tuples come out as arrays, maps as keyed tables
+    vm.set_global("list", to_lua(&vec![(1, "one"), (2, "two")]).unwrap());
+    vm.set_global("map", to_lua(&[("k", vec![true])].into_iter().collect::<BTreeMap<_, _>>()).unwrap());
+    let chunk = compile(b"return #list, list[2][2], #map, map.k[1]", "=serde").unwrap();
+    let results: Vec<String> = vm.process_chunk(chunk).unwrap().iter().map(|v| v.borrow().as_string(false)).collect();
+    assert_eq!(results, ["2", "two", "0", "true"]);
+}
+
+#[test]
+fn errors_name_the_offending_value() {
+    let mut vm = LuaVM::new();
+    let source = "
+        return {
+            name = 'x', debug = true, ratio = 0, tags = {}, mode = 'Off',
+            servers = {{host = 'a', port = 1}, {host = 'b', port = 2}, {host = 'c', port = 70000}},
+        }
+    ";
+    let value = eval(&mut vm, source);
+    let e = from_lua_named::<Config>(&value, "config").unwrap_err();
+    assert_eq!(e.path.as_deref(), Some("config.servers[3].port"));
+    assert_eq!(e.to_string(), "config.servers[3].port: expected u16, got 70000");
+    assert_eq!(from_lua::<Config>(&value).unwrap_err().to_string(), "servers[3].port: expected u16, got 70000");
+
+    let value = eval(&mut vm, "return {name = 'x', debug = 1}");
+    assert_eq!(from_lua_named::<Config>(&value, "c").unwrap_err().to_string(), "c.debug: expected boolean, got number");
+    let value = eval(&mut vm, "return {host = 'h'}");
+    assert_eq!(from_lua_named::<Server>(&value, "s").unwrap_err().to_string(), "s: missing field `port`");
+    let value = eval(&mut vm, "return {['a b'] = {host = 'h', port = 'p'}}");
+    assert_eq!(
+        from_lua_named::<BTreeMap<String, Server>>(&value, "m").unwrap_err().to_string(),
+        "m[\"a b\"].port: expected number, got string"
+    );
+    let value = eval(&mut vm, "return {Bogus = 1}");
+    assert!(from_lua_named::<Mode>(&value, "mode").unwrap_err().to_string().starts_with("mode: unknown variant `Bogus`"));
+}
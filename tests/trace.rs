@@ -0,0 +1,200 @@
+//! Execution traces: the text and JSON sinks, a sink of our own, and
+//! `luatest run --trace`.
+
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    process::{Command, Stdio},
+    rc::Rc,
+};
+
+use luatest::{
+    compiler::compile,
+    vm::{
+        hostio::MemoryHostIo,
+        json::Json,
+        trace::{JsonSink, TextSink, TraceEvent, TraceSink},
+        LuaVM,
+    },
+};
+
+/// A writer whose contents can be read while a sink owns it.
+#[derive(Clone, Default)]
+struct Buffer(Rc<RefCell<Vec<u8>>>);
+impl Buffer {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn traced(source: &str, sink: Box<dyn TraceSink>) {
+    let mut vm = LuaVM::new();
+    vm.open_base(Rc::new(MemoryHostIo::new()));
+    vm.set_trace(Some(sink));
+    vm.process_chunk(compile(source.as_bytes(), "=t").unwrap()).unwrap();
+}
+
+const CALLS: &str = "function add(a, b) return a + b end\nlocal s = type(add(1, 2))\nreturn s";
+
+#[test]
+fn text_sink() {
+    let out = Buffer::default();
+    traced("local a = 1\nlocal b = a + 2\nreturn b", Box::new(TextSink::new(out.clone())));
+    assert_eq!(
+        out.text(),
+        "load =t (4 instructions)
+  call =t:0 with 0 args
+  [1] line 1  LOADK     0 -1 ; 1  | r0=nil r1=nil
+  constant 0 = 1
+  [2] line 2  ADD       1 0 -2 ; - 2  | r0=1 r1=nil
+  constant 1 = 2
+  [3] line 3  RETURN    1 2  | r0=1 r1=3
+  return 1 results
+"
+    );
+
+    // calls are indented by the depth of the frame they make
+    let out = Buffer::default();
+    traced(CALLS, Box::new(TextSink::new(out.clone())));
+    let text = out.text();
+    let calls: Vec<&str> =
+        text.lines().filter(|l| !l.trim_start().starts_with(['[', 'c']) || l.contains("call")).collect();
+    assert_eq!(
+        calls,
+        [
+            "load =t (10 instructions)",
+            "  call =t:0 with 0 args",
+            "    call =t:1 with 2 args",
+            "    return 1 results",
+            "  call native type with 1 args",
+            "  return 1 results"
+        ]
+    );
+    assert!(text.contains("\n    [1] line 1  ADD       2 0 1  | r0=1 r1=2 r2=nil\n"), "{}", text);
+}
+
+#[test]
+fn json_sink() {
+    let out = Buffer::default();
+    traced(CALLS, Box::new(JsonSink::new(out.clone())));
+    let events: Vec<Json> = out.text().lines().map(|l| Json::parse(l).unwrap()).collect();
+    let kinds: Vec<&str> = events.iter().map(|e| e.get("event").and_then(Json::as_str).unwrap()).collect();
+    assert_eq!(kinds[..3], ["load", "call", "instruction"]);
+    // all of `add`, and all of the main chunk but the RETURN ending it
+    assert_eq!(kinds.iter().filter(|k| **k == "instruction").count(), 2 + 9);
+    assert_eq!(
+        events[0],
+        Json::object([("event", "load".into()), ("source", "=t".into()), ("instructions", 10usize.into())])
+    );
+    let add = events.iter().find(|e| e.get("op").and_then(Json::as_str) == Some("ADD")).unwrap();
+    assert_eq!(
+        *add,
+        Json::object([
+            ("event", "instruction".into()),
+            ("function", "=t:1".into()),
+            ("pc", 1usize.into()),
+            ("line", 1usize.into()),
+            ("depth", 2usize.into()),
+            ("op", "ADD".into()),
+            ("text", "ADD       2 0 1".into()),
+            ("registers", vec!["1".into(), "2".into(), "nil".into()].into()),
+        ])
+    );
+    let native = events.iter().find(|e| e.get("event").and_then(Json::as_str) == Some("native_call")).unwrap();
+    assert_eq!(
+        *native,
+        Json::object([
+            ("event", "native_call".into()),
+            ("name", "type".into()),
+            ("depth", 1usize.into()),
+            ("args", 1usize.into())
+        ])
+    );
+    assert!(events.contains(&Json::object([
+        ("event", "constant".into()),
+        ("index", 1usize.into()),
+        ("depth", 1usize.into()),
+        ("value", "\"type\"".into())
+    ])));
+    assert_eq!(
+        events.last().unwrap(),
+        &Json::object([("event", "return".into()), ("depth", 1usize.into()), ("results", 1usize.into())])
+    );
+}
+
+/// Counts instructions per frame depth, and calls.
+#[derive(Default)]
+struct Counts {
+    instructions: Vec<usize>,
+    calls: usize,
+    returns: usize,
+}
+struct Counter(Rc<RefCell<Counts>>);
+impl TraceSink for Counter {
+    fn event(&mut self, event: &TraceEvent) {
+        let mut counts = self.0.borrow_mut();
+        match event {
+            TraceEvent::Instruction { depth, .. } => {
+                if counts.instructions.len() <= *depth {
+                    counts.instructions.resize(depth + 1, 0);
+                }
+                counts.instructions[*depth] += 1;
+            }
+            TraceEvent::Call { .. } | TraceEvent::NativeCall { .. } => counts.calls += 1,
+            TraceEvent::Return { .. } => counts.returns += 1,
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn sinks_of_our_own_and_turning_tracing_off() {
+    let counts = Rc::new(RefCell::new(Counts::default()));
+    let mut vm = LuaVM::new();
+    vm.set_trace(Some(Box::new(Counter(counts.clone()))));
+    let source = "local function f(n) if n > 0 then return f(n - 1) + 1 end return 0 end\nlocal r = f(3)\nreturn r";
+    vm.process_chunk(compile(source.as_bytes(), "=t").unwrap()).unwrap();
+    {
+        let counts = counts.borrow();
+        // the MOVE after the CLOSURE names an upvalue and is not run; f(0)
+        // takes the short way out
+        assert_eq!(counts.instructions, [0, 5, 6, 6, 6, 4]);
+        assert_eq!((counts.calls, counts.returns), (5, 5));
+    }
+    vm.set_trace(None);
+    vm.process_chunk(compile(source.as_bytes(), "=t").unwrap()).unwrap();
+    assert_eq!(counts.borrow().calls, 5);
+}
+
+#[test]
+fn run_with_trace() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_luatest"))
+        .args(["run", "--trace", "json", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"print(1 + 1)").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    // the script's output stays apart from the trace
+    assert_eq!(output.stdout, b"2\n");
+    let trace = String::from_utf8(output.stderr).unwrap();
+    let events: Vec<Json> = trace.lines().map(|l| Json::parse(l).unwrap()).collect();
+    assert_eq!(events[0].get("source").and_then(Json::as_str), Some("=stdin"));
+    assert!(events.iter().any(|e| e.get("name").and_then(Json::as_str) == Some("print")));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_luatest")).args(["run", "--trace", "xml", "x.lua"]).output().unwrap();
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "luatest: unknown trace format `xml`, expected text or json\n");
+}
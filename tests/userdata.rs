@@ -0,0 +1,124 @@
+//! Rust types exposed to scripts with `UserDataType`, and what happens when
+//! a method calls back into the object it was called on.
+#![allow(non_local_definitions)]
+
+use std::{cell::Cell, rc::Rc};
+
+use gc::{Finalize, Trace};
+use luatest::{
+    compiler::compile,
+    vm::{
+        convert::FromLua,
+        hostio::MemoryHostIo,
+        userdata::{UserDataClass, UserDataType},
+        GCLuaValue, LuaNativeFunction, LuaVM, LuaValue,
+    },
+};
+
+#[derive(Debug, Trace, Finalize)]
+struct Counter {
+    count: i64,
+}
+
+fn eval(vm: &mut LuaVM, source: &str) -> anyhow::Result<Vec<GCLuaValue>> {
+    let chunk = compile(source.as_bytes(), "=ud").unwrap();
+    vm.process_chunk(chunk)
+}
+fn run(vm: &mut LuaVM, source: &str) -> Vec<String> {
+    eval(vm, source).unwrap().iter().map(|v| v.borrow().as_string(false)).collect()
+}
+fn counters(vm: &mut LuaVM, finalized: Rc<Cell<i64>>) -> UserDataClass<Counter> {
+    UserDataType::<Counter>::new("Counter")
+        .method("get", |_, this, _| Ok(vec![LuaValue::Number(this.count as f64).to_gc()]))
+        .method_mut("add", |_, this, args| {
+            this.count += args.first().map(i64::from_lua).transpose()?.unwrap_or(1);
+            Ok(Vec::new())
+        })
+        // calls `f(self)` while holding the counter
+        .method("with", |vm, _, args| vm.call_value(args[0].clone(), vec![args[1].clone()]))
+        .method_mut("with_mut", |vm, _, args| vm.call_value(args[0].clone(), vec![args[1].clone()]))
+        .index(|_, this, key| {
+            Ok(match &*key.borrow() {
+                LuaValue::String(s) if s == "double" => LuaValue::Number(this.count as f64 * 2.0).to_gc(),
+                _ => LuaValue::Nil.to_gc(),
+            })
+        })
+        .metamethod("__len", |_, _| Ok(vec![LuaValue::Number(1.0).to_gc()]))
+        .tostring(|this| format!("Counter({})", this.count))
+        .eq(|a, b| a.count == b.count)
+        .gc(move |this| finalized.set(finalized.get() + this.count))
+        .register(vm)
+}
+fn vm() -> (LuaVM, Rc<Cell<i64>>) {
+    let mut vm = LuaVM::new();
+    vm.open_base(Rc::new(MemoryHostIo::new()));
+    let finalized = Rc::new(Cell::new(0));
+    let class = counters(&mut vm, finalized.clone());
+    let make = LuaNativeFunction::new("Counter", move |vm, args| {
+        let count = args.first().map(i64::from_lua).transpose()?.unwrap_or(0);
+        Ok(vec![class.create(vm, Counter { count })])
+    });
+    vm.set_global("Counter", LuaValue::NativeFunction(make).to_gc());
+    (vm, finalized)
+}
+
+#[test]
+fn builder() {
+    let (mut vm, _) = vm();
+    let source = "
+        local c = Counter(5)
+        c:add() c:add(10)
+        return c:get(), c.double, c.missing, #c, tostring(c), type(c), c == Counter(16), c == Counter(1)
+    ";
+    assert_eq!(run(&mut vm, source), ["16", "32", "nil", "1", "Counter(16)", "userdata", "true", "false"]);
+    let results = eval(&mut vm, "local c = Counter(2) c:add(3) return c").unwrap();
+    match &*results[0].borrow() {
+        LuaValue::UserData(u) => {
+            assert_eq!(u.borrow::<Counter>().unwrap().count, 5);
+            u.borrow_mut::<Counter>().unwrap().count = 7;
+            assert!(u.borrow::<String>().is_none());
+        }
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(
+        run(&mut vm, "return pcall(Counter(1).get, {})"),
+        ["false", "bad argument #1 to 'get' (Counter expected, got table)"]
+    );
+    assert_eq!(
+        run(&mut vm, "return pcall(Counter(1).add, Counter(1), 'x')"),
+        ["false", "number expected, got string"]
+    );
+}
+
+#[test]
+fn reentering_a_borrowed_object_is_an_error() {
+    let (mut vm, _) = vm();
+    // reading while reading is fine
+    assert_eq!(run(&mut vm, "local c = Counter(3) return c:with(function(c) return c:get(), c.double, tostring(c) end, c)"), ["3", "6", "Counter(3)"]);
+    let source = "local c = Counter(3) return pcall(c.with, c, function(c) c:add() end, c)";
+    assert_eq!(run(&mut vm, source), ["false", "ud:1: userdata already borrowed"]);
+    let source = "local c = Counter(3) return pcall(c.with_mut, c, function(c) return c:get() end, c)";
+    assert_eq!(run(&mut vm, source), ["false", "ud:1: userdata already borrowed"]);
+    let source = "local c = Counter(3) return pcall(c.with_mut, c, function(c) return tostring(c) end, c)";
+    assert_eq!(run(&mut vm, source), ["false", "ud:1: userdata already borrowed"]);
+    // the metatable is not part of the borrow, nor are other objects
+    let source = "
+        local c, d = Counter(3), Counter(4)
+        c:with_mut(function(c) d:add(c == c and 1 or 0) end, c)
+        return getmetatable(c) == getmetatable(d), d:get(), c:get()
+    ";
+    assert_eq!(run(&mut vm, source), ["true", "5", "3"]);
+    // and the borrow ends with the method, error or not
+    assert_eq!(run(&mut vm, "local c = Counter(3) pcall(c.with_mut, c, error, 'x') c:add() return c:get()"), ["4"]);
+}
+
+#[test]
+fn finalizers_run_on_collection() {
+    let (mut vm, finalized) = vm();
+    run(&mut vm, "keep = Counter(1) local drop = Counter(10)");
+    vm.collect_garbage();
+    assert_eq!(finalized.get(), 10);
+    run(&mut vm, "keep = nil");
+    vm.collect_garbage();
+    assert_eq!(finalized.get(), 11);
+}
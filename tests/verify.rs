@@ -0,0 +1,127 @@
+//! The bytecode verifier: whatever the compiler emits passes, and chunks
+//! patched to reach outside their stack, constants or code are refused.
+
+use std::fs;
+
+use luatest::{
+    compiler::compile,
+    vm::{
+        chunk_parser::FunctionBlock,
+        instruction::{VMInst, VMOpcode},
+        verify::verify,
+    },
+};
+
+fn abc(op: VMOpcode, a: u32, b: u32, c: u32) -> VMInst {
+    VMInst::from_u32(op.to_num() | a << 6 | c << 14 | b << 23).unwrap()
+}
+fn compiled(source: &str) -> FunctionBlock {
+    compile(source.as_bytes(), "=verify").unwrap().func
+}
+/// The verifier's complaint about `f`, with the context it was found in.
+fn rejection(f: &FunctionBlock) -> String {
+    format!("{:#}", verify(f).unwrap_err())
+}
+
+#[test]
+fn compiled_code_passes() {
+    let sources = [
+        "local a, b = ...\nreturn a",
+        "local function f(...) local a, b = ... return a end",
+        "local a, b, c, d, e = ... return e",
+        "return ...",
+        "local t = {...} return #t",
+        "local t = {1, 2, ...} return t",
+        "local function f(...) return select('#', ...) end return f(...)",
+        "print(...)",
+        "local x = (...) return x",
+        "local function g() return 1, 2, 3 end local a, b = g() return f(g())",
+        "return f(1, 2, 3), g(...)",
+        "local a = {} a.b.c:d(1, ...)",
+        "for k, v in pairs(...) do print(k, v) end",
+    ];
+    for source in sources {
+        verify(&compiled(source)).unwrap_or_else(|e| panic!("{:?} rejected: {:#}", source, e));
+    }
+    for entry in fs::read_dir("tests/compiler").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|e| e == "lua") {
+            let chunk = compile(&fs::read(&path).unwrap(), "=verify").unwrap();
+            verify(&chunk.func).unwrap_or_else(|e| panic!("{} rejected: {:#}", path.display(), e));
+        }
+    }
+}
+
+#[test]
+fn vararg_out_of_the_stack() {
+    // VARARG 0 3 puts two values in registers 0 and 1
+    let mut f = compiled("local a, b = ...\nreturn a");
+    assert_eq!(f.list_instructions[0].to_u32(), abc(VMOpcode::VARARG, 0, 3, 0).to_u32());
+    assert_eq!(f.max_stack_size, 2);
+    f.list_instructions[0] = abc(VMOpcode::VARARG, 0, 4, 0);
+    assert_eq!(rejection(&f), "pc 1: register 2 is out of the stack of 2");
+    f.list_instructions[0] = abc(VMOpcode::VARARG, 1, 3, 0);
+    assert_eq!(rejection(&f), "pc 1: register 2 is out of the stack of 2");
+    // no values at all
+    f.list_instructions[0] = abc(VMOpcode::VARARG, 1, 1, 0);
+    verify(&f).unwrap();
+
+    let mut f = compiled("local a, b = ...\nreturn a");
+    f.is_vararg = 0;
+    assert_eq!(rejection(&f), "pc 1: VARARG in a function without `...`");
+
+    // open results must be taken by a CALL, TAILCALL, RETURN or SETLIST
+    let mut f = compiled("return ...");
+    f.list_instructions[1] = abc(VMOpcode::RETURN, 0, 2, 0);
+    assert_eq!(rejection(&f), "pc 1: open results are not used up by the next instruction");
+}
+
+#[test]
+fn calls_out_of_the_stack() {
+    let mut f = compiled("local x = f(1, 2)");
+    let call = f.list_instructions.iter().position(|i| matches!(i.opcode, VMOpcode::CALL)).unwrap();
+    let size = f.max_stack_size as u32;
+    let pc = call + 1;
+    // arguments past the top of the stack
+    f.list_instructions[call] = abc(VMOpcode::CALL, 0, size + 1, 2);
+    assert_eq!(rejection(&f), format!("pc {}: register {} is out of the stack of {}", pc, size, size));
+    // results past the top of the stack
+    f.list_instructions[call] = abc(VMOpcode::CALL, 0, 3, size + 2);
+    assert_eq!(rejection(&f), format!("pc {}: register {} is out of the stack of {}", pc, size, size));
+    f.list_instructions[call] = abc(VMOpcode::CALL, 0, 3, 0);
+    assert_eq!(rejection(&f), format!("pc {}: open results are not used up by the next instruction", pc));
+
+    let mut f = compiled("local a = 1 return a");
+    let ret = f.list_instructions.len() - 2;
+    f.list_instructions[ret] = abc(VMOpcode::RETURN, 0, f.max_stack_size as u32 + 2, 0);
+    assert_eq!(rejection(&f), format!("pc {}: register 2 is out of the stack of 2", ret + 1));
+}
+
+#[test]
+fn crafted_chunks_are_refused() {
+    let mut f = compiled("local a = 'x'");
+    f.list_instructions[0] = VMInst::from_u32(VMOpcode::LOADK.to_num() | 5 << 14).unwrap();
+    assert_eq!(rejection(&f), "pc 1: constant 5 is out of 1");
+
+    let mut f = compiled("local a = 'x'");
+    f.list_instructions[0] = abc(VMOpcode::GETUPVAL, 0, 0, 0);
+    assert_eq!(rejection(&f), "pc 1: upvalue 0 is out of 0");
+
+    let mut f = compiled("while x do end");
+    let jmp = f.list_instructions.iter().position(|i| matches!(i.opcode, VMOpcode::JMP)).unwrap();
+    f.list_instructions[jmp] = VMInst::from_u32(VMOpcode::JMP.to_num() | (131071 + 40) << 14).unwrap();
+    assert_eq!(rejection(&f), format!("pc {}: jump to {} is out of the code", jmp + 1, jmp + 42));
+
+    let mut f = compiled("local a = 1");
+    f.list_instructions.pop();
+    f.line_info.pop();
+    assert_eq!(rejection(&f), "code does not end in RETURN");
+
+    // the checks reach nested functions; register 0 of `g` is its `arg`
+    let mut f = compiled("local function g(...) local a, b = ... return a end");
+    let mut g = (*f.list_fnproto[0]).clone();
+    assert_eq!(g.list_instructions[0].to_u32(), abc(VMOpcode::VARARG, 1, 3, 0).to_u32());
+    g.list_instructions[0] = abc(VMOpcode::VARARG, 1, 4, 0);
+    f.list_fnproto[0] = gc::Gc::new(g);
+    assert_eq!(rejection(&f), "function 0 defined at line 1: pc 1: register 3 is out of the stack of 3");
+}